@@ -0,0 +1,148 @@
+/*
+ * Copyright (c) 2023 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+use log::warn;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// A ref created, updated or deleted on a destination
+#[derive(Serialize)]
+struct RefChange<'a> {
+    time: String,
+    origin: &'a str,
+    destination: &'a str,
+    #[serde(rename = "ref")]
+    name: &'a str,
+    /// All zeros if the ref was created
+    old: String,
+    /// All zeros if the ref was deleted
+    new: String,
+}
+
+/// Append-only ledger of the ref changes on the destinations, one JSON line per change.
+/// Concurrent jobs and runs only ever append whole lines.
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    /// Open the log for appending, creating it if needed
+    pub fn open(path: &Path) -> Result<AuditLog, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("Unable to open audit log {path:?} ({e})"))?;
+        Ok(AuditLog {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Record the differences between the refs of the destination before and after the push
+    pub(crate) fn record(
+        &self,
+        origin: &str,
+        destination: &str,
+        before: &BTreeMap<String, String>,
+        after: &BTreeMap<String, String>,
+    ) {
+        let time = OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_default();
+        let lines: String = changes(before, after)
+            .into_iter()
+            .map(|(name, old, new)| RefChange {
+                time: time.clone(),
+                origin,
+                destination,
+                name,
+                old,
+                new,
+            })
+            .filter_map(|c| serde_json::to_string(&c).ok())
+            .map(|l| l + "\n")
+            .collect();
+        if lines.is_empty() {
+            return;
+        }
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        // A single write, so the lines of concurrent writers don't interleave
+        if let Err(e) = file.write_all(lines.as_bytes()) {
+            warn!(
+                "Unable to write the ref changes of {} to the audit log ({})",
+                destination, e
+            );
+        }
+    }
+}
+
+/// Get the changed refs with their old and new object ids, without `HEAD` and the peeled tags
+fn changes<'a>(
+    before: &'a BTreeMap<String, String>,
+    after: &'a BTreeMap<String, String>,
+) -> Vec<(&'a str, String, String)> {
+    let zero = |id: &str| "0".repeat(id.len());
+    let mut names: Vec<&str> = before
+        .keys()
+        .chain(after.keys())
+        .map(String::as_str)
+        .collect();
+    names.sort_unstable();
+    names.dedup();
+    names
+        .into_iter()
+        .filter(|n| n.starts_with("refs/") && !n.ends_with("^{}"))
+        .filter_map(|n| match (before.get(n), after.get(n)) {
+            (Some(old), Some(new)) if old != new => Some((n, old.clone(), new.clone())),
+            (Some(old), None) => Some((n, old.clone(), zero(old))),
+            (None, Some(new)) => Some((n, zero(new), new.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ref_changes() {
+        let refs = |r: &[(&str, &str)]| -> BTreeMap<String, String> {
+            r.iter()
+                .map(|(n, id)| (n.to_string(), id.to_string()))
+                .collect()
+        };
+        let before = refs(&[
+            ("HEAD", "aa"),
+            ("refs/heads/main", "aa"),
+            ("refs/heads/old", "bb"),
+            ("refs/tags/v1", "cc"),
+            ("refs/tags/v1^{}", "aa"),
+        ]);
+        let after = refs(&[
+            ("HEAD", "dd"),
+            ("refs/heads/main", "dd"),
+            ("refs/heads/new", "ee"),
+            ("refs/tags/v1", "cc"),
+            ("refs/tags/v1^{}", "aa"),
+        ]);
+        assert_eq!(
+            changes(&before, &after),
+            vec![
+                ("refs/heads/main", "aa".to_string(), "dd".to_string()),
+                ("refs/heads/new", "00".to_string(), "ee".to_string()),
+                ("refs/heads/old", "bb".to_string(), "00".to_string()),
+            ]
+        );
+        assert!(changes(&before, &before).is_empty());
+    }
+}
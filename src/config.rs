@@ -0,0 +1,144 @@
+/*
+ * Copyright (c) 2023 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Deserializer};
+
+use crate::filter::RepoPattern;
+use crate::provider::{Mirror, MirrorError, MirrorResult};
+use crate::rewrite::repo_path;
+
+/// Options by their long name on the command line (e.g. `worker-count`)
+pub type ConfigOptions = BTreeMap<String, serde_yaml::Value>;
+
+/// Content of the YAML file given with `--config`
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    /// Options of all runs
+    #[serde(default)]
+    pub options: ConfigOptions,
+    /// Options of each provider or group to mirror, one run each
+    #[serde(default)]
+    pub sources: Vec<ConfigOptions>,
+    /// Overrides of the listed repositories, the first matching one is applied
+    #[serde(default)]
+    pub repos: Vec<RepoOverride>,
+}
+
+impl ConfigFile {
+    pub fn read(path: &Path) -> Result<ConfigFile, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read config file {path:?} ({e})"))?;
+        serde_yaml::from_str(&content).map_err(|e| format!("Invalid config file {path:?} ({e})"))
+    }
+}
+
+/// Settings replacing the ones of the repositories selected by `path`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RepoOverride {
+    /// Glob matched against the path of the destination as listed by the provider
+    #[serde(deserialize_with = "glob")]
+    pub path: RepoPattern,
+    pub refspec: Option<Vec<String>>,
+    pub destination: Option<String>,
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+fn glob<'de, D: Deserializer<'de>>(d: D) -> Result<RepoPattern, D::Error> {
+    RepoPattern::parse_glob(&String::deserialize(d)?).map_err(serde::de::Error::custom)
+}
+
+impl RepoOverride {
+    fn apply(&self, mut m: Mirror) -> MirrorResult {
+        if self.disabled {
+            return Err(MirrorError::Skip(m.destination));
+        }
+        if let Some(ref refspec) = self.refspec {
+            m.refspec = Some(refspec.clone());
+            m.prune_refs = false;
+        }
+        if let Some(ref destination) = self.destination {
+            m.destination = destination.clone();
+        }
+        Ok(m)
+    }
+}
+
+/// Apply the first override matching the listed repository
+pub fn apply_overrides(overrides: &[RepoOverride], m: MirrorResult) -> MirrorResult {
+    let m = m?;
+    let path = repo_path(&m.destination);
+    match overrides.iter().find(|o| o.path.matches(path)) {
+        Some(o) => o.apply(m),
+        None => Ok(m),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mirror(destination: &str) -> MirrorResult {
+        Ok(Mirror {
+            origin: "https://example.com/upstream/app.git".to_string(),
+            destination: destination.to_string(),
+            refspec: None,
+            prune_refs: false,
+            lfs: false,
+            has_wiki: false,
+            wiki: false,
+            dest_token: None,
+            project: None,
+            visibility: None,
+            id: None,
+            subtree_prefix: None,
+            size: None,
+            stars: None,
+            forks: None,
+            default_branch: None,
+            archived: false,
+            fork: false,
+            activity: None,
+        })
+    }
+
+    #[test]
+    fn overrides() {
+        let config: ConfigFile = serde_yaml::from_str(
+            r#"
+            repos:
+              - path: team/app
+                refspec: ["+refs/heads/main:refs/heads/main"]
+                destination: git@example.org:mirror/app.git
+              - path: "team/**"
+                disabled: true
+            "#,
+        )
+        .unwrap();
+        let overrides = &config.repos;
+
+        let m = apply_overrides(overrides, mirror("git@example.com:team/app.git")).unwrap();
+        assert_eq!(m.destination, "git@example.org:mirror/app.git");
+        assert_eq!(
+            m.refspec.as_deref(),
+            Some(&["+refs/heads/main:refs/heads/main".to_string()][..])
+        );
+        assert!(matches!(
+            apply_overrides(overrides, mirror("git@example.com:team/b.git")),
+            Err(MirrorError::Skip(url)) if url == "git@example.com:team/b.git"
+        ));
+        let m = apply_overrides(overrides, mirror("git@example.com:other/b.git")).unwrap();
+        assert_eq!(m.destination, "git@example.com:other/b.git");
+
+        assert!(serde_yaml::from_str::<ConfigFile>("repos: [{path: a, refspecs: []}]").is_err());
+    }
+}
@@ -0,0 +1,301 @@
+/*
+ * Copyright (c) 2023 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::collections::BTreeSet;
+use std::io::Read;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Used for error and debug logging
+use log::{error, info, trace, warn};
+
+use prometheus::{Encoder, TextEncoder};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::error::{GitMirrorError, Result};
+use crate::provider::{Mirror, MirrorResult, Provider};
+use crate::summary::Summary;
+use crate::webhook::{self, Hook, WebhookError};
+use crate::{listed_jobs, run, MirrorOptions};
+
+/// Largest webhook payload that is read, GitHub doesn't send larger ones
+const MAX_WEBHOOK_BODY: u64 = 25 << 20;
+
+/// State of the daemon as reported by `/status`
+#[derive(Serialize, Debug, Default)]
+struct Status {
+    /// Number of completed runs
+    runs: u64,
+    /// Number of completed runs triggered by webhooks
+    webhook_runs: u64,
+    running: bool,
+    /// End of the last run (RFC 3339)
+    last_run_finished_at: Option<String>,
+    last_run: Option<Summary>,
+}
+
+/// Answer a request for `url` with the status code, content type and body
+fn respond(url: &str, status: &Mutex<Status>) -> (u16, String, String) {
+    match url.split('?').next().unwrap_or_default() {
+        "/healthz" => (200, "text/plain".to_string(), "ok\n".to_string()),
+        "/metrics" => {
+            let encoder = TextEncoder::new();
+            let mut buffer = Vec::new();
+            match encoder.encode(&prometheus::gather(), &mut buffer) {
+                Ok(()) => (
+                    200,
+                    encoder.format_type().to_string(),
+                    String::from_utf8_lossy(&buffer).into_owned(),
+                ),
+                Err(e) => (
+                    500,
+                    "text/plain".to_string(),
+                    format!("Unable to encode metrics ({e})\n"),
+                ),
+            }
+        }
+        "/status" => {
+            let status = status.lock().unwrap();
+            (
+                200,
+                "application/json".to_string(),
+                serde_json::to_string(&*status).expect("Unable to serialize status"),
+            )
+        }
+        _ => (404, "text/plain".to_string(), "not found\n".to_string()),
+    }
+}
+
+/// Queue the jobs of the repository a webhook reports a push to, answering with the status code
+/// and a message
+fn webhook(request: &mut Request, secret: &str, queue: &Sender<Vec<Mirror>>) -> (u16, String) {
+    let mut body = Vec::new();
+    if let Err(e) = request
+        .as_reader()
+        .take(MAX_WEBHOOK_BODY)
+        .read_to_end(&mut body)
+    {
+        return (400, format!("Unable to read the webhook ({e})\n"));
+    }
+    let header = |name: &str| {
+        request
+            .headers()
+            .iter()
+            .find(|h| h.field.as_str().as_str().eq_ignore_ascii_case(name))
+            .map(|h| h.value.to_string())
+    };
+    let push = match webhook::parse(header, &body, secret) {
+        Ok(Hook::Push(push)) => push,
+        Ok(Hook::Ignored(event)) => {
+            trace!("Ignoring webhook event {}", event);
+            return (200, format!("Ignored {event} event\n"));
+        }
+        Err(e @ WebhookError::Unauthorized) => {
+            warn!("Rejected webhook from {:?}: {}", request.remote_addr(), e);
+            return (401, format!("{e}\n"));
+        }
+        Err(e) => return (400, format!("{e}\n")),
+    };
+    let jobs: Vec<Mirror> = listed_jobs()
+        .into_iter()
+        .filter(|m| push.matches(&m.origin))
+        // Listed before the push, so `--only-changed` can't rely on it
+        .map(|m| Mirror {
+            activity: None,
+            ..m
+        })
+        .collect();
+    if jobs.is_empty() {
+        info!(
+            "Webhook of {}/{} matches no listed repository",
+            push.host, push.path
+        );
+        return (
+            404,
+            format!("No listed repository mirrors {}/{}\n", push.host, push.path),
+        );
+    }
+    info!(
+        "Webhook of {}/{}, queueing {} job(s)",
+        push.host,
+        push.path,
+        jobs.len()
+    );
+    let n = jobs.len();
+    match queue.send(jobs) {
+        Ok(()) => (202, format!("Queued {n} job(s)\n")),
+        Err(_) => (503, "The daemon is stopping\n".to_string()),
+    }
+}
+
+fn serve(server: Server, status: Arc<Mutex<Status>>, hooks: Option<(String, Sender<Vec<Mirror>>)>) {
+    for mut request in server.incoming_requests() {
+        trace!("HTTP request: {} {}", request.method(), request.url());
+        let is_webhook = request.method() == &Method::Post
+            && request.url().split('?').next() == Some("/webhook");
+        let (code, content_type, body) = match hooks {
+            Some((ref secret, ref queue)) if is_webhook => {
+                let (code, body) = webhook(&mut request, secret, queue);
+                (code, "text/plain".to_string(), body)
+            }
+            _ => respond(request.url(), &status),
+        };
+        let mut response = Response::from_string(body).with_status_code(code);
+        if let Ok(header) = Header::from_bytes("Content-Type", content_type) {
+            response = response.with_header(header);
+        }
+        if let Err(e) = request.respond(response) {
+            warn!("Unable to send HTTP response ({})", e);
+        }
+    }
+}
+
+/// Time to wait after a run that took `took` until the next run and the number of skipped runs.
+/// Runs start on multiples of `interval` after the start of the previous run, the ones that would
+/// have started while the previous run was still running are skipped instead of overlapping.
+fn next_run(took: Duration, interval: Duration) -> (Duration, u64) {
+    if interval.is_zero() || took <= interval {
+        return (interval.saturating_sub(took), 0);
+    }
+    let skipped = ((took.as_nanos() - 1) / interval.as_nanos()) as u64;
+    let next = interval.as_nanos() * (u128::from(skipped) + 1) - took.as_nanos();
+    (Duration::from_nanos(next as u64), skipped)
+}
+
+/// Run a sync of all repositories, or only of `jobs`, and record it in the status
+fn sync(
+    provider: &dyn Provider,
+    opts: &MirrorOptions,
+    jobs: Option<Vec<MirrorResult>>,
+    status: &Mutex<Status>,
+) {
+    let start = Instant::now();
+    let triggered = jobs.is_some();
+    status.lock().unwrap().running = true;
+
+    let (result, summary) = panic::catch_unwind(AssertUnwindSafe(|| run(provider, opts, jobs)))
+        .unwrap_or_else(|_| {
+            let result = Err(GitMirrorError::GenericError(
+                "Sync run panicked".to_string(),
+            ));
+            let mut summary = Summary::default();
+            summary.finish(start.elapsed(), &result);
+            (result, summary)
+        });
+
+    match result {
+        Ok(()) => info!("Sync run finished"),
+        Err(e) => error!("Sync run failed: {}", e),
+    }
+
+    let mut status = status.lock().unwrap();
+    if triggered {
+        status.webhook_runs += 1;
+    } else {
+        status.runs += 1;
+    }
+    status.running = false;
+    status.last_run_finished_at = OffsetDateTime::now_utc().format(&Rfc3339).ok();
+    status.last_run = Some(summary);
+}
+
+/// Sync the repositories of the provider every `interval`, while serving `/healthz`,
+/// `/metrics` and `/status` on `listen`. With a `webhook_secret` the pushes reported to
+/// `/webhook` are synced right away, between the runs. Only returns if the HTTP server can't be
+/// started, failed runs are reported and retried on the next interval.
+pub fn run_daemon(
+    provider: Box<dyn Provider>,
+    opts: &MirrorOptions,
+    interval: Duration,
+    listen: &str,
+    webhook_secret: Option<String>,
+) -> Result<()> {
+    let server = Server::http(listen)
+        .map_err(|e| GitMirrorError::GenericError(format!("Unable to listen on {listen} ({e})")))?;
+    match webhook_secret {
+        Some(_) => info!(
+            "Serving /healthz, /metrics, /status and /webhook on {}",
+            listen
+        ),
+        None => info!("Serving /healthz, /metrics and /status on {}", listen),
+    }
+
+    let status = Arc::new(Mutex::new(Status::default()));
+    // The sender is kept here, so waiting for webhooks works the same without them
+    let (queue, queued) = mpsc::channel::<Vec<Mirror>>();
+    {
+        let status = status.clone();
+        let hooks = webhook_secret.map(|secret| (secret, queue.clone()));
+        thread::spawn(move || serve(server, status, hooks));
+    }
+
+    loop {
+        let start = Instant::now();
+        sync(provider.as_ref(), opts, None, &status);
+
+        let (next, skipped) = next_run(start.elapsed(), interval);
+        if skipped > 0 {
+            warn!(
+                "Sync run took longer than the interval of {:?}, skipped {} run(s)",
+                interval, skipped
+            );
+        }
+        info!("Next sync run in {:?}", next);
+        let next = Instant::now() + next;
+        while let Ok(mut jobs) = queued.recv_timeout(next.saturating_duration_since(Instant::now()))
+        {
+            // Pushes queued during a run are synced together, each repository once
+            jobs.extend(queued.try_iter().flatten());
+            let mut seen = BTreeSet::new();
+            jobs.retain(|m| seen.insert(m.destination.clone()));
+            info!("Sync run of {} job(s) triggered by webhooks", jobs.len());
+            sync(
+                provider.as_ref(),
+                opts,
+                Some(jobs.into_iter().map(Ok).collect()),
+                &status,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes() {
+        let status = Mutex::new(Status::default());
+
+        assert_eq!(respond("/healthz", &status).0, 200);
+        assert_eq!(respond("/metrics", &status).0, 200);
+        assert_eq!(respond("/unknown", &status).0, 404);
+
+        let (code, content_type, body) = respond("/status?pretty", &status);
+        assert_eq!((code, content_type.as_str()), (200, "application/json"));
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["runs"], 0);
+        assert!(body["last_run"].is_null());
+    }
+
+    #[test]
+    fn skip_overlapping_runs() {
+        let hour = Duration::from_secs(3600);
+        let mins = |m: u64| Duration::from_secs(m * 60);
+
+        assert_eq!(next_run(mins(20), hour), (mins(40), 0));
+        assert_eq!(next_run(hour, hour), (Duration::ZERO, 0));
+        assert_eq!(next_run(mins(70), hour), (mins(50), 1));
+        assert_eq!(next_run(mins(150), hour), (mins(30), 2));
+        assert_eq!(next_run(mins(180), hour), (Duration::ZERO, 2));
+        assert_eq!(next_run(mins(5), Duration::ZERO), (Duration::ZERO, 0));
+    }
+}
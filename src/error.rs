@@ -1,4 +1,4 @@
-use crate::git::GitError;
+use crate::git::{GitError, GitFailureKind};
 use crate::provider::MirrorError;
 use thiserror::Error;
 
@@ -12,17 +12,37 @@ pub enum GitMirrorError {
     MirrorError(#[from] MirrorError),
     #[error("{0} sync tasks failed")]
     SyncError(usize),
+    #[error("Another instance is already running, unable to lock {0}")]
+    Locked(String),
 }
 
-impl From<GitMirrorError> for i32 {
-    fn from(mirror: GitMirrorError) -> i32 {
-        match mirror {
+impl GitMirrorError {
+    /// Exit code of the executable for this error
+    pub fn exit_code(&self) -> i32 {
+        match self {
             GitMirrorError::SyncError(_) => 1,
             GitMirrorError::GenericError(_) => 2,
             GitMirrorError::GitError(_) => 3,
             GitMirrorError::MirrorError(_) => 4,
+            GitMirrorError::Locked(_) => 5,
+        }
+    }
+}
+
+impl GitMirrorError {
+    /// Cause of a failed sync job, `None` for errors not caused by a git command
+    pub fn failure_kind(&self) -> Option<GitFailureKind> {
+        match self {
+            GitMirrorError::GitError(e) => Some(e.kind()),
+            _ => None,
         }
     }
 }
 
+impl From<GitMirrorError> for i32 {
+    fn from(mirror: GitMirrorError) -> i32 {
+        mirror.exit_code()
+    }
+}
+
 pub type Result<T> = core::result::Result<T, GitMirrorError>;
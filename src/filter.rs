@@ -0,0 +1,117 @@
+/*
+ * Copyright (c) 2023 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use regex::Regex;
+
+/// Pattern selecting repositories by the path of their destination (e.g. `group/project`)
+#[derive(Debug, Clone)]
+pub struct RepoPattern {
+    /// The pattern as given
+    source: String,
+    regex: Regex,
+}
+
+impl RepoPattern {
+    /// Parse a glob, `*` matches within a path segment, `**` across segments and `?` a single
+    /// character. The glob has to match the whole path.
+    pub fn parse_glob(s: &str) -> Result<RepoPattern, String> {
+        if s.is_empty() {
+            return Err("Empty repository pattern".to_string());
+        }
+        let mut pattern = String::from("^");
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    pattern.push_str(".*");
+                }
+                '*' => pattern.push_str("[^/]*"),
+                '?' => pattern.push_str("[^/]"),
+                c => pattern.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        pattern.push('$');
+        let regex =
+            Regex::new(&pattern).map_err(|e| format!("Invalid repository pattern: {s} ({e})"))?;
+        Ok(RepoPattern {
+            source: s.to_owned(),
+            regex,
+        })
+    }
+
+    /// Parse a regex, matching anywhere in the path unless anchored
+    pub fn parse_regex(s: &str) -> Result<RepoPattern, String> {
+        let regex = Regex::new(s).map_err(|e| format!("Invalid repository regex: {s} ({e})"))?;
+        Ok(RepoPattern {
+            source: s.to_owned(),
+            regex,
+        })
+    }
+
+    /// Check if the repository path matches the pattern
+    pub fn matches(&self, path: &str) -> bool {
+        self.regex.is_match(path)
+    }
+}
+
+impl std::fmt::Display for RepoPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+/// Get the reason a repository at `path` isn't selected by the `includes` and `excludes`.
+/// Without includes all repositories are included, excludes take precedence.
+pub fn excluded(includes: &[RepoPattern], excludes: &[RepoPattern], path: &str) -> Option<String> {
+    if !includes.is_empty() && !includes.iter().any(|p| p.matches(path)) {
+        return Some("not included".to_string());
+    }
+    excludes
+        .iter()
+        .find(|p| p.matches(path))
+        .map(|p| format!("excluded by {p}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob() {
+        let p = RepoPattern::parse_glob("group/*-legacy").unwrap();
+        assert!(p.matches("group/app-legacy"));
+        assert!(!p.matches("group/sub/app-legacy"));
+        assert!(!p.matches("other/group/app-legacy"));
+
+        let p = RepoPattern::parse_glob("group/**").unwrap();
+        assert!(p.matches("group/sub/app"));
+        assert!(!p.matches("groups/app"));
+
+        let p = RepoPattern::parse_glob("a.b?").unwrap();
+        assert!(p.matches("a.bc"));
+        assert!(!p.matches("axbc"));
+        assert!(RepoPattern::parse_glob("").is_err());
+    }
+
+    #[test]
+    fn include_and_exclude() {
+        let includes = vec![RepoPattern::parse_glob("team/**").unwrap()];
+        let excludes = vec![RepoPattern::parse_regex("(^|/)sandbox-").unwrap()];
+
+        assert_eq!(excluded(&includes, &excludes, "team/app"), None);
+        assert_eq!(
+            excluded(&includes, &excludes, "other/app").as_deref(),
+            Some("not included")
+        );
+        assert_eq!(
+            excluded(&includes, &excludes, "team/sandbox-x").as_deref(),
+            Some("excluded by (^|/)sandbox-")
+        );
+        assert_eq!(excluded(&[], &excludes, "other/app"), None);
+        assert!(RepoPattern::parse_regex("(").is_err());
+    }
+}
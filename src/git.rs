@@ -4,98 +4,1185 @@
  * SPDX-License-Identifier:     MIT
  */
 
-use std::path::Path;
-use std::process::Command;
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
-use log::debug;
+use log::{debug, info, warn};
+
+use slug::slugify;
+
+use crate::repo_log::RepoLog;
+use crate::transfer;
+
+#[cfg(feature = "libgit2")]
+mod libgit2;
 
 /// An error occuring during git command execution
 #[derive(Debug, Error)]
 pub enum GitError {
     #[error("Command {cmd:?} failed with error: {err}")]
-    CommandError { cmd: Command, err: std::io::Error },
+    CommandError {
+        cmd: Box<Command>,
+        err: std::io::Error,
+    },
     #[error("Command {cmd:?} failed with exit code: {code}, Stderr: {stderr}")]
     GitCommandError {
         code: i32,
+        stdout: String,
         stderr: String,
-        cmd: Command,
+        cmd: Box<Command>,
     },
+    #[error("Destination rejected refs: {}", refs.join(", "))]
+    RefsRejected { refs: Vec<String> },
+    #[error("Destination diverged, not overwriting refs: {}", refs.join(", "))]
+    Diverged { refs: Vec<String> },
+    #[error("Unable to {what} ({err})")]
+    IoError { what: String, err: std::io::Error },
+    #[error("Command {cmd:?} killed, the repository exceeded its time budget of {}", humantime::format_duration(*budget))]
+    TimedOut { cmd: Box<Command>, budget: Duration },
+    #[cfg(feature = "libgit2")]
+    #[error("libgit2 {op} of {url} failed: {err}")]
+    Libgit2Error {
+        op: String,
+        url: String,
+        err: git2::Error,
+    },
+}
+
+/// Cause of a failed git command, derived from its stderr
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum GitFailureKind {
+    /// Credentials missing or rejected
+    Auth,
+    /// The SSH host key is unknown or changed
+    HostKey,
+    /// The repository doesn't exist (or isn't visible with the credentials)
+    NotFound,
+    /// Connection problems and timeouts
+    Network,
+    /// The sync took longer than its time budget
+    Timeout,
+    /// The destination has commits the origin doesn't have, which a push without force doesn't
+    /// overwrite
+    Diverged,
+    /// Anything else
+    Other,
+}
+
+impl GitFailureKind {
+    /// Classify a failure by the stderr of the git command
+    pub fn classify(stderr: &str) -> GitFailureKind {
+        let stderr = stderr.to_lowercase();
+        let any = |patterns: &[&str]| patterns.iter().any(|p| stderr.contains(p));
+        if any(&[
+            "host key verification failed",
+            "remote host identification has changed",
+            "no matching host key",
+        ]) {
+            GitFailureKind::HostKey
+        } else if any(&[
+            "authentication failed",
+            "permission denied",
+            "could not read username",
+            "could not read password",
+            "access denied",
+            "invalid username or password",
+            "returned error: 401",
+            "returned error: 403",
+        ]) {
+            GitFailureKind::Auth
+        } else if (stderr.contains("repository '") && any(&["' not found", "' does not exist"]))
+            || any(&[
+                "repository not found",
+                "does not appear to be a git repository",
+                "project not found",
+                "returned error: 404",
+            ])
+        {
+            GitFailureKind::NotFound
+        } else if any(&["(non-fast-forward)", "(fetch first)", "(already exists)"]) {
+            GitFailureKind::Diverged
+        } else if any(&[
+            "could not resolve host",
+            "connection timed out",
+            "operation timed out",
+            "connection refused",
+            "connection reset",
+            "network is unreachable",
+            "failed to connect",
+            "the remote end hung up unexpectedly",
+            "early eof",
+            "rpc failed",
+        ]) {
+            GitFailureKind::Network
+        } else {
+            GitFailureKind::Other
+        }
+    }
+
+    /// Whether retrying can help. Unknown failures are assumed to be transient.
+    pub fn is_transient(self) -> bool {
+        matches!(self, GitFailureKind::Network | GitFailureKind::Other)
+    }
+}
+
+impl fmt::Display for GitFailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            GitFailureKind::Auth => "auth",
+            GitFailureKind::HostKey => "host_key",
+            GitFailureKind::NotFound => "not_found",
+            GitFailureKind::Network => "network",
+            GitFailureKind::Timeout => "timeout",
+            GitFailureKind::Diverged => "diverged",
+            GitFailureKind::Other => "other",
+        })
+    }
+}
+
+impl GitError {
+    pub fn kind(&self) -> GitFailureKind {
+        match self {
+            GitError::GitCommandError { stderr, .. } => GitFailureKind::classify(stderr),
+            GitError::TimedOut { .. } => GitFailureKind::Timeout,
+            GitError::Diverged { .. } => GitFailureKind::Diverged,
+            #[cfg(feature = "libgit2")]
+            GitError::Libgit2Error { err, .. } => libgit2::failure_kind(err),
+            GitError::CommandError { .. }
+            | GitError::RefsRejected { .. }
+            | GitError::IoError { .. } => GitFailureKind::Other,
+        }
+    }
+}
+
+/// Time limit for the git commands of a sync job
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline {
+    pub at: Instant,
+    /// Total time granted to the job, reported when it is exceeded
+    pub budget: Duration,
+}
+
+impl Deadline {
+    pub fn after(budget: Duration) -> Deadline {
+        Deadline {
+            at: Instant::now() + budget,
+            budget,
+        }
+    }
+}
+
+thread_local! {
+    static DEADLINE: Cell<Option<Deadline>> = const { Cell::new(None) };
+}
+
+/// Restores the deadline of the enclosing scope
+struct DeadlineGuard(Option<Deadline>);
+
+impl Drop for DeadlineGuard {
+    fn drop(&mut self) {
+        DEADLINE.with(|d| d.set(self.0));
+    }
+}
+
+/// Run `f`, killing the git commands it runs on this thread once `deadline` is reached.
+/// Commands started after the deadline fail right away.
+pub fn with_deadline<T>(deadline: Option<Deadline>, f: impl FnOnce() -> T) -> T {
+    let _guard = DeadlineGuard(DEADLINE.with(|d| d.replace(deadline)));
+    f()
+}
+
+/// How often a command running against a deadline is checked
+const DEADLINE_POLL: Duration = Duration::from_millis(50);
+
+/// Like `Command::output`, but kill the command at `deadline`. Returns `None` if it was killed.
+fn output_until(
+    cmd: &mut Command,
+    input: Option<&str>,
+    deadline: Instant,
+) -> std::io::Result<Option<Output>> {
+    let mut child = cmd
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let (Some(mut stdin), Some(input)) = (child.stdin.take(), input) {
+        stdin.write_all(input.as_bytes())?;
+    }
+    fn read_all(pipe: Option<impl Read + Send + 'static>) -> thread::JoinHandle<Vec<u8>> {
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.read_to_end(&mut buf);
+            }
+            buf
+        })
+    }
+    let stdout = read_all(child.stdout.take());
+    let stderr = read_all(child.stderr.take());
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(Output {
+                status,
+                stdout: stdout.join().unwrap_or_default(),
+                stderr: stderr.join().unwrap_or_default(),
+            }));
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            // The readers are left behind, children of the command can keep the pipes open
+            let _ = child.kill();
+            let _ = child.wait();
+            return Ok(None);
+        }
+        thread::sleep(DEADLINE_POLL.min(deadline - now));
+    }
+}
+
+/// Get the refs rejected by the destination from the output of `git push --porcelain`.
+/// Returns the refspec to retry the ref with as well as the destination ref name.
+fn rejected_refs(porcelain: &str) -> Vec<(String, String)> {
+    porcelain
+        .lines()
+        .filter_map(|l| l.strip_prefix("!\t"))
+        .filter_map(|l| l.split('\t').next())
+        .filter_map(|spec| {
+            let (src, dst) = spec.split_once(':')?;
+            let retry = if src.is_empty() {
+                format!(":{dst}")
+            } else {
+                format!("+{src}:{dst}")
+            };
+            Some((retry, dst.to_owned()))
+        })
+        .collect()
+}
+
+/// Get the destination refs a push without force rejected as not fast-forward (`[rejected]`, as
+/// opposed to `[remote rejected]` by the destination) from the output of `git push --porcelain`
+fn diverged_refs(porcelain: &str) -> Vec<String> {
+    porcelain
+        .lines()
+        .filter_map(|l| l.strip_prefix("!\t"))
+        .filter_map(|l| {
+            let (spec, summary) = l.split_once('\t')?;
+            summary.starts_with("[rejected]").then_some(spec)
+        })
+        .filter_map(|spec| spec.split_once(':'))
+        .map(|(_, dst)| dst.to_owned())
+        .collect()
 }
 
 /// Common interface to different git backends
 /// - [x] git command line
-/// - [ ] libgit2
+/// - [x] libgit2, only the transfers (`libgit2` feature)
 /// - [ ] gitoxide
 ///
 pub trait GitWrapper {
-    /// Get the git version
-    fn git_version(&self) -> Result<(), GitError>;
+    /// Get the git version as major and minor version, `(0, 0)` if it can't be parsed
+    fn git_version(&self) -> Result<(u32, u32), GitError>;
     fn git_lfs_version(&self) -> Result<(), GitError>;
-    fn git_clone_mirror(&self, origin: &str, repo_dir: &Path, lfs: bool) -> Result<(), GitError>;
-    fn git_update_mirror(&self, origin: &str, repo_dir: &Path, lfs: bool) -> Result<(), GitError>;
+    /// List the refs of a remote repository as a map of ref name to object id
+    fn git_ls_remote(&self, origin: &str) -> Result<BTreeMap<String, String>, GitError>;
+    /// List the refs of the destination like [`GitWrapper::git_ls_remote`], with the credentials
+    /// of the destination
+    fn git_ls_dest(
+        &self,
+        dest: &str,
+        repo_dir: &Path,
+    ) -> Result<BTreeMap<String, String>, GitError>;
+    /// Get the ref HEAD of a remote repository points to (e.g. `refs/heads/main`), `None` if it
+    /// has no symbolic HEAD
+    fn git_remote_head(&self, remote: &str) -> Result<Option<String>, GitError>;
+    /// Point HEAD of the repository to `branch`, which must exist
+    fn git_set_head(&self, repo_dir: &Path, branch: &str) -> Result<(), GitError>;
+    fn git_clone_mirror(&self, origin: &str, repo_dir: &Path) -> Result<(), GitError>;
+    fn git_update_mirror(&self, origin: &str, repo_dir: &Path) -> Result<(), GitError>;
+    /// Whether the repository uses LFS, i.e. a `.gitattributes` at the tip of a branch or tag
+    /// assigns the `lfs` filter
+    fn git_uses_lfs(&self, repo_dir: &Path) -> Result<bool, GitError>;
+    /// Object ids the refs of the repository point to
+    fn git_ref_tips(&self, repo_dir: &Path) -> Result<Vec<String>, GitError>;
+    /// List the refs of the local repository as a map of ref name to object id
+    fn git_local_refs(&self, repo_dir: &Path) -> Result<BTreeMap<String, String>, GitError>;
+    /// Write the `refs` with the objects reachable from them, but not from the `prerequisites`,
+    /// to the bundle `file`. Returns the refs in the bundle, git leaves out the refs pointing to
+    /// a prerequisite.
+    fn git_bundle_create(
+        &self,
+        repo_dir: &Path,
+        file: &Path,
+        refs: &[&str],
+        prerequisites: &[&str],
+    ) -> Result<BTreeMap<String, String>, GitError>;
+    /// Number of commits reachable from the refs of the repository, but not from the `tips`.
+    /// Tips that aren't in the repository are ignored.
+    fn git_count_commits(&self, repo_dir: &Path, tips: &[String]) -> Result<u64, GitError>;
+    /// Fetch the LFS objects of all refs from the origin (`git lfs fetch --all`)
+    fn git_lfs_fetch(&self, repo_dir: &Path) -> Result<(), GitError>;
+    /// Push the LFS objects of all refs to the destination (`git lfs push --all`)
+    fn git_lfs_push(&self, dest: &str, repo_dir: &Path) -> Result<(), GitError>;
+    /// Copy the objects borrowed from alternates into the repository and stop using the alternates
+    fn git_dissociate(&self, repo_dir: &Path) -> Result<(), GitError>;
+    /// Run `git maintenance` with the given tasks, or the tasks needed (`--auto`) if empty.
+    /// Falls back to `git gc --auto` before git 2.29.
+    fn git_maintenance(&self, repo_dir: &Path, tasks: &[String]) -> Result<(), GitError>;
+    /// Write the commit-graph of all reachable commits (`git commit-graph write --reachable`).
+    /// Returns false if git is too old, before 2.19.
+    fn git_write_commit_graph(&self, repo_dir: &Path) -> Result<bool, GitError>;
+    /// Write the multi-pack-index of the packs (`git multi-pack-index write`).
+    /// Returns false if git is too old, before 2.21.
+    fn git_write_midx(&self, repo_dir: &Path) -> Result<bool, GitError>;
+    /// Verify the connectivity and validity of the objects of the repository (`git fsck`)
+    fn git_fsck(&self, repo_dir: &Path) -> Result<(), GitError>;
+    /// Verify the signatures of the branch and tag tips with the keys of the GnuPG home
+    /// `gpg_home`. Returns the refs without a valid signature and why.
+    fn git_verify_signatures(
+        &self,
+        repo_dir: &Path,
+        gpg_home: &Path,
+    ) -> Result<Vec<(String, &'static str)>, GitError>;
+    /// Size of the objects of the repository in bytes (`git count-objects -v`)
+    fn git_repo_size(&self, repo_dir: &Path) -> Result<u64, GitError>;
+    /// Run the shell command `hook` in the repository, which is passed as `GIT_MIRROR_REPO`
+    /// together with `GIT_MIRROR_ORIGIN` and `GIT_MIRROR_DESTINATION`
+    fn run_hook(
+        &self,
+        hook: &str,
+        repo_dir: &Path,
+        origin: &str,
+        dest: &str,
+    ) -> Result<(), GitError>;
     fn git_push_mirror(
         &self,
         dest: &str,
         repo_dir: &Path,
         refspec: &Option<Vec<String>>,
-        lfs: bool,
     ) -> Result<(), GitError>;
+    /// Push the refspecs, deleting the destination refs they map to if they no longer exist locally
+    fn git_push_refs(
+        &self,
+        dest: &str,
+        repo_dir: &Path,
+        refspecs: &[String],
+    ) -> Result<(), GitError>;
+    /// Import the tree of the default branch of `origin` into the directory `prefix` of `branch`
+    /// on the destination, replacing what was in the directory before. `repo_dir` is a bare
+    /// repository shared by all imports into the destination. Returns false if the directory
+    /// already had this content and nothing was pushed.
+    fn git_import_subtree(
+        &self,
+        origin: &str,
+        dest: &str,
+        repo_dir: &Path,
+        prefix: &str,
+        branch: &str,
+    ) -> Result<bool, GitError>;
+    /// Point `meta_ref` on the destination to a commit containing `metadata` as `file_name`
+    fn git_push_metadata(
+        &self,
+        dest: &str,
+        repo_dir: &Path,
+        meta_ref: &str,
+        file_name: &str,
+        metadata: &str,
+    ) -> Result<(), GitError>;
+}
+
+/// What runs the clones, fetches, pushes and listings of the remote refs, see `Git::with_backend`
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GitBackend {
+    /// The git command line
+    #[default]
+    Cli,
+    /// libgit2 in-process, needs the `libgit2` feature
+    Libgit2,
 }
 
 /// Git command line wrapper
 pub struct Git {
     executable: String,
-    lfs_enabled: bool,
+    push_options: Vec<String>,
+    work_tree: bool,
+    keep_refs: Vec<String>,
+    exclude_refs: Vec<String>,
+    dest_token: Option<String>,
+    filter: Option<String>,
+    shared: Option<String>,
+    pack_compression: Option<u8>,
+    protocol_version: Option<u8>,
+    insecure: Vec<String>,
+    alternates: Option<PathBuf>,
+    track_transfer: bool,
+    ssh: SshOptions,
+    fsck_objects: bool,
+    push_batch: Option<usize>,
+    skip_push_hooks: bool,
+    no_force: bool,
+    prune_refs: bool,
+    #[cfg_attr(not(feature = "libgit2"), allow(dead_code))]
+    backend: GitBackend,
+    log: Arc<RepoLog>,
+}
+
+/// Environment variable passing the destination token to the credential helper
+const DEST_TOKEN_ENV: &str = "GIT_MIRROR_DEST_TOKEN";
+
+/// Credential helper answering with the destination token, so it never appears on the command line
+const DEST_TOKEN_HELPER: &str = "credential.helper=!f() { test \"$1\" = get && echo username=git-mirror && echo \"password=$GIT_MIRROR_DEST_TOKEN\"; }; f";
+
+/// How git connects to SSH remotes, passed as `GIT_SSH_COMMAND` instead of changing the SSH
+/// configuration of the user
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SshOptions {
+    /// Jump host(s), checked by `parse_ssh_jump`
+    pub jump: Option<String>,
+    /// Only authenticate with this private key, not with the keys of the agent or the config
+    pub key: Option<PathBuf>,
+    /// Look up and store the host keys in this file instead of `~/.ssh/known_hosts`
+    pub known_hosts: Option<PathBuf>,
+    /// `StrictHostKeyChecking`, the SSH default (ask, failing without terminal) if unset
+    pub strict_host_key_checking: Option<bool>,
+}
+
+/// Quote `s` for the shell running `GIT_SSH_COMMAND`
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Command connecting to `%h:%p` through the jump hosts, each one reached through the ones before
+/// it. The command is expanded by the ssh running it, so everything but `%h:%p` is escaped.
+fn proxy_command(ssh: &str, options: &str, hops: &[&str]) -> String {
+    let (last, before) = match hops.split_last() {
+        Some(hops) => hops,
+        None => return String::new(),
+    };
+    let mut command = format!("{ssh}{options}").replace('%', "%%");
+    if !before.is_empty() {
+        let inner = proxy_command(ssh, options, before).replace('%', "%%");
+        command.push_str(&format!(" -o ProxyCommand={}", shell_quote(&inner)));
+    }
+    format!("{command} -W %h:%p ssh://{}", last.replace('%', "%%"))
+}
+
+impl SshOptions {
+    /// Arguments of the key and host key options, also used for the jump hosts
+    fn options(&self) -> String {
+        let mut options = String::new();
+        if let Some(ref key) = self.key {
+            options.push_str(&format!(
+                " -i {} -o IdentitiesOnly=yes",
+                shell_quote(&key.to_string_lossy())
+            ));
+        }
+        let known_hosts = match (&self.known_hosts, self.strict_host_key_checking) {
+            (Some(file), _) => Some(file.to_string_lossy().into_owned()),
+            // Accepted host keys would otherwise end up in the known hosts of the user
+            (None, Some(false)) => Some("/dev/null".to_string()),
+            (None, _) => None,
+        };
+        if let Some(file) = known_hosts {
+            options.push_str(&format!(" -o UserKnownHostsFile={}", shell_quote(&file)));
+        }
+        if let Some(strict) = self.strict_host_key_checking {
+            options.push_str(&format!(
+                " -o StrictHostKeyChecking={}",
+                if strict { "yes" } else { "no" }
+            ));
+        }
+        options
+    }
+
+    /// SSH command with the options, based on the `GIT_SSH_COMMAND` of the environment if set.
+    /// `None` if no option is set.
+    fn command(&self) -> Option<String> {
+        if *self == SshOptions::default() {
+            return None;
+        }
+        let ssh = std::env::var("GIT_SSH_COMMAND").unwrap_or_else(|_| "ssh".to_string());
+        let options = self.options();
+        let mut command = format!("{ssh}{options}");
+        if let Some(ref jump) = self.jump {
+            // Unlike with `-J` the jump hosts are connected with the same key and host keys
+            let hops: Vec<&str> = jump.split(',').collect();
+            command.push_str(&format!(
+                " -o ProxyCommand={}",
+                shell_quote(&proxy_command(&ssh, &options, &hops))
+            ));
+        }
+        Some(command)
+    }
+}
+
+/// Parse a jump host, `[user@]host[:port]`, several separated by `,`
+pub fn parse_ssh_jump(s: &str) -> Result<String, String> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || "@.:-_,[]".contains(c);
+    if s.is_empty() || s.starts_with('-') || !s.chars().all(valid) {
+        Err(format!(
+            "Invalid SSH jump host, expected [user@]host[:port]: {s}"
+        ))
+    } else {
+        Ok(s.to_owned())
+    }
+}
+
+/// Why a signature wasn't verified, from the GnuPG status lines (`--raw`) of `git verify-commit`
+/// and `git verify-tag`
+fn signature_problem(stderr: &str) -> &'static str {
+    let status = |s: &str| stderr.contains(&format!("[GNUPG:] {s} "));
+    if stderr.trim().is_empty() {
+        "unsigned"
+    } else if status("BADSIG") {
+        "bad signature"
+    } else if status("NO_PUBKEY") || status("ERRSIG") {
+        "unknown key"
+    } else if status("EXPKEYSIG") || status("REVKEYSIG") || status("EXPSIG") {
+        "expired or revoked key"
+    } else {
+        "invalid signature"
+    }
+}
+
+/// Add up the sizes of the loose objects, packs and garbage reported by `git count-objects -v` in KiB
+fn parse_count_objects(output: &str) -> u64 {
+    output
+        .lines()
+        .filter_map(|l| l.split_once(": "))
+        .filter(|(key, _)| ["size", "size-pack", "size-garbage"].contains(key))
+        .filter_map(|(_, kib)| kib.trim().parse::<u64>().ok())
+        .sum::<u64>()
+        * 1024
+}
+
+/// Configuration disabling the TLS verification for the URL, or all URLs if empty
+fn ssl_verify_config(url: &str) -> String {
+    match url {
+        "" => "http.sslVerify=false".to_string(),
+        url => format!("http.{url}.sslVerify=false"),
+    }
+}
+
+/// Parse a URL for `--git-insecure`, empty for all URLs
+pub fn parse_insecure_url(s: &str) -> Result<String, String> {
+    if s.is_empty() || s.starts_with("https://") || s.starts_with("http://") {
+        Ok(s.to_owned())
+    } else {
+        Err(format!(
+            "Expected an http(s) URL like https://git.example.com/: {s}"
+        ))
+    }
+}
+
+/// First version supporting `git maintenance run`
+const MAINTENANCE_VERSION: (u32, u32) = (2, 29);
+
+/// First version supporting `git commit-graph write --reachable`
+const COMMIT_GRAPH_VERSION: (u32, u32) = (2, 19);
+
+/// First version supporting `git multi-pack-index write`
+const MIDX_VERSION: (u32, u32) = (2, 21);
+
+/// Map the ref names to the object ids in the output of `git ls-remote`
+fn parse_ls_remote(output: &str) -> BTreeMap<String, String> {
+    output
+        .lines()
+        .filter_map(|l| l.split_once('\t'))
+        .map(|(id, name)| (name.to_owned(), id.to_owned()))
+        .collect()
+}
+
+/// Get the ref HEAD points to from the output of `git ls-remote --symref <remote> HEAD`
+fn parse_head_symref(output: &str) -> Option<&str> {
+    output
+        .lines()
+        .filter_map(|l| l.strip_prefix("ref: "))
+        .filter_map(|l| l.strip_suffix("\tHEAD"))
+        .next()
+}
+
+/// Parse the major and minor version from the output of `git --version`,
+/// e.g. `git version 2.39.2.windows.1`
+fn parse_version(output: &str) -> Option<(u32, u32)> {
+    let version = output.trim().strip_prefix("git version ")?;
+    let mut parts = version.split(|c: char| !c.is_ascii_digit());
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+/// File listing the object directories of the alternates, for a bare repository or one with work tree
+fn alternates_file(repo_dir: &Path) -> PathBuf {
+    let git_dir = repo_dir.join(".git");
+    let git_dir = if git_dir.is_dir() {
+        git_dir
+    } else {
+        repo_dir.to_path_buf()
+    };
+    git_dir.join("objects/info/alternates")
+}
+
+/// Get the object directories the repository borrows objects from
+pub fn alternates(repo_dir: &Path) -> Vec<PathBuf> {
+    let file = alternates_file(repo_dir);
+    let objects = file.parent().and_then(Path::parent).unwrap_or(repo_dir);
+    fs::read_to_string(&file)
+        .unwrap_or_default()
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        // Relative paths are relative to the objects directory
+        .map(|l| objects.join(l))
+        .collect()
+}
+
+/// Commits per push of the history of a branch, if a push exceeded the pack size limit
+const DEFAULT_PUSH_BATCH: usize = 1000;
+
+/// Number of ref tips searched for LFS attributes by one `git grep`, to stay below the
+/// command line length limit
+const LFS_GREP_BATCH: usize = 100;
+
+/// Check if the push failed because the pack was too large for the destination
+fn pack_too_large(err: &GitError) -> bool {
+    match err {
+        GitError::GitCommandError { stderr, .. } => {
+            stderr.contains("pack exceeds maximum allowed size")
+                || stderr.contains("HTTP 413")
+                || stderr.contains("Request Entity Too Large")
+        }
+        _ => false,
+    }
+}
+
+/// Check if the push failed because the destination doesn't support push options
+fn push_options_unsupported(err: &GitError) -> bool {
+    match err {
+        GitError::GitCommandError { stderr, .. } => {
+            stderr.contains("does not support push options")
+        }
+        _ => false,
+    }
 }
 
 impl Git {
-    pub fn new(executable: String, lfs_enabled: bool) -> Git {
+    pub fn new(executable: String) -> Git {
         Git {
             executable,
-            lfs_enabled,
+            push_options: Vec::new(),
+            work_tree: false,
+            keep_refs: Vec::new(),
+            exclude_refs: Vec::new(),
+            dest_token: None,
+            filter: None,
+            shared: None,
+            pack_compression: None,
+            protocol_version: None,
+            insecure: Vec::new(),
+            alternates: None,
+            track_transfer: false,
+            ssh: SshOptions::default(),
+            fsck_objects: false,
+            push_batch: None,
+            skip_push_hooks: false,
+            no_force: false,
+            prune_refs: false,
+            backend: GitBackend::Cli,
+            log: Arc::new(RepoLog::disabled()),
+        }
+    }
+
+    /// Never delete refs matching the given patterns on the destination with a mirror push
+    pub fn with_keep_refs(mut self, keep_refs: Vec<String>) -> Git {
+        self.keep_refs = keep_refs;
+        self
+    }
+
+    /// Never fetch refs matching the given patterns from the origin
+    pub fn with_exclude_refs(mut self, exclude_refs: Vec<String>) -> Git {
+        self.exclude_refs = exclude_refs;
+        self
+    }
+
+    /// Create new clones as partial clones with the given object filter (e.g. `blob:none`)
+    pub fn with_filter(mut self, filter: Option<String>) -> Git {
+        self.filter = filter;
+        self
+    }
+
+    /// Set `core.sharedRepository` (e.g. `group`) of the local repositories
+    pub fn with_shared(mut self, shared: Option<String>) -> Git {
+        self.shared = shared;
+        self
+    }
+
+    /// Compression level (0-9) of the packs sent on push, the git configuration is used if `None`
+    pub fn with_pack_compression(mut self, level: Option<u8>) -> Git {
+        self.pack_compression = level;
+        self
+    }
+
+    /// Wire protocol version (0-2) used to talk to the remotes, the git configuration is used if `None`.
+    /// Servers not supporting the requested version answer with the one they support.
+    pub fn with_protocol_version(mut self, version: Option<u8>) -> Git {
+        self.protocol_version = version;
+        self
+    }
+
+    /// Don't verify the TLS certificates of the URLs (e.g. `https://git.example.com/`),
+    /// of all servers for an empty URL
+    pub fn with_insecure(mut self, insecure: Vec<String>) -> Git {
+        self.insecure = insecure;
+        self
+    }
+
+    /// Borrow the objects of new clones from the object pool in `dir`, which is created if missing
+    pub fn with_alternates(mut self, dir: Option<PathBuf>) -> Git {
+        self.alternates = dir;
+        self
+    }
+
+    /// Count the bytes transferred by fetches and pushes, see `transfer::transferred`
+    pub fn with_transfer_tracking(mut self, track_transfer: bool) -> Git {
+        self.track_transfer = track_transfer;
+        self
+    }
+
+    /// Connect to SSH remotes with the key, known hosts and jump host(s) of the options
+    pub fn with_ssh(mut self, ssh: SshOptions) -> Git {
+        self.ssh = ssh;
+        self
+    }
+
+    /// Check the received objects during fetches and clones (`transfer.fsckObjects`)
+    pub fn with_fsck_objects(mut self, fsck_objects: bool) -> Git {
+        self.fsck_objects = fsck_objects;
+        self
+    }
+
+    /// Push the new history of the branches in batches of at most `push_batch` commits
+    /// before mirroring. Without, it is only done with `DEFAULT_PUSH_BATCH` after a push
+    /// exceeded the pack size limit of the destination.
+    pub fn with_push_batch(mut self, push_batch: Option<usize>) -> Git {
+        self.push_batch = push_batch;
+        self
+    }
+
+    /// Push without running the pre-push hooks (`--no-verify`), e.g. the LFS upload installed by
+    /// an earlier `git lfs install`
+    pub fn with_skip_push_hooks(mut self, skip_push_hooks: bool) -> Git {
+        self.skip_push_hooks = skip_push_hooks;
+        self
+    }
+
+    /// Push the mirrored refs without force, so refs that aren't fast-forwards on the destination
+    /// are rejected instead of overwritten
+    pub fn with_no_force(mut self, no_force: bool) -> Git {
+        self.no_force = no_force;
+        self
+    }
+
+    /// Delete the refs matching the refspec of the push on the destination if they don't exist
+    /// locally, except the kept ones
+    pub fn with_prune_refs(mut self, prune_refs: bool) -> Git {
+        self.prune_refs = prune_refs;
+        self
+    }
+
+    /// Run the transfers with libgit2 instead of git for bare repositories. Everything else,
+    /// e.g. the maintenance and LFS, still runs git. Without the `libgit2` feature git is always
+    /// used.
+    pub fn with_backend(mut self, backend: GitBackend) -> Git {
+        self.backend = backend;
+        self
+    }
+
+    /// Whether the transfers run with libgit2
+    #[cfg(feature = "libgit2")]
+    fn in_process(&self) -> bool {
+        self.backend == GitBackend::Libgit2 && !self.work_tree
+    }
+
+    /// The refspecs forced like with `git push -f`, unless `no_force`
+    #[cfg(feature = "libgit2")]
+    fn forced_specs(&self, specs: &[String]) -> Vec<String> {
+        specs
+            .iter()
+            .map(|spec| match spec.chars().next() {
+                Some('+' | '^') => spec.clone(),
+                _ if self.no_force => spec.clone(),
+                _ => format!("+{spec}"),
+            })
+            .collect()
+    }
+
+    /// The refspec to push the mirrored refs with, without the leading `+` for `no_force`
+    fn push_spec<'a>(&self, spec: &'a str) -> &'a str {
+        if self.no_force {
+            spec.strip_prefix('+').unwrap_or(spec)
+        } else {
+            spec
+        }
+    }
+
+    /// Make the transferring commands report their progress, which includes the size
+    fn progress_arg(&self, cmd: &mut Command) {
+        if self.track_transfer {
+            cmd.arg("--progress");
+        }
+    }
+
+    /// Fetch `origin` into the object pool, so the clone only needs the objects not in the pool.
+    /// Each repository gets its own ref namespace, keeping its objects reachable in the pool.
+    fn fill_pool(&self, pool: &Path, origin: &str, repo_dir: &Path) -> Result<PathBuf, GitError> {
+        if !pool.join("objects").is_dir() {
+            let mut init_cmd = self.git_base_cmd();
+            init_cmd.args(["init", "--bare", "--quiet"]).arg(pool);
+            self.run_cmd(init_cmd)?;
+        }
+        let pool = pool.canonicalize().map_err(|err| GitError::IoError {
+            what: format!("access object pool {pool:?}"),
+            err,
+        })?;
+
+        let namespace = repo_dir
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let mut fetch_cmd = self.git_base_cmd();
+        fetch_cmd
+            .current_dir(&pool)
+            .args(["fetch", "--prune", "--no-tags"]);
+        self.progress_arg(&mut fetch_cmd);
+        fetch_cmd
+            .arg(origin)
+            .arg(format!("+refs/*:refs/pool/{namespace}/*"));
+        self.run_cmd(fetch_cmd)?;
+        Ok(pool)
+    }
+
+    /// Authenticate pushes to http(s) destinations with the given token
+    pub fn with_dest_token(mut self, dest_token: Option<String>) -> Git {
+        self.dest_token = dest_token.filter(|t| !t.is_empty());
+        self
+    }
+
+    /// Hide the destination token in text that is logged
+    fn redact(&self, text: String) -> String {
+        match self.dest_token {
+            Some(ref token) => text.replace(token.as_str(), "***"),
+            None => text,
+        }
+    }
+
+    /// Write the executed commands and their output to the given repository log
+    pub fn with_log(mut self, log: Arc<RepoLog>) -> Git {
+        self.log = log;
+        self
+    }
+
+    /// Use repositories with a working tree instead of bare mirrors
+    pub fn with_work_tree(mut self, work_tree: bool) -> Git {
+        self.work_tree = work_tree;
+        self
+    }
+
+    /// Fetch all refs of the origin that are not excluded
+    fn git_fetch_cmd(&self, repo_dir: &Path) -> Command {
+        let mut fetch_cmd = self.git_base_cmd();
+        fetch_cmd.current_dir(repo_dir).args(["fetch", "--prune"]);
+        if self.work_tree {
+            fetch_cmd.arg("--update-head-ok");
+        }
+        self.progress_arg(&mut fetch_cmd);
+        fetch_cmd.arg("origin");
+        if !self.exclude_refs.is_empty() {
+            fetch_cmd.arg("+refs/*:refs/*");
+            for r in self.exclude_refs.iter() {
+                fetch_cmd.arg(format!("^{r}"));
+            }
+        }
+        fetch_cmd
+    }
+
+    /// Fetch all refs into a repository with a working tree and check out the default branch of the origin
+    fn git_fetch_work_tree(&self, repo_dir: &Path) -> Result<(), GitError> {
+        self.run_cmd(self.git_fetch_cmd(repo_dir))?;
+
+        let mut head_cmd = self.git_base_cmd();
+        head_cmd
+            .current_dir(repo_dir)
+            .args(["ls-remote", "--symref", "origin", "HEAD"]);
+
+        let stdout = self.run_cmd_output(head_cmd)?;
+        match parse_head_symref(&stdout) {
+            Some(head) => {
+                let mut symbolic_ref_cmd = self.git_base_cmd();
+                symbolic_ref_cmd
+                    .current_dir(repo_dir)
+                    .args(["symbolic-ref", "HEAD", head]);
+                self.run_cmd(symbolic_ref_cmd)?;
+
+                let mut reset_cmd = self.git_base_cmd();
+                reset_cmd.current_dir(repo_dir).args(["reset", "--hard"]);
+                self.run_cmd(reset_cmd)
+            }
+            None => {
+                debug!("Origin has no HEAD, skipping checkout");
+                Ok(())
+            }
+        }
+    }
+
+    /// Set the push options (`-o`) transmitted to the destination on push
+    pub fn with_push_options(mut self, push_options: Vec<String>) -> Git {
+        self.push_options = push_options;
+        self
+    }
+
+    /// Base command for git commands accessing the destination
+    fn git_dest_cmd(&self, repo_dir: &Path) -> Command {
+        let mut cmd = self.git_base_cmd();
+        cmd.current_dir(repo_dir);
+        if let Some(ref token) = self.dest_token {
+            // Replace any configured helper, so the token is used for the destination
+            cmd.env(DEST_TOKEN_ENV, token).args([
+                "-c",
+                "credential.helper=",
+                "-c",
+                DEST_TOKEN_HELPER,
+            ]);
+        }
+        cmd
+    }
+
+    fn git_push_cmd(&self, repo_dir: &Path, push_options: &[String]) -> Command {
+        let mut push_cmd = self.git_push_base_cmd(repo_dir);
+        if !self.no_force {
+            push_cmd.arg("-f");
+        }
+        if self.skip_push_hooks {
+            push_cmd.arg("--no-verify");
+        }
+        self.progress_arg(&mut push_cmd);
+        for o in push_options {
+            push_cmd.arg(format!("--push-option={o}"));
+        }
+        push_cmd
+    }
+
+    /// Push the history of the branches not on the destination yet in batches of at most `batch`
+    /// commits along the first parents, so every pack stays bounded. The tips are left to the
+    /// following push.
+    fn git_push_batches(&self, dest: &str, repo_dir: &Path, batch: usize) -> Result<(), GitError> {
+        let mut ls_remote_cmd = self.git_dest_cmd(repo_dir);
+        ls_remote_cmd.arg("ls-remote").arg(dest);
+        let remote = self.run_cmd_output(ls_remote_cmd)?;
+        // Only the commits the destination has and that are known locally bound the history
+        let remote_ids: String = remote
+            .lines()
+            .filter_map(|l| l.split_whitespace().next())
+            .map(|id| format!("{id}\n"))
+            .collect();
+        let mut check_cmd = self.git_base_cmd();
+        check_cmd
+            .current_dir(repo_dir)
+            .args(["cat-file", "--batch-check=%(objectname) %(objecttype)"]);
+        let known = self.run_cmd_input(check_cmd, Some(&remote_ids))?;
+        let exclude: String = known
+            .lines()
+            .filter_map(|l| l.strip_suffix(" commit"))
+            .map(|id| format!("^{id}\n"))
+            .collect();
+
+        let mut refs_cmd = self.git_base_cmd();
+        refs_cmd
+            .current_dir(repo_dir)
+            .args(["for-each-ref", "--format=%(refname)", "refs/heads"]);
+        for branch in self.run_cmd_output(refs_cmd)?.lines() {
+            let mut rev_list_cmd = self.git_base_cmd();
+            rev_list_cmd.current_dir(repo_dir).args([
+                "rev-list",
+                "--first-parent",
+                "--reverse",
+                "--stdin",
+            ]);
+            let commits =
+                self.run_cmd_input(rev_list_cmd, Some(&format!("{branch}\n{exclude}")))?;
+            let commits: Vec<&str> = commits.lines().collect();
+            if commits.len() <= batch {
+                continue;
+            }
+            info!(
+                "Push {} commits of {} in batches of {}",
+                commits.len(),
+                branch,
+                batch
+            );
+            for id in commits[..commits.len() - 1]
+                .iter()
+                .skip(batch - 1)
+                .step_by(batch)
+            {
+                let mut push_cmd = self.git_push_cmd(repo_dir, &self.push_options);
+                push_cmd.arg(dest).arg(format!("{id}:{branch}"));
+                self.run_cmd(push_cmd)?;
+            }
         }
+        Ok(())
+    }
+
+    /// `git push` to the destination, without `-f`
+    fn git_push_base_cmd(&self, repo_dir: &Path) -> Command {
+        let mut push_cmd = self.git_dest_cmd(repo_dir);
+        if let Some(level) = self.pack_compression {
+            push_cmd
+                .arg("-c")
+                .arg(format!("pack.compression={level}"))
+                .arg("-c")
+                .arg(format!("core.compression={level}"));
+        }
+        push_cmd.arg("push");
+        push_cmd
     }
 
     fn git_base_cmd(&self) -> Command {
         let mut git = Command::new(self.executable.clone());
         git.env("GIT_TERMINAL_PROMPT", "0");
+        if let Some(version) = self.protocol_version {
+            git.arg("-c").arg(format!("protocol.version={version}"));
+        }
+        // Only for this command, not written to any git configuration
+        for url in self.insecure.iter() {
+            git.arg("-c").arg(ssl_verify_config(url));
+        }
+        if let Some(ssh) = self.ssh.command() {
+            git.env("GIT_SSH_COMMAND", ssh);
+        }
+        if self.fsck_objects {
+            git.args([
+                "-c",
+                "fetch.fsckObjects=true",
+                "-c",
+                "transfer.fsckObjects=true",
+            ]);
+        }
         git
     }
 
-    fn run_cmd(&self, mut cmd: Command) -> Result<(), GitError> {
-        debug!("Run command: {:?}", cmd);
-        match cmd.output() {
-            Ok(o) => {
-                let stdout = String::from_utf8_lossy(&o.stdout).to_string();
+    fn run_cmd(&self, cmd: Command) -> Result<(), GitError> {
+        self.run_cmd_output(cmd).map(|_| ())
+    }
+
+    fn run_cmd_output(&self, cmd: Command) -> Result<String, GitError> {
+        self.run_cmd_input(cmd, None)
+    }
+
+    /// Run the command, passing `input` to its stdin
+    fn run_cmd_input(&self, mut cmd: Command, input: Option<&str>) -> Result<String, GitError> {
+        let shown = self.redact(format!("{cmd:?}"));
+        debug!("Run command: {}", shown);
+        self.log.log(format_args!("Run command: {shown}"));
+        let deadline = DEADLINE.with(Cell::get);
+        let output = match (deadline, input) {
+            (Some(d), _) if Instant::now() >= d.at => Ok(None),
+            (Some(d), input) => output_until(&mut cmd, input, d.at),
+            (None, None) => cmd.output().map(Some),
+            (None, Some(input)) => cmd
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .and_then(|mut child| {
+                    if let Some(mut stdin) = child.stdin.take() {
+                        stdin.write_all(input.as_bytes())?;
+                    }
+                    child.wait_with_output()
+                })
+                .map(Some),
+        };
+        if self.dest_token.is_some() {
+            // The command ends up in the error messages
+            cmd.env(DEST_TOKEN_ENV, "***");
+        }
+        match output {
+            Ok(None) => {
+                let budget = deadline.map(|d| d.budget).unwrap_or_default();
+                self.log.log(format_args!(
+                    "Killed, the time budget of {} is exceeded",
+                    humantime::format_duration(budget)
+                ));
+                Err(GitError::TimedOut {
+                    cmd: Box::new(cmd),
+                    budget,
+                })
+            }
+            Ok(Some(o)) => {
+                let stdout = self.redact(String::from_utf8_lossy(&o.stdout).to_string());
                 if !stdout.is_empty() {
                     debug!("Stdout: {}", stdout);
+                    self.log.log(format_args!("Stdout: {}", stdout.trim_end()));
+                }
+                let mut stderr = self.redact(String::from_utf8_lossy(&o.stderr).to_string());
+                if self.track_transfer {
+                    transfer::add_job_progress(&stderr);
+                    let bytes = transfer::parse_progress(&stderr);
+                    if bytes > 0 {
+                        debug!("Transferred {}", transfer::format_bytes(bytes));
+                        transfer::add(bytes);
+                    }
+                    stderr = transfer::final_progress(&stderr);
                 }
-                let stderr = String::from_utf8_lossy(&o.stderr).to_string();
                 if !stderr.is_empty() {
                     debug!("Stderr: {}", stderr);
+                    self.log.log(format_args!("Stderr: {}", stderr.trim_end()));
                 }
+                self.log.log(format_args!("Finished with {}", o.status));
                 if o.status.success() {
-                    Ok(())
+                    Ok(stdout)
                 } else {
                     Err(GitError::GitCommandError {
-                        cmd,
+                        cmd: Box::new(cmd),
                         code: o.status.code().unwrap_or_default(),
+                        stdout,
                         stderr,
                     })
                 }
             }
-            Err(e) => Err(GitError::CommandError { cmd, err: e }),
+            Err(e) => {
+                self.log.log(format_args!("Unable to run command: {e}"));
+                Err(GitError::CommandError {
+                    cmd: Box::new(cmd),
+                    err: e,
+                })
+            }
         }
     }
 }
 
 impl GitWrapper for Git {
-    fn git_version(&self) -> Result<(), GitError> {
+    fn git_version(&self) -> Result<(u32, u32), GitError> {
         let mut cmd = self.git_base_cmd();
         cmd.arg("--version");
 
-        self.run_cmd(cmd)
+        let stdout = self.run_cmd_output(cmd)?;
+        Ok(parse_version(&stdout).unwrap_or_else(|| {
+            debug!("Unable to parse git version: {}", stdout.trim());
+            (0, 0)
+        }))
     }
 
     fn git_lfs_version(&self) -> Result<(), GitError> {
@@ -106,26 +1193,391 @@ impl GitWrapper for Git {
         self.run_cmd(cmd)
     }
 
-    fn git_clone_mirror(&self, origin: &str, repo_dir: &Path, lfs: bool) -> Result<(), GitError> {
-        let mut clone_cmd = self.git_base_cmd();
-        clone_cmd
-            .args(["clone", "--mirror"])
-            .arg(origin)
-            .arg(repo_dir);
+    fn git_uses_lfs(&self, repo_dir: &Path) -> Result<bool, GitError> {
+        let mut refs_cmd = self.git_base_cmd();
+        refs_cmd.current_dir(repo_dir).args([
+            "for-each-ref",
+            "--format=%(objecttype) %(objectname)",
+            "refs/heads",
+            "refs/tags",
+        ]);
+        let refs = self.run_cmd_output(refs_cmd)?;
+        // Tags of trees and blobs have no .gitattributes
+        let tips: BTreeSet<&str> = refs
+            .lines()
+            .filter_map(|l| match l.split_once(' ') {
+                Some(("commit" | "tag", id)) => Some(id),
+                _ => None,
+            })
+            .collect();
+        let tips: Vec<&str> = tips.into_iter().collect();
+
+        for batch in tips.chunks(LFS_GREP_BATCH) {
+            let mut grep_cmd = self.git_base_cmd();
+            grep_cmd
+                .current_dir(repo_dir)
+                .args(["grep", "-q", "-e", "filter=lfs"])
+                .args(batch)
+                .args(["--", ".gitattributes", "*/.gitattributes"]);
+            match self.run_cmd(grep_cmd) {
+                Ok(()) => return Ok(true),
+                // Nothing found
+                Err(GitError::GitCommandError { code: 1, .. }) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(false)
+    }
 
-        self.run_cmd(clone_cmd)?;
+    fn git_ref_tips(&self, repo_dir: &Path) -> Result<Vec<String>, GitError> {
+        let mut cmd = self.git_base_cmd();
+        cmd.current_dir(repo_dir)
+            .args(["for-each-ref", "--format=%(objectname)"]);
+        let stdout = self.run_cmd_output(cmd)?;
+        let tips: BTreeSet<&str> = stdout.lines().collect();
+        Ok(tips.into_iter().map(str::to_string).collect())
+    }
 
-        if self.lfs_enabled && lfs {
-            let mut lfs_fetch_cmd = self.git_base_cmd();
-            lfs_fetch_cmd.args(["lfs", "fetch"]).current_dir(repo_dir);
+    fn git_local_refs(&self, repo_dir: &Path) -> Result<BTreeMap<String, String>, GitError> {
+        let mut cmd = self.git_base_cmd();
+        cmd.current_dir(repo_dir)
+            .args(["for-each-ref", "--format=%(objectname)%09%(refname)"]);
+        Ok(parse_ls_remote(&self.run_cmd_output(cmd)?))
+    }
 
-            self.run_cmd(lfs_fetch_cmd)
+    fn git_bundle_create(
+        &self,
+        repo_dir: &Path,
+        file: &Path,
+        refs: &[&str],
+        prerequisites: &[&str],
+    ) -> Result<BTreeMap<String, String>, GitError> {
+        let revs: String = refs
+            .iter()
+            .map(|r| format!("{r}\n"))
+            .chain(prerequisites.iter().map(|id| format!("^{id}\n")))
+            .collect();
+        let mut bundle_cmd = self.git_base_cmd();
+        bundle_cmd
+            .current_dir(repo_dir)
+            .args(["bundle", "create", "--quiet"])
+            .arg(file)
+            .arg("--stdin");
+        self.run_cmd_input(bundle_cmd, Some(&revs))?;
+
+        let mut heads_cmd = self.git_base_cmd();
+        heads_cmd
+            .current_dir(repo_dir)
+            .args(["bundle", "list-heads"])
+            .arg(file);
+        let heads = self.run_cmd_output(heads_cmd)?;
+        Ok(heads
+            .lines()
+            .filter_map(|l| l.split_once(' '))
+            .map(|(id, name)| (name.to_owned(), id.to_owned()))
+            .collect())
+    }
+
+    fn git_count_commits(&self, repo_dir: &Path, tips: &[String]) -> Result<u64, GitError> {
+        let mut cmd = self.git_base_cmd();
+        cmd.current_dir(repo_dir).args([
+            "rev-list",
+            "--count",
+            "--all",
+            "--ignore-missing",
+            "--stdin",
+        ]);
+        let input: String = tips.iter().map(|t| format!("^{t}\n")).collect();
+        let stdout = self.run_cmd_input(cmd, Some(&input))?;
+        Ok(stdout.trim().parse().unwrap_or_else(|_| {
+            debug!("Unable to parse commit count: {}", stdout.trim());
+            0
+        }))
+    }
+
+    fn git_lfs_fetch(&self, repo_dir: &Path) -> Result<(), GitError> {
+        let mut lfs_fetch_cmd = self.git_base_cmd();
+        lfs_fetch_cmd
+            .args(["lfs", "fetch", "--all"])
+            .current_dir(repo_dir);
+
+        self.run_cmd(lfs_fetch_cmd)
+    }
+
+    fn git_lfs_push(&self, dest: &str, repo_dir: &Path) -> Result<(), GitError> {
+        let mut lfs_push_cmd = self.git_dest_cmd(repo_dir);
+        lfs_push_cmd.args(["lfs", "push", "--all"]).arg(dest);
+
+        self.run_cmd(lfs_push_cmd)
+    }
+
+    fn git_ls_remote(&self, origin: &str) -> Result<BTreeMap<String, String>, GitError> {
+        #[cfg(feature = "libgit2")]
+        if self.in_process() {
+            return libgit2::ls_remote(self, origin, false).map(|(refs, _)| refs);
+        }
+        let mut cmd = self.git_base_cmd();
+        cmd.arg("ls-remote").arg(origin);
+
+        let stdout = self.run_cmd_output(cmd)?;
+        Ok(parse_ls_remote(&stdout))
+    }
+
+    fn git_ls_dest(
+        &self,
+        dest: &str,
+        repo_dir: &Path,
+    ) -> Result<BTreeMap<String, String>, GitError> {
+        #[cfg(feature = "libgit2")]
+        if self.in_process() {
+            return libgit2::ls_remote(self, dest, true).map(|(refs, _)| refs);
+        }
+        let mut cmd = self.git_dest_cmd(repo_dir);
+        cmd.arg("ls-remote").arg(dest);
+
+        let stdout = self.run_cmd_output(cmd)?;
+        Ok(parse_ls_remote(&stdout))
+    }
+
+    fn git_remote_head(&self, remote: &str) -> Result<Option<String>, GitError> {
+        #[cfg(feature = "libgit2")]
+        if self.in_process() {
+            return libgit2::ls_remote(self, remote, false).map(|(_, head)| head);
+        }
+        let mut cmd = self.git_base_cmd();
+        cmd.args(["ls-remote", "--symref"]).arg(remote).arg("HEAD");
+
+        let stdout = self.run_cmd_output(cmd)?;
+        Ok(parse_head_symref(&stdout).map(str::to_owned))
+    }
+
+    fn git_set_head(&self, repo_dir: &Path, branch: &str) -> Result<(), GitError> {
+        let branch = format!("refs/heads/{branch}");
+        let mut verify_cmd = self.git_base_cmd();
+        verify_cmd
+            .current_dir(repo_dir)
+            .args(["show-ref", "--verify", "--quiet", &branch]);
+        self.run_cmd(verify_cmd)?;
+
+        let mut cmd = self.git_base_cmd();
+        cmd.current_dir(repo_dir)
+            .args(["symbolic-ref", "HEAD", &branch]);
+        self.run_cmd(cmd)
+    }
+
+    fn git_clone_mirror(&self, origin: &str, repo_dir: &Path) -> Result<(), GitError> {
+        #[cfg(feature = "libgit2")]
+        if self.in_process() {
+            return libgit2::clone_mirror(self, origin, repo_dir);
+        }
+        if self.work_tree || !self.exclude_refs.is_empty() {
+            // `clone --mirror` can't exclude refs, so set up the mirror remote manually
+            let mut init_cmd = self.git_base_cmd();
+            init_cmd.arg("init");
+            if !self.work_tree {
+                init_cmd.arg("--bare");
+            }
+            if let Some(ref shared) = self.shared {
+                init_cmd.arg(format!("--shared={shared}"));
+            }
+            init_cmd.arg(repo_dir);
+
+            self.run_cmd(init_cmd)?;
+
+            if let Some(ref pool) = self.alternates {
+                let pool = self.fill_pool(pool, origin, repo_dir)?;
+                let file = alternates_file(repo_dir);
+                fs::write(&file, format!("{}\n", pool.join("objects").display())).map_err(
+                    |err| GitError::IoError {
+                        what: format!("write {file:?}"),
+                        err,
+                    },
+                )?;
+            }
+
+            let mut remote_add_cmd = self.git_base_cmd();
+            remote_add_cmd
+                .current_dir(repo_dir)
+                .args(["remote", "add", "--mirror=fetch", "origin"])
+                .arg(origin);
+
+            self.run_cmd(remote_add_cmd)?;
+
+            if let Some(ref filter) = self.filter {
+                // Same configuration as `clone --filter`, used by all further fetches
+                for (key, value) in [
+                    ("remote.origin.promisor", "true"),
+                    ("remote.origin.partialclonefilter", filter),
+                ] {
+                    let mut config_cmd = self.git_base_cmd();
+                    config_cmd
+                        .current_dir(repo_dir)
+                        .args(["config", key, value]);
+                    self.run_cmd(config_cmd)?;
+                }
+            }
+
+            if self.work_tree {
+                self.git_fetch_work_tree(repo_dir)?;
+            } else {
+                self.run_cmd(self.git_fetch_cmd(repo_dir))?;
+            }
         } else {
-            Ok(())
+            let mut clone_cmd = self.git_base_cmd();
+            clone_cmd.args(["clone", "--mirror"]);
+            self.progress_arg(&mut clone_cmd);
+            if let Some(ref shared) = self.shared {
+                // Not `--shared`, which means something different for clone
+                clone_cmd.arg(format!("--config=core.sharedRepository={shared}"));
+            }
+            if let Some(ref filter) = self.filter {
+                clone_cmd.arg(format!("--filter={filter}"));
+            }
+            if let Some(ref pool) = self.alternates {
+                let pool = self.fill_pool(pool, origin, repo_dir)?;
+                clone_cmd.arg("--reference").arg(pool);
+            }
+            clone_cmd.arg(origin).arg(repo_dir);
+
+            self.run_cmd(clone_cmd)?;
+        }
+        Ok(())
+    }
+
+    fn git_dissociate(&self, repo_dir: &Path) -> Result<(), GitError> {
+        // Without `-l`, which would leave out the borrowed objects
+        let mut repack_cmd = self.git_base_cmd();
+        repack_cmd
+            .current_dir(repo_dir)
+            .args(["repack", "-a", "-d"]);
+        self.run_cmd(repack_cmd)?;
+
+        let file = alternates_file(repo_dir);
+        fs::remove_file(&file).map_err(|err| GitError::IoError {
+            what: format!("remove {file:?}"),
+            err,
+        })
+    }
+
+    fn git_maintenance(&self, repo_dir: &Path, tasks: &[String]) -> Result<(), GitError> {
+        let mut cmd = self.git_base_cmd();
+        cmd.current_dir(repo_dir);
+        if self.git_version()? < MAINTENANCE_VERSION {
+            debug!("git maintenance not supported, using git gc");
+            cmd.args(["gc", "--auto"]);
+        } else if tasks.is_empty() {
+            cmd.args(["maintenance", "run", "--auto"]);
+        } else {
+            cmd.args(["maintenance", "run"]);
+            for t in tasks {
+                cmd.arg(format!("--task={t}"));
+            }
+        }
+        self.run_cmd(cmd)
+    }
+
+    fn git_write_commit_graph(&self, repo_dir: &Path) -> Result<bool, GitError> {
+        if self.git_version()? < COMMIT_GRAPH_VERSION {
+            return Ok(false);
+        }
+        let mut cmd = self.git_base_cmd();
+        cmd.current_dir(repo_dir)
+            .args(["commit-graph", "write", "--reachable"]);
+        self.run_cmd(cmd).map(|_| true)
+    }
+
+    fn git_write_midx(&self, repo_dir: &Path) -> Result<bool, GitError> {
+        if self.git_version()? < MIDX_VERSION {
+            return Ok(false);
+        }
+        let mut cmd = self.git_base_cmd();
+        cmd.current_dir(repo_dir)
+            .args(["multi-pack-index", "write"]);
+        self.run_cmd(cmd).map(|_| true)
+    }
+
+    fn git_fsck(&self, repo_dir: &Path) -> Result<(), GitError> {
+        let mut cmd = self.git_base_cmd();
+        cmd.current_dir(repo_dir)
+            .args(["fsck", "--no-dangling", "--no-progress"]);
+        self.run_cmd(cmd)
+    }
+
+    fn git_verify_signatures(
+        &self,
+        repo_dir: &Path,
+        gpg_home: &Path,
+    ) -> Result<Vec<(String, &'static str)>, GitError> {
+        let mut refs_cmd = self.git_base_cmd();
+        refs_cmd.current_dir(repo_dir).args([
+            "for-each-ref",
+            "--format=%(refname) %(objecttype) %(objectname)",
+            "refs/heads",
+            "refs/tags",
+        ]);
+        let refs = self.run_cmd_output(refs_cmd)?;
+
+        let mut unverified = Vec::new();
+        for line in refs.lines() {
+            let mut fields = line.split(' ');
+            let (name, verify, id) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(name), Some("commit"), Some(id)) => (name, "verify-commit", id),
+                (Some(name), Some("tag"), Some(id)) => (name, "verify-tag", id),
+                // Tags of trees and blobs can't be signed
+                _ => continue,
+            };
+            let mut verify_cmd = self.git_base_cmd();
+            verify_cmd
+                .current_dir(repo_dir)
+                .env("GNUPGHOME", gpg_home)
+                .args([verify, "--raw", id]);
+            match self.run_cmd(verify_cmd) {
+                Ok(()) => {}
+                Err(GitError::GitCommandError { stderr, .. }) => {
+                    unverified.push((name.to_string(), signature_problem(&stderr)))
+                }
+                Err(e) => return Err(e),
+            }
         }
+        Ok(unverified)
+    }
+
+    fn git_repo_size(&self, repo_dir: &Path) -> Result<u64, GitError> {
+        let mut cmd = self.git_base_cmd();
+        cmd.current_dir(repo_dir).args(["count-objects", "-v"]);
+        let stdout = self.run_cmd_output(cmd)?;
+        Ok(parse_count_objects(&stdout))
+    }
+
+    fn run_hook(
+        &self,
+        hook: &str,
+        repo_dir: &Path,
+        origin: &str,
+        dest: &str,
+    ) -> Result<(), GitError> {
+        let mut cmd = if cfg!(windows) {
+            let mut cmd = Command::new("cmd");
+            cmd.arg("/C");
+            cmd
+        } else {
+            let mut cmd = Command::new("sh");
+            cmd.arg("-c");
+            cmd
+        };
+        cmd.arg(hook)
+            .current_dir(repo_dir)
+            .env("GIT_MIRROR_REPO", repo_dir)
+            .env("GIT_MIRROR_ORIGIN", origin)
+            .env("GIT_MIRROR_DESTINATION", dest);
+        self.run_cmd(cmd)
     }
 
-    fn git_update_mirror(&self, origin: &str, repo_dir: &Path, lfs: bool) -> Result<(), GitError> {
+    fn git_update_mirror(&self, origin: &str, repo_dir: &Path) -> Result<(), GitError> {
+        #[cfg(feature = "libgit2")]
+        if self.in_process() {
+            return libgit2::update_mirror(self, origin, repo_dir);
+        }
         let mut set_url_cmd = self.git_base_cmd();
         set_url_cmd
             .current_dir(repo_dir)
@@ -134,21 +1586,28 @@ impl GitWrapper for Git {
 
         self.run_cmd(set_url_cmd)?;
 
-        let mut remote_update_cmd = self.git_base_cmd();
-        remote_update_cmd
-            .current_dir(repo_dir)
-            .args(["remote", "update", "--prune"]);
-
-        self.run_cmd(remote_update_cmd)?;
-
-        if self.lfs_enabled && lfs {
-            let mut lfs_fetch_cmd = self.git_base_cmd();
-            lfs_fetch_cmd.args(["lfs", "fetch"]).current_dir(repo_dir);
+        if let Some(ref shared) = self.shared {
+            let mut config_cmd = self.git_base_cmd();
+            config_cmd
+                .current_dir(repo_dir)
+                .args(["config", "core.sharedRepository", shared]);
+            self.run_cmd(config_cmd)?;
+        }
 
-            self.run_cmd(lfs_fetch_cmd)
+        if self.work_tree {
+            self.git_fetch_work_tree(repo_dir)?;
+        } else if !self.exclude_refs.is_empty() || self.track_transfer {
+            // `git remote update` doesn't take `--progress`
+            self.run_cmd(self.git_fetch_cmd(repo_dir))?;
         } else {
-            Ok(())
+            let mut remote_update_cmd = self.git_base_cmd();
+            remote_update_cmd
+                .current_dir(repo_dir)
+                .args(["remote", "update", "--prune"]);
+
+            self.run_cmd(remote_update_cmd)?;
         }
+        Ok(())
     }
 
     fn git_push_mirror(
@@ -156,27 +1615,445 @@ impl GitWrapper for Git {
         dest: &str,
         repo_dir: &Path,
         refspec: &Option<Vec<String>>,
-        lfs: bool,
     ) -> Result<(), GitError> {
-        if self.lfs_enabled && lfs {
-            let mut lfs_install_cmd = self.git_base_cmd();
-            lfs_install_cmd
-                .args(["lfs", "install"])
-                .current_dir(repo_dir);
-            self.run_cmd(lfs_install_cmd)?;
-        }
-
-        let mut push_cmd = self.git_base_cmd();
-        push_cmd.current_dir(repo_dir);
-        push_cmd.args(["push", "-f"]);
-        if let Some(r) = &refspec {
-            push_cmd.arg(dest);
-            for spec in r.iter() {
-                push_cmd.arg(spec);
+        #[cfg(feature = "libgit2")]
+        if self.in_process() {
+            let (specs, prune) = match refspec {
+                Some(r) => (r.as_slice(), self.prune_refs),
+                None => (&["+refs/*:refs/*".to_string()][..], true),
+            };
+            let specs: Vec<String> = specs
+                .iter()
+                .map(|s| self.push_spec(s).to_string())
+                .collect();
+            let mut specs = self.forced_specs(&specs);
+            if prune {
+                specs.extend(self.keep_refs.iter().map(|r| format!("^{r}")));
             }
+            return libgit2::push(self, dest, repo_dir, &specs, prune);
+        }
+        // Only the mirrored branches keep their names on the destination
+        let mut batched = refspec.is_some() || self.push_batch.is_some();
+        if let (Some(batch), None) = (self.push_batch, refspec) {
+            self.git_push_batches(dest, repo_dir, batch)?;
+        }
+
+        let mut push_options = self.push_options.as_slice();
+        let err = loop {
+            let mut push_cmd = self.git_push_cmd(repo_dir, push_options);
+            push_cmd.arg("--porcelain");
+            if let Some(r) = &refspec {
+                if self.prune_refs {
+                    push_cmd.arg("--prune");
+                }
+                push_cmd.arg(dest);
+                for spec in r.iter() {
+                    push_cmd.arg(self.push_spec(spec));
+                }
+                if self.prune_refs {
+                    for r in self.keep_refs.iter() {
+                        push_cmd.arg(format!("^{r}"));
+                    }
+                }
+            } else if self.keep_refs.is_empty() && !self.no_force {
+                push_cmd.args(["--mirror", dest]);
+            } else {
+                // Like --mirror, but never delete the kept refs on the destination
+                push_cmd.args(["--prune", dest, self.push_spec("+refs/*:refs/*")]);
+                for r in self.keep_refs.iter() {
+                    push_cmd.arg(format!("^{r}"));
+                }
+            }
+
+            match self.run_cmd_output(push_cmd) {
+                Ok(_) => return Ok(()),
+                Err(e) if !push_options.is_empty() && push_options_unsupported(&e) => {
+                    warn!(
+                        "Destination {} does not support push options, pushing without",
+                        dest
+                    );
+                    push_options = &[];
+                }
+                Err(e) if !batched && pack_too_large(&e) => {
+                    warn!(
+                        "Push to {} exceeds the pack size limit, pushing the history in batches of {} commits",
+                        dest, DEFAULT_PUSH_BATCH
+                    );
+                    self.git_push_batches(dest, repo_dir, DEFAULT_PUSH_BATCH)?;
+                    batched = true;
+                }
+                Err(e) => break e,
+            }
+        };
+
+        // Some refs may have been rejected (e.g. protected branches), blocking the whole push.
+        // Retry them one by one so the rejected ones don't prevent the others from being pushed.
+        // Refs that aren't fast-forwards are rejected again, they don't need a retry
+        let (retry, diverged) = match &err {
+            GitError::GitCommandError { stdout, .. } if self.no_force => {
+                let diverged = diverged_refs(stdout);
+                let retry = rejected_refs(stdout)
+                    .into_iter()
+                    .filter(|(_, name)| !diverged.contains(name))
+                    .collect();
+                (retry, diverged)
+            }
+            GitError::GitCommandError { stdout, .. } => (rejected_refs(stdout), Vec::new()),
+            _ => (Vec::new(), Vec::new()),
+        };
+        if retry.is_empty() && diverged.is_empty() {
+            return Err(err);
+        }
+        if !retry.is_empty() {
+            warn!(
+                "Destination {} rejected {} refs, retrying them individually",
+                dest,
+                retry.len()
+            );
+        }
+
+        let mut rejected = Vec::new();
+        for (spec, name) in retry {
+            let mut retry_cmd = self.git_push_cmd(repo_dir, push_options);
+            retry_cmd.arg(dest).arg(self.push_spec(&spec));
+            if let Err(e) = self.run_cmd(retry_cmd) {
+                debug!("Ref {} rejected: {}", name, e);
+                rejected.push(name);
+            }
+        }
+
+        if !diverged.is_empty() {
+            if !rejected.is_empty() {
+                warn!(
+                    "Destination {} rejected refs: {}",
+                    dest,
+                    rejected.join(", ")
+                );
+            }
+            Err(GitError::Diverged { refs: diverged })
+        } else if rejected.is_empty() {
+            Ok(())
         } else {
-            push_cmd.args(["--mirror", dest]);
+            Err(GitError::RefsRejected { refs: rejected })
         }
+    }
+
+    fn git_push_refs(
+        &self,
+        dest: &str,
+        repo_dir: &Path,
+        refspecs: &[String],
+    ) -> Result<(), GitError> {
+        #[cfg(feature = "libgit2")]
+        if self.in_process() {
+            return libgit2::push(self, dest, repo_dir, &self.forced_specs(refspecs), true);
+        }
+        let mut push_cmd = self.git_push_cmd(repo_dir, &[]);
+        push_cmd.arg("--prune").arg(dest).args(refspecs);
         self.run_cmd(push_cmd)
     }
+
+    fn git_import_subtree(
+        &self,
+        origin: &str,
+        dest: &str,
+        repo_dir: &Path,
+        prefix: &str,
+        branch: &str,
+    ) -> Result<bool, GitError> {
+        if !repo_dir.is_dir() {
+            let mut init_cmd = self.git_base_cmd();
+            init_cmd.args(["init", "--bare", "--quiet"]).arg(repo_dir);
+            self.run_cmd(init_cmd)?;
+        }
+        // The tree is built in a separate index, relative to the bare repository
+        let git = |args: &[&str]| {
+            let mut cmd = self.git_base_cmd();
+            cmd.current_dir(repo_dir)
+                .env("GIT_INDEX_FILE", "subtree.index")
+                .args(args);
+            cmd
+        };
+
+        let source = format!("refs/subtree/{}", slugify(prefix));
+        let mut fetch_cmd = git(&["fetch", "--no-tags"]);
+        self.progress_arg(&mut fetch_cmd);
+        fetch_cmd.arg(origin).arg(format!("+HEAD:{source}"));
+        self.run_cmd(fetch_cmd)?;
+
+        // The current destination branch is the base of the import
+        let target = format!("refs/heads/{branch}");
+        let mut ls_remote_cmd = self.git_dest_cmd(repo_dir);
+        ls_remote_cmd.arg("ls-remote").arg(dest).arg(&target);
+        let parent = if self.run_cmd_output(ls_remote_cmd)?.trim().is_empty() {
+            None
+        } else {
+            let mut fetch_cmd = self.git_dest_cmd(repo_dir);
+            fetch_cmd
+                .args(["fetch", "--no-tags"])
+                .arg(dest)
+                .arg(format!("+{target}:{target}"));
+            self.run_cmd(fetch_cmd)?;
+            let parent = self.run_cmd_output(git(&["rev-parse", &target]))?;
+            Some(parent.trim().to_string())
+        };
+
+        // Everything outside of the prefix is kept, the prefix gets the source tree
+        let below = format!("{prefix}/");
+        let mut entries = String::new();
+        if let Some(ref parent) = parent {
+            let tree = self.run_cmd_output(git(&["ls-tree", "-r", "-z", "--full-tree", parent]))?;
+            for e in tree.split_terminator('\0') {
+                let path = e.split_once('\t').map(|(_, p)| p).unwrap_or_default();
+                if path != prefix && !path.starts_with(&below) {
+                    entries.push_str(e);
+                    entries.push('\0');
+                }
+            }
+        }
+        let tree = self.run_cmd_output(git(&["ls-tree", "-r", "-z", "--full-tree", &source]))?;
+        for e in tree.split_terminator('\0') {
+            if let Some((info, path)) = e.split_once('\t') {
+                entries.push_str(&format!("{info}\t{below}{path}\0"));
+            }
+        }
+        self.run_cmd(git(&["read-tree", "--empty"]))?;
+        self.run_cmd_input(git(&["update-index", "-z", "--index-info"]), Some(&entries))?;
+        let tree = self.run_cmd_output(git(&["write-tree"]))?;
+        let tree = tree.trim();
+
+        if let Some(ref parent) = parent {
+            let parent_tree =
+                self.run_cmd_output(git(&["rev-parse", &format!("{parent}^{{tree}}")]))?;
+            if parent_tree.trim() == tree {
+                return Ok(false);
+            }
+        }
+
+        let mut commit_cmd = git(&["commit-tree", tree, "-m"]);
+        commit_cmd
+            .arg(format!("Import {origin} into {prefix}/"))
+            .envs([
+                ("GIT_AUTHOR_NAME", "git-mirror"),
+                ("GIT_AUTHOR_EMAIL", "git-mirror@localhost"),
+                ("GIT_COMMITTER_NAME", "git-mirror"),
+                ("GIT_COMMITTER_EMAIL", "git-mirror@localhost"),
+            ]);
+        if let Some(ref parent) = parent {
+            commit_cmd.args(["-p", parent]);
+        }
+        let commit = self.run_cmd_output(commit_cmd)?;
+
+        // Not forced, changes pushed to the destination branch in the meantime are kept
+        let mut push_cmd = self.git_push_base_cmd(repo_dir);
+        self.progress_arg(&mut push_cmd);
+        for o in self.push_options.iter() {
+            push_cmd.arg(format!("--push-option={o}"));
+        }
+        push_cmd
+            .arg(dest)
+            .arg(format!("{}:{}", commit.trim(), target));
+        self.run_cmd(push_cmd)?;
+        Ok(true)
+    }
+
+    fn git_push_metadata(
+        &self,
+        dest: &str,
+        repo_dir: &Path,
+        meta_ref: &str,
+        file_name: &str,
+        metadata: &str,
+    ) -> Result<(), GitError> {
+        let mut blob_cmd = self.git_base_cmd();
+        blob_cmd
+            .current_dir(repo_dir)
+            .args(["hash-object", "-w", "--stdin"]);
+        let blob = self.run_cmd_input(blob_cmd, Some(metadata))?;
+
+        let mut tree_cmd = self.git_base_cmd();
+        tree_cmd.current_dir(repo_dir).arg("mktree");
+        let tree = self.run_cmd_input(
+            tree_cmd,
+            Some(&format!("100644 blob {}\t{}\n", blob.trim(), file_name)),
+        )?;
+
+        let mut commit_cmd = self.git_base_cmd();
+        commit_cmd
+            .current_dir(repo_dir)
+            .envs([
+                ("GIT_AUTHOR_NAME", "git-mirror"),
+                ("GIT_AUTHOR_EMAIL", "git-mirror@localhost"),
+                ("GIT_COMMITTER_NAME", "git-mirror"),
+                ("GIT_COMMITTER_EMAIL", "git-mirror@localhost"),
+            ])
+            .args(["commit-tree", "-m", "git-mirror sync metadata"])
+            .arg(tree.trim());
+        let commit = self.run_cmd_output(commit_cmd)?;
+
+        let mut push_cmd = self.git_push_cmd(repo_dir, &[]);
+        push_cmd
+            .arg(dest)
+            .arg(format!("+{}:{}", commit.trim(), meta_ref));
+        self.run_cmd(push_cmd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        diverged_refs, parse_count_objects, parse_head_symref, parse_insecure_url, parse_ssh_jump,
+        parse_version, proxy_command, rejected_refs, signature_problem, ssl_verify_config,
+        GitFailureKind, SshOptions,
+    };
+
+    #[test]
+    fn insecure() {
+        assert_eq!(ssl_verify_config(""), "http.sslVerify=false");
+        assert_eq!(
+            ssl_verify_config("https://git.example.com/"),
+            "http.https://git.example.com/.sslVerify=false"
+        );
+        assert!(parse_insecure_url("https://*.example.com").is_ok());
+        assert!(parse_insecure_url("git.example.com").is_err());
+    }
+
+    #[test]
+    fn count_objects() {
+        let output = "count: 2\nsize: 8\nin-pack: 10\npacks: 1\nsize-pack: 20\n\
+            prune-packable: 0\ngarbage: 1\nsize-garbage: 4\n";
+        assert_eq!(parse_count_objects(output), 32 * 1024);
+    }
+
+    #[test]
+    fn signature_problems() {
+        assert_eq!(signature_problem(""), "unsigned");
+        assert_eq!(
+            signature_problem("[GNUPG:] NEWSIG\n[GNUPG:] BADSIG 3F81D59DEDBB3E0C Mirror Test\n"),
+            "bad signature"
+        );
+        assert_eq!(
+            signature_problem("[GNUPG:] ERRSIG 3F81D59DEDBB3E0C 22 8\n[GNUPG:] NO_PUBKEY 3F81D5\n"),
+            "unknown key"
+        );
+        assert_eq!(
+            signature_problem("[GNUPG:] EXPKEYSIG 3F81D59DEDBB3E0C Mirror Test\n"),
+            "expired or revoked key"
+        );
+        assert_eq!(
+            signature_problem("error: no signature found\n"),
+            "invalid signature"
+        );
+    }
+
+    #[test]
+    fn ssh_jump() {
+        assert!(parse_ssh_jump("bastion.example.com").is_ok());
+        assert!(parse_ssh_jump("jump@bastion:2222,admin@[2001:db8::1]").is_ok());
+        assert!(parse_ssh_jump("").is_err());
+        assert!(parse_ssh_jump("-oProxyCommand=x").is_err());
+        assert!(parse_ssh_jump("host; rm -rf /").is_err());
+    }
+
+    #[test]
+    fn ssh_jump_proxy() {
+        let ssh = SshOptions {
+            jump: Some("jump@bastion:2222,inner".to_string()),
+            strict_host_key_checking: Some(true),
+            ..Default::default()
+        };
+        let options = ssh.options();
+        assert_eq!(options, " -o StrictHostKeyChecking=yes");
+        // The inner hop is reached through the first one, its tokens are expanded by the outer ssh
+        assert_eq!(
+            proxy_command("ssh", &options, &["jump@bastion:2222", "inner"]),
+            "ssh -o StrictHostKeyChecking=yes -o ProxyCommand='ssh -o StrictHostKeyChecking=yes \
+             -W %%h:%%p ssh://jump@bastion:2222' -W %h:%p ssh://inner"
+        );
+    }
+
+    #[test]
+    fn version() {
+        assert_eq!(parse_version("git version 2.39.2\n"), Some((2, 39)));
+        assert_eq!(
+            parse_version("git version 2.37.1 (Apple Git-137.1)"),
+            Some((2, 37))
+        );
+        assert_eq!(parse_version("git version 2.41.0.windows.1"), Some((2, 41)));
+        assert_eq!(parse_version("hub version 2.14.2"), None);
+    }
+
+    #[test]
+    fn head_symref() {
+        let output = "ref: refs/heads/main\tHEAD\n6f3c0e2d1b8a4c9e7f5d3b1a0c8e6f4d2b0a9c7e\tHEAD\n";
+        assert_eq!(parse_head_symref(output), Some("refs/heads/main"));
+        assert_eq!(parse_head_symref(""), None);
+    }
+
+    #[test]
+    fn classify_failures() {
+        use GitFailureKind::*;
+        let cases = [
+            (
+                "fatal: Authentication failed for 'https://example.com/a.git/'",
+                Auth,
+            ),
+            ("git@example.com: Permission denied (publickey).", Auth),
+            ("Host key verification failed.", HostKey),
+            ("remote: Repository not found.", NotFound),
+            (
+                "fatal: '/tmp/a' does not appear to be a git repository",
+                NotFound,
+            ),
+            (
+                "fatal: unable to access: Could not resolve host: example.com",
+                Network,
+            ),
+            (
+                "ssh: connect to host example.com port 22: Connection timed out",
+                Network,
+            ),
+            (
+                "error: pathspec 'x' did not match any file(s) known to git",
+                Other,
+            ),
+        ];
+        for (stderr, kind) in cases {
+            assert_eq!(GitFailureKind::classify(stderr), kind, "{}", stderr);
+        }
+        assert!(!Auth.is_transient());
+        assert!(Network.is_transient());
+    }
+
+    #[test]
+    fn parse_rejected_refs() {
+        let porcelain = "To git@example.com:group/repo.git\n\
+             =\trefs/heads/dev:refs/heads/dev\t[up to date]\n\
+             !\trefs/heads/main:refs/heads/main\t[remote rejected] (protected branch hook declined)\n\
+             !\t:refs/heads/old\t[remote rejected] (deletion prohibited)\n\
+             Done\n";
+        assert_eq!(
+            rejected_refs(porcelain),
+            vec![
+                (
+                    "+refs/heads/main:refs/heads/main".to_owned(),
+                    "refs/heads/main".to_owned()
+                ),
+                (":refs/heads/old".to_owned(), "refs/heads/old".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_diverged_refs() {
+        let porcelain = "To git@example.com:group/repo.git\n\
+             !\trefs/heads/main:refs/heads/main\t[rejected] (non-fast-forward)\n\
+             !\trefs/tags/v1:refs/tags/v1\t[rejected] (already exists)\n\
+             !\trefs/heads/dev:refs/heads/dev\t[remote rejected] (protected branch hook declined)\n\
+             Done\n";
+        assert_eq!(
+            diverged_refs(porcelain),
+            vec!["refs/heads/main".to_owned(), "refs/tags/v1".to_owned()]
+        );
+    }
 }
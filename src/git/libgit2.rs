@@ -0,0 +1,649 @@
+/*
+ * Copyright (c) 2023 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+//! In-process transfers with libgit2, used by `Git` with `GitBackend::Libgit2`
+
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::time::Instant;
+
+use git2::{
+    AutotagOption, CertificateCheckStatus, Cred, CredentialType, Direction, ErrorClass, ErrorCode,
+    FetchOptions, FetchPrune, ObjectType, Oid, ProxyOptions, PushOptions, Remote, RemoteCallbacks,
+    Repository, RepositoryInitMode, RepositoryInitOptions,
+};
+
+use log::{debug, warn};
+
+use super::{Deadline, Git, GitError, GitFailureKind, DEADLINE};
+use crate::transfer::{self, Phase};
+
+/// Classify a libgit2 failure, by its code and otherwise like the stderr of git
+pub(super) fn failure_kind(err: &git2::Error) -> GitFailureKind {
+    match err.code() {
+        ErrorCode::Auth => GitFailureKind::Auth,
+        ErrorCode::Certificate => GitFailureKind::HostKey,
+        _ if err.message().contains("status code: 404") => GitFailureKind::NotFound,
+        // A local path without repository
+        ErrorCode::NotFound if matches!(err.class(), ErrorClass::Repository | ErrorClass::Os) => {
+            GitFailureKind::NotFound
+        }
+        _ => match GitFailureKind::classify(err.message()) {
+            GitFailureKind::Other
+                if matches!(
+                    err.class(),
+                    ErrorClass::Net | ErrorClass::Http | ErrorClass::Ssh | ErrorClass::Ssl
+                ) =>
+            {
+                GitFailureKind::Network
+            }
+            kind => kind,
+        },
+    }
+}
+
+fn expired(deadline: Option<Deadline>) -> bool {
+    deadline.is_some_and(|d| Instant::now() >= d.at)
+}
+
+/// Run the transfer `op` with the remote `url`, logged and limited by the deadline like the git
+/// commands. libgit2 can only be interrupted between the progress updates of a transfer.
+fn run<T>(
+    git: &Git,
+    op: &str,
+    url: &str,
+    f: impl FnOnce() -> Result<T, git2::Error>,
+) -> Result<T, GitError> {
+    let shown = git.redact(format!("libgit2 {op} {url}"));
+    debug!("Run transfer: {}", shown);
+    git.log.log(format_args!("Run transfer: {shown}"));
+    let deadline = DEADLINE.with(Cell::get);
+    let result = if expired(deadline) { None } else { Some(f()) };
+    match result {
+        Some(Ok(t)) => {
+            git.log.log("Finished");
+            Ok(t)
+        }
+        Some(Err(err)) if !expired(deadline) => {
+            let err = git2::Error::new(err.code(), err.class(), git.redact(err.message().into()));
+            git.log.log(format_args!("Failed: {}", err.message()));
+            Err(GitError::Libgit2Error {
+                op: op.to_owned(),
+                url: url.to_owned(),
+                err,
+            })
+        }
+        _ => {
+            let budget = deadline.map(|d| d.budget).unwrap_or_default();
+            git.log.log(format_args!(
+                "Cancelled, the time budget of {} is exceeded",
+                humantime::format_duration(budget)
+            ));
+            let mut cmd = Command::new("libgit2");
+            cmd.arg(op).arg(url);
+            Err(GitError::TimedOut {
+                cmd: Box::new(cmd),
+                budget,
+            })
+        }
+    }
+}
+
+/// Callbacks authenticating against `url`, pushes to the destination with its token. Every kind
+/// of credential is only tried once, libgit2 would otherwise ask again after every rejection.
+fn callbacks<'a>(git: &'a Git, url: &str, dest: bool) -> RemoteCallbacks<'a> {
+    let token = git.dest_token.as_deref().filter(|_| dest);
+    let mut tried = CredentialType::empty();
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.credentials(move |url, username, allowed| {
+        let user = username.unwrap_or("git");
+        let kind = [
+            CredentialType::USERNAME,
+            CredentialType::SSH_KEY,
+            CredentialType::USER_PASS_PLAINTEXT,
+        ]
+        .into_iter()
+        .find(|&k| allowed.contains(k) && !tried.contains(k));
+        let failed = || {
+            git2::Error::new(
+                ErrorCode::Auth,
+                ErrorClass::Callback,
+                format!("authentication failed for '{url}'"),
+            )
+        };
+        let kind = kind.ok_or_else(failed)?;
+        tried |= kind;
+        match (kind, &git.ssh.key, token) {
+            (CredentialType::USERNAME, _, _) => Cred::username(user),
+            (CredentialType::SSH_KEY, Some(key), _) => Cred::ssh_key(user, None, key, None),
+            (CredentialType::SSH_KEY, None, _) => Cred::ssh_key_from_agent(user),
+            (_, _, Some(token)) => Cred::userpass_plaintext("git-mirror", token),
+            // The credential helpers configured for git
+            _ => git2::Config::open_default()
+                .and_then(|config| Cred::credential_helper(&config, url, username))
+                .map_err(|_| failed()),
+        }
+    });
+
+    let insecure = git.insecure.iter().any(|u| url.starts_with(u.as_str()));
+    let accept_host_keys = git.ssh.strict_host_key_checking == Some(false);
+    callbacks.certificate_check(move |cert, _| {
+        let host_key = cert.as_hostkey().is_some();
+        Ok(
+            if (host_key && accept_host_keys) || (!host_key && insecure) {
+                CertificateCheckStatus::CertificateOk
+            } else {
+                CertificateCheckStatus::CertificatePassthrough
+            },
+        )
+    });
+
+    // Returning false cancels the transfer
+    let deadline = DEADLINE.with(Cell::get);
+    callbacks.transfer_progress(move |_| !expired(deadline));
+    callbacks.sideband_progress(move |_| !expired(deadline));
+    callbacks
+}
+
+/// Use the proxy configured for git or in the environment, like git
+fn proxy_options() -> ProxyOptions<'static> {
+    let mut proxy = ProxyOptions::new();
+    proxy.auto();
+    proxy
+}
+
+/// The path of a remote libgit2 uses its local transport for, a path or `file://` URL
+fn local_path(url: &str) -> Option<&Path> {
+    match url.split_once("://") {
+        Some(("file", path)) => Some(Path::new(path)),
+        Some(_) => None,
+        // `host:path` is SSH
+        None if url.split('/').next().is_some_and(|s| s.contains(':')) => None,
+        None => Some(Path::new(url)),
+    }
+}
+
+/// List the refs of a local repository like the local transport, which can't list repositories
+/// without refs (`Remote::list` fails on the empty list)
+fn ls_local(path: &Path) -> Result<(BTreeMap<String, String>, Option<String>), git2::Error> {
+    let repo = Repository::open(path)?;
+    let mut refs = BTreeMap::new();
+    for r in repo.references()? {
+        let r = r?;
+        let (Some(name), Some(id)) = (r.name(), r.target()) else {
+            continue;
+        };
+        refs.insert(name.to_owned(), id.to_string());
+        // Annotated tags are listed with the object they point to as well
+        if let Ok(peeled) = r.peel(ObjectType::Any) {
+            if peeled.id() != id {
+                refs.insert(format!("{name}^{{}}"), peeled.id().to_string());
+            }
+        }
+    }
+    let mut head = None;
+    if let Ok(id) = repo.refname_to_id("HEAD") {
+        refs.insert("HEAD".to_string(), id.to_string());
+        let target = repo.find_reference("HEAD")?;
+        head = target.symbolic_target().map(str::to_owned);
+    }
+    Ok((refs, head))
+}
+
+/// List the refs of the remote and the branch its HEAD points to
+pub(super) fn ls_remote(
+    git: &Git,
+    url: &str,
+    dest: bool,
+) -> Result<(BTreeMap<String, String>, Option<String>), GitError> {
+    run(git, "ls-remote", url, || {
+        if let Some(path) = local_path(url) {
+            return ls_local(path);
+        }
+        let mut remote = Remote::create_detached(url)?;
+        let connection = remote.connect_auth(
+            Direction::Fetch,
+            Some(callbacks(git, url, dest)),
+            Some(proxy_options()),
+        )?;
+        let refs = connection
+            .list()?
+            .iter()
+            .map(|head| (head.name().to_owned(), head.oid().to_string()))
+            .collect();
+        let head = connection
+            .default_branch()
+            .ok()
+            .and_then(|branch| branch.as_str().map(str::to_owned));
+        Ok((refs, head))
+    })
+}
+
+/// Fetch all refs of `origin` that are not excluded into the bare repository, pruning the
+/// refs the origin no longer has
+fn fetch(git: &Git, repo: &Repository, origin: &str) -> Result<(), git2::Error> {
+    let mut remote = repo.find_remote("origin")?;
+    let mut specs = vec!["+refs/*:refs/*".to_string()];
+    specs.extend(git.exclude_refs.iter().map(|r| format!("^{r}")));
+    let mut options = FetchOptions::new();
+    options
+        .remote_callbacks(callbacks(git, origin, false))
+        .proxy_options(proxy_options())
+        .prune(FetchPrune::On)
+        .download_tags(AutotagOption::None);
+    remote.fetch(&specs, Some(&mut options), None)?;
+    if git.track_transfer {
+        let stats = remote.stats();
+        transfer::add_job_transfer(
+            Phase::Fetch,
+            stats.received_objects() as u64,
+            stats.received_bytes() as u64,
+        );
+    }
+    Ok(())
+}
+
+/// Set `core.sharedRepository` like `git init --shared`
+fn init_mode(shared: &str) -> RepositoryInitMode {
+    match shared {
+        "group" => RepositoryInitMode::SHARED_GROUP,
+        "all" => RepositoryInitMode::SHARED_ALL,
+        _ => RepositoryInitMode::SHARED_UMASK,
+    }
+}
+
+/// Like `git clone --mirror`, the repository is removed again if the clone fails
+pub(super) fn clone_mirror(git: &Git, origin: &str, repo_dir: &Path) -> Result<(), GitError> {
+    let (_, head) = ls_remote(git, origin, false)?;
+    let result = run(git, "clone", origin, || {
+        let mut init = RepositoryInitOptions::new();
+        init.bare(true);
+        if let Some(ref shared) = git.shared {
+            init.mode(init_mode(shared));
+        }
+        let repo = Repository::init_opts(repo_dir, &init)?;
+        repo.remote_with_fetch("origin", origin, "+refs/*:refs/*")?;
+        repo.config()?.set_bool("remote.origin.mirror", true)?;
+        fetch(git, &repo, origin)?;
+
+        // HEAD points to the default branch of the origin
+        match head {
+            Some(ref head) => repo.set_head(head),
+            None => Ok(()),
+        }
+    });
+    if result.is_err() && repo_dir.is_dir() {
+        let _ = fs::remove_dir_all(repo_dir);
+    }
+    result
+}
+
+/// Fetch into an existing mirror like `git remote update --prune`
+pub(super) fn update_mirror(git: &Git, origin: &str, repo_dir: &Path) -> Result<(), GitError> {
+    run(git, "fetch", origin, || {
+        let repo = Repository::open_bare(repo_dir)?;
+        repo.remote_set_url("origin", origin)?;
+        if let Some(ref shared) = git.shared {
+            repo.config()?.set_str("core.sharedRepository", shared)?;
+        }
+        fetch(git, &repo, origin)
+    })
+}
+
+/// Match `name` against a pattern with at most one `*`, returns the part matched by the `*`
+fn glob<'a>(pattern: &str, name: &'a str) -> Option<&'a str> {
+    match pattern.split_once('*') {
+        Some((prefix, suffix)) if name.len() >= prefix.len() + suffix.len() => name
+            .strip_prefix(prefix)
+            .and_then(|rest| rest.strip_suffix(suffix)),
+        Some(_) => None,
+        None => (name == pattern).then_some(""),
+    }
+}
+
+/// Full ref names of a refspec side, short names can be branches or tags
+fn prefixes(src: &str) -> &'static [&'static str] {
+    if src.starts_with("refs/") || src == "HEAD" {
+        &[""]
+    } else {
+        &["refs/heads/", "refs/tags/"]
+    }
+}
+
+/// Refs pushed with a refspec, mapped to their source and whether they are forced
+type Pushed = BTreeMap<String, (String, bool)>;
+
+/// The refspecs updating the destination to the local refs as `git push` would with `specs`.
+/// Only what differs is pushed, refs excluded with `^` are neither pushed nor deleted, and with
+/// `prune` the remote refs matching a destination pattern but not pushed are deleted.
+/// Without force, refs that aren't fast-forwards and existing tags are returned as diverged.
+/// Returns the refspecs with their destination ref and the diverged refs.
+fn push_plan(
+    specs: &[String],
+    local: &BTreeMap<String, String>,
+    remote: &BTreeMap<String, String>,
+    prune: bool,
+    fast_forward: impl Fn(&str, &str) -> bool,
+) -> (Vec<(String, String)>, Vec<String>) {
+    let excluded = |name: &str| {
+        specs.iter().filter_map(|s| s.strip_prefix('^')).any(|p| {
+            prefixes(p)
+                .iter()
+                .any(|pre| glob(&format!("{pre}{p}"), name).is_some())
+        })
+    };
+    let mut pushed = Pushed::new();
+    let mut deleted = BTreeSet::new();
+    let mut patterns = Vec::new();
+    for spec in specs.iter().filter(|s| !s.starts_with('^')) {
+        let (force, spec) = match spec.strip_prefix('+') {
+            Some(spec) => (true, spec),
+            None => (false, spec.as_str()),
+        };
+        let (src, dst) = spec.split_once(':').unwrap_or((spec, spec));
+        if src.is_empty() {
+            deleted.insert(dst.to_owned());
+            continue;
+        }
+        for prefix in prefixes(src) {
+            let src = format!("{prefix}{src}");
+            let dst = if dst.starts_with("refs/") || dst == "HEAD" {
+                dst.to_owned()
+            } else {
+                format!("{prefix}{dst}")
+            };
+            let mut matched = false;
+            for name in local.keys().filter(|name| !excluded(name)) {
+                if let Some(m) = glob(&src, name) {
+                    pushed.insert(dst.replacen('*', m, 1), (name.clone(), force));
+                    matched = true;
+                }
+            }
+            if matched || src.contains('*') {
+                patterns.push(dst);
+            }
+        }
+    }
+
+    let mut updates = Vec::new();
+    let mut diverged = Vec::new();
+    for (dst, (src, force)) in pushed.iter() {
+        let id = &local[src];
+        match remote.get(dst) {
+            Some(old) if old == id => {}
+            Some(old) if !force && (dst.starts_with("refs/tags/") || !fast_forward(old, id)) => {
+                diverged.push(dst.clone())
+            }
+            _ => updates.push((
+                format!("{}{src}:{dst}", if *force { "+" } else { "" }),
+                dst.clone(),
+            )),
+        }
+    }
+    if prune {
+        deleted.extend(
+            remote
+                .keys()
+                .filter(|name| !name.ends_with("^{}") && !pushed.contains_key(*name))
+                .filter(|name| !excluded(name))
+                .filter(|name| patterns.iter().any(|p| glob(p, name).is_some()))
+                .cloned(),
+        );
+    }
+    updates.extend(
+        deleted
+            .into_iter()
+            .filter(|name| remote.contains_key(name))
+            .map(|name| (format!(":{name}"), name)),
+    );
+    (updates, diverged)
+}
+
+/// The local refs with the object ids they point to, including `HEAD`
+fn local_refs(repo: &Repository) -> Result<BTreeMap<String, String>, git2::Error> {
+    let mut refs = BTreeMap::new();
+    for r in repo.references()? {
+        let r = r?;
+        if let (Some(name), Some(id)) = (r.name(), r.target()) {
+            refs.insert(name.to_owned(), id.to_string());
+        }
+    }
+    if let Ok(id) = repo.refname_to_id("HEAD") {
+        refs.insert("HEAD".to_string(), id.to_string());
+    }
+    Ok(refs)
+}
+
+/// Push the refspecs in one push, returns the refs the destination rejected
+fn push_specs(
+    git: &Git,
+    repo: &Repository,
+    dest: &str,
+    specs: &[&str],
+    push_options: &[String],
+) -> Result<Vec<String>, git2::Error> {
+    let rejected = RefCell::new(Vec::new());
+    let sent = Cell::new((0, 0));
+    let mut remote = repo.remote_anonymous(dest)?;
+    let mut callbacks = callbacks(git, dest, true);
+    callbacks.push_update_reference(|name, status| {
+        if let Some(status) = status {
+            debug!("Ref {} rejected: {}", name, status);
+            rejected.borrow_mut().push(name.to_owned());
+        }
+        Ok(())
+    });
+    callbacks.push_transfer_progress(|_, total, bytes| sent.set((total as u64, bytes as u64)));
+    let push_options: Vec<&str> = push_options.iter().map(String::as_str).collect();
+    let mut options = PushOptions::new();
+    options
+        .remote_callbacks(callbacks)
+        .proxy_options(proxy_options())
+        .remote_push_options(&push_options);
+    remote.push(specs, Some(&mut options))?;
+    // Releases the callbacks borrowing the rejected refs
+    drop(options);
+    if git.track_transfer {
+        let (objects, bytes) = sent.get();
+        transfer::add_job_transfer(Phase::Push, objects, bytes);
+    }
+    Ok(rejected.into_inner())
+}
+
+/// Push the refs like `git push` with `specs`, see `push_plan`. Refs the destination rejects are
+/// retried one by one like with the git command line.
+pub(super) fn push(
+    git: &Git,
+    dest: &str,
+    repo_dir: &Path,
+    specs: &[String],
+    prune: bool,
+) -> Result<(), GitError> {
+    let (remote, _) = ls_remote(git, dest, true)?;
+    let (updates, rejected, diverged) = run(git, "push", dest, || {
+        let repo = Repository::open(repo_dir)?;
+        let local = local_refs(&repo)?;
+        let (updates, diverged) = push_plan(specs, &local, &remote, prune, |old, new| {
+            match (Oid::from_str(old), Oid::from_str(new)) {
+                // Fails for commits of the destination missing locally, no fast-forward either
+                (Ok(old), Ok(new)) => repo.graph_descendant_of(new, old).unwrap_or(false),
+                _ => false,
+            }
+        });
+        if updates.is_empty() {
+            return Ok((updates, Vec::new(), diverged));
+        }
+        let refspecs: Vec<&str> = updates.iter().map(|(spec, _)| spec.as_str()).collect();
+        let rejected = match push_specs(git, &repo, dest, &refspecs, &git.push_options) {
+            Err(e) if !git.push_options.is_empty() && e.message().contains("push options") => {
+                warn!(
+                    "Destination {} does not support push options, pushing without",
+                    dest
+                );
+                push_specs(git, &repo, dest, &refspecs, &[])?
+            }
+            result => result?,
+        };
+        Ok((updates, rejected, diverged))
+    })?;
+
+    let retry: Vec<&(String, String)> = updates
+        .iter()
+        .filter(|(_, name)| rejected.contains(name))
+        .collect();
+    let mut rejected = Vec::new();
+    if retry.len() > 1 {
+        warn!(
+            "Destination {} rejected {} refs, retrying them individually",
+            dest,
+            retry.len()
+        );
+        for (spec, name) in retry {
+            let pushed = run(git, "push", dest, || {
+                let repo = Repository::open(repo_dir)?;
+                push_specs(git, &repo, dest, &[spec], &[])
+            })?;
+            if !pushed.is_empty() {
+                rejected.push(name.clone());
+            }
+        }
+    } else {
+        rejected.extend(retry.into_iter().map(|(_, name)| name.clone()));
+    }
+
+    if !diverged.is_empty() {
+        if !rejected.is_empty() {
+            warn!(
+                "Destination {} rejected refs: {}",
+                dest,
+                rejected.join(", ")
+            );
+        }
+        Err(GitError::Diverged { refs: diverged })
+    } else if rejected.is_empty() {
+        Ok(())
+    } else {
+        Err(GitError::RefsRejected { refs: rejected })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn refs(refs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        refs.iter()
+            .map(|(name, id)| (name.to_string(), id.to_string()))
+            .collect()
+    }
+
+    fn specs(specs: &[&str]) -> Vec<String> {
+        specs.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn push_plan_mirror() {
+        let local = refs(&[
+            ("HEAD", "1"),
+            ("refs/heads/main", "1"),
+            ("refs/heads/new", "2"),
+            ("refs/tags/v1", "3"),
+            ("refs/mirror-meta/last-sync", "4"),
+        ]);
+        let remote = refs(&[
+            ("HEAD", "0"),
+            ("refs/heads/main", "0"),
+            ("refs/heads/gone", "5"),
+            ("refs/tags/v1", "3"),
+            ("refs/tags/v1^{}", "6"),
+            ("refs/mirror-meta/last-sync", "7"),
+            ("refs/mirror-meta/other", "8"),
+        ]);
+        let (updates, diverged) = push_plan(
+            &specs(&["+refs/*:refs/*", "^refs/mirror-meta/*"]),
+            &local,
+            &remote,
+            true,
+            |_, _| false,
+        );
+        assert_eq!(
+            updates,
+            vec![
+                (
+                    "+refs/heads/main:refs/heads/main".to_string(),
+                    "refs/heads/main".to_string()
+                ),
+                (
+                    "+refs/heads/new:refs/heads/new".to_string(),
+                    "refs/heads/new".to_string()
+                ),
+                (
+                    ":refs/heads/gone".to_string(),
+                    "refs/heads/gone".to_string()
+                ),
+            ]
+        );
+        assert!(diverged.is_empty());
+    }
+
+    #[test]
+    fn push_plan_refspec() {
+        let local = refs(&[
+            ("refs/heads/main", "1"),
+            ("refs/heads/dev", "2"),
+            ("refs/tags/v1", "3"),
+            ("refs/tags/v2", "4"),
+        ]);
+        let remote = refs(&[
+            ("refs/heads/main", "0"),
+            ("refs/heads/dev", "9"),
+            ("refs/tags/v1", "8"),
+            ("refs/heads/old", "5"),
+        ]);
+        // Without force only fast-forwards, main is one, dev is not
+        let (updates, diverged) = push_plan(
+            &specs(&["refs/heads/*:refs/heads/*", "v*"]),
+            &local,
+            &remote,
+            true,
+            |old, _| old == "0",
+        );
+        assert_eq!(
+            updates,
+            vec![
+                (
+                    "refs/heads/main:refs/heads/main".to_string(),
+                    "refs/heads/main".to_string()
+                ),
+                (
+                    "refs/tags/v2:refs/tags/v2".to_string(),
+                    "refs/tags/v2".to_string()
+                ),
+                (":refs/heads/old".to_string(), "refs/heads/old".to_string()),
+            ]
+        );
+        assert_eq!(diverged, vec!["refs/heads/dev", "refs/tags/v1"]);
+
+        // Without prune nothing is deleted
+        let (updates, _) = push_plan(
+            &specs(&["+main:mirror/main"]),
+            &local,
+            &remote,
+            false,
+            |_, _| false,
+        );
+        assert_eq!(
+            updates,
+            vec![(
+                "+refs/heads/main:refs/heads/mirror/main".to_string(),
+                "refs/heads/mirror/main".to_string()
+            )]
+        );
+    }
+}
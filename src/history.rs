@@ -0,0 +1,232 @@
+/*
+ * Copyright (c) 2023 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use junit_report::TestResult;
+use log::{debug, warn};
+use rusqlite::{params, Connection};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::summary::Summary;
+use crate::JobResult;
+
+/// Wait for other instances writing to the same database
+const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS runs (
+    id INTEGER PRIMARY KEY,
+    started_at TEXT NOT NULL,
+    finished_at TEXT,
+    duration_secs REAL,
+    total INTEGER,
+    success INTEGER,
+    skipped INTEGER,
+    failed INTEGER,
+    exit_reason TEXT,
+    exit_code INTEGER,
+    dry_run INTEGER
+);
+CREATE TABLE IF NOT EXISTS repo_results (
+    run_id INTEGER NOT NULL REFERENCES runs(id),
+    repository TEXT NOT NULL,
+    status TEXT NOT NULL,
+    failure_kind TEXT,
+    message TEXT,
+    duration_secs REAL NOT NULL,
+    size_bytes INTEGER
+);
+CREATE INDEX IF NOT EXISTS repo_results_repository ON repo_results(repository);
+";
+
+/// The database and the run in progress
+struct Writer {
+    conn: Connection,
+    run_id: Option<i64>,
+}
+
+/// History of the runs and the results of their jobs in an SQLite database. All writes of the
+/// workers go through the connection, failures are only logged.
+pub struct HistoryDb {
+    writer: Mutex<Writer>,
+}
+
+impl HistoryDb {
+    /// Open the database, creating it and its tables if needed
+    pub fn open(path: &Path) -> Result<HistoryDb, String> {
+        let init = || -> rusqlite::Result<Connection> {
+            let conn = Connection::open(path)?;
+            conn.busy_timeout(BUSY_TIMEOUT)?;
+            // Readers don't block the writer
+            conn.pragma_update(None, "journal_mode", "WAL")?;
+            conn.execute_batch(SCHEMA)?;
+            Ok(conn)
+        };
+        let conn = init().map_err(|e| format!("Unable to open history database {path:?} ({e})"))?;
+        Ok(HistoryDb {
+            writer: Mutex::new(Writer { conn, run_id: None }),
+        })
+    }
+
+    /// Record the start of a run, the following results belong to it
+    pub(crate) fn run_started(&self) {
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        let started_at = now();
+        match writer
+            .conn
+            .execute("INSERT INTO runs (started_at) VALUES (?1)", [&started_at])
+        {
+            Ok(_) => {
+                let id = writer.conn.last_insert_rowid();
+                debug!("Recording run {} in the history database", id);
+                writer.run_id = Some(id);
+            }
+            Err(e) => {
+                warn!("Unable to record the run in the history database ({})", e);
+                writer.run_id = None;
+            }
+        }
+    }
+
+    /// Record the result of a sync job
+    pub(crate) fn repo_finished(&self, result: &JobResult) {
+        let (status, message) = match &result.testcase.result {
+            TestResult::Success => ("success", None),
+            TestResult::Skipped => ("skipped", None),
+            TestResult::Error { message, .. } | TestResult::Failure { message, .. } => {
+                ("failure", Some(message.as_str()))
+            }
+        };
+        let writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        let Writer { ref conn, run_id } = *writer;
+        if let Some(run_id) = run_id {
+            if let Err(e) = conn.execute(
+                "INSERT INTO repo_results \
+                 (run_id, repository, status, failure_kind, message, duration_secs, size_bytes) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    run_id,
+                    result.testcase.name,
+                    status,
+                    result.failure.map(|k| k.to_string()),
+                    message,
+                    result.testcase.time.as_seconds_f64(),
+                    result.size.as_ref().map(|s| s.bytes as i64),
+                ],
+            ) {
+                warn!(
+                    "Unable to record {} in the history database ({})",
+                    result.testcase.name, e
+                );
+            }
+        }
+    }
+
+    /// Record the counts and the result of the run
+    pub(crate) fn run_finished(&self, summary: &Summary) {
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(run_id) = writer.run_id.take() {
+            if let Err(e) = writer.conn.execute(
+                "UPDATE runs SET finished_at = ?2, duration_secs = ?3, total = ?4, success = ?5, \
+                 skipped = ?6, failed = ?7, exit_reason = ?8, exit_code = ?9, dry_run = ?10 \
+                 WHERE id = ?1",
+                params![
+                    run_id,
+                    now(),
+                    summary.duration_secs,
+                    summary.total as i64,
+                    summary.success as i64,
+                    summary.skipped as i64,
+                    summary.failed as i64,
+                    summary.exit_reason,
+                    summary.exit_code,
+                    summary.dry_run,
+                ],
+            ) {
+                warn!("Unable to record the run in the history database ({})", e);
+            }
+        }
+    }
+}
+
+fn now() -> String {
+    OffsetDateTime::now_utc()
+        .format(&Rfc3339)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GitFailureKind;
+    use junit_report::TestCaseBuilder;
+
+    #[test]
+    fn records_runs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("history.db");
+        let db = HistoryDb::open(&file).unwrap();
+        let job = |name: &str, failure: Option<GitFailureKind>| JobResult {
+            testcase: match failure {
+                None => TestCaseBuilder::success(name, time::Duration::seconds(2)),
+                Some(_) => {
+                    TestCaseBuilder::error(name, time::Duration::seconds(1), "sync error", "denied")
+                }
+            }
+            .build(),
+            repo: None,
+            stats: Default::default(),
+            retries: 0,
+            failure,
+            skip: None,
+            size: None,
+            transfer: None,
+            lfs_error: None,
+        };
+        // Not part of a run
+        db.repo_finished(&job("a -> b", None));
+        for _ in 0..2 {
+            db.run_started();
+            db.repo_finished(&job("a -> b", None));
+            db.repo_finished(&job("c -> d", Some(GitFailureKind::Auth)));
+            let summary = Summary {
+                total: 2,
+                success: 1,
+                failed: 1,
+                exit_reason: "sync_failures".to_string(),
+                exit_code: 1,
+                ..Default::default()
+            };
+            db.run_finished(&summary);
+        }
+        drop(db);
+
+        let conn = Connection::open(&file).unwrap();
+        let runs: i64 = conn
+            .query_row("SELECT COUNT(*) FROM runs WHERE failed = 1", [], |r| {
+                r.get(0)
+            })
+            .unwrap();
+        assert_eq!(runs, 2);
+        let failing: (String, String, i64) = conn
+            .query_row(
+                "SELECT repository, failure_kind, COUNT(*) FROM repo_results \
+                 WHERE status = 'failure' GROUP BY repository",
+                [],
+                |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(failing, ("c -> d".to_string(), "auth".to_string(), 2));
+        let results: i64 = conn
+            .query_row("SELECT COUNT(*) FROM repo_results", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(results, 4);
+    }
+}
@@ -4,20 +4,51 @@
  * SPDX-License-Identifier:     MIT
  */
 
+pub mod audit;
+pub mod bundle;
+pub mod config;
+pub mod daemon;
 pub mod error;
+#[cfg(feature = "nats")]
+pub mod events;
+pub mod filter;
 mod git;
+#[cfg(feature = "sqlite")]
+pub mod history;
+pub mod notify;
+pub mod progress;
 pub mod provider;
+pub mod refmap;
+mod releases;
+pub mod repo_log;
+pub mod retry;
+pub mod rewrite;
+mod scratch;
+pub mod shard;
+mod state;
+pub mod summary;
+mod transfer;
+mod webhook;
 
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
 use std::fs;
 use std::fs::File;
+use std::io::{self, Write};
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
 // File locking
 use fs2::FileExt;
 
 // Used for error and debug logging
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 
 // Used to create sane local directory names
 use slug::slugify;
@@ -27,38 +58,784 @@ use slug::slugify;
 extern crate serde_derive;
 
 // Used to allow multiple paralell sync tasks
-use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use rayon::iter::{
+    IndexedParallelIterator, IntoParallelRefIterator, ParallelBridge, ParallelIterator,
+};
 
 // Time handling
+use time::format_description::well_known::Rfc3339;
 use time::OffsetDateTime;
 
-use junit_report::{ReportBuilder, TestCase, TestCaseBuilder, TestSuite, TestSuiteBuilder};
+use junit_report::{
+    ReportBuilder, TestCase, TestCaseBuilder, TestResult, TestSuite, TestSuiteBuilder,
+};
 
 // Monitoring;
-use prometheus::register_gauge_vec;
+use base64::engine::general_purpose::URL_SAFE;
+use base64::Engine;
+use prometheus::{register_gauge_vec, register_histogram_vec, GaugeVec, HistogramVec};
 use prometheus::{Encoder, TextEncoder};
 
-use provider::{MirrorError, MirrorResult, Provider};
+use provider::{
+    transient_api_error, wiki_url, Mirror, MirrorError, MirrorResult, Provider, PruneRemote, Secret,
+};
+use refmap::{filter_refspec, RefMapping};
 
-use git::{Git, GitWrapper};
+use git::{alternates, with_deadline, Deadline, Git, GitError, GitWrapper};
+pub use git::{parse_insecure_url, parse_ssh_jump, GitBackend, GitFailureKind, SshOptions};
 
+use config::{apply_overrides, RepoOverride};
 use error::{GitMirrorError, Result};
 
+use filter::{excluded, RepoPattern};
+use releases::{mirror_releases, releases_dir};
+use repo_log::RepoLog;
+use retry::RetryPolicy;
+use rewrite::{rename_destination, repo_path, rewrite_destination, DestCase, DestRewrite, Rename};
+
+use bundle::Bundles;
+use progress::{Progress, ProgressEvent};
+use shard::Shard;
+use state::RepoState;
+use summary::{
+    load_baseline, JobReport, JobStatus, JsonReport, RepoSize, RepoTransfer, ReportDiff,
+    ShardSummary, Summary, SummaryFormat,
+};
+use transfer::{Phase, TransferStats};
+
+/// Outcome of a successful mirror job
+#[derive(Debug, PartialEq, Eq)]
+pub enum MirrorOutcome {
+    /// The repository was synced to the destination
+    Synced,
+    /// The origin didn't change since the last sync, nothing was done
+    UpToDate,
+    /// The repository was synced, except for the listed refs rejected by the destination
+    Partial(Vec<String>),
+    /// Nothing was done because of `--dry-run`, the repository would have been cloned
+    /// (or updated if `clone` is false) and pushed
+    DryRun { clone: bool },
+    /// The repository was fetched and verified, but not pushed because of `--stage fetch`
+    Fetched,
+    /// Nothing was done for the given reason, e.g. the origin has more than `--max-refs` refs
+    Skipped(SkipReason, String),
+    /// The refs were synced (or fetched with `--stage fetch`), but the LFS objects failed for the
+    /// given reason with `--lfs-failure warn`
+    LfsFailed(String),
+}
+
+/// Time a provider may take to update the listed activity of a project after a push, GitLab
+/// updates `last_activity_at` at most once an hour
+const ACTIVITY_LAG: i64 = 60 * 60;
+
+/// Ref on the destination pointing to the metadata of the last sync
+const SYNC_META_REF: &str = "refs/mirror-meta/last-sync";
+
+/// Pull/merge request refs and the namespace they are pushed to with `--include-pr-refs`.
+/// Most destinations don't allow writing to the original namespaces.
+const PR_REFS: [(&str, &str); 2] = [
+    ("refs/pull/*", "refs/mirror/pull/*"),
+    ("refs/merge-requests/*", "refs/mirror/merge-requests/*"),
+];
+
+/// Metadata of a sync, stored on the destination with `--annotate-sync`
+#[derive(Serialize, Debug)]
+struct SyncMetadata<'a> {
+    origin: &'a str,
+    destination: &'a str,
+    synced_at: String,
+    git_mirror_version: &'static str,
+}
+
+/// Permissions of the local repositories, see `core.sharedRepository`
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SharedRepository {
+    /// Permissions from the umask
+    False,
+    /// Group writable
+    Group,
+    /// Group writable and readable by all users
+    All,
+}
+
+impl SharedRepository {
+    fn value(self) -> &'static str {
+        match self {
+            SharedRepository::False => "false",
+            SharedRepository::Group => "group",
+            SharedRepository::All => "all",
+        }
+    }
+}
+
+/// Objects left out of new local clones, fetched on demand
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PartialClone {
+    /// Leave out all blobs (`--filter=blob:none`)
+    Blobless,
+    /// Leave out all trees and blobs (`--filter=tree:0`)
+    Treeless,
+}
+
+impl PartialClone {
+    fn filter(self) -> &'static str {
+        match self {
+            PartialClone::Blobless => "blob:none",
+            PartialClone::Treeless => "tree:0",
+        }
+    }
+}
+
+/// What the local repositories are named after
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LocalDirName {
+    /// The origin URL, the local repository is kept if the destination changes
+    Origin,
+    /// The destination URL after renames and rewrites, for origins mirrored to several destinations
+    Destination,
+}
+
+/// Task of `git maintenance run`, see `--maintenance-task`
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MaintenanceTask {
+    /// Repack everything and remove unreachable objects (`git gc`)
+    Gc,
+    /// Update the commit-graph, speeds up serving the repository
+    CommitGraph,
+    /// Pack the loose objects
+    LooseObjects,
+    /// Combine the pack files incrementally
+    IncrementalRepack,
+    /// Pack the refs (git 2.42 and newer)
+    PackRefs,
+}
+
+impl MaintenanceTask {
+    fn name(self) -> &'static str {
+        match self {
+            MaintenanceTask::Gc => "gc",
+            MaintenanceTask::CommitGraph => "commit-graph",
+            MaintenanceTask::LooseObjects => "loose-objects",
+            MaintenanceTask::IncrementalRepack => "incremental-repack",
+            MaintenanceTask::PackRefs => "pack-refs",
+        }
+    }
+}
+
+/// Phases of a sync run with `--stage`
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Stage {
+    /// Only fetch into the local repositories and verify them
+    Fetch,
+    /// Only push the local repositories fetched before
+    Push,
+    /// Fetch and push
+    #[default]
+    All,
+}
+
+/// Handling of failed LFS transfers, see `--lfs-failure`
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LfsFailure {
+    /// Fail the sync of the repository
+    #[default]
+    Fatal,
+    /// Sync the refs anyway and report the repository as partially synced
+    Warn,
+}
+
+/// Removal of the local repositories, see `--cleanup`
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Cleanup {
+    /// Keep the local repositories
+    #[default]
+    Never,
+    /// Remove the local repository after each sync, requiring a full clone on the next run
+    Always,
+    /// Remove the local repositories not synced completely within `--cleanup-after`
+    Stale,
+}
+
+/// Layout of the local repositories
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloneMode {
+    /// Bare mirror without working tree (`git clone --mirror`)
+    Bare,
+    /// Repository with the default branch checked out in a working tree
+    Work,
+}
+
+/// Directory of the local repository mirrored from `origin` to `destination`
+fn local_repo_dir(opts: &MirrorOptions, origin: &str, destination: &str) -> PathBuf {
+    let name = match opts.local_dir_name {
+        LocalDirName::Origin => origin,
+        LocalDirName::Destination => destination,
+    };
+    Path::new(&opts.mirror_dir).join(slugify(name))
+}
+
+/// Number of refs of the origins checked against `--max-refs` during the run
+static REF_COUNTS: Mutex<BTreeMap<String, usize>> = Mutex::new(BTreeMap::new());
+
+/// Jobs of the last listing, the ones a webhook can trigger
+static LISTED: Mutex<Vec<Mirror>> = Mutex::new(Vec::new());
+
+/// The jobs of the last complete or streamed listing
+pub(crate) fn listed_jobs() -> Vec<Mirror> {
+    LISTED.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Job results of the last complete run, with the results of the later webhook runs merged in
+static LAST_RESULTS: Mutex<Vec<JobResult>> = Mutex::new(Vec::new());
+
+/// Local repositories by the stable identifier of their project, collected at the start of a run
+static REPO_IDS: Mutex<BTreeMap<String, PathBuf>> = Mutex::new(BTreeMap::new());
+
+/// Origin of the first listed repository of each destination, to detect destinations colliding
+/// after the case normalization
+static DEST_CLAIMS: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+/// Move the local repository of a renamed project to its new directory, so it isn't cloned again.
+/// The repository is found by the stable identifier of the project.
+fn adopt_renamed(x: &Mirror, opts: &MirrorOptions, log: &RepoLog) {
+    let id = match x.id {
+        Some(ref id) => id,
+        None => return,
+    };
+    let dir = local_repo_dir(opts, &x.origin, &x.destination);
+    if opts.dry_run || dir.exists() {
+        return;
+    }
+    let ids = REPO_IDS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(old) = ids.get(id).filter(|old| old.is_dir()) {
+        match fs::rename(old, &dir) {
+            Ok(()) => {
+                info!("Renamed local repository {:?} of {} to {:?}", old, id, dir);
+                log.log(format_args!("Renamed local repository {old:?} to {dir:?}"));
+            }
+            Err(e) => warn!(
+                "Unable to rename {:?} to {:?}, cloning again ({})",
+                old, dir, e
+            ),
+        }
+    }
+}
+
+/// Remember the stable identifier, the origin, the destination and the API identifier of the
+/// project in the state of its local repository, used to find it after a rename and to prune it
+/// once removed
+fn record_project(x: &Mirror, opts: &MirrorOptions) {
+    let dir = local_repo_dir(opts, &x.origin, &x.destination);
+    if opts.dry_run || !dir.is_dir() {
+        return;
+    }
+    let mut state = RepoState::load(&dir);
+    let origin = Some(x.origin.clone());
+    let destination = Some(x.destination.clone());
+    if x.id.is_some() && state.id != x.id
+        || state.origin != origin
+        || state.destination != destination
+        || state.project != x.project
+    {
+        state.id = x.id.clone().or(state.id);
+        state.origin = origin;
+        state.destination = destination;
+        state.project = x.project.clone();
+        if let Err(e) = state.store(&dir) {
+            warn!("Unable to store state of {:?} ({})", dir, e);
+        }
+    }
+}
+
+/// Measure the size of the local repository of a synced job for `--report-sizes`
+fn repo_size(
+    x: &Mirror,
+    label: &str,
+    opts: &MirrorOptions,
+    metrics: &SyncMetrics,
+) -> Option<RepoSize> {
+    let dir = local_repo_dir(opts, &x.origin, &x.destination);
+    let git = Git::new(opts.git_executable.clone());
+    match git.git_repo_size(&dir) {
+        Ok(bytes) => {
+            metrics
+                .repo_size
+                .with_label_values(&[&x.origin, &x.destination, label])
+                .set(bytes as f64);
+            Some(RepoSize {
+                origin: x.origin.clone(),
+                destination: x.destination.clone(),
+                bytes,
+            })
+        }
+        Err(e) => {
+            warn!("Unable to get the size of {:?}: {}", dir, e);
+            None
+        }
+    }
+}
+
+/// Export the last complete sync of the project, taken from the state of its local repository
+/// if this sync wasn't `complete`
+fn last_success_metric(
+    x: &Mirror,
+    label: &str,
+    opts: &MirrorOptions,
+    metrics: &SyncMetrics,
+    complete: bool,
+) {
+    let time = if complete {
+        Some(OffsetDateTime::now_utc().unix_timestamp())
+    } else {
+        let dir = local_repo_dir(opts, &x.origin, &x.destination);
+        dir.is_dir()
+            .then(|| RepoState::load(&dir).last_success)
+            .flatten()
+    };
+    if let Some(time) = time {
+        metrics
+            .proj_last_success
+            .with_label_values(&[&x.origin, &x.destination, label])
+            .set(time as f64);
+    }
+}
+
+/// Write the commit-graph and multi-pack-index of the local repository as requested, after the
+/// maintenance which can repack. Failures are only logged.
+fn write_indexes(git: &Git, opts: &MirrorOptions, repo_dir: &Path, log: &RepoLog) {
+    if opts.cleanup == Cleanup::Always {
+        return;
+    }
+    let report = |name: &str, result: std::result::Result<bool, GitError>| match result {
+        Ok(true) => {
+            debug!("Wrote the {} of {:?}", name, repo_dir);
+            log.log(format_args!("Wrote the {name}"));
+        }
+        Ok(false) => {
+            warn!(
+                "Unable to write the {} of {:?}, git is too old",
+                name, repo_dir
+            );
+            log.log(format_args!("Unable to write the {name}, git is too old"));
+        }
+        Err(e) => {
+            warn!("Unable to write the {} of {:?}: {}", name, repo_dir, e);
+            log.log(format_args!("Unable to write the {name}: {e}"));
+        }
+    };
+    if opts.write_commit_graph {
+        report("commit-graph", git.git_write_commit_graph(repo_dir));
+    }
+    if opts.write_midx {
+        report("multi-pack-index", git.git_write_midx(repo_dir));
+    }
+}
+
+/// Time budget of a sync job: `repo_timeout`, plus `repo_timeout_per_gb` for every GiB the provider
+/// reports for the repository or, if it doesn't, the local repository has.
+/// Without any known size the flat `repo_timeout` applies.
+fn repo_budget(x: &Mirror, opts: &MirrorOptions) -> Option<Duration> {
+    let base = opts.repo_timeout?;
+    let per_gb = match opts.repo_timeout_per_gb {
+        Some(per_gb) => per_gb,
+        None => return Some(base),
+    };
+    let size = x.size.filter(|&bytes| bytes > 0).or_else(|| {
+        let dir = local_repo_dir(opts, &x.origin, &x.destination);
+        dir.is_dir()
+            .then(|| Git::new(opts.git_executable.clone()).git_repo_size(&dir))
+            .and_then(|size| size.ok())
+    });
+    match size {
+        Some(bytes) => {
+            let budget = base + per_gb.mul_f64(bytes as f64 / (1u64 << 30) as f64);
+            debug!(
+                "Time budget of {} ({}): {}",
+                x.destination,
+                transfer::format_bytes(bytes),
+                humantime::format_duration(budget)
+            );
+            Some(budget)
+        }
+        None => {
+            debug!("Size of {} unknown, using the flat timeout", x.destination);
+            Some(base)
+        }
+    }
+}
+
+/// Transfer stats of a job for `--transfer-stats`, with the size of its local repository
+fn job_transfer(
+    x: &Mirror,
+    stats: TransferStats,
+    label: &str,
+    opts: &MirrorOptions,
+    metrics: &SyncMetrics,
+) -> RepoTransfer {
+    // Only phases that ran, e.g. not the push of `--stage fetch`
+    if stats.fetch_secs > 0.0 {
+        metrics
+            .fetch_seconds
+            .with_label_values(&[label])
+            .observe(stats.fetch_secs);
+    }
+    if stats.push_secs > 0.0 {
+        metrics
+            .push_seconds
+            .with_label_values(&[label])
+            .observe(stats.push_secs);
+    }
+    for (direction, objects, bytes) in [
+        ("received", stats.objects_received, stats.bytes_received),
+        ("sent", stats.objects_sent, stats.bytes_sent),
+    ] {
+        let labels = [x.origin.as_str(), &x.destination, label, direction];
+        metrics
+            .objects_transferred
+            .with_label_values(&labels)
+            .set(objects as f64);
+        metrics
+            .bytes_transferred
+            .with_label_values(&labels)
+            .set(bytes as f64);
+    }
+    let dir = local_repo_dir(opts, &x.origin, &x.destination);
+    let repo_bytes = dir
+        .is_dir()
+        .then(|| Git::new(opts.git_executable.clone()).git_repo_size(&dir))
+        .and_then(|size| size.ok());
+    RepoTransfer {
+        origin: x.origin.clone(),
+        destination: x.destination.clone(),
+        stats,
+        repo_bytes,
+    }
+}
+
+/// Git with the settings for accessing the origin and the destination
+fn transport_git(opts: &MirrorOptions, dest_token: Option<String>, log: Arc<RepoLog>) -> Git {
+    Git::new(opts.git_executable.clone())
+        .with_push_options(opts.push_options.clone())
+        .with_log(log)
+        .with_pack_compression(opts.pack_compression)
+        .with_protocol_version(opts.git_protocol)
+        .with_insecure(opts.git_insecure.clone())
+        .with_dest_token(dest_token)
+        .with_transfer_tracking(opts.max_transfer.is_some() || opts.transfer_stats)
+        .with_ssh(opts.ssh.clone())
+        .with_fsck_objects(opts.fsck)
+        .with_push_batch(opts.push_batch)
+        .with_backend(opts.git_backend)
+}
+
+/// Imports into the same destination share a repository and must not run concurrently
+static SUBTREE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Normalize the `subtree_prefix` of a mirror, e.g. `/vendor/lib/` to `vendor/lib`
+fn subtree_prefix(prefix: &str) -> std::result::Result<String, String> {
+    let prefix = prefix.trim_matches('/');
+    if prefix.is_empty() {
+        return Err("Subtree prefix must not be empty".to_string());
+    }
+    if prefix
+        .split('/')
+        .any(|c| c.is_empty() || c == "." || c == ".." || c == ".git")
+    {
+        return Err(format!("Invalid subtree prefix: {prefix}"));
+    }
+    Ok(prefix.to_string())
+}
+
+/// Cause of a skipped repository
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    /// Paused with `skip: true` or `enabled: false` in the description
+    Disabled,
+    /// The `--max-transfer` budget of the run was used up
+    TransferBudget,
+    /// The origin has more than `--max-refs` refs
+    TooManyRefs,
+    /// The project has fewer than `--min-stars` stars
+    TooFewStars,
+    /// The project has fewer than `--min-forks` forks
+    TooFewForks,
+    /// The path doesn't match `--include` or matches `--exclude`
+    Excluded,
+    /// The project is archived, with `--skip-archived`
+    Archived,
+    /// The project is a fork, with `--skip-forks`
+    Fork,
+    /// Nothing to do in this `--stage`, e.g. subtree imports in the fetch stage
+    Stage,
+    /// The run was cancelled through its `progress::CancelToken`
+    Cancelled,
+    /// The wiki of the project is enabled, but no page was created yet
+    NoWiki,
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SkipReason::Disabled => "disabled",
+            SkipReason::TransferBudget => "transfer_budget",
+            SkipReason::TooManyRefs => "too_many_refs",
+            SkipReason::TooFewStars => "too_few_stars",
+            SkipReason::TooFewForks => "too_few_forks",
+            SkipReason::Excluded => "excluded",
+            SkipReason::Archived => "archived",
+            SkipReason::Fork => "fork",
+            SkipReason::Stage => "stage",
+            SkipReason::Cancelled => "cancelled",
+            SkipReason::NoWiki => "no_wiki",
+        })
+    }
+}
+
+/// Import the default branch of `x.origin` into the directory `prefix` of
+/// `opts.subtree_branch` on the destination
+fn import_subtree(
+    x: &Mirror,
+    prefix: &str,
+    dest_token: Option<&Secret>,
+    opts: &MirrorOptions,
+    log: Arc<RepoLog>,
+) -> Result<MirrorOutcome> {
+    let prefix = subtree_prefix(prefix).map_err(GitMirrorError::GenericError)?;
+    if opts.bundles.is_some() {
+        return Err(GitMirrorError::GenericError(
+            "Subtree imports are only pushed, not bundled".to_string(),
+        ));
+    }
+    if opts.stage == Stage::Fetch {
+        return Ok(MirrorOutcome::Skipped(
+            SkipReason::Stage,
+            "subtree imports are done in the push stage".to_string(),
+        ));
+    }
+    let repo_dir = opts
+        .mirror_dir
+        .join(format!("{}.subtree", slugify(&x.destination)));
+    debug!("Using subtree dir: {0:?}", repo_dir);
+
+    if opts.dry_run {
+        return Ok(MirrorOutcome::DryRun {
+            clone: !repo_dir.is_dir(),
+        });
+    }
+
+    let dest_token = dest_token
+        .map(|t| t.resolve())
+        .transpose()
+        .map_err(GitMirrorError::GenericError)?;
+    let git = transport_git(opts, dest_token, log);
+    git.git_version()?;
+
+    let _guard = SUBTREE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let pushed = git.git_import_subtree(
+        &x.origin,
+        &x.destination,
+        &repo_dir,
+        &prefix,
+        &opts.subtree_branch,
+    )?;
+    Ok(if pushed {
+        MirrorOutcome::Synced
+    } else {
+        MirrorOutcome::UpToDate
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn mirror_repo(
     origin: &str,
     destination: &str,
     refspec: &Option<Vec<String>>,
+    prune_refs: bool,
     lfs: bool,
+    dest_token: Option<&Secret>,
     opts: &MirrorOptions,
+    log: Arc<RepoLog>,
+) -> Result<MirrorOutcome> {
+    let local_dir = local_repo_dir(opts, origin, destination);
+    let scratch = match opts.work_tmp {
+        // The push stage only reads the repository, moving it wouldn't pay off
+        Some(ref work_tmp) if opts.stage != Stage::Push && !opts.dry_run => {
+            scratch::scratch_dir(work_tmp, &opts.mirror_dir, &local_dir)
+        }
+        _ => {
+            return mirror_local(
+                &local_dir,
+                origin,
+                destination,
+                refspec,
+                prune_refs,
+                lfs,
+                dest_token,
+                opts,
+                log,
+            )
+        }
+    };
+    scratch::enter(&local_dir, &scratch).map_err(|e| {
+        GitMirrorError::GenericError(format!(
+            "Unable to move {local_dir:?} to the scratch directory {scratch:?} ({e})"
+        ))
+    })?;
+    let result = mirror_local(
+        &scratch,
+        origin,
+        destination,
+        refspec,
+        prune_refs,
+        lfs,
+        dest_token,
+        opts,
+        log,
+    );
+    // Also after a failure, so the fetched objects are kept for the next run
+    scratch::leave(&scratch, &local_dir).map_err(|e| {
+        GitMirrorError::GenericError(format!(
+            "Unable to move the scratch directory {scratch:?} to {local_dir:?} ({e})"
+        ))
+    })?;
+    result
+}
+
+/// Count the commits of the local repository not reachable from the `tips` it or the destination
+/// had before the phase, for the `--json-report`. Failures are only logged.
+fn count_new_commits(
+    git: &Git,
+    repo_dir: &Path,
+    phase: Phase,
+    tips: std::result::Result<Vec<String>, GitError>,
+) {
+    match tips.and_then(|tips| git.git_count_commits(repo_dir, &tips)) {
+        Ok(commits) => transfer::set_job_commits(phase, commits),
+        Err(e) => warn!("Unable to count the commits of {:?}: {}", repo_dir, e),
+    }
+}
+
+/// Write a bundle of the local repository in `origin_dir` instead of pushing it to the
+/// destination, recording the bundled refs in its state
+#[allow(clippy::too_many_arguments)]
+fn write_bundle(
+    bundles: &Bundles,
+    git: &Git,
+    origin_dir: &Path,
+    origin: &str,
+    destination: &str,
+    refspec: &Option<Vec<String>>,
+    opts: &MirrorOptions,
+    log: &RepoLog,
 ) -> Result<()> {
+    info!("Bundle {} for destination {}", origin, destination);
+    log.log(format_args!("Bundle for destination {destination}"));
+    progress::report(&opts.progress, || ProgressEvent::Pushing {
+        repository: format!("{origin} -> {destination}"),
+    });
+
+    let start = Instant::now();
+    let mut state = RepoState::load(origin_dir);
+    let last = state.bundled_refs.remove(destination).unwrap_or_default();
+    let written = bundles.write(git, origin_dir, destination, refspec, &last, log);
+    transfer::add_job_time(Phase::Push, start.elapsed());
+    let refs = match written? {
+        Some((refs, kind)) => {
+            info!("Wrote {:?} bundle for {}", kind, destination);
+            refs
+        }
+        None => {
+            info!("No changes to bundle for {}", destination);
+            log.log("No changes to bundle");
+            last
+        }
+    };
+    state.bundled_refs.insert(destination.to_string(), refs);
+    state.store(origin_dir).map_err(|e| {
+        GitMirrorError::GenericError(format!(
+            "Unable to store state of {}: {}",
+            &origin_dir.to_string_lossy(),
+            e
+        ))
+    })
+}
+
+/// Sync the repository using the local repository in `origin_dir`
+#[allow(clippy::too_many_arguments)]
+fn mirror_local(
+    origin_dir: &Path,
+    origin: &str,
+    destination: &str,
+    refspec: &Option<Vec<String>>,
+    prune_refs: bool,
+    lfs: bool,
+    dest_token: Option<&Secret>,
+    opts: &MirrorOptions,
+    log: Arc<RepoLog>,
+) -> Result<MirrorOutcome> {
+    let origin_dir = origin_dir.to_path_buf();
+    debug!("Using origin dir: {0:?}", origin_dir);
+
+    let fetch = opts.stage != Stage::Push;
+    if !fetch && !origin_dir.is_dir() {
+        return Err(GitMirrorError::GenericError(format!(
+            "Local repository {origin_dir:?} not fetched yet, run --stage fetch first"
+        )));
+    }
+
     if opts.dry_run {
-        return Ok(());
+        return Ok(MirrorOutcome::DryRun {
+            clone: !origin_dir.is_dir(),
+        });
     }
 
-    let origin_dir = Path::new(&opts.mirror_dir).join(slugify(origin));
-    debug!("Using origin dir: {0:?}", origin_dir);
+    let dest_token = dest_token
+        .map(|t| t.resolve())
+        .transpose()
+        .map_err(GitMirrorError::GenericError)?;
+
+    // Keep using the layout of an existing repository
+    let clone_mode = if !origin_dir.is_dir() {
+        opts.clone_mode
+    } else if origin_dir.join(".git").is_dir() {
+        CloneMode::Work
+    } else {
+        CloneMode::Bare
+    };
+    if clone_mode != opts.clone_mode {
+        warn!(
+            "Local repository {:?} uses clone mode {:?} instead of {:?}, \
+             remove it to switch the clone mode",
+            origin_dir, clone_mode, opts.clone_mode
+        );
+    }
+
+    let lfs = opts.mirror_lfs && lfs;
 
-    let git = Git::new(opts.git_executable.clone(), opts.mirror_lfs);
+    let git =
+        transport_git(opts, dest_token, log.clone()).with_work_tree(clone_mode == CloneMode::Work);
+    let mut keep_refs = opts.prune_protect.clone();
+    // Excluded refs are neither fetched nor pushed
+    keep_refs.extend(opts.exclude_refs.iter().cloned());
+    if opts.annotate_sync {
+        keep_refs.push("refs/mirror-meta/*".to_string());
+    }
+    if opts.include_pr_refs {
+        // Pushed separately to their own namespace
+        keep_refs.extend(PR_REFS.iter().map(|(src, _)| src.to_string()));
+        keep_refs.push("refs/mirror/*".to_string());
+    }
+    let git = git
+        .with_shared(opts.shared.map(|s| s.value().to_string()))
+        .with_filter(opts.partial_clone.map(|p| p.filter().to_string()))
+        .with_keep_refs(keep_refs)
+        .with_exclude_refs(opts.exclude_refs.clone())
+        .with_alternates(opts.alternates.clone())
+        // The LFS objects are pushed with `git lfs push`
+        .with_skip_push_hooks(opts.mirror_lfs)
+        .with_no_force(opts.no_force)
+        .with_prune_refs(prune_refs);
 
     git.git_version()?;
 
@@ -66,172 +843,1355 @@ pub fn mirror_repo(
         git.git_lfs_version()?;
     }
 
-    if origin_dir.is_dir() {
+    if fetch && origin_dir.is_dir() {
+        let borrowed = alternates(&origin_dir);
+        if let Some(missing) = borrowed.iter().find(|a| !a.is_dir()) {
+            // The repository lacks the borrowed objects, it can't be repaired
+            warn!(
+                "Object pool {:?} of {:?} is missing, cloning again",
+                missing, origin_dir
+            );
+            log.log(format_args!(
+                "Object pool {missing:?} is missing, cloning again"
+            ));
+            fs::remove_dir_all(&origin_dir).map_err(|e| {
+                GitMirrorError::GenericError(format!(
+                    "Unable to delete local repository: {} ({})",
+                    &origin_dir.to_string_lossy(),
+                    e
+                ))
+            })?;
+        } else if opts.dissociate && !borrowed.is_empty() {
+            info!("Dissociate {:?} from its object pool", origin_dir);
+            log.log("Dissociate from object pool");
+            git.git_dissociate(&origin_dir)?;
+        }
+    }
+
+    let mut listed_refs = None;
+    if let Some(max) = opts.max_refs.filter(|_| fetch) {
+        // Checked before fetching anything, origins mirrored to several destinations once per run
+        let cached = REF_COUNTS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(origin)
+            .copied();
+        let count = match cached {
+            Some(count) => count,
+            None => {
+                let refs = git.git_ls_remote(origin)?;
+                // Without HEAD and the peeled annotated tags
+                let count = refs
+                    .keys()
+                    .filter(|r| r.starts_with("refs/") && !r.ends_with("^{}"))
+                    .count();
+                REF_COUNTS
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .insert(origin.to_string(), count);
+                listed_refs = Some(refs);
+                count
+            }
+        };
+        if count > max {
+            info!("Origin {} has {} refs, more than {}", origin, count, max);
+            log.log(format_args!("Origin has {count} refs, more than {max}"));
+            return Ok(MirrorOutcome::Skipped(
+                SkipReason::TooManyRefs,
+                format!("too many refs: {count} > {max}"),
+            ));
+        }
+    }
+
+    // Remember the state of the origin to detect changes on the next run
+    // The state is only updated by complete syncs
+    let origin_refs = if opts.only_changed && opts.stage == Stage::All {
+        let refs = match listed_refs {
+            Some(refs) => refs,
+            None => git.git_ls_remote(origin)?,
+        };
+        let mut state = RepoState::load(&origin_dir);
+        if origin_dir.is_dir() && !refs.is_empty() && state.origin_refs == refs && !opts.force_sync
+        {
+            info!("Origin unchanged since last sync for {}", origin);
+            log.log("Origin unchanged since last sync");
+            // Still in sync, so the repository isn't stale for `--cleanup stale`
+            state.last_success = Some(OffsetDateTime::now_utc().unix_timestamp());
+            if let Err(e) = state.store(&origin_dir) {
+                warn!("Unable to store state of {:?} ({})", origin_dir, e);
+            }
+            return Ok(MirrorOutcome::UpToDate);
+        }
+        Some(refs)
+    } else {
+        None
+    };
+
+    // Counted for the `--json-report`
+    let count_commits = opts.json_report.is_some();
+    let tips_before_fetch = match (count_commits && fetch, origin_dir.is_dir()) {
+        (false, _) => None,
+        (true, true) => Some(git.git_ref_tips(&origin_dir)),
+        (true, false) => Some(Ok(Vec::new())),
+    };
+
+    let fetch_start = Instant::now();
+    if !fetch {
+        info!("Push the fetched {}", origin);
+        log.log(format_args!("Push the fetched {origin}"));
+    } else if origin_dir.is_dir() {
         info!("Local Update for {}", origin);
+        log.log(format_args!("Local Update for {origin}"));
+        progress::report(&opts.progress, || ProgressEvent::Fetching {
+            repository: format!("{origin} -> {destination}"),
+        });
 
-        git.git_update_mirror(origin, &origin_dir, lfs)?;
+        git.git_update_mirror(origin, &origin_dir)?;
     } else if !origin_dir.exists() {
         info!("Local Checkout for {}", origin);
+        log.log(format_args!("Local Checkout for {origin}"));
+        progress::report(&opts.progress, || ProgressEvent::Fetching {
+            repository: format!("{origin} -> {destination}"),
+        });
 
-        git.git_clone_mirror(origin, &origin_dir, lfs)?;
+        git.git_clone_mirror(origin, &origin_dir)?;
     } else {
         return Err(GitMirrorError::GenericError(format!(
             "Local origin dir is a file: {origin_dir:?}"
         )));
     }
+    if let Some(tips) = tips_before_fetch {
+        count_new_commits(&git, &origin_dir, Phase::Fetch, tips);
+    }
+    // Most repositories don't use LFS, they don't need the LFS server
+    let lfs = lfs
+        && git.git_uses_lfs(&origin_dir).unwrap_or_else(|e| {
+            warn!(
+                "Unable to check if {} uses LFS, assuming it does: {}",
+                origin, e
+            );
+            true
+        });
+    // With `--lfs-failure warn` a failure of the LFS server doesn't fail the sync of the refs
+    let lfs_warn = opts.lfs_failure == LfsFailure::Warn;
+    let mut lfs_error = None;
+    if fetch && lfs {
+        info!("Fetch the LFS objects of {}", origin);
+        log.log("Fetch the LFS objects");
+        match git.git_lfs_fetch(&origin_dir) {
+            Ok(()) => {}
+            Err(e) if lfs_warn => {
+                warn!("Unable to fetch the LFS objects of {}: {}", origin, e);
+                log.log(format_args!("Unable to fetch the LFS objects: {e}"));
+                lfs_error = Some(format!("fetch failed ({e})"));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    transfer::add_job_time(Phase::Fetch, fetch_start.elapsed());
+
+    // Corrupt objects are never pushed
+    if fetch && (opts.fsck || opts.stage == Stage::Fetch) {
+        info!("Verify {:?}", origin_dir);
+        log.log("Verify the local repository");
+        git.git_fsck(&origin_dir).map_err(|e| {
+            GitMirrorError::GenericError(format!(
+                "Local repository {origin_dir:?} is corrupt ({e})"
+            ))
+        })?;
+    }
+    // Also checked by the push stage if required, the fetch stage only fails the job
+    if let Some(ref gpg_home) = opts.verify_signatures {
+        if fetch || opts.require_signatures {
+            info!("Verify the signatures of {:?}", origin_dir);
+            log.log("Verify the signatures of the branches and tags");
+            let unverified = git.git_verify_signatures(&origin_dir, gpg_home)?;
+            if !unverified.is_empty() {
+                let refs = unverified
+                    .iter()
+                    .map(|(name, problem)| format!("{name} ({problem})"))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                log.log(format_args!("Unverified signatures: {refs}"));
+                if opts.require_signatures {
+                    return Err(GitMirrorError::GenericError(format!(
+                        "Unverified signatures in {origin}: {refs}"
+                    )));
+                }
+                warn!("Unverified signatures in {}: {}", origin, refs);
+            }
+        }
+    }
+    if opts.stage == Stage::Fetch {
+        write_indexes(&git, opts, &origin_dir, &log);
+        return Ok(match lfs_error {
+            Some(e) => MirrorOutcome::LfsFailed(e),
+            None => MirrorOutcome::Fetched,
+        });
+    }
+
+    if let Some(ref hook) = opts.transform_hook {
+        info!("Run the transform hook in {:?}", origin_dir);
+        log.log("Run the transform hook");
+        git.run_hook(hook, &origin_dir, origin, destination)
+            .map_err(|e| match e {
+                GitError::TimedOut { .. } => GitMirrorError::GitError(e),
+                e => GitMirrorError::GenericError(format!(
+                    "Transform hook failed, not pushing {origin} ({e})"
+                )),
+            })?;
+    }
+
+    // Refs rejected by the destination with `--tolerate-rejected-refs`, the rest of the sync
+    // continues as usual
+    let mut rejected = Vec::new();
+    if let Some(ref bundles) = opts.bundles {
+        write_bundle(
+            bundles,
+            &git,
+            &origin_dir,
+            origin,
+            destination,
+            refspec,
+            opts,
+            &log,
+        )?;
+    } else {
+        // Nothing is pushed if the changes can't be audited
+        let audit_before = match opts.audit_log {
+            Some(_) => Some(git.git_ls_dest(destination, &origin_dir)?),
+            None => None,
+        };
+
+        info!("Push to destination {}", destination);
+        log.log(format_args!("Push to destination {destination}"));
+        progress::report(&opts.progress, || ProgressEvent::Pushing {
+            repository: format!("{origin} -> {destination}"),
+        });
+
+        let tips_before_push = match (count_commits, &audit_before) {
+            (false, _) => None,
+            (true, Some(refs)) => Some(Ok(refs.values().cloned().collect())),
+            (true, None) => Some(
+                git.git_ls_dest(destination, &origin_dir)
+                    .map(|refs| refs.into_values().collect()),
+            ),
+        };
+
+        let push_start = Instant::now();
+        // Before the refs, so the destination never has refs without their LFS objects
+        if lfs && !lfs_warn {
+            log.log("Push the LFS objects");
+            git.git_lfs_push(destination, &origin_dir)?;
+        }
+        let pushed = git.git_push_mirror(destination, &origin_dir, refspec);
+        // The objects of the refs rejected by the destination aren't needed, but don't hurt either
+        let refs_pushed = matches!(
+            pushed,
+            Ok(()) | Err(GitError::RefsRejected { .. } | GitError::Diverged { .. })
+        );
+        if lfs && lfs_warn && lfs_error.is_none() && refs_pushed {
+            if let Err(e) = git.git_lfs_push(destination, &origin_dir) {
+                warn!("Unable to push the LFS objects to {}: {}", destination, e);
+                log.log(format_args!("Unable to push the LFS objects: {e}"));
+                lfs_error = Some(format!("push failed ({e})"));
+            }
+        }
+        transfer::add_job_time(Phase::Push, push_start.elapsed());
+        if let (Some(tips), true) = (tips_before_push, refs_pushed) {
+            count_new_commits(&git, &origin_dir, Phase::Push, tips);
+        }
+        let mut tolerate = |pushed| match pushed {
+            Err(GitError::RefsRejected { refs }) if opts.tolerate_rejected_refs => {
+                rejected.extend(refs);
+                Ok(())
+            }
+            r => r,
+        };
+        let pushed = tolerate(pushed).and_then(|()| {
+            if opts.include_pr_refs {
+                let refspecs: Vec<String> = PR_REFS
+                    .iter()
+                    .map(|(src, dst)| format!("+{src}:{dst}"))
+                    .collect();
+                tolerate(git.git_push_refs(destination, &origin_dir, &refspecs))?;
+            }
+            Ok(())
+        });
+        // Also the refs pushed before a failure
+        if let (Some(audit), Some(before)) = (&opts.audit_log, audit_before) {
+            match git.git_ls_dest(destination, &origin_dir) {
+                Ok(after) => audit.record(origin, destination, &before, &after),
+                Err(e) => {
+                    warn!("Unable to audit the push to {}: {}", destination, e);
+                    log.log(format_args!("Unable to audit the push: {e}"));
+                }
+            }
+        }
+        pushed?;
+        if !rejected.is_empty() {
+            warn!(
+                "Destination {} rejected refs: {}",
+                destination,
+                rejected.join(", ")
+            );
+        }
+
+        if opts.annotate_sync {
+            let metadata = SyncMetadata {
+                origin,
+                destination,
+                synced_at: OffsetDateTime::now_utc()
+                    .format(&Rfc3339)
+                    .unwrap_or_default(),
+                git_mirror_version: env!("CARGO_PKG_VERSION"),
+            };
+            let metadata = serde_json::to_string_pretty(&metadata).unwrap_or_default();
+            if let Err(e) = git.git_push_metadata(
+                destination,
+                &origin_dir,
+                SYNC_META_REF,
+                "last-sync.json",
+                &metadata,
+            ) {
+                warn!("Unable to store sync metadata on {}: {}", destination, e);
+                log.log(format_args!("Unable to store sync metadata: {e}"));
+            }
+        }
+    }
+
+    let mut state = RepoState::load(&origin_dir);
+    // The rejected refs are pushed again by the next run, also with `--only-changed`, like the
+    // LFS objects that failed with `--lfs-failure warn`
+    if let Some(origin_refs) = origin_refs.filter(|_| rejected.is_empty() && lfs_error.is_none()) {
+        state.origin_refs = origin_refs;
+    }
+    state.last_success = Some(OffsetDateTime::now_utc().unix_timestamp());
+    state.store(&origin_dir).map_err(|e| {
+        GitMirrorError::GenericError(format!(
+            "Unable to store state of {}: {}",
+            &origin_dir.to_string_lossy(),
+            e
+        ))
+    })?;
+
+    if opts.maintenance && opts.cleanup != Cleanup::Always {
+        let tasks: Vec<String> = opts
+            .maintenance_tasks
+            .iter()
+            .map(|t| t.name().to_string())
+            .collect();
+        // The sync itself succeeded, the next run tries again
+        if let Err(e) = git.git_maintenance(&origin_dir, &tasks) {
+            warn!("Maintenance of {:?} failed: {}", origin_dir, e);
+            log.log(format_args!("Maintenance failed: {e}"));
+        }
+    }
+    write_indexes(&git, opts, &origin_dir, &log);
+
+    if opts.cleanup == Cleanup::Always {
+        fs::remove_dir_all(&origin_dir).map_err(|e| {
+            GitMirrorError::GenericError(format!(
+                "Unable to delete working repository: {} because of error: {}",
+                &origin_dir.to_string_lossy(),
+                e
+            ))
+        })?;
+    }
+
+    Ok(match lfs_error {
+        _ if !rejected.is_empty() => MirrorOutcome::Partial(rejected),
+        Some(e) => MirrorOutcome::LfsFailed(e),
+        None => MirrorOutcome::Synced,
+    })
+}
+
+/// Remove the local repositories not synced completely within `--cleanup-after`, after the sync
+/// so the repositories synced during the run are kept. Failures are only logged.
+fn remove_stale(opts: &MirrorOptions) {
+    let cutoff = OffsetDateTime::now_utc().unix_timestamp() - opts.cleanup_after.as_secs() as i64;
+    let stale = state::stale_repos(&opts.mirror_dir, cutoff);
+    let mut removed = 0;
+    for dir in stale {
+        info!("Remove the stale local repository {:?}", dir);
+        match fs::remove_dir_all(&dir) {
+            Ok(()) => removed += 1,
+            Err(e) => warn!(
+                "Unable to remove the stale local repository {:?}: {}",
+                dir, e
+            ),
+        }
+    }
+    println!("CLEANUP: {removed} stale repositories removed");
+}
+
+/// Local repositories of the listed repository `m` and its wiki
+fn local_dirs(m: &Mirror, opts: &MirrorOptions) -> Vec<PathBuf> {
+    let destination = resolve_destination(opts, &m.destination);
+    let mut dirs = vec![local_repo_dir(opts, &m.origin, &destination)];
+    if opts.include_wikis && m.has_wiki {
+        dirs.push(local_repo_dir(
+            opts,
+            &wiki_url(&m.origin),
+            &wiki_url(&destination),
+        ));
+    }
+    dirs
+}
+
+/// Remove the local repositories of the projects that are no longer listed, and with
+/// `--prune-remote` archive or delete their destinations first. Only repositories synced before
+/// are known, failures are only logged and the local repository is kept to retry the next run.
+fn prune_removed(provider: &dyn Provider, listed: &BTreeSet<PathBuf>, opts: &MirrorOptions) {
+    let (mut removed, mut pruned) = (0, 0);
+    for dir in state::repo_dirs(&opts.mirror_dir) {
+        if listed.contains(&dir) {
+            continue;
+        }
+        let state = RepoState::load(&dir);
+        let destination = state
+            .destination
+            .as_deref()
+            .unwrap_or("unknown destination");
+        if let Some(how) = opts.prune_remote {
+            // Disabled projects and those not selected by `--topic` or `--visibility` aren't
+            // listed either, only the destinations of deleted origins are pruned
+            let log = Arc::new(RepoLog::disabled());
+            let exists = match state.origin {
+                Some(ref origin) => remote_exists(opts, origin, &log).map_err(|e| e.to_string()),
+                None => Err("unknown origin".to_string()),
+            };
+            match exists {
+                Ok(false) => {}
+                Ok(true) => {
+                    info!(
+                        "Keep the destination {}, its origin still exists",
+                        destination
+                    );
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Unable to {} the destination {} ({})", how, destination, e);
+                    continue;
+                }
+            }
+        }
+        if opts.dry_run {
+            println!("PRUNE {} ({}, dry run)", dir.display(), destination);
+            continue;
+        }
+        if let Some(how) = opts.prune_remote {
+            let result = match state.project {
+                Some(ref project) => provider.prune_destination(project, how),
+                None => Err("unknown project".to_string()),
+            };
+            match result {
+                Ok(()) => pruned += 1,
+                Err(e) => {
+                    warn!("Unable to {} the destination {} ({})", how, destination, e);
+                    continue;
+                }
+            }
+        }
+        info!("Remove the local repository {:?} of a removed project", dir);
+        match fs::remove_dir_all(&dir) {
+            Ok(()) => removed += 1,
+            Err(e) => warn!("Unable to remove the local repository {:?}: {}", dir, e),
+        }
+    }
+    match opts.prune_remote {
+        Some(how) => println!(
+            "PRUNE: {removed} local repositories of removed projects removed, {pruned} destinations pruned ({how})"
+        ),
+        None => println!("PRUNE: {removed} local repositories of removed projects removed"),
+    }
+}
+
+/// Prometheus metrics of the sync jobs
+struct SyncMetrics {
+    /// 1 if the results are simulated by `--dry-run`
+    dry_run: GaugeVec,
+    start_time: GaugeVec,
+    end_time: GaugeVec,
+    proj_total: GaugeVec,
+    proj_skip: GaugeVec,
+    /// Skipped projects by cause
+    skipped_total: GaugeVec,
+    proj_fail: GaugeVec,
+    proj_ok: GaugeVec,
+    proj_start: GaugeVec,
+    proj_end: GaugeVec,
+    /// Retries of the sync of the project
+    proj_retries: GaugeVec,
+    /// Duration of the last sync of the project, including its retries
+    proj_duration: GaugeVec,
+    /// 1 for the result of the last sync of the project (`ok`, `failed` or `skipped`)
+    proj_result: GaugeVec,
+    /// Last complete sync of the project, also of an earlier run
+    proj_last_success: GaugeVec,
+    /// Size of the local repositories with `--report-sizes`
+    repo_size: GaugeVec,
+    /// Per job with `--transfer-stats`
+    fetch_seconds: HistogramVec,
+    push_seconds: HistogramVec,
+    objects_transferred: GaugeVec,
+    bytes_transferred: GaugeVec,
+    /// Jobs running right now
+    in_flight: GaugeVec,
+    /// Listed jobs waiting for a worker
+    queue_depth: GaugeVec,
+}
+
+/// Buckets of the phase durations in seconds, from one second to a few hours
+const PHASE_BUCKETS: &[f64] = &[
+    1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 1800.0, 3600.0, 10800.0,
+];
+
+static METRICS: OnceLock<SyncMetrics> = OnceLock::new();
+
+/// Values of the `result` label of `git_mirror_project_result`
+const PROJECT_RESULTS: [&str; 3] = ["ok", "failed", "skipped"];
+
+impl SyncMetrics {
+    /// Get the metrics, registering them on first use
+    fn get() -> &'static SyncMetrics {
+        METRICS.get_or_init(SyncMetrics::register)
+    }
+
+    fn register() -> SyncMetrics {
+        SyncMetrics {
+            dry_run: register_gauge_vec!(
+                "git_mirror_dry_run",
+                "1 if the sync was a dry run without any git commands",
+                &["mirror"]
+            )
+            .unwrap(),
+            start_time: register_gauge_vec!(
+                "git_mirror_start_time",
+                "Start time of the sync as unix timestamp",
+                &["mirror"]
+            )
+            .unwrap(),
+            end_time: register_gauge_vec!(
+                "git_mirror_end_time",
+                "End time of the sync as unix timestamp",
+                &["mirror"]
+            )
+            .unwrap(),
+            proj_total: register_gauge_vec!("git_mirror_total", "Total projects", &["mirror"])
+                .unwrap(),
+            proj_skip: register_gauge_vec!("git_mirror_skip", "Skipped projects", &["mirror"])
+                .unwrap(),
+            skipped_total: register_gauge_vec!(
+                "git_mirror_skipped_total",
+                "Skipped projects by cause",
+                &["mirror", "reason"]
+            )
+            .unwrap(),
+            proj_fail: register_gauge_vec!("git_mirror_fail", "Failed projects", &["mirror"])
+                .unwrap(),
+            proj_ok: register_gauge_vec!("git_mirror_ok", "OK projects", &["mirror"]).unwrap(),
+            proj_start: register_gauge_vec!(
+                "git_mirror_project_start",
+                "Start of project mirror as unix timestamp",
+                &["origin", "destination", "mirror"]
+            )
+            .unwrap(),
+            proj_end: register_gauge_vec!(
+                "git_mirror_project_end",
+                "End of project mirror as unix timestamp",
+                &["origin", "destination", "mirror"]
+            )
+            .unwrap(),
+            proj_retries: register_gauge_vec!(
+                "git_mirror_project_retries",
+                "Number of retries of the last project mirror",
+                &["origin", "destination", "mirror"]
+            )
+            .unwrap(),
+            proj_duration: register_gauge_vec!(
+                "git_mirror_project_duration_seconds",
+                "Duration of the last project mirror including its retries",
+                &["origin", "destination", "mirror"]
+            )
+            .unwrap(),
+            proj_result: register_gauge_vec!(
+                "git_mirror_project_result",
+                "1 for the result (ok, failed or skipped) of the last project mirror",
+                &["origin", "destination", "mirror", "result"]
+            )
+            .unwrap(),
+            proj_last_success: register_gauge_vec!(
+                "git_mirror_project_last_success",
+                "Last complete project mirror as unix timestamp",
+                &["origin", "destination", "mirror"]
+            )
+            .unwrap(),
+            repo_size: register_gauge_vec!(
+                "git_mirror_repo_size_bytes",
+                "Size of the objects of the local repository in bytes",
+                &["origin", "destination", "mirror"]
+            )
+            .unwrap(),
+            fetch_seconds: register_histogram_vec!(
+                "git_mirror_fetch_seconds",
+                "Time of fetching a repository from the origin",
+                &["mirror"],
+                PHASE_BUCKETS.to_vec()
+            )
+            .unwrap(),
+            push_seconds: register_histogram_vec!(
+                "git_mirror_push_seconds",
+                "Time of pushing a repository to the destination",
+                &["mirror"],
+                PHASE_BUCKETS.to_vec()
+            )
+            .unwrap(),
+            objects_transferred: register_gauge_vec!(
+                "git_mirror_objects_transferred",
+                "Objects received from the origin or sent to the destination by the last sync",
+                &["origin", "destination", "mirror", "direction"]
+            )
+            .unwrap(),
+            bytes_transferred: register_gauge_vec!(
+                "git_mirror_bytes_transferred",
+                "Bytes received from the origin or sent to the destination by the last sync",
+                &["origin", "destination", "mirror", "direction"]
+            )
+            .unwrap(),
+            in_flight: register_gauge_vec!(
+                "git_mirror_in_flight",
+                "Sync jobs currently running",
+                &["mirror"]
+            )
+            .unwrap(),
+            queue_depth: register_gauge_vec!(
+                "git_mirror_queue_depth",
+                "Listed sync jobs waiting for a worker",
+                &["mirror"]
+            )
+            .unwrap(),
+        }
+    }
+
+    /// Count a skipped project with its cause
+    fn skipped(&self, label: &str, reason: SkipReason) {
+        self.proj_skip.with_label_values(&[label]).inc();
+        self.skipped_total
+            .with_label_values(&[label, &reason.to_string()])
+            .inc();
+    }
+
+    /// Count the merged results of a webhook run, the jobs of the run were counted on top of the
+    /// last complete run. Only daemons run webhooks, which have a single mirror label.
+    fn totals(&self, label: &str, results: &[JobResult]) {
+        let (mut ok, mut failed, mut skipped) = (0, 0, 0);
+        self.skipped_total.reset();
+        for r in results {
+            match r.testcase.result {
+                // Also the invalid descriptions
+                _ if r.repo.is_none() => skipped += 1,
+                TestResult::Success => ok += 1,
+                TestResult::Skipped => skipped += 1,
+                TestResult::Error { .. } | TestResult::Failure { .. } => failed += 1,
+            }
+            if let Some(reason) = r.skip {
+                self.skipped_total
+                    .with_label_values(&[label, &reason.to_string()])
+                    .inc();
+            }
+        }
+        self.proj_total
+            .with_label_values(&[label])
+            .set(results.len() as f64);
+        self.proj_ok.with_label_values(&[label]).set(ok as f64);
+        self.proj_fail
+            .with_label_values(&[label])
+            .set(failed as f64);
+        self.proj_skip
+            .with_label_values(&[label])
+            .set(skipped as f64);
+    }
+
+    /// Record the result of the project, with the duration of attempted syncs
+    fn project_result(&self, x: &Mirror, label: &str, result: &str, duration: Option<f64>) {
+        let labels = [x.origin.as_str(), &x.destination, label];
+        // The result of a webhook run replaces the one of the last complete run
+        for other in PROJECT_RESULTS.iter().filter(|r| **r != result) {
+            let _ = self
+                .proj_result
+                .remove_label_values(&[labels[0], labels[1], labels[2], other]);
+        }
+        self.proj_result
+            .with_label_values(&[labels[0], labels[1], labels[2], result])
+            .set(1.0);
+        if let Some(duration) = duration {
+            self.proj_duration.with_label_values(&labels).set(duration);
+        }
+    }
+
+    /// Clear the values of a previous run
+    fn reset(&self) {
+        for g in [
+            &self.dry_run,
+            &self.start_time,
+            &self.end_time,
+            &self.proj_total,
+            &self.proj_skip,
+            &self.skipped_total,
+            &self.proj_fail,
+            &self.proj_ok,
+            &self.proj_start,
+            &self.proj_end,
+            &self.proj_retries,
+            &self.proj_duration,
+            &self.proj_result,
+            &self.proj_last_success,
+            &self.repo_size,
+            &self.objects_transferred,
+            &self.bytes_transferred,
+            &self.in_flight,
+            &self.queue_depth,
+        ] {
+            g.reset();
+        }
+        self.fetch_seconds.reset();
+        self.push_seconds.reset();
+    }
+}
+
+/// Result of a sync job
+#[derive(Clone)]
+struct JobResult {
+    testcase: TestCase,
+    /// Origin and destination of the job, not known for invalid descriptions
+    repo: Option<(String, String)>,
+    /// Transfers of the job, also recorded without `--transfer-stats`
+    stats: TransferStats,
+    /// Number of retries of the sync
+    retries: u32,
+    /// Cause of a failed sync
+    failure: Option<GitFailureKind>,
+    /// Cause of a skipped repository
+    skip: Option<SkipReason>,
+    /// Size of the local repository with `--report-sizes`
+    size: Option<RepoSize>,
+    /// Transfers of the job with `--transfer-stats`
+    transfer: Option<RepoTransfer>,
+    /// Why the LFS objects weren't mirrored with `--lfs-failure warn`, the refs were
+    lfs_error: Option<String>,
+}
+
+/// Results of all sync jobs of a run
+struct SyncReport {
+    suite: TestSuite,
+    /// Number of failed jobs by cause
+    failure_kinds: BTreeMap<GitFailureKind, usize>,
+    /// Number of skipped jobs by cause
+    skip_reasons: BTreeMap<SkipReason, usize>,
+    sizes: Vec<RepoSize>,
+    transfers: Vec<RepoTransfer>,
+    /// Jobs that synced the refs, but not the LFS objects
+    lfs_failures: Vec<String>,
+    jobs: Vec<JobReport>,
+}
+
+/// Run the sync job with index `i` out of `total` and report the result,
+/// together with the cause if the sync failed
+fn sync_repo(
+    i: usize,
+    total: &str,
+    x: &MirrorResult,
+    provider: &dyn Provider,
+    label: &str,
+    opts: &MirrorOptions,
+    metrics: &SyncMetrics,
+) -> JobResult {
+    metrics.proj_total.with_label_values(&[label]).inc();
+    let start = OffsetDateTime::now_utc();
+    match (x, stop_reason(opts)) {
+        (Ok(x), Some((reason, detail))) => {
+            let name = format!("{} -> {}", x.origin, x.destination);
+            println!(
+                "SKIP {}/{} [{}]: {} ({})",
+                i,
+                total,
+                OffsetDateTime::now_utc(),
+                name,
+                detail
+            );
+            metrics.skipped(label, reason);
+            metrics.project_result(x, label, "skipped", None);
+            let mut tc = TestCaseBuilder::skipped(&name);
+            tc.set_system_out(&format!("Skipped: {detail}"));
+            JobResult {
+                testcase: tc.build(),
+                repo: Some((x.origin.clone(), x.destination.clone())),
+                stats: TransferStats::default(),
+                retries: 0,
+                failure: None,
+                skip: Some(reason),
+                size: None,
+                transfer: None,
+                lfs_error: None,
+            }
+        }
+        (Ok(x), None) => {
+            let name = format!("{} -> {}", x.origin, x.destination);
+            println!(
+                "START {}/{} [{}]: {}",
+                i,
+                total,
+                OffsetDateTime::now_utc(),
+                name
+            );
+            metrics
+                .proj_start
+                .with_label_values(&[&x.origin, &x.destination, label])
+                .set(OffsetDateTime::now_utc().unix_timestamp() as f64);
+            // Only the refspec of the branch and tag patterns prunes
+            let filter = filter_refspec(&opts.branches, &opts.tags);
+            let default_prune = opts.refspec.is_none() && filter.is_some();
+            let default_refspec = opts
+                .refspec
+                .clone()
+                .or(filter)
+                .or_else(|| opts.ref_mapping.refspec());
+            let (refspec, prune_refs) = match &x.refspec {
+                Some(r) => {
+                    debug!("Using repo specific refspec: {:?}", r);
+                    (&x.refspec, x.prune_refs)
+                }
+                None => {
+                    match default_refspec {
+                        Some(ref r) => {
+                            debug!("Using global custom refspec: {:?}", r);
+                        }
+                        None => {
+                            debug!("Using no custom refspec.");
+                        }
+                    }
+                    (&default_refspec, default_prune)
+                }
+            };
+            trace!("Refspec used: {:?}", refspec);
+            let log = Arc::new(match opts.repo_log_dir {
+                Some(ref dir) if !opts.dry_run => RepoLog::create(dir, &x.destination),
+                _ => RepoLog::disabled(),
+            });
+            log.log(format_args!("START {name}"));
+            let collision = dest_collision(x, opts);
+            if x.subtree_prefix.is_none() && collision.is_none() {
+                adopt_renamed(x, opts, &log);
+            }
+            let retries = Cell::new(0);
+            // Retrying doesn't help against e.g. rejected credentials
+            let (result, stats) = transfer::record(|| match (collision, skip_reason(x, opts)) {
+                (Some(other), _) => Err(GitMirrorError::GenericError(format!(
+                    "Destination {} collides with the destination of {} after changing the case",
+                    x.destination, other
+                ))),
+                (None, Some((reason, detail))) => Ok(MirrorOutcome::Skipped(reason, detail)),
+                (None, None) if activity_unchanged(x, opts) => {
+                    info!("No activity since last sync for {}", x.origin);
+                    log.log("No activity since last sync");
+                    Ok(MirrorOutcome::UpToDate)
+                }
+                // The retries share the budget
+                (None, None) => with_deadline(repo_budget(x, opts).map(Deadline::after), || {
+                    let (result, n) = opts.retry.retry_counted(
+                        &format!("Sync of {name}"),
+                        || {
+                            create_destination(provider, x, opts)?;
+                            let dest_token = provider
+                                .dest_token(x)
+                                .map_err(GitMirrorError::GenericError)?;
+                            match x.subtree_prefix {
+                                Some(ref prefix) => import_subtree(
+                                    x,
+                                    prefix,
+                                    dest_token.as_ref(),
+                                    opts,
+                                    log.clone(),
+                                ),
+                                None if x.wiki && !wiki_exists(x, opts, &log)? => {
+                                    info!("Wiki {} was never created", x.origin);
+                                    log.log("Wiki was never created");
+                                    Ok(MirrorOutcome::Skipped(
+                                        SkipReason::NoWiki,
+                                        "wiki never created".to_string(),
+                                    ))
+                                }
+                                None => {
+                                    // Pushes after this change the activity
+                                    let fetched_at = OffsetDateTime::now_utc().unix_timestamp();
+                                    let outcome = mirror_repo(
+                                        &x.origin,
+                                        &x.destination,
+                                        refspec,
+                                        prune_refs,
+                                        x.lfs,
+                                        dest_token.as_ref(),
+                                        opts,
+                                        log.clone(),
+                                    )?;
+                                    if matches!(
+                                        outcome,
+                                        MirrorOutcome::Synced | MirrorOutcome::UpToDate
+                                    ) {
+                                        record_activity(x, opts, fetched_at);
+                                    }
+                                    Ok(outcome)
+                                }
+                            }
+                        },
+                        |e| e.failure_kind().is_none_or(GitFailureKind::is_transient),
+                    );
+                    retries.set(n);
+                    result
+                }),
+            });
+            let retries = retries.get();
+            metrics
+                .proj_retries
+                .with_label_values(&[&x.origin, &x.destination, label])
+                .set(retries as f64);
+            let retried = match retries {
+                0 => String::new(),
+                1 => " (after 1 retry)".to_string(),
+                n => format!(" (after {n} retries)"),
+            };
+            let record_transfer = opts.transfer_stats && !opts.dry_run;
+            let result = result.and_then(|outcome| match outcome {
+                MirrorOutcome::DryRun { .. } | MirrorOutcome::Skipped(..) => Ok((outcome, 0)),
+                // The releases are downloaded with the repository
+                _ if opts.include_releases && x.project.is_some() && opts.stage != Stage::Push => {
+                    let dir = releases_dir(&opts.mirror_dir, &x.origin);
+                    mirror_releases(provider, x, &dir)
+                        .map(|assets| (outcome, assets))
+                        .map_err(|e| {
+                            GitMirrorError::GenericError(format!("Unable to mirror releases ({e})"))
+                        })
+                }
+                _ => Ok((outcome, 0)),
+            });
+            if (opts.set_default_branch || opts.sync_metadata) && x.subtree_prefix.is_none() {
+                if let Ok((
+                    MirrorOutcome::Synced | MirrorOutcome::Partial(_) | MirrorOutcome::LfsFailed(_),
+                    _,
+                )) = result
+                {
+                    if opts.set_default_branch {
+                        set_default_branch(provider, x, opts, &log);
+                    }
+                    if opts.sync_metadata {
+                        sync_metadata(provider, x, opts, &log);
+                    }
+                }
+            }
+            match result {
+                Ok((MirrorOutcome::Skipped(reason, detail), _)) => {
+                    println!(
+                        "SKIP {}/{} [{}]: {} ({})",
+                        i,
+                        total,
+                        OffsetDateTime::now_utc(),
+                        name,
+                        detail
+                    );
+                    log.log(format_args!("SKIP {name} ({detail})"));
+                    metrics.skipped(label, reason);
+                    metrics.project_result(x, label, "skipped", None);
+                    let mut tc = TestCaseBuilder::skipped(&name);
+                    tc.set_system_out(&format!("Skipped: {detail}"));
+                    JobResult {
+                        testcase: tc.build(),
+                        repo: Some((x.origin.clone(), x.destination.clone())),
+                        stats,
+                        retries,
+                        failure: None,
+                        skip: Some(reason),
+                        size: None,
+                        transfer: None,
+                        lfs_error: None,
+                    }
+                }
+                Ok((outcome, assets)) => {
+                    record_project(x, opts);
+                    let note = match &outcome {
+                        MirrorOutcome::Synced => String::new(),
+                        MirrorOutcome::UpToDate => " (up-to-date)".to_string(),
+                        MirrorOutcome::Fetched => " (fetched, not pushed)".to_string(),
+                        MirrorOutcome::Partial(refs) => {
+                            format!(" (partial, rejected: {})", refs.join(", "))
+                        }
+                        MirrorOutcome::LfsFailed(e) => format!(" (partial, LFS {e})"),
+                        MirrorOutcome::DryRun { clone: true } => {
+                            " (dry run, would clone and push)".to_string()
+                        }
+                        MirrorOutcome::DryRun { clone: false } => {
+                            " (dry run, would update and push)".to_string()
+                        }
+                        MirrorOutcome::Skipped(..) => unreachable!("Reported as skipped"),
+                    };
+                    let note = match assets {
+                        0 => note,
+                        n => format!("{note} ({n} release assets downloaded)"),
+                    };
+                    let note = note + &retried;
+                    println!(
+                        "END(OK) {}/{} [{}]: {}{}",
+                        i,
+                        total,
+                        OffsetDateTime::now_utc(),
+                        name,
+                        note
+                    );
+                    log.log(format_args!("END(OK) {name}{note}"));
+                    metrics
+                        .proj_end
+                        .with_label_values(&[&x.origin, &x.destination, label])
+                        .set(OffsetDateTime::now_utc().unix_timestamp() as f64);
+                    metrics.proj_ok.with_label_values(&[label]).inc();
+                    let duration = OffsetDateTime::now_utc() - start;
+                    metrics.project_result(x, label, "ok", Some(duration.as_seconds_f64()));
+                    let complete = !matches!(
+                        outcome,
+                        MirrorOutcome::Fetched | MirrorOutcome::DryRun { .. }
+                    );
+                    last_success_metric(x, label, opts, metrics, complete);
+                    let mut tc = TestCaseBuilder::success(&name, duration);
+                    match &outcome {
+                        MirrorOutcome::Partial(refs) => {
+                            tc.set_system_out(&format!("Rejected refs: {}", refs.join(", ")));
+                        }
+                        MirrorOutcome::LfsFailed(e) => {
+                            tc.set_system_out(&format!("LFS objects not mirrored, {e}"));
+                        }
+                        MirrorOutcome::DryRun { clone } => {
+                            tc.set_system_out(&format!(
+                                "Dry run: would {} {} and push to {}",
+                                if *clone { "clone" } else { "update" },
+                                x.origin,
+                                x.destination
+                            ));
+                        }
+                        _ => {}
+                    }
+                    let measure =
+                        opts.report_sizes && !opts.dry_run && opts.cleanup != Cleanup::Always;
+                    JobResult {
+                        testcase: tc.build(),
+                        repo: Some((x.origin.clone(), x.destination.clone())),
+                        stats: stats.clone(),
+                        retries,
+                        failure: None,
+                        skip: None,
+                        size: measure
+                            .then(|| repo_size(x, label, opts, metrics))
+                            .flatten(),
+                        transfer: record_transfer
+                            .then(|| job_transfer(x, stats, label, opts, metrics)),
+                        lfs_error: match outcome {
+                            MirrorOutcome::LfsFailed(e) => Some(e),
+                            _ => None,
+                        },
+                    }
+                }
+                Err(e) => {
+                    println!(
+                        "END(FAIL) {}/{} [{}]: {} ({}){}",
+                        i,
+                        total,
+                        OffsetDateTime::now_utc(),
+                        name,
+                        e,
+                        retried
+                    );
+                    log.log(format_args!("END(FAIL) {name} ({e}){retried}"));
+                    metrics
+                        .proj_end
+                        .with_label_values(&[&x.origin, &x.destination, label])
+                        .set(OffsetDateTime::now_utc().unix_timestamp() as f64);
+                    metrics.proj_fail.with_label_values(&[label]).inc();
+                    let duration = OffsetDateTime::now_utc() - start;
+                    metrics.project_result(x, label, "failed", Some(duration.as_seconds_f64()));
+                    last_success_metric(x, label, opts, metrics, false);
+                    let kind = e.failure_kind().unwrap_or(GitFailureKind::Other);
+                    error!("Unable to sync repo {} ({}: {})", name, kind, e);
+                    let tc =
+                        TestCaseBuilder::error(&name, duration, "sync error", &format!("{e:?}"))
+                            .build();
+                    JobResult {
+                        testcase: tc,
+                        repo: Some((x.origin.clone(), x.destination.clone())),
+                        stats: stats.clone(),
+                        retries,
+                        failure: Some(kind),
+                        skip: None,
+                        size: None,
+                        transfer: record_transfer
+                            .then(|| job_transfer(x, stats, label, opts, metrics)),
+                        lfs_error: None,
+                    }
+                }
+            }
+        }
+        (Err(e), _) => {
+            metrics.proj_skip.with_label_values(&[label]).inc();
+            let duration = OffsetDateTime::now_utc() - start;
+
+            let (tc, skip) = match e {
+                MirrorError::Description(d, se) => {
+                    error!("Error parsing YAML: {}, Error: {:?}", d, se);
+                    let tc = TestCaseBuilder::error("", duration, "parse error", &format!("{e:?}"));
+                    (tc.build(), None)
+                }
+                MirrorError::Missing(d) => {
+                    error!("Missing description: {}", d);
+                    let tc = TestCaseBuilder::error("", duration, "parse error", &format!("{e:?}"));
+                    (tc.build(), None)
+                }
+                MirrorError::Skip(url) => {
+                    println!(
+                        "SKIP {}/{} [{}]: {} (disabled by repo config)",
+                        i,
+                        total,
+                        OffsetDateTime::now_utc(),
+                        url
+                    );
+                    metrics
+                        .skipped_total
+                        .with_label_values(&[label, &SkipReason::Disabled.to_string()])
+                        .inc();
+                    let mut tc = TestCaseBuilder::skipped(url);
+                    tc.set_system_out("Disabled by repo config");
+                    (tc.build(), Some(SkipReason::Disabled))
+                }
+            };
+            JobResult {
+                testcase: tc,
+                repo: None,
+                stats: TransferStats::default(),
+                retries: 0,
+                failure: None,
+                skip,
+                size: None,
+                transfer: None,
+                lfs_error: None,
+            }
+        }
+    }
+}
 
-    info!("Push to destination {}", destination);
-
-    git.git_push_mirror(destination, &origin_dir, refspec, lfs)?;
+/// Why the jobs not started yet are skipped
+fn stop_reason(opts: &MirrorOptions) -> Option<(SkipReason, &'static str)> {
+    if opts.progress.as_ref().is_some_and(Progress::is_cancelled) {
+        return Some((SkipReason::Cancelled, "run cancelled"));
+    }
+    if opts
+        .max_transfer
+        .is_some_and(|max| transfer::transferred() >= max)
+    {
+        return Some((SkipReason::TransferBudget, "transfer budget exhausted"));
+    }
+    None
+}
 
-    if opts.remove_workrepo {
-        fs::remove_dir_all(&origin_dir).map_err(|e| {
-            GitMirrorError::GenericError(format!(
-                "Unable to delete working repository: {} because of error: {}",
-                &origin_dir.to_string_lossy(),
-                e
-            ))
-        })?;
+/// Report the queued job
+fn queued(x: &MirrorResult, opts: &MirrorOptions) {
+    if let Ok(x) = x {
+        progress::report(&opts.progress, || ProgressEvent::Queued {
+            repository: format!("{} -> {}", x.origin, x.destination),
+        });
     }
+}
 
-    Ok(())
+/// Report the finished job
+fn finished(r: &JobResult, opts: &MirrorOptions) {
+    progress::report(&opts.progress, || {
+        let report = job_report(r);
+        match report.result {
+            JobStatus::Failed => ProgressEvent::Failed(report),
+            JobStatus::Success | JobStatus::Skipped => ProgressEvent::Done(report),
+        }
+    });
 }
 
-fn run_sync_task(v: &[MirrorResult], label: &str, opts: &MirrorOptions) -> TestSuite {
-    // Give the work to the worker pool
-    rayon::ThreadPoolBuilder::new()
+fn init_worker_pool(opts: &MirrorOptions) {
+    // Give the work to the worker pool, it is kept for further runs in daemon mode
+    if let Err(e) = rayon::ThreadPoolBuilder::new()
         .num_threads(opts.worker_count)
         .build_global()
-        .unwrap();
-
-    let proj_total =
-        register_gauge_vec!("git_mirror_total", "Total projects", &["mirror"]).unwrap();
-    let proj_skip =
-        register_gauge_vec!("git_mirror_skip", "Skipped projects", &["mirror"]).unwrap();
-    let proj_fail = register_gauge_vec!("git_mirror_fail", "Failed projects", &["mirror"]).unwrap();
-    let proj_ok = register_gauge_vec!("git_mirror_ok", "OK projects", &["mirror"]).unwrap();
-    let proj_start = register_gauge_vec!(
-        "git_mirror_project_start",
-        "Start of project mirror as unix timestamp",
-        &["origin", "destination", "mirror"]
-    )
-    .unwrap();
-    let proj_end = register_gauge_vec!(
-        "git_mirror_project_end",
-        "End of project mirror as unix timestamp",
-        &["origin", "destination", "mirror"]
-    )
-    .unwrap();
-
-    let total = v.len();
-    let results = v
-        .par_iter()
-        .enumerate()
-        .map(|(i, x)| {
-            proj_total.with_label_values(&[label]).inc();
-            let start = OffsetDateTime::now_utc();
-            match x {
-                Ok(x) => {
-                    let name = format!("{} -> {}", x.origin, x.destination);
-                    let proj_fail = proj_fail.clone();
-                    let proj_ok = proj_ok.clone();
-                    let proj_start = proj_start.clone();
-                    let proj_end = proj_end.clone();
-                    let label = label.to_string();
-                    println!(
-                        "START {}/{} [{}]: {}",
-                        i,
-                        total,
-                        OffsetDateTime::now_utc(),
-                        name
-                    );
-                    proj_start
-                        .with_label_values(&[&x.origin, &x.destination, &label])
-                        .set(OffsetDateTime::now_utc().unix_timestamp() as f64);
-                    let refspec = match &x.refspec {
-                        Some(r) => {
-                            debug!("Using repo specific refspec: {:?}", r);
-                            &x.refspec
-                        }
-                        None => {
-                            match opts.refspec.clone() {
-                                Some(r) => {
-                                    debug!("Using global custom refspec: {:?}", r);
-                                }
-                                None => {
-                                    debug!("Using no custom refspec.");
-                                }
-                            }
-                            &opts.refspec
-                        }
-                    };
-                    trace!("Refspec used: {:?}", refspec);
-                    match mirror_repo(&x.origin, &x.destination, refspec, x.lfs, opts) {
-                        Ok(_) => {
-                            println!(
-                                "END(OK) {}/{} [{}]: {}",
-                                i,
-                                total,
-                                OffsetDateTime::now_utc(),
-                                name
-                            );
-                            proj_end
-                                .with_label_values(&[&x.origin, &x.destination, &label])
-                                .set(OffsetDateTime::now_utc().unix_timestamp() as f64);
-                            proj_ok.with_label_values(&[&label]).inc();
-                            TestCaseBuilder::success(&name, OffsetDateTime::now_utc() - start)
-                                .build()
-                        }
-                        Err(e) => {
-                            println!(
-                                "END(FAIL) {}/{} [{}]: {} ({})",
-                                i,
-                                total,
-                                OffsetDateTime::now_utc(),
-                                name,
-                                e
-                            );
-                            proj_end
-                                .with_label_values(&[&x.origin, &x.destination, &label])
-                                .set(OffsetDateTime::now_utc().unix_timestamp() as f64);
-                            proj_fail.with_label_values(&[&label]).inc();
-                            error!("Unable to sync repo {} ({})", name, e);
-                            TestCaseBuilder::error(
-                                &name,
-                                OffsetDateTime::now_utc() - start,
-                                "sync error",
-                                &format!("{e:?}"),
-                            )
-                            .build()
-                        }
-                    }
+    {
+        trace!("Worker pool already initialized ({})", e);
+    }
+}
+
+fn run_sync_task(
+    v: &[MirrorResult],
+    provider: &dyn Provider,
+    label: &str,
+    opts: &MirrorOptions,
+    complete: bool,
+) -> Vec<JobResult> {
+    init_worker_pool(opts);
+    let metrics = SyncMetrics::get();
+
+    // The reports of a webhook run are the merged ones written at its end
+    let partial = complete.then(|| PartialReports::new(opts));
+    let total = v.len().to_string();
+    metrics
+        .queue_depth
+        .with_label_values(&[label])
+        .set(v.len() as f64);
+    for x in v {
+        queued(x, opts);
+    }
+    with_metrics_ticker(opts, || {
+        v.par_iter()
+            .enumerate()
+            .map(|(i, x)| run_job(i, &total, x, provider, label, opts, metrics))
+            .inspect(|r| {
+                if let Some(ref partial) = partial {
+                    partial.add(&r.testcase);
                 }
-                Err(e) => {
-                    proj_skip.with_label_values(&[label]).inc();
-                    let duration = OffsetDateTime::now_utc() - start;
+                finished(r, opts);
+                #[cfg(feature = "nats")]
+                if let Some(ref events) = opts.events {
+                    events.repo_finished(r);
+                }
+                #[cfg(feature = "sqlite")]
+                if let Some(ref history) = opts.history {
+                    history.repo_finished(r);
+                }
+            })
+            .collect::<Vec<_>>()
+    })
+}
 
-                    match e {
-                        MirrorError::Description(d, se) => {
-                            error!("Error parsing YAML: {}, Error: {:?}", d, se);
-                            TestCaseBuilder::error("", duration, "parse error", &format!("{e:?}"))
-                                .build()
-                        }
-                        MirrorError::Skip(url) => {
-                            println!(
-                                "SKIP {}/{} [{}]: {}",
-                                i,
-                                total,
-                                OffsetDateTime::now_utc(),
-                                url
-                            );
-                            TestCaseBuilder::skipped(url).build()
-                        }
-                    }
+/// Run the sync jobs as they are received, while the repositories are still being listed
+fn run_sync_stream(
+    rx: Receiver<MirrorResult>,
+    provider: &dyn Provider,
+    label: &str,
+    opts: &MirrorOptions,
+) -> Vec<JobResult> {
+    init_worker_pool(opts);
+    let metrics = SyncMetrics::get();
+
+    let partial = PartialReports::new(opts);
+    // The total is unknown until the listing is complete
+    let index = AtomicUsize::new(0);
+    with_metrics_ticker(opts, || {
+        rx.into_iter()
+            .inspect(|x| queued(x, opts))
+            .par_bridge()
+            .map(|x| {
+                let i = index.fetch_add(1, Ordering::SeqCst);
+                run_job(i, "?", &x, provider, label, opts, metrics)
+            })
+            .inspect(|r| {
+                partial.add(&r.testcase);
+                finished(r, opts);
+                #[cfg(feature = "nats")]
+                if let Some(ref events) = opts.events {
+                    events.repo_finished(r);
+                }
+                #[cfg(feature = "sqlite")]
+                if let Some(ref history) = opts.history {
+                    history.repo_finished(r);
+                }
+            })
+            .collect::<Vec<_>>()
+    })
+}
+
+/// Run a sync job taken from the queue, counting it as in flight while it runs
+fn run_job(
+    i: usize,
+    total: &str,
+    x: &MirrorResult,
+    provider: &dyn Provider,
+    label: &str,
+    opts: &MirrorOptions,
+    metrics: &SyncMetrics,
+) -> JobResult {
+    let in_flight = metrics.in_flight.with_label_values(&[label]);
+    metrics.queue_depth.with_label_values(&[label]).dec();
+    in_flight.inc();
+    let r = sync_repo(i, total, x, provider, label, opts, metrics);
+    in_flight.dec();
+    r
+}
+
+/// Also write the metrics file every `--report-interval` while `f` runs, not only when a job
+/// finishes, so the gauges of the running jobs stay current during long syncs
+fn with_metrics_ticker<T>(opts: &MirrorOptions, f: impl FnOnce() -> T) -> T {
+    let file = match opts.metrics_file {
+        Some(ref file) if !opts.report_interval.is_zero() => file,
+        _ => return f(),
+    };
+    let (done, stop) = mpsc::channel::<()>();
+    thread::scope(|s| {
+        s.spawn(move || {
+            while let Err(mpsc::RecvTimeoutError::Timeout) = stop.recv_timeout(opts.report_interval)
+            {
+                if let Err(e) = try_write_metrics(file) {
+                    warn!("Unable to write partial metrics file {:?}: {}", file, e);
                 }
             }
-        })
-        .collect::<Vec<TestCase>>();
+        });
+        let r = f();
+        drop(done);
+        r
+    })
+}
+
+/// Remember the results of a complete run. The results of a webhook run replace the ones of the
+/// same jobs among those of the last complete run, so its reports still cover all repositories.
+fn merge_results(results: Vec<JobResult>, complete: bool) -> Vec<JobResult> {
+    let mut last = LAST_RESULTS.lock().unwrap_or_else(|e| e.into_inner());
+    if complete {
+        last.clone_from(&results);
+        return results;
+    }
+    for r in results {
+        match last.iter_mut().find(|l| l.testcase.name == r.testcase.name) {
+            Some(l) => *l = r,
+            None => last.push(r),
+        }
+    }
+    last.clone()
+}
 
+/// Collect the results into a test suite and count the failed jobs by cause
+fn finish_sync_task(results: Vec<JobResult>) -> SyncReport {
+    let mut kinds = BTreeMap::new();
+    let mut skips = BTreeMap::new();
+    let mut sizes = Vec::new();
+    let mut transfers = Vec::new();
+    let mut lfs_failures = Vec::new();
+    let mut jobs = Vec::new();
+    let results: Vec<TestCase> = results
+        .into_iter()
+        .map(|r| {
+            jobs.push(job_report(&r));
+            if let Some(kind) = r.failure {
+                *kinds.entry(kind).or_insert(0) += 1;
+            }
+            if let Some(reason) = r.skip {
+                *skips.entry(reason).or_insert(0) += 1;
+            }
+            sizes.extend(r.size);
+            transfers.extend(r.transfer);
+            if r.lfs_error.is_some() {
+                lfs_failures.push(r.testcase.name.clone());
+            }
+            r.testcase
+        })
+        .collect();
+    let total = results.len();
     let success = results.iter().filter(|x| x.is_success()).count();
     let ts = TestSuiteBuilder::new("Sync Job")
         .add_testcases(results)
@@ -242,35 +2202,540 @@ fn run_sync_task(v: &[MirrorResult], label: &str, opts: &MirrorOptions) -> TestS
         total,
         OffsetDateTime::now_utc()
     );
-    ts
+    if !kinds.is_empty() {
+        let counts: Vec<String> = kinds.iter().map(|(k, n)| format!("{n} {k}")).collect();
+        println!("FAILURES: {}", counts.join(", "));
+    }
+    if !skips.is_empty() {
+        let counts: Vec<String> = skips.iter().map(|(r, n)| format!("{n} {r}")).collect();
+        println!("SKIPPED: {}", counts.join(", "));
+    }
+    if !lfs_failures.is_empty() {
+        println!(
+            "LFS FAILURES: {} repositories synced without their LFS objects",
+            lfs_failures.len()
+        );
+    }
+    SyncReport {
+        suite: ts,
+        failure_kinds: kinds,
+        skip_reasons: skips,
+        sizes,
+        transfers,
+        lfs_failures,
+        jobs,
+    }
+}
+
+/// The result of a job as reported in the `--json-report`
+fn job_report(r: &JobResult) -> JobReport {
+    let tc = &r.testcase;
+    let error = match tc.result {
+        TestResult::Error { ref message, .. } | TestResult::Failure { ref message, .. } => {
+            Some(message.clone())
+        }
+        TestResult::Success | TestResult::Skipped => None,
+    };
+    JobReport {
+        name: tc.name.clone(),
+        source: r.repo.as_ref().map(|(origin, _)| origin.clone()),
+        destination: r.repo.as_ref().map(|(_, destination)| destination.clone()),
+        duration_secs: tc.time.as_seconds_f64(),
+        result: JobStatus::of(tc),
+        error,
+        failure_kind: r.failure,
+        skip_reason: r.skip,
+        commits_fetched: r.stats.commits_fetched,
+        commits_pushed: r.stats.commits_pushed,
+        retries: r.retries,
+    }
+}
+
+/// Check if the listed repository belongs to the shard of this run. The destination as listed
+/// by the provider is used, so wikis end up in the same shard as their project.
+fn in_shard(m: &MirrorResult, opts: &MirrorOptions) -> bool {
+    let shard = match opts.shard {
+        Some(shard) => shard,
+        None => return true,
+    };
+    let key = match m {
+        Ok(m) => &m.destination,
+        Err(
+            MirrorError::Skip(url) | MirrorError::Missing(url) | MirrorError::Description(url, _),
+        ) => url,
+    };
+    shard.contains(key)
+}
+
+/// Apply the renames, the rewrites and the case normalization to the destination as listed by
+/// the provider
+fn resolve_destination(opts: &MirrorOptions, destination: &str) -> String {
+    let destination = rename_destination(&opts.renames, destination);
+    let destination = rewrite_destination(&opts.dest_rewrites, &destination);
+    opts.dest_case.apply(&destination)
+}
+
+/// The repository of a destination on the local file system (a path or a `file://` URL)
+fn local_destination(destination: &str) -> Option<&Path> {
+    let path = Path::new(destination.strip_prefix("file://").unwrap_or(destination));
+    path.is_dir().then_some(path)
+}
+
+/// Whether the wiki repository of the job `x` exists on the origin, GitHub lists wikis without
+/// pages as enabled. The push stage only checks whether the wiki was fetched.
+fn wiki_exists(x: &Mirror, opts: &MirrorOptions, log: &Arc<RepoLog>) -> Result<bool> {
+    if opts.dry_run {
+        return Ok(true);
+    }
+    if opts.stage == Stage::Push {
+        return Ok(local_repo_dir(opts, &x.origin, &x.destination).is_dir());
+    }
+    Ok(remote_exists(opts, &x.origin, log)?)
+}
+
+/// Whether the repository at `url` exists, `false` only if the remote reports it as not found
+fn remote_exists(
+    opts: &MirrorOptions,
+    url: &str,
+    log: &Arc<RepoLog>,
+) -> std::result::Result<bool, GitError> {
+    match transport_git(opts, None, log.clone()).git_ls_remote(url) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == GitFailureKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Create the destination of `x` with the API of the provider if it doesn't exist yet
+/// (`--dest-provider`). Not needed if nothing is pushed.
+fn create_destination(provider: &dyn Provider, x: &Mirror, opts: &MirrorOptions) -> Result<()> {
+    if opts.dry_run || opts.stage == Stage::Fetch {
+        return Ok(());
+    }
+    provider.create_destination(x).map_err(|e| {
+        GitMirrorError::GenericError(format!(
+            "Unable to create the destination {} ({e})",
+            x.destination
+        ))
+    })
+}
+
+/// Point the default branch of the destination to the default branch of the origin, either in
+/// the local repository or with the API of the provider. The refs are already synced, so
+/// failures only warn.
+fn set_default_branch(
+    provider: &dyn Provider,
+    x: &Mirror,
+    opts: &MirrorOptions,
+    log: &Arc<RepoLog>,
+) {
+    let git = transport_git(opts, None, log.clone());
+    let set = || -> std::result::Result<(), String> {
+        let head = git.git_remote_head(&x.origin).map_err(|e| e.to_string())?;
+        let branch = match head.as_deref().and_then(|h| h.strip_prefix("refs/heads/")) {
+            Some(branch) => branch,
+            None => {
+                debug!("Origin {} has no default branch", x.origin);
+                return Ok(());
+            }
+        };
+        if x.default_branch.as_deref() == Some(branch) {
+            return Ok(());
+        }
+        info!("Set the default branch of {} to {}", x.destination, branch);
+        log.log(format_args!("Set the default branch to {branch}"));
+        match local_destination(&x.destination) {
+            Some(dir) => git.git_set_head(dir, branch).map_err(|e| e.to_string()),
+            None => provider.set_default_branch(x, branch),
+        }
+    };
+    if let Err(e) = set() {
+        warn!(
+            "Unable to set the default branch of {}: {}",
+            x.destination, e
+        );
+        log.log(format_args!("Unable to set the default branch: {e}"));
+    }
+}
+
+/// Copy the topics and the default branch of the origin to the destination with the API of the
+/// provider. Only listed projects have settings, failures only warn like for the default branch.
+fn sync_metadata(provider: &dyn Provider, x: &Mirror, opts: &MirrorOptions, log: &Arc<RepoLog>) {
+    if x.project.is_none() {
+        debug!("No settings to sync for {}", x.destination);
+        return;
+    }
+    let sync = || -> std::result::Result<(), String> {
+        let mut metadata = provider.get_origin_metadata(x)?;
+        // Already set from the origin refs by `--set-default-branch`
+        if opts.set_default_branch || metadata.default_branch == x.default_branch {
+            metadata.default_branch = None;
+        }
+        info!("Sync the settings of {} to {}", x.origin, x.destination);
+        log.log("Sync the repository settings");
+        provider.set_metadata(x, &metadata)
+    };
+    if let Err(e) = sync() {
+        warn!("Unable to sync the settings of {}: {}", x.destination, e);
+        log.log(format_args!("Unable to sync the settings: {e}"));
+    }
+}
+
+/// Check if the provider lists no activity of the project since the last complete sync with
+/// `--only-changed`, which saves the `git ls-remote` of the origin. Providers may update the
+/// activity lazily, so it is only trusted if the origin was fetched `ACTIVITY_LAG` after it.
+fn activity_unchanged(x: &Mirror, opts: &MirrorOptions) -> bool {
+    if !opts.only_changed || opts.force_sync || opts.dry_run || opts.stage != Stage::All {
+        return false;
+    }
+    let activity = match x.activity {
+        Some(activity) => activity,
+        None => return false,
+    };
+    let repo_dir = local_repo_dir(opts, &x.origin, &x.destination);
+    if !repo_dir.is_dir() {
+        return false;
+    }
+    let mut state = RepoState::load(&repo_dir);
+    let unchanged = state.activity == Some(activity)
+        && state
+            .fetched_at
+            .is_some_and(|fetched| fetched >= activity + ACTIVITY_LAG);
+    if unchanged {
+        // Still in sync, so the repository isn't stale for `--cleanup stale`
+        state.last_success = Some(OffsetDateTime::now_utc().unix_timestamp());
+        if let Err(e) = state.store(&repo_dir) {
+            warn!("Unable to store state of {:?} ({})", repo_dir, e);
+        }
+    }
+    unchanged
+}
+
+/// Remember the listed activity of the project after a complete sync with `--only-changed`,
+/// with the time before the fetch
+fn record_activity(x: &Mirror, opts: &MirrorOptions, fetched_at: i64) {
+    if !opts.only_changed || opts.stage != Stage::All {
+        return;
+    }
+    let repo_dir = local_repo_dir(opts, &x.origin, &x.destination);
+    if !repo_dir.is_dir() {
+        return;
+    }
+    let mut state = RepoState::load(&repo_dir);
+    state.activity = x.activity;
+    state.fetched_at = Some(fetched_at);
+    if let Err(e) = state.store(&repo_dir) {
+        warn!("Unable to store state of {:?} ({})", repo_dir, e);
+    }
+}
+
+/// Get the reason to skip a project that is filtered out by its path, state or popularity
+fn skip_reason(x: &Mirror, opts: &MirrorOptions) -> Option<(SkipReason, String)> {
+    if let Some(detail) = excluded(&opts.includes, &opts.excludes, repo_path(&x.destination)) {
+        return Some((SkipReason::Excluded, detail));
+    }
+    if opts.skip_archived && x.archived {
+        return Some((SkipReason::Archived, "archived".to_string()));
+    }
+    if opts.skip_forks && x.fork {
+        return Some((SkipReason::Fork, "fork".to_string()));
+    }
+    let below = |count: Option<u64>, min: Option<u64>, what: &str, reason| match (count, min) {
+        (Some(count), Some(min)) if count < min => {
+            Some((reason, format!("too few {what}: {count} < {min}")))
+        }
+        _ => None,
+    };
+    below(x.stars, opts.min_stars, "stars", SkipReason::TooFewStars)
+        .or_else(|| below(x.forks, opts.min_forks, "forks", SkipReason::TooFewForks))
+}
+
+/// Get the origin of an earlier listed repository with the same destination. Only checked with
+/// a case normalization, imports into a monorepo share the destination on purpose.
+fn dest_collision(x: &Mirror, opts: &MirrorOptions) -> Option<String> {
+    if opts.dest_case == DestCase::Preserve || x.subtree_prefix.is_some() {
+        return None;
+    }
+    DEST_CLAIMS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(&x.destination)
+        .filter(|origin| **origin != x.origin)
+        .cloned()
+}
+
+/// Apply the destination rewrites and add the wiki mirror for a listed repository
+fn prepare_mirror(mut m: MirrorResult, opts: &MirrorOptions) -> Vec<MirrorResult> {
+    if let Ok(ref mut m) = m {
+        let destination = resolve_destination(opts, &m.destination);
+        if destination != m.destination {
+            debug!("Rewrite destination {} -> {}", m.destination, destination);
+            m.destination = destination;
+        }
+    }
+
+    let wiki = match m {
+        Ok(ref m) if opts.include_wikis && m.has_wiki => Some(Ok(m.wiki())),
+        _ => None,
+    };
+
+    let mut v = vec![m];
+    v.extend(wiki);
+    if opts.dest_case != DestCase::Preserve {
+        // In listing order, so the first listed repository keeps the destination
+        let mut claims = DEST_CLAIMS.lock().unwrap_or_else(|e| e.into_inner());
+        for m in v.iter().flatten().filter(|m| m.subtree_prefix.is_none()) {
+            claims
+                .entry(m.destination.clone())
+                .or_insert_with(|| m.origin.clone());
+        }
+    }
+    v
 }
 
 pub struct MirrorOptions {
     pub mirror_dir: PathBuf,
     pub dry_run: bool,
     pub metrics_file: Option<PathBuf>,
+    /// Prometheus Pushgateway the metrics are pushed to after every run
+    pub pushgateway_url: Option<String>,
+    /// Job the metrics are grouped by on the Pushgateway, together with the provider label
+    pub pushgateway_job: String,
     pub junit_file: Option<PathBuf>,
+    /// Write the summary and the result of every job as JSON to this file
+    pub json_report: Option<PathBuf>,
     pub worker_count: usize,
     pub git_executable: String,
+    /// Run the transfers with git or in-process with libgit2
+    pub git_backend: GitBackend,
     pub refspec: Option<Vec<String>>,
-    pub remove_workrepo: bool,
+    /// Destinations of the ref types, combined into the default refspec if `refspec` isn't set
+    pub ref_mapping: RefMapping,
+    pub cleanup: Cleanup,
+    /// Age of the last complete sync after which `Cleanup::Stale` removes a local repository
+    pub cleanup_after: Duration,
+    /// Remove the local repositories of projects that are no longer listed
+    pub prune: bool,
+    /// Archive or delete the destinations of pruned repositories
+    pub prune_remote: Option<PruneRemote>,
+    /// Fast local directory the repositories are fetched in before being moved to `mirror_dir`
+    pub work_tmp: Option<PathBuf>,
     pub fail_on_sync_error: bool,
     pub mirror_lfs: bool,
+    pub lfs_failure: LfsFailure,
+    pub dest_rewrites: Vec<DestRewrite>,
+    /// Renames of repositories on the destination, applied before `dest_rewrites`
+    pub renames: Vec<Rename>,
+    /// Overrides of the listed repositories from the config file
+    pub overrides: Vec<RepoOverride>,
+    /// Case of the repository paths on the destination, applied after the rewrites
+    pub dest_case: DestCase,
+    /// Name the local repositories after the origin or the destination
+    pub local_dir_name: LocalDirName,
+    pub include_wikis: bool,
+    pub only_changed: bool,
+    /// Sync the repositories `only_changed` would skip, still recording their state
+    pub force_sync: bool,
+    pub tolerate_rejected_refs: bool,
+    pub push_options: Vec<String>,
+    pub stream_listing: bool,
+    pub clone_mode: CloneMode,
+    pub retry: RetryPolicy,
+    pub repo_log_dir: Option<PathBuf>,
+    pub annotate_sync: bool,
+    /// Point the default branch of the destination to the default branch of the origin
+    pub set_default_branch: bool,
+    /// Copy the topics and the default branch of the origins to the destinations
+    pub sync_metadata: bool,
+    /// Push without force, reporting destinations with diverged refs instead of overwriting them
+    pub no_force: bool,
+    pub prune_protect: Vec<String>,
+    pub summary_format: SummaryFormat,
+    pub max_failures: Option<usize>,
+    pub min_success_rate: Option<f64>,
+    pub include_pr_refs: bool,
+    pub exclude_refs: Vec<String>,
+    /// Minimum number of repositories the provider has to list, 0 allows an empty listing
+    pub min_expected_repos: usize,
+    pub partial_clone: Option<PartialClone>,
+    pub shard: Option<Shard>,
+    pub shared: Option<SharedRepository>,
+    /// Permissions of the mirror directory
+    pub dir_mode: Option<u32>,
+    /// Run `git maintenance` on the local repositories after the push
+    pub maintenance: bool,
+    /// Tasks of `git maintenance run`, the needed tasks are run if empty
+    pub maintenance_tasks: Vec<MaintenanceTask>,
+    /// Object pool new clones borrow their objects from
+    pub alternates: Option<PathBuf>,
+    /// Stop using the object pool in existing local repositories
+    pub dissociate: bool,
+    /// Download the release assets of the listed projects
+    pub include_releases: bool,
+    /// Compression level (0-9) of the pushed packs
+    pub pack_compression: Option<u8>,
+    /// Wire protocol version (`protocol.version`) used for the remotes
+    pub git_protocol: Option<u8>,
+    /// URLs whose TLS certificates git doesn't verify, all if one is empty. The API requests
+    /// are always verified.
+    pub git_insecure: Vec<String>,
+    /// Additional lock preventing overlapping runs, independent of the mirror directory
+    pub lock_file: Option<PathBuf>,
+    /// Skip the remaining repositories once git reported this many transferred bytes
+    pub max_transfer: Option<u64>,
+    /// Key, known hosts and jump host(s) to connect to SSH origins and destinations with
+    pub ssh: SshOptions,
+    /// Measure the size of the local repositories after the sync
+    pub report_sizes: bool,
+    /// Minimum time between two writes of the partial reports during the run
+    pub report_interval: Duration,
+    /// Destination branch of the imports of mirrors with a `subtree_prefix`
+    pub subtree_branch: String,
+    /// Capacity of the queue between the streamed listing and the workers,
+    /// 4 times `worker_count` if `None`
+    pub queue_depth: Option<usize>,
+    /// Skip origins with more refs
+    pub max_refs: Option<usize>,
+    /// Skip projects with fewer stars, unknown counts aren't filtered
+    pub min_stars: Option<u64>,
+    /// Skip projects with fewer forks, unknown counts aren't filtered
+    pub min_forks: Option<u64>,
+    /// Only sync the repositories whose destination path matches one of these, all if empty
+    pub includes: Vec<RepoPattern>,
+    /// Skip the repositories whose destination path matches one of these
+    pub excludes: Vec<RepoPattern>,
+    /// Skip archived projects
+    pub skip_archived: bool,
+    /// Skip forked projects
+    pub skip_forks: bool,
+    /// Only fetch or only push
+    pub stage: Stage,
+    /// Verify the received objects and the local repository before pushing
+    pub fsck: bool,
+    /// Patterns of the branches pushed if neither the repository nor `refspec` sets a refspec
+    pub branches: Vec<String>,
+    /// Patterns of the tags pushed if neither the repository nor `refspec` sets a refspec
+    pub tags: Vec<String>,
+    /// Verify the signatures of the branch and tag tips with the keys of this GnuPG home
+    pub verify_signatures: Option<PathBuf>,
+    /// Fail instead of only warning about tips without a valid signature
+    pub require_signatures: bool,
+    /// Push the new history of the branches in batches of at most this many commits
+    pub push_batch: Option<usize>,
+    /// Write the commit-graph of the local repositories after syncing
+    pub write_commit_graph: bool,
+    /// Write the multi-pack-index of the local repositories after syncing
+    pub write_midx: bool,
+    /// Record the transfers and the fetch and push time of every job
+    pub transfer_stats: bool,
+    /// Shell command rewriting the local repository after fetching and before pushing
+    pub transform_hook: Option<String>,
+    /// Time budget of a sync job including its retries
+    pub repo_timeout: Option<Duration>,
+    /// Added to `repo_timeout` for every GiB of the repository
+    pub repo_timeout_per_gb: Option<Duration>,
+    /// Retries of the provider listing after transient API errors
+    pub list_retry: RetryPolicy,
+    /// JSON summary of a previous run to compare the results with
+    pub baseline_report: Option<PathBuf>,
+    /// Where to write the changes since the baseline as JSON
+    pub diff_report: Option<PathBuf>,
+    /// Publish an event per finished job and run
+    #[cfg(feature = "nats")]
+    pub events: Option<events::NatsPublisher>,
+    /// Record the run and its results
+    #[cfg(feature = "sqlite")]
+    pub history: Option<history::HistoryDb>,
+    /// Record the ref changes of every push
+    pub audit_log: Option<audit::AuditLog>,
+    /// Send a summary of the runs with failures
+    pub notify: Option<notify::Notifier>,
+    /// Report the progress of the jobs to a callback
+    pub progress: Option<Progress>,
+    /// Write bundles instead of pushing to the destinations
+    pub bundles: Option<Bundles>,
 }
 
 pub fn do_mirror(provider: Box<dyn Provider>, opts: &MirrorOptions) -> Result<()> {
-    let start_time = register_gauge_vec!(
-        "git_mirror_start_time",
-        "Start time of the sync as unix timestamp",
-        &["mirror"]
-    )
-    .unwrap();
-    let end_time = register_gauge_vec!(
-        "git_mirror_end_time",
-        "End time of the sync as unix timestamp",
-        &["mirror"]
-    )
-    .unwrap();
+    run(provider.as_ref(), opts, None).0
+}
+
+/// Run a complete sync of all repositories of the provider and summarize it. With `jobs` only
+/// these are synced, without listing the repositories again.
+fn run(
+    provider: &dyn Provider,
+    opts: &MirrorOptions,
+    jobs: Option<Vec<MirrorResult>>,
+) -> (Result<()>, Summary) {
+    let start = Instant::now();
+    let mut summary = Summary::default();
+    #[cfg(feature = "sqlite")]
+    if let Some(ref history) = opts.history {
+        history.run_started();
+    }
+
+    let result = run_mirror(provider, opts, jobs, &mut summary);
+
+    summary.finish(start.elapsed(), &result);
+    #[cfg(feature = "nats")]
+    if let Some(ref events) = opts.events {
+        events.run_finished(&summary);
+    }
+    #[cfg(feature = "sqlite")]
+    if let Some(ref history) = opts.history {
+        history.run_finished(&summary);
+    }
+    if opts.summary_format == SummaryFormat::Json {
+        println!(
+            "{}",
+            serde_json::to_string(&summary).expect("Unable to serialize summary")
+        );
+    }
+    // The report of the running instance is kept
+    let locked = matches!(result, Err(GitMirrorError::Locked(_)));
+    // The running instance notifies about its own run
+    if let (Some(ref notify), false) = (&opts.notify, locked) {
+        notify.run_finished(&provider.get_label(), &summary);
+    }
+    if let (Some(ref f), false) = (&opts.json_report, locked) {
+        if let Err(e) = try_write_json_report(f, &summary) {
+            error!("Unable to write JSON report {:?}: {}", f, e);
+        }
+    }
+    progress::report(&opts.progress, || {
+        ProgressEvent::Finished(Box::new(summary.clone()))
+    });
+    (result, summary)
+}
+
+fn run_mirror(
+    provider: &dyn Provider,
+    opts: &MirrorOptions,
+    jobs: Option<Vec<MirrorResult>>,
+    summary: &mut Summary,
+) -> Result<()> {
+    // Held until the end of the run, the kernel releases it if the process gets killed
+    let _lock_file = opts.lock_file.as_deref().map(lock).transpose()?;
+    // Only the listed jobs can be compared, pruned or cleaned up
+    let complete = jobs.is_none();
+    // Read before the sync, so an invalid baseline doesn't waste a run
+    let baseline = match opts.baseline_report {
+        Some(ref f) if complete => Some(load_baseline(f)?),
+        _ => None,
+    };
+
+    let metrics = SyncMetrics::get();
+    // A webhook run replaces the values of its jobs
+    if complete {
+        metrics.reset();
+    }
+    transfer::reset();
+    REF_COUNTS.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    *REPO_IDS.lock().unwrap_or_else(|e| e.into_inner()) = state::repo_ids(&opts.mirror_dir);
+    DEST_CLAIMS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clear();
 
     // Make sure the mirror directory exists
     trace!("Create mirror directory at {:?}", opts.mirror_dir);
@@ -281,68 +2746,506 @@ pub fn do_mirror(provider: Box<dyn Provider>, opts: &MirrorOptions) -> Result<()
         ))
     })?;
 
+    if let Some(mode) = opts.dir_mode {
+        set_dir_mode(&opts.mirror_dir, mode)?;
+    }
+
     // Check that only one instance is running against a mirror directory
-    let lockfile_path = opts.mirror_dir.join("git-mirror.lock");
-    let lockfile = fs::File::create(&lockfile_path).map_err(|e| {
-        GitMirrorError::GenericError(format!(
-            "Unable to open lockfile: {:?} ({})",
-            &lockfile_path, e
-        ))
-    })?;
+    let _lockfile = lock(&opts.mirror_dir.join("git-mirror.lock"))?;
 
-    lockfile.try_lock_exclusive().map_err(|e| {
-        GitMirrorError::GenericError(format!(
-            "Another instance is already running against the same mirror directory: {:?} ({})",
-            &opts.mirror_dir, e
-        ))
-    })?;
+    let label = provider.get_label();
+    metrics
+        .dry_run
+        .with_label_values(&[&label])
+        .set(if opts.dry_run { 1.0 } else { 0.0 });
+    summary.dry_run = opts.dry_run;
 
-    trace!("Aquired lockfile: {:?}", &lockfile);
+    // Number of listed repositories and of those in the shard
+    let (mut listed, mut selected) = (0, 0);
+    // Local repositories of all listed repositories, also outside of the shard
+    let mut listed_dirs = BTreeSet::new();
+    let (results, listing) = if let Some(v) = jobs {
+        (listed, selected) = (v.len(), v.len());
+        metrics
+            .start_time
+            .with_label_values(&[&label])
+            .set(OffsetDateTime::now_utc().unix_timestamp() as f64);
+        (run_sync_task(&v, provider, &label, opts, false), Ok(()))
+    } else if opts.stream_listing {
+        metrics
+            .start_time
+            .with_label_values(&[&label])
+            .set(OffsetDateTime::now_utc().unix_timestamp() as f64);
 
-    // Get the list of repos to sync from gitlabsss
-    let v = provider.get_mirror_repos().map_err(|e| -> GitMirrorError {
-        GitMirrorError::GenericError(format!("Unable to get mirror repos ({e})"))
-    })?;
+        // Start mirroring while the provider is still listing the repos. The listing
+        // blocks on a full queue, so it doesn't get ahead of the workers.
+        let depth = opts.queue_depth.unwrap_or(4 * opts.worker_count.max(1));
+        let (tx, rx) = mpsc::sync_channel(depth);
+        let queue_depth = metrics.queue_depth.with_label_values(&[&label]);
+        let label = &label;
+        let mut jobs = Vec::new();
+        let (results, listing) = thread::scope(|s| {
+            let sync = s.spawn(move || run_sync_stream(rx, provider, label, opts));
+            // Listing again after the first repository would sync repositories twice
+            let started = Cell::new(false);
+            let listing = opts.list_retry.retry_if(
+                "Listing of the repositories",
+                || {
+                    provider.stream_mirror_repos(&mut |m| {
+                        started.set(true);
+                        let m = apply_overrides(&opts.overrides, m);
+                        listed += 1;
+                        if let (true, Ok(x)) = (opts.prune, &m) {
+                            listed_dirs.extend(local_dirs(x, opts));
+                        }
+                        if !in_shard(&m, opts) {
+                            return;
+                        }
+                        selected += 1;
+                        for m in prepare_mirror(m, opts) {
+                            jobs.extend(m.as_ref().ok().cloned());
+                            queue_depth.inc();
+                            tx.send(m).expect("Sync jobs stopped unexpectedly");
+                        }
+                    })
+                },
+                |e| !started.get() && transient_api_error(e),
+            );
+            drop(tx);
+            (sync.join().expect("Sync jobs panicked"), listing)
+        });
+        *LISTED.lock().unwrap_or_else(|e| e.into_inner()) = jobs;
+        // Only checked after the sync, as the jobs already started during the listing
+        let listing = listing.and_then(|_| check_listed(listed, opts));
+        (results, listing)
+    } else {
+        // Get the list of repos to sync from the provider
+        let v: Vec<MirrorResult> = list_repos(provider, opts).map_err(|e| -> GitMirrorError {
+            GitMirrorError::GenericError(format!("Unable to get mirror repos ({e})"))
+        })?;
+        check_listed(v.len(), opts).map_err(GitMirrorError::GenericError)?;
+        listed = v.len();
+        if opts.prune {
+            listed_dirs.extend(v.iter().flatten().flat_map(|x| local_dirs(x, opts)));
+        }
+        let v: Vec<MirrorResult> = v.into_iter().filter(|m| in_shard(m, opts)).collect();
+        selected = v.len();
+        let v: Vec<MirrorResult> = v
+            .into_iter()
+            .flat_map(|m| prepare_mirror(m, opts))
+            .collect();
+        *LISTED.lock().unwrap_or_else(|e| e.into_inner()) = v.iter().flatten().cloned().collect();
 
-    start_time
-        .with_label_values(&[&provider.get_label()])
-        .set(OffsetDateTime::now_utc().unix_timestamp() as f64);
+        metrics
+            .start_time
+            .with_label_values(&[&label])
+            .set(OffsetDateTime::now_utc().unix_timestamp() as f64);
+
+        (run_sync_task(&v, provider, &label, opts, true), Ok(()))
+    };
+    let results = merge_results(results, complete);
+    if !complete {
+        metrics.totals(&label, &results);
+    }
+    let report = finish_sync_task(results);
 
-    let ts = run_sync_task(&v, &provider.get_label(), opts);
+    if opts.max_transfer.is_some() {
+        let transferred = transfer::transferred();
+        println!("TRANSFERRED: {}", transfer::format_bytes(transferred));
+        summary.transferred_bytes = Some(transferred);
+    }
+
+    if let Some(shard) = opts.shard {
+        info!(
+            "Shard {}/{}: {} of {} repositories",
+            shard.index, shard.total, selected, listed
+        );
+        summary.shard = Some(ShardSummary {
+            index: shard.index,
+            total: shard.total,
+            listed,
+            repos: selected,
+        });
+    }
 
-    end_time
-        .with_label_values(&[&provider.get_label()])
+    metrics
+        .end_time
+        .with_label_values(&[&label])
         .set(OffsetDateTime::now_utc().unix_timestamp() as f64);
 
+    if opts.cleanup == Cleanup::Stale && !opts.dry_run && complete {
+        remove_stale(opts);
+    }
+
+    if opts.prune && complete {
+        // An incomplete listing would prune the repositories that are still there
+        match listing {
+            Ok(()) => prune_removed(provider, &listed_dirs, opts),
+            Err(_) => warn!("Not pruning, the repositories couldn't be listed completely"),
+        }
+    }
+
     match opts.metrics_file {
         Some(ref f) => write_metrics(f),
         None => trace!("Skipping metrics file creation"),
     };
+    if let Some(ref url) = opts.pushgateway_url {
+        match try_push_metrics(url, &opts.pushgateway_job, &label) {
+            Ok(()) => info!("Pushed the metrics to {}", url),
+            Err(e) => warn!("Unable to push the metrics to {} ({})", url, e),
+        }
+    }
 
     // Check if any tasks failed
+    let mut ts = report.suite;
     let error_count = ts.errors() + ts.failures();
+    summary.count(&ts);
+    summary.retries = report.jobs.iter().map(|j| u64::from(j.retries)).sum();
+    summary.jobs = report.jobs;
+    summary.failure_kinds = report.failure_kinds;
+    summary.skip_reasons = report.skip_reasons;
+    if let Some(baseline) = baseline {
+        summary.statuses(&ts);
+        let diff = ReportDiff::new(
+            &baseline,
+            summary.repos.as_ref().unwrap_or(&BTreeMap::new()),
+        );
+        println!(
+            "DIFF: {} newly failed, {} newly succeeded, {} disappeared",
+            diff.newly_failed.len(),
+            diff.newly_succeeded.len(),
+            diff.disappeared.len()
+        );
+        for (what, names) in [
+            ("NEWLY FAILED", &diff.newly_failed),
+            ("NEWLY SUCCEEDED", &diff.newly_succeeded),
+            ("DISAPPEARED", &diff.disappeared),
+        ] {
+            for name in names {
+                println!("{what}: {name}");
+            }
+        }
+        if let Some(ref f) = opts.diff_report {
+            write_atomic(f, |file| {
+                serde_json::to_writer_pretty(&mut *file, &diff)?;
+                writeln!(file)
+            })
+            .map_err(|e| {
+                GitMirrorError::GenericError(format!("Unable to write diff report {f:?} ({e})"))
+            })?;
+        }
+        summary.diff = Some(diff);
+    }
+    if opts.lfs_failure == LfsFailure::Warn {
+        let mut failures = report.lfs_failures;
+        failures.sort();
+        summary.lfs_failures = Some(failures);
+    }
+    if opts.report_sizes {
+        let repos = report.sizes.len();
+        summary.sizes(report.sizes);
+        let total = summary.total_size_bytes.unwrap_or_default();
+        println!(
+            "SIZE: {} in {} repositories",
+            transfer::format_bytes(total),
+            repos
+        );
+    }
+    if opts.transfer_stats {
+        let repos = report.transfers.len();
+        let total = report
+            .transfers
+            .iter()
+            .fold(TransferStats::default(), |mut total, t| {
+                total.fetch_secs += t.stats.fetch_secs;
+                total.push_secs += t.stats.push_secs;
+                total.objects_received += t.stats.objects_received;
+                total.bytes_received += t.stats.bytes_received;
+                total.objects_sent += t.stats.objects_sent;
+                total.bytes_sent += t.stats.bytes_sent;
+                total
+            });
+        println!(
+            "TRANSFER: received {} ({} objects), sent {} ({} objects), {:.1}s fetching, {:.1}s pushing in {} repositories",
+            transfer::format_bytes(total.bytes_received),
+            total.objects_received,
+            transfer::format_bytes(total.bytes_sent),
+            total.objects_sent,
+            total.fetch_secs,
+            total.push_secs,
+            repos
+        );
+        summary.transfers(report.transfers);
+    }
+
+    if opts.dry_run {
+        ts.system_out = Some("Dry run, no git commands were run".to_string());
+    }
 
     match opts.junit_file {
         Some(ref f) => write_junit_report(f, ts),
         None => trace!("Skipping junit report"),
     }
 
-    if opts.fail_on_sync_error && error_count > 0 {
+    // A failed listing while streaming aborts the run after the already listed repos
+    listing.map_err(|e| {
+        GitMirrorError::GenericError(format!("Unable to get all mirror repos ({e})"))
+    })?;
+
+    if opts.max_failures.is_some() || opts.min_success_rate.is_some() {
+        let too_many = opts.max_failures.is_some_and(|max| error_count > max);
+        let rate_too_low = opts
+            .min_success_rate
+            .is_some_and(|min| summary.success_rate < min);
+        if too_many || rate_too_low {
+            error!(
+                "{} sync tasks failed, success rate {:.3} is beyond the threshold",
+                error_count, summary.success_rate
+            );
+            return Err(GitMirrorError::SyncError(error_count));
+        }
+        if error_count > 0 {
+            warn!(
+                "{} sync tasks failed, success rate {:.3} is within the threshold",
+                error_count, summary.success_rate
+            );
+        }
+        Ok(())
+    } else if opts.fail_on_sync_error && error_count > 0 {
         Err(GitMirrorError::SyncError(error_count))
     } else {
         Ok(())
     }
 }
 
-fn write_metrics(f: &Path) {
-    let mut file = File::create(f).unwrap();
+/// List the repositories, retrying transient API errors
+fn list_repos(
+    provider: &dyn Provider,
+    opts: &MirrorOptions,
+) -> std::result::Result<Vec<MirrorResult>, String> {
+    opts.list_retry
+        .retry_if(
+            "Listing of the repositories",
+            || provider.get_mirror_repos(),
+            |e| transient_api_error(e),
+        )
+        .map(|v| {
+            v.into_iter()
+                .map(|m| apply_overrides(&opts.overrides, m))
+                .collect()
+        })
+}
+
+/// Check the descriptions of all repositories of the provider without running any git commands
+pub fn validate_config(provider: Box<dyn Provider>, opts: &MirrorOptions) -> Result<()> {
+    let mirrors = list_repos(provider.as_ref(), opts)
+        .map_err(|e| GitMirrorError::GenericError(format!("Unable to get mirror repos ({e})")))?;
+
+    let (mut valid, mut skipped, mut invalid) = (0, 0, 0);
+    for m in mirrors {
+        match m {
+            Ok(m) => {
+                let destination = resolve_destination(opts, &m.destination);
+                if let Some(Err(e)) = m.subtree_prefix.as_deref().map(subtree_prefix) {
+                    println!("INVALID {}: {}", m.origin, e);
+                    invalid += 1;
+                    continue;
+                }
+                match m.visibility {
+                    Some(v) => println!("VALID {} -> {} ({})", m.origin, destination, v),
+                    None => println!("VALID {} -> {}", m.origin, destination),
+                }
+                valid += 1;
+            }
+            Err(MirrorError::Skip(url)) => {
+                println!("SKIP {url} (disabled by repo config)");
+                skipped += 1;
+            }
+            Err(MirrorError::Missing(url)) => {
+                println!("MISSING {url}");
+                invalid += 1;
+            }
+            Err(MirrorError::Description(url, e)) => {
+                println!("INVALID {url}: {e}");
+                invalid += 1;
+            }
+        }
+    }
+    println!("DONE: {valid} valid, {skipped} skipped, {invalid} invalid or missing");
+
+    if invalid > 0 {
+        Err(GitMirrorError::GenericError(format!(
+            "{invalid} repositories have an invalid or missing description"
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// Take an exclusive lock on the file at `path`, held until the file is dropped
+fn lock(path: &Path) -> Result<File> {
+    let lockfile = File::create(path).map_err(|e| {
+        GitMirrorError::GenericError(format!("Unable to open lockfile: {path:?} ({e})"))
+    })?;
+
+    lockfile
+        .try_lock_exclusive()
+        .map_err(|e| GitMirrorError::Locked(format!("{path:?} ({e})")))?;
+
+    trace!("Aquired lockfile: {:?}", path);
+    Ok(lockfile)
+}
+
+#[cfg(unix)]
+fn set_dir_mode(dir: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(dir, fs::Permissions::from_mode(mode)).map_err(|e| {
+        GitMirrorError::GenericError(format!(
+            "Unable to set mode {mode:o} of mirror dir: {dir:?} ({e})"
+        ))
+    })
+}
+
+#[cfg(not(unix))]
+fn set_dir_mode(_dir: &Path, _mode: u32) -> Result<()> {
+    warn!("--dir-mode is only supported on Unix");
+    Ok(())
+}
+
+/// Check that the provider listed enough repositories, an empty listing is usually caused by
+/// an expired token or a wrong group rather than an empty group
+fn check_listed(listed: usize, opts: &MirrorOptions) -> std::result::Result<(), String> {
+    if listed < opts.min_expected_repos {
+        Err(format!(
+            "Provider listed {} repositories, expected at least {} (use --allow-empty if this is expected)",
+            listed, opts.min_expected_repos
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Write to a temporary file next to `f` and rename it, so readers never see a half written file
+fn write_atomic(f: &Path, write: impl FnOnce(&mut File) -> io::Result<()>) -> io::Result<()> {
+    let mut tmp = f.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let mut file = File::create(&tmp)?;
+    write(&mut file)?;
+    fs::rename(&tmp, f)
+}
+
+fn try_write_metrics(f: &Path) -> io::Result<()> {
+    write_atomic(f, |file| {
+        let encoder = TextEncoder::new();
+        let metric_familys = prometheus::gather();
+        encoder
+            .encode(&metric_familys, file)
+            .map_err(io::Error::other)
+    })
+}
+
+/// Replace the metrics of the job and mirror on the Pushgateway with the ones of this run
+fn try_push_metrics(url: &str, job: &str, label: &str) -> std::result::Result<(), String> {
     let encoder = TextEncoder::new();
-    let metric_familys = prometheus::gather();
-    encoder.encode(&metric_familys, &mut file).unwrap();
+    let mut body = Vec::new();
+    encoder
+        .encode(&prometheus::gather(), &mut body)
+        .map_err(|e| e.to_string())?;
+    // The grouping key is base64 encoded, the label contains slashes
+    let url = format!(
+        "{}/metrics/job@base64/{}/mirror@base64/{}",
+        url.trim_end_matches('/'),
+        URL_SAFE.encode(job),
+        URL_SAFE.encode(label)
+    );
+    let client = reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .build()
+        .map_err(|e| e.to_string())?;
+    let res = client
+        .put(url)
+        .header(reqwest::header::CONTENT_TYPE, encoder.format_type())
+        .body(body)
+        .send()
+        .map_err(|e| e.without_url().to_string())?;
+    if !res.status().is_success() {
+        return Err(format!("received status {}", res.status()));
+    }
+    Ok(())
 }
 
-fn write_junit_report(f: &Path, ts: TestSuite) {
+fn try_write_junit_report(f: &Path, ts: TestSuite) -> io::Result<()> {
     let report = ReportBuilder::default().add_testsuite(ts).build();
-    let mut file = File::create(f).unwrap();
-    report.write_xml(&mut file).unwrap();
+    write_atomic(f, |file| report.write_xml(file).map_err(io::Error::other))
+}
+
+fn try_write_json_report(f: &Path, summary: &Summary) -> io::Result<()> {
+    let report = JsonReport {
+        summary,
+        projects: &summary.jobs,
+    };
+    write_atomic(f, |file| {
+        serde_json::to_writer_pretty(&mut *file, &report)?;
+        writeln!(file)
+    })
+}
+
+fn write_metrics(f: &Path) {
+    try_write_metrics(f).unwrap();
+}
+
+fn write_junit_report(f: &Path, ts: TestSuite) {
+    try_write_junit_report(f, ts).unwrap();
+}
+
+/// Rewrites the report files with the jobs finished so far during the run, at most every
+/// `--report-interval`, so a run that gets killed still leaves usable reports behind
+struct PartialReports<'a> {
+    opts: &'a MirrorOptions,
+    /// Finished jobs and the time of the last write
+    state: Mutex<(Vec<TestCase>, Instant)>,
+}
+
+impl PartialReports<'_> {
+    fn new(opts: &MirrorOptions) -> PartialReports<'_> {
+        PartialReports {
+            opts,
+            state: Mutex::new((Vec::new(), Instant::now())),
+        }
+    }
+
+    /// Record a finished job and write the reports if they are due
+    fn add(&self, tc: &TestCase) {
+        if self.opts.junit_file.is_none() && self.opts.metrics_file.is_none() {
+            return;
+        }
+        let mut state = self.state.lock().expect("Partial reports poisoned");
+        let (results, last_write) = &mut *state;
+        results.push(tc.clone());
+        if last_write.elapsed() < self.opts.report_interval {
+            return;
+        }
+        *last_write = Instant::now();
+
+        trace!("Write partial reports of {} jobs", results.len());
+        if let Some(ref f) = self.opts.metrics_file {
+            if let Err(e) = try_write_metrics(f) {
+                warn!("Unable to write partial metrics file {:?}: {}", f, e);
+            }
+        }
+        if let Some(ref f) = self.opts.junit_file {
+            let mut ts = TestSuiteBuilder::new("Sync Job")
+                .add_testcases(results.iter().cloned())
+                .build();
+            ts.system_out = Some(format!(
+                "Partial report, {} jobs finished so far",
+                results.len()
+            ));
+            if let Err(e) = try_write_junit_report(f, ts) {
+                warn!("Unable to write partial junit report {:?}: {}", f, e);
+            }
+        }
+    }
 }
@@ -5,21 +5,53 @@
  */
 
 use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
 use std::env;
+use std::ffi::OsString;
 
 // Used for error and debug logging
 use env_logger::Env;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 
 // Used to do command line parsing
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
 use clap::{crate_name, crate_version};
-use clap::{ArgAction, Parser, ValueEnum};
-use std::path::PathBuf;
+use clap::{ArgAction, ArgMatches, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 // Load the real functionality
-use git_mirror::do_mirror;
-use git_mirror::provider::{GitHub, GitLab, Provider};
-use git_mirror::MirrorOptions;
+use git_mirror::audit::AuditLog;
+use git_mirror::bundle::{Bundles, S3Bucket, Target};
+use git_mirror::config::{ConfigFile, ConfigOptions, RepoOverride};
+use git_mirror::daemon::run_daemon;
+use git_mirror::error::GitMirrorError;
+#[cfg(feature = "nats")]
+use git_mirror::events::{NatsPublisher, NatsUrl};
+use git_mirror::filter::RepoPattern;
+#[cfg(feature = "sqlite")]
+use git_mirror::history::HistoryDb;
+use git_mirror::notify::{Notifier, Smtp};
+use git_mirror::provider::{
+    ApiOptions, Bitbucket, DestProvider, Destination, ExternalCommand, GitHub, GitHubApp, GitLab,
+    Http2, ListingCache, LocalSource, NamespaceType, Provider, PruneRemote, Throttle, TopicFilter,
+    TopicMatch, Transport, Visibility,
+};
+use git_mirror::refmap::{parse_branch_pattern, parse_tag_pattern, RefMapping};
+use git_mirror::retry::{Jitter, RetryPolicy};
+use git_mirror::rewrite::{DestCase, DestRewrite, Rename};
+use git_mirror::shard::Shard;
+use git_mirror::summary::SummaryFormat;
+use git_mirror::{
+    do_mirror, parse_insecure_url, parse_ssh_jump, validate_config, GitBackend, SshOptions,
+};
+use git_mirror::{
+    Cleanup, CloneMode, LfsFailure, LocalDirName, MaintenanceTask, MirrorOptions, PartialClone,
+    SharedRepository, Stage,
+};
+use reqwest::header::{HeaderName, HeaderValue};
 
 use std::process::exit;
 
@@ -28,6 +60,16 @@ use std::process::exit;
 enum Providers {
     GitLab,
     GitHub,
+    /// Bitbucket Cloud or Bitbucket Data Center
+    Bitbucket,
+    External,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+#[value(rename_all = "verbatim")]
+enum DestProviders {
+    GitLab,
+    GitHub,
 }
 
 /// command line options
@@ -44,6 +86,10 @@ struct Opt {
     )]
     provider: Providers,
 
+    /// Command printing the repositories to mirror as JSON lines, used by the External provider
+    #[arg(long, required_if_eq("provider", "External"))]
+    provider_command: Option<String>,
+
     /// URL of the instance to get repositories from
     #[arg(
         long = "url",
@@ -51,27 +97,143 @@ struct Opt {
         default_value_ifs([
             ("provider", "GitLab", Some("https://gitlab.com")),
             ("provider", "GitHub", Some("https://api.github.com")),
+            ("provider", "Bitbucket", Some("https://api.bitbucket.org/2.0")),
         ])
     )]
-    url: String,
+    url: Option<String>,
 
     /// Name of the group to check for repositories to sync
-    #[arg(long = "group", short = 'g')]
-    group: String,
+    #[arg(
+        long = "group",
+        short = 'g',
+        required_unless_present_any = ["provider_command", "local_source"]
+    )]
+    group: Option<String>,
+
+    /// Mirror the bare repositories (`*.git` directories) found below this directory
+    /// instead of asking the provider. Requires `--dest-template`.
+    #[arg(long, requires = "dest_template")]
+    local_source: Option<PathBuf>,
+
+    /// Destination of the repositories found with `--local-source`. `{path}` is replaced by the
+    /// relative path of the repository and `{name}` by its name, both without `.git`.
+    #[arg(long)]
+    dest_template: Option<String>,
+
+    /// Mirror all repositories of `--group` to this provider, creating them below `--dest-group`
+    /// if they don't exist yet, instead of reading the origins from the descriptions
+    #[arg(
+        long,
+        ignore_case = true,
+        value_enum,
+        requires = "dest_group",
+        conflicts_with_all = ["local_source", "include_releases"]
+    )]
+    dest_provider: Option<DestProviders>,
+
+    /// URL of the destination instance for `--dest-provider`
+    #[arg(
+        long,
+        requires = "dest_provider",
+        default_value_ifs([
+            ("dest_provider", "GitLab", Some("https://gitlab.com")),
+            ("dest_provider", "GitHub", Some("https://api.github.com")),
+        ])
+    )]
+    dest_url: Option<String>,
+
+    /// Group or organization the repositories are created in with `--dest-provider`.
+    /// Repositories of subgroups keep their path below it.
+    #[arg(long, requires = "dest_provider")]
+    dest_group: Option<String>,
+
+    /// Private token or Personal access token for the API of the destination instance, also
+    /// used to push over http(s)
+    #[arg(long, env = "DEST_PRIVATE_TOKEN")]
+    dest_private_token: Option<String>,
 
     /// Directory where the local clones are stored
     #[arg(long = "mirror-dir", short = 'm', default_value = "./mirror-dir")]
     mirror_dir: PathBuf,
 
+    /// Where the synced repositories go: `push` to the destinations, `bundle` to write git
+    /// bundles to `--bundle-dir` or `s3://bucket/prefix` to upload the bundles to S3. The
+    /// bundles are named after the path of the destination.
+    #[arg(long, value_name = "TARGET", default_value = "push", value_parser = Target::parse)]
+    target: Target,
+
+    /// Directory the bundles are written to, required by `--target bundle`. With an S3 target
+    /// the uploaded bundles are also kept here.
+    #[arg(long, value_name = "DIR")]
+    bundle_dir: Option<PathBuf>,
+
+    /// Endpoint of an S3 compatible API, e.g. `http://minio:9000`. Defaults to AWS in
+    /// `--s3-region`.
+    #[arg(long, value_name = "URL", env = "AWS_ENDPOINT_URL")]
+    s3_endpoint: Option<String>,
+
+    /// Region of the S3 bucket
+    #[arg(
+        long,
+        value_name = "REGION",
+        env = "AWS_REGION",
+        default_value = "us-east-1"
+    )]
+    s3_region: String,
+
+    /// Access key id for the S3 API
+    #[arg(long, env = "AWS_ACCESS_KEY_ID")]
+    s3_access_key_id: Option<String>,
+
+    /// Secret access key for the S3 API
+    #[arg(long, env = "AWS_SECRET_ACCESS_KEY")]
+    s3_secret_access_key: Option<String>,
+
+    /// Session token of temporary credentials for the S3 API
+    #[arg(long, env = "AWS_SESSION_TOKEN")]
+    s3_session_token: Option<String>,
+
     /// Verbosity level
     #[arg(short, long, action = ArgAction::Count)]
     verbose: u8,
 
-    /// Use http(s) instead of SSH to sync the GitLab repository
+    /// Use http(s) instead of SSH to sync the GitLab repository, same as `--dest-transport http`
     #[arg(long)]
     http: bool,
 
-    /// Only print what to do without actually running any git commands
+    /// Convert the origins given in the project descriptions to this transport, e.g. `http` to
+    /// fetch through a proxy. By default the origins are used as written. Projects can override
+    /// it with `origin_transport` in their description.
+    #[arg(long, value_enum)]
+    origin_transport: Option<Transport>,
+
+    /// Push to the SSH or the http(s) URL of the projects (default `ssh`). Projects can override
+    /// it with `dest_transport` in their description.
+    #[arg(long, value_enum, conflicts_with = "http")]
+    dest_transport: Option<Transport>,
+
+    /// Reach SSH remotes through this jump host (an ssh `ProxyCommand`), in the form
+    /// `[user@]host[:port]`. Ignored with `--http`.
+    #[arg(long, value_name = "HOST", value_parser = parse_ssh_jump)]
+    ssh_jump: Option<String>,
+
+    /// Authenticate to SSH remotes only with this private key instead of the keys of the SSH
+    /// agent and config
+    #[arg(long, value_name = "PATH")]
+    ssh_key: Option<PathBuf>,
+
+    /// Check the host keys of SSH remotes against this file instead of `~/.ssh/known_hosts`
+    #[arg(long, value_name = "PATH")]
+    ssh_known_hosts: Option<PathBuf>,
+
+    /// `true` only connects to SSH remotes with a known host key, `false` accepts any host key
+    /// (without storing it in `~/.ssh/known_hosts`). By default SSH asks, which fails without
+    /// terminal.
+    #[arg(long, value_name = "BOOL")]
+    ssh_strict_host_key_checking: Option<bool>,
+
+    /// Only print what to do without actually running any git commands. The report
+    /// and metric files are still written, with the simulated results.
     #[arg(long)]
     dry_run: bool,
 
@@ -79,63 +241,1129 @@ struct Opt {
     #[arg(short = 'c', long, default_value = "1")]
     worker_count: usize,
 
+    /// Number of concurrent API requests while listing the repositories (e.g. GitLab subgroups),
+    /// independent of `--worker-count`
+    #[arg(long, default_value = "2")]
+    list_concurrency: usize,
+
+    /// Levels of GitLab subgroups to list below the group: `0` only lists the group itself, `1`
+    /// adds its direct subgroups. All subgroups are listed by default.
+    #[arg(long, value_name = "N")]
+    recursive_depth: Option<usize>,
+
+    /// Whether the GitLab `--group` is a group (with subgroups) or a user with personal projects.
+    /// `auto` uses the group if it exists.
+    #[arg(long, value_enum, default_value_t = NamespaceType::Auto)]
+    namespace_type: NamespaceType,
+
     /// Location where to store metrics for consumption by
     /// Prometheus node exporter's text file colloctor
     #[arg(long)]
     metric_file: Option<PathBuf>,
 
+    /// Push the metrics to this Prometheus Pushgateway after every run, e.g.
+    /// `http://pushgateway:9091`
+    #[arg(long, value_name = "URL")]
+    pushgateway_url: Option<String>,
+
+    /// Job the metrics are pushed as, they are also grouped by the `mirror` label
+    #[arg(
+        long,
+        value_name = "JOB",
+        default_value = "git-mirror",
+        requires = "pushgateway_url"
+    )]
+    pushgateway_job: String,
+
     /// Location where to store the Junit XML report
     #[arg(long)]
     junit_report: Option<PathBuf>,
 
+    /// Location where to store the JSON report, with the summary of the run and the result of
+    /// every job including the number of commits fetched and pushed
+    #[arg(long)]
+    json_report: Option<PathBuf>,
+
     /// Git executable to use
     #[arg(long, default_value = "git")]
     git_executable: String,
 
-    /// Private token or Personal access token to access the GitLab or GitHub API
+    /// Run the clones, fetches, pushes and listings of the remote refs with the git command line
+    /// or in-process with libgit2 (needs the `libgit2` feature). Everything else, e.g. the
+    /// maintenance and LFS, still runs the git executable.
+    #[arg(long, default_value = "cli", value_enum)]
+    git_backend: GitBackend,
+
+    /// Private token or Personal access token to access the GitLab or GitHub API, the access
+    /// token or with `--bitbucket-user` the app password for the Bitbucket API
     #[arg(long, env = "PRIVATE_TOKEN")]
     private_token: Option<String>,
 
+    /// User of the Bitbucket app password given as `--private-token`
+    #[arg(long, env = "BITBUCKET_USER")]
+    bitbucket_user: Option<String>,
+
+    /// Authenticate to the GitHub API as the installation of this GitHub App instead of with
+    /// `--private-token`. The installation tokens are also used to push to http(s) destinations
+    /// and are renewed before they expire.
+    #[arg(long, requires_all = ["github_installation_id", "github_app_key"])]
+    github_app_id: Option<u64>,
+
+    /// Installation of the GitHub App in the organization, see `--github-app-id`
+    #[arg(long, requires = "github_app_id")]
+    github_installation_id: Option<u64>,
+
+    /// Private key (PEM) of the GitHub App, see `--github-app-id`
+    #[arg(long, requires = "github_app_id")]
+    github_app_key: Option<PathBuf>,
+
+    /// User-Agent of the API requests [default: git-mirror/<version>]
+    #[arg(long)]
+    user_agent: Option<String>,
+
+    /// Additional header sent with every API request, in the form <name>=<value>. Can be repeated.
+    #[arg(long = "api-header", value_parser = ApiOptions::parse_header)]
+    api_headers: Vec<(HeaderName, HeaderValue)>,
+
+    /// Maximum number of idle connections kept open per API host [default: unlimited]
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    api_pool_size: Option<u64>,
+
+    /// Use HTTP/2 for the API requests. `auto` uses it if the server offers it, `on` also for
+    /// http:// URLs.
+    #[arg(long, value_enum, default_value_t = Http2::Auto)]
+    api_http2: Http2,
+
+    /// Maximum number of API requests per second, shared by all workers (e.g. `0.5` for one
+    /// request every two seconds) [default: unlimited]
+    #[arg(long, value_parser = parse_rps)]
+    max_api_rps: Option<f64>,
+
+    /// File to cache the listing responses of the API in. The next listings send their ETags and
+    /// reuse the cached pages the server reports as unchanged.
+    #[arg(long, conflicts_with = "local_source")]
+    listing_cache: Option<PathBuf>,
+
+    /// Only mirror repositories with this topic (GitHub) or topic/tag (GitLab). Can be repeated,
+    /// repositories without topics are excluded.
+    #[arg(long = "topic", conflicts_with = "local_source")]
+    topics: Vec<String>,
+
+    /// Only mirror repositories with this visibility
+    #[arg(long, value_enum, default_value_t = Visibility::All, conflicts_with = "local_source")]
+    visibility: Visibility,
+
+    /// Whether repositories need any or all of the `--topic`s
+    #[arg(long, value_enum, default_value_t = TopicMatch::Any, requires = "topics")]
+    topic_match: TopicMatch,
+
     /// Default refspec used to mirror repositories, can be overridden per project
     #[arg(long)]
     refspec: Option<Vec<String>>,
 
+    /// Only push the branches matching the pattern (e.g. `main` or `release/*`), instead of all refs.
+    /// Can be repeated, overridden by the `branches`, `tags` or `refspec` of a project.
+    #[arg(
+        long = "branch",
+        visible_alias = "mirror-branches",
+        value_name = "PATTERN",
+        value_parser = parse_branch_pattern,
+        conflicts_with_all = ["refspec", "branch_refspec", "tag_refspec", "notes_refspec"]
+    )]
+    branches: Vec<String>,
+
+    /// Only push the tags matching the pattern (e.g. `v*`), instead of all refs. Can be repeated
+    /// and combined with `--branch`, overridden by the `branches`, `tags` or `refspec` of a project.
+    #[arg(
+        long = "mirror-tags",
+        value_name = "PATTERN",
+        value_parser = parse_tag_pattern,
+        conflicts_with_all = ["refspec", "branch_refspec", "tag_refspec", "notes_refspec"]
+    )]
+    tags: Vec<String>,
+
+    /// Push the branches (`refs/heads/*`) to this destination pattern, e.g. `refs/upstream-heads/*`.
+    /// Together with `--tag-refspec` and `--notes-refspec` this replaces the mirror push,
+    /// the ref types without destination are pushed 1:1 and other refs not at all.
+    #[arg(long, value_name = "DEST", value_parser = RefMapping::parse_dest, conflicts_with = "refspec")]
+    branch_refspec: Option<String>,
+
+    /// Push the tags (`refs/tags/*`) to this destination pattern, e.g. `refs/upstream-tags/*`
+    #[arg(long, value_name = "DEST", value_parser = RefMapping::parse_dest, conflicts_with = "refspec")]
+    tag_refspec: Option<String>,
+
+    /// Push the notes (`refs/notes/*`) to this destination pattern, e.g. `refs/upstream-notes/*`
+    #[arg(long, value_name = "DEST", value_parser = RefMapping::parse_dest, conflicts_with = "refspec")]
+    notes_refspec: Option<String>,
+
     /// Remove the local working repository after pushing. This requires a full re-clone on the next run.
-    #[arg(long)]
+    /// Same as `--cleanup always`.
+    #[arg(long, conflicts_with = "cleanup")]
     remove_workrepo: bool,
 
+    /// Remove the local repositories: `always` after each sync, `stale` at the end of the run
+    /// if they weren't synced completely within `--cleanup-after`
+    #[arg(long, value_enum, default_value_t = Cleanup::Never)]
+    cleanup: Cleanup,
+
+    /// Age of the last complete sync after which `--cleanup stale` removes a local repository
+    #[arg(long, default_value = "30days", value_parser = humantime::parse_duration)]
+    cleanup_after: Duration,
+
+    /// Remove the local repositories of the projects that are no longer listed by the provider,
+    /// e.g. deleted or moved out of the group, at the end of the run
+    #[arg(long)]
+    prune: bool,
+
+    /// Also archive or delete the destinations of the removed projects whose origin is gone,
+    /// before their local repositories are removed. Needs `--dest-provider`.
+    #[arg(long, value_enum, requires_all = ["prune", "dest_provider"])]
+    prune_remote: Option<PruneRemote>,
+
+    /// Fetch and maintain the repositories in this directory, e.g. on fast local storage, and move
+    /// them to the mirror directory after the sync
+    #[arg(long, value_name = "DIR")]
+    work_tmp: Option<PathBuf>,
+
+    /// Only run one phase of the sync: `fetch` updates and verifies (`git fsck`) the local
+    /// repositories, `push` pushes the local repositories without fetching them again.
+    #[arg(long, value_enum, default_value_t = Stage::All)]
+    stage: Stage,
+
+    /// Check the objects while fetching (`transfer.fsckObjects`) and run `git fsck` before
+    /// pushing. A corrupt repository fails and isn't pushed.
+    #[arg(long)]
+    fsck: bool,
+
+    /// Push the new history of every branch in batches of at most this many commits (along the
+    /// first parents) before the actual push, for destinations with a pack size limit. Without,
+    /// a push exceeding the limit is retried in batches of 1000 commits.
+    #[arg(long, value_name = "COMMITS", value_parser = clap::value_parser!(u64).range(1..))]
+    push_batch: Option<u64>,
+
+    /// Write the commit-graph of the local repository (`git commit-graph write --reachable`)
+    /// after every sync, after `--maintenance`. Needs git 2.19 or newer, failures are warnings.
+    #[arg(long)]
+    write_commit_graph: bool,
+
+    /// Write the multi-pack-index of the local repository (`git multi-pack-index write`) after
+    /// every sync, after `--maintenance`. Needs git 2.21 or newer, failures are warnings.
+    #[arg(long)]
+    write_midx: bool,
+
+    /// Record the objects and bytes transferred and the time of fetching and pushing for every
+    /// repository, printed as `TRANSFER` line, in the JSON summary as `repo_transfers` and as
+    /// metrics
+    #[arg(long)]
+    transfer_stats: bool,
+
+    /// Shell command run in the local repository after fetching and before pushing, e.g. to
+    /// rewrite its history. The path of the repository is passed as `GIT_MIRROR_REPO`, the
+    /// origin and destination as `GIT_MIRROR_ORIGIN` and `GIT_MIRROR_DESTINATION`. If it fails,
+    /// the repository isn't pushed and fails. The hook modifies the local repository.
+    #[arg(long, value_name = "CMD")]
+    transform_hook: Option<String>,
+
+    /// Time budget of every repository (e.g. `30m`), including its retries. The git command
+    /// running when it is exceeded is killed and the repository fails with kind `timeout`.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    repo_timeout: Option<Duration>,
+
+    /// Extend the budget of `--repo-timeout` by this much per GiB of the repository (e.g. `2m`),
+    /// using the size reported by the provider or else of the local repository. Repositories of
+    /// unknown size get the flat `--repo-timeout`.
+    #[arg(long, value_parser = humantime::parse_duration, requires = "repo_timeout")]
+    repo_timeout_per_gb: Option<Duration>,
+
+    /// Verify the signatures of the branch and tag tips after fetching (`git verify-commit` and
+    /// `git verify-tag`), tips without a valid signature are reported as warnings
+    #[arg(long, requires = "gpg_home")]
+    verify_signatures: bool,
+
+    /// Like --verify-signatures, but fail and don't push repositories with unverified tips
+    #[arg(long, requires = "gpg_home")]
+    require_signatures: bool,
+
+    /// GnuPG home directory with the keyring of the trusted keys for the signature verification
+    #[arg(long)]
+    gpg_home: Option<PathBuf>,
+
     /// Fail on sync task error. If set the executable will exit with 1 if any sync task failed.
     #[arg(long)]
     fail_on_sync_error: bool,
 
-    /// Mirror lfs objects as well
+    /// Mirror the LFS objects of all refs as well (`git lfs fetch --all` and `git lfs push --all`),
+    /// for the repositories that use LFS
     #[arg(long, default_value = "false")]
     lfs: bool,
+
+    /// Handling of failed LFS transfers. With `warn` the refs are synced anyway and the
+    /// repository is reported as partially synced.
+    #[arg(long, value_enum, default_value_t = LfsFailure::Fatal, requires = "lfs")]
+    lfs_failure: LfsFailure,
+
+    /// Rewrite the destination URL before pushing, in the form <from>=<to>.
+    /// Replaces the first occurrence of <from>. Can be repeated, all rewrites are applied in
+    /// the order they are given.
+    #[arg(long, value_parser = DestRewrite::parse_replace)]
+    dest_rewrite: Vec<DestRewrite>,
+
+    /// Rewrite the destination URL before pushing, in the form <pattern>=<replacement>.
+    /// The replacement can reference capture groups (e.g. `$1`). Can be repeated and mixed
+    /// with `--dest-rewrite`.
+    #[arg(long, value_parser = DestRewrite::parse_regex)]
+    dest_rewrite_regex: Vec<DestRewrite>,
+
+    /// Rename a repository on the destination, in the form <from>=<to> with repository paths
+    /// (e.g. `upstream/Foo=mirror/foo`). Applied before the rewrites. Can be repeated.
+    #[arg(long, value_parser = Rename::parse)]
+    rename: Vec<Rename>,
+
+    /// File with a <from>=<to> rename per line, see `--rename`
+    #[arg(long)]
+    rename_file: Option<PathBuf>,
+
+    /// Change the case of the repository paths on the destination, after the renames and
+    /// rewrites. Repositories whose destinations collide after the change fail.
+    #[arg(long, value_enum, default_value_t = DestCase::Preserve)]
+    dest_case: DestCase,
+
+    /// Name the local repositories after the origin or the destination. Changing it clones all
+    /// repositories again.
+    #[arg(long, value_enum, default_value_t = LocalDirName::Origin)]
+    local_dir_name: LocalDirName,
+
+    /// Mirror the wiki repository of projects that have the wiki enabled as well
+    #[arg(long)]
+    include_wikis: bool,
+
+    /// List the GitLab projects with their reduced field set (`simple=true`), which makes the
+    /// responses smaller on large groups. Full listings are still used if `--include-wikis`,
+    /// `--visibility` or `--repo-timeout-per-gb` need the missing fields. No effect on GitHub.
+    #[arg(long)]
+    simple_listing: bool,
+
+    /// Skip fetching and pushing repositories whose origin refs didn't change since the last sync.
+    /// Repositories the provider lists without activity since then aren't even checked.
+    #[arg(long)]
+    only_changed: bool,
+
+    /// Sync all repositories, also the unchanged ones `--only-changed` would skip
+    #[arg(long)]
+    force_sync: bool,
+
+    /// Count a repository as (partially) successful if the destination only rejected some refs,
+    /// e.g. because of branch protection
+    #[arg(long)]
+    tolerate_rejected_refs: bool,
+
+    /// Push without force (no `-f`, the leading `+` of the refspecs is dropped), so refs that
+    /// diverged on the destination are kept and the repository fails as diverged
+    #[arg(long)]
+    no_force: bool,
+
+    /// Push option to transmit to the destination (e.g. `ci.skip` for GitLab). Can be repeated.
+    #[arg(long = "push-option")]
+    push_options: Vec<String>,
+
+    /// Start mirroring while the repositories are still being listed.
+    /// The order of the jobs in the reports is not deterministic in this mode.
+    #[arg(long)]
+    stream_listing: bool,
+
+    /// Number of listed repositories waiting for a worker with `--stream-listing`, the listing
+    /// pauses while the queue is full. Default is 4 times `--worker-count`.
+    #[arg(long, requires = "stream_listing")]
+    queue_depth: Option<usize>,
+
+    /// Skip origins with more than this many refs (branches, tags and others), checked with
+    /// `git ls-remote` before fetching. The skipped repositories are reported as `too many refs`.
+    #[arg(long)]
+    max_refs: Option<usize>,
+
+    /// Skip projects with fewer stars. Only GitHub (`stargazers_count`), GitLab (`star_count`)
+    /// and the `stars` field of the external provider report them, projects without a count
+    /// are not filtered.
+    #[arg(long)]
+    min_stars: Option<u64>,
+
+    /// Skip projects with fewer forks. Only GitHub, GitLab (`forks_count`) and the `forks` field
+    /// of the external provider report them, projects without a count are not filtered.
+    #[arg(long)]
+    min_forks: Option<u64>,
+
+    /// Only sync the repositories whose destination path (e.g. `group/project`) matches this
+    /// glob, `*` matches within a path segment and `**` across them. Can be repeated.
+    #[arg(long = "include", value_name = "GLOB", value_parser = RepoPattern::parse_glob)]
+    includes: Vec<RepoPattern>,
+
+    /// Like `--include` with a regex, matching anywhere in the path unless anchored
+    #[arg(long = "include-regex", value_name = "REGEX", value_parser = RepoPattern::parse_regex)]
+    include_regexes: Vec<RepoPattern>,
+
+    /// Skip the repositories whose destination path matches this glob, also if included.
+    /// Can be repeated.
+    #[arg(long = "exclude", value_name = "GLOB", value_parser = RepoPattern::parse_glob)]
+    excludes: Vec<RepoPattern>,
+
+    /// Like `--exclude` with a regex, matching anywhere in the path unless anchored
+    #[arg(long = "exclude-regex", value_name = "REGEX", value_parser = RepoPattern::parse_regex)]
+    exclude_regexes: Vec<RepoPattern>,
+
+    /// Skip archived projects. Only GitHub, GitLab and the `archived` field of the external
+    /// provider report them.
+    #[arg(long)]
+    skip_archived: bool,
+
+    /// Skip forked projects. Only GitHub, GitLab and the `fork` field of the external provider
+    /// report them.
+    #[arg(long)]
+    skip_forks: bool,
+
+    /// Publish a JSON event per finished job and per run to this NATS server
+    /// (`nats://[user:password@]host[:port]`)
+    #[cfg(feature = "nats")]
+    #[arg(long, value_name = "URL", value_parser = NatsUrl::parse)]
+    nats_url: Option<NatsUrl>,
+
+    /// Subject prefix of the events, they are published to `<subject>.repo` and `<subject>.run`
+    #[cfg(feature = "nats")]
+    #[arg(long, default_value = "git-mirror")]
+    nats_subject: String,
+
+    /// Record every run and the result of every repository in this SQLite database, created if
+    /// it doesn't exist
+    #[cfg(feature = "sqlite")]
+    #[arg(long, value_name = "PATH")]
+    history_db: Option<PathBuf>,
+
+    /// Append every ref created, updated or deleted on a destination by the push to this file,
+    /// as JSON lines with the old and new object ids. The refs of the destination are listed
+    /// before and after the push, so the refs pushed before a failure are recorded as well.
+    #[arg(long, value_name = "PATH")]
+    audit_log: Option<PathBuf>,
+
+    /// Post a summary of the runs with failures as JSON to this URL, e.g. a Slack incoming
+    /// webhook. Can be repeated.
+    #[arg(long, value_name = "URL")]
+    notify_webhook: Vec<String>,
+
+    /// Mail a summary of the runs with failures through this SMTP relay (`host[:port]`), without
+    /// TLS or authentication
+    #[arg(long, value_name = "HOST", requires = "notify_email")]
+    notify_smtp: Option<String>,
+
+    /// Recipient of the mailed summaries. Can be repeated.
+    #[arg(long, value_name = "ADDRESS", requires = "notify_smtp")]
+    notify_email: Vec<String>,
+
+    /// Sender of the mailed summaries
+    #[arg(long, value_name = "ADDRESS", default_value = "git-mirror@localhost")]
+    notify_from: String,
+
+    /// Also notify about runs without failures
+    #[arg(long)]
+    notify_always: bool,
+
+    /// Layout of the local repositories. Existing repositories keep their layout.
+    #[arg(long, default_value = "bare", value_enum)]
+    clone_mode: CloneMode,
+
+    /// Permissions of the local repositories, sets `core.sharedRepository` on clone and update
+    #[arg(long, value_enum)]
+    shared: Option<SharedRepository>,
+
+    /// Run `git maintenance run --auto` on the local repositories after the push, `git gc --auto`
+    /// before git 2.29
+    #[arg(long)]
+    maintenance: bool,
+
+    /// Run the maintenance task instead of the automatically selected ones. Can be repeated.
+    #[arg(long = "maintenance-task", value_enum, requires = "maintenance")]
+    maintenance_tasks: Vec<MaintenanceTask>,
+
+    /// Shared object pool (a bare repository, created if missing). Each origin is fetched into it
+    /// before new clones, which then borrow the objects from it (`git clone --reference`).
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["partial_clone", "dissociate"])]
+    alternates: Option<PathBuf>,
+
+    /// Copy the objects borrowed from an object pool into the existing local repositories
+    /// (`git repack -a -d`) and stop using the pool, so it can be removed
+    #[arg(long)]
+    dissociate: bool,
+
+    /// Download the release assets of GitLab and GitHub projects to `<mirror-dir>/<repo>.releases/<tag>/`
+    #[arg(long)]
+    include_releases: bool,
+
+    /// Compression level of the packs sent to the destination, from 0 (none, fastest) to 9
+    /// (smallest) [default: from the git configuration]
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=9))]
+    pack_compression: Option<u8>,
+
+    /// Git wire protocol version used to fetch and push (`protocol.version`), 2 is much faster
+    /// for repositories with many refs [default: from the git configuration]
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=2))]
+    git_protocol: Option<u8>,
+
+    /// Don't verify the TLS certificates of git remotes starting with the URL (e.g.
+    /// `--git-insecure=https://git.example.com/`), of all remotes without URL. The certificates
+    /// of the provider API are still verified. Can be repeated.
+    #[arg(long, value_name = "URL", num_args = 0..=1, default_missing_value = "", require_equals = true, value_parser = parse_insecure_url)]
+    git_insecure: Vec<String>,
+
+    /// Exclusive lock held during the run, exit with code 5 if another instance holds it.
+    /// The mirror directory is always locked as well.
+    #[arg(long)]
+    lock_file: Option<PathBuf>,
+
+    /// Permissions of the mirror directory as octal mode (e.g. `2775`)
+    #[arg(long, value_parser = parse_mode)]
+    dir_mode: Option<u32>,
+
+    /// Create new local repositories as partial clones, missing objects are fetched on demand
+    #[arg(long, value_enum)]
+    partial_clone: Option<PartialClone>,
+
+    /// Number of times a failed sync task is retried, also the default of `--list-retry-count`
+    #[arg(long, default_value = "0")]
+    retries: u32,
+
+    /// Delay before the first retry, doubled for every further retry (e.g. `10s`, `1m`),
+    /// also the default of `--list-retry-delay`
+    #[arg(long, default_value = "10s", value_parser = humantime::parse_duration)]
+    retry_backoff: Duration,
+
+    /// Randomization of the retry delay, to avoid synchronized retries of parallel jobs
+    #[arg(long, default_value = "none", value_enum)]
+    retry_jitter: Jitter,
+
+    /// Number of times the listing of the repositories is retried after connection problems,
+    /// timeouts, rate limits and server errors of the API. Rejected credentials aren't retried.
+    /// Defaults to `--retries`.
+    #[arg(long)]
+    list_retry_count: Option<u32>,
+
+    /// Delay before the first retry of the listing, doubled for every further retry.
+    /// Defaults to `--retry-backoff`.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    list_retry_delay: Option<Duration>,
+
+    /// Directory to write a log file with the git output for every repository to.
+    /// The logs are overwritten on every run.
+    #[arg(long)]
+    repo_log_dir: Option<PathBuf>,
+
+    /// Store the origin and time of the last sync as JSON in `refs/mirror-meta/last-sync` on the destination
+    #[arg(long)]
+    annotate_sync: bool,
+
+    /// After the push, point the default branch (HEAD) of the destination to the default branch
+    /// of the origin. Local destinations are updated directly, GitLab and GitHub destinations with
+    /// the API (the token needs the permission to change the project settings).
+    #[arg(long)]
+    set_default_branch: bool,
+
+    /// After the push, copy the topics and the default branch of origins on the same GitLab or
+    /// GitHub instance to the destination with the API. The description holds the mirror
+    /// configuration and is kept.
+    #[arg(long)]
+    sync_metadata: bool,
+
+    /// Ref pattern (e.g. `refs/keep/*`) that is never deleted on the destination by the mirror push.
+    /// Matching refs are not pushed either. Can be repeated.
+    #[arg(long)]
+    prune_protect: Vec<String>,
+
+    /// Format of the summary at the end of the run. `json` prints the counts, the duration and
+    /// the exit reason as single JSON line after the `DONE` line.
+    #[arg(long, default_value = "text", value_enum)]
+    summary_format: SummaryFormat,
+
+    /// Exit with an error if more than this number of sync tasks failed. Overrides `--fail-on-sync-error`.
+    #[arg(long)]
+    max_failures: Option<usize>,
+
+    /// Exit with an error if the ratio of successful to attempted sync tasks is below this value
+    /// (0.0-1.0). Overrides `--fail-on-sync-error`.
+    #[arg(long, value_parser = parse_rate)]
+    min_success_rate: Option<f64>,
+
+    /// Mirror pull request (`refs/pull/*`) and merge request (`refs/merge-requests/*`) refs
+    /// to `refs/mirror/pull/*` and `refs/mirror/merge-requests/*` on the destination
+    #[arg(long)]
+    include_pr_refs: bool,
+
+    /// Ref pattern (e.g. `refs/changes/*`) that is neither fetched from the origin nor pushed
+    /// to the destination. Can be repeated.
+    #[arg(long = "exclude-ref", visible_alias = "skip-refs")]
+    exclude_refs: Vec<String>,
+
+    /// Fail if the provider lists fewer repositories, as this usually indicates an expired
+    /// token or a wrong group
+    #[arg(long, default_value = "1")]
+    min_expected_repos: usize,
+
+    /// Don't fail if the provider lists no repositories
+    #[arg(long, conflicts_with = "min_expected_repos")]
+    allow_empty: bool,
+
+    /// Stop syncing once about this many gigabytes (GiB) were received and sent, as reported by
+    /// git in its progress output. The remaining repositories are skipped.
+    #[arg(long, value_name = "GB", value_parser = parse_gigabytes)]
+    max_transfer: Option<u64>,
+
+    /// Rewrite the `--junit-report` and `--metric-file` with the jobs finished so far at most
+    /// this often during the run (e.g. `0s` after every job), so they survive a killed run
+    #[arg(long, default_value = "30s", value_parser = humantime::parse_duration)]
+    report_interval: Duration,
+
+    /// Report the size of every local repository and the total after the sync, in the JSON
+    /// summary and as `git_mirror_repo_size_bytes` metric
+    #[arg(long)]
+    report_sizes: bool,
+
+    /// JSON summary (or the output ending with it) of a previous run with this option. The
+    /// repositories that newly failed, newly succeeded or disappeared since are reported, and the
+    /// results of all repositories are added to the JSON summary as the baseline of the next run.
+    #[arg(long)]
+    baseline_report: Option<PathBuf>,
+
+    /// Write the changes since the --baseline-report to this file as JSON
+    #[arg(long, requires = "baseline_report")]
+    diff_report: Option<PathBuf>,
+
+    /// Branch of the destination the repositories with a `subtree_prefix` are imported into
+    #[arg(long, default_value = "main")]
+    subtree_branch: String,
+
+    /// Only sync a part of the repositories, in the form <index>/<total> (e.g. `0/3`).
+    /// The repositories are split by a stable hash of their destination.
+    #[arg(long, value_parser = Shard::parse)]
+    shard: Option<Shard>,
+
+    /// Only check the descriptions of all repositories and report the invalid ones,
+    /// without running any git commands
+    #[arg(long, conflicts_with = "daemon")]
+    validate_config: bool,
+
+    /// Print the effective value of every option and where it comes from (command line,
+    /// environment, config file or default) as JSON and exit. Tokens and header values are redacted.
+    #[arg(long)]
+    print_config: bool,
+
+    /// YAML file with default options, the providers or groups to mirror and overrides of single
+    /// repositories, see the README. Options on the command line or in the environment take
+    /// precedence.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Keep running and sync every `--interval`, serving `/healthz`, `/metrics` and `/status`
+    /// over HTTP on `--listen`
+    #[arg(long)]
+    daemon: bool,
+
+    /// Time between the start of two sync runs in daemon mode (e.g. `30m`, `6h`)
+    #[arg(long, default_value = "1h", value_parser = humantime::parse_duration)]
+    interval: Duration,
+
+    /// Address of the HTTP server in daemon mode
+    #[arg(long, default_value = "0.0.0.0:8080")]
+    listen: String,
+
+    /// Accept GitHub and GitLab push webhooks with this secret on `/webhook` in daemon mode and
+    /// sync the pushed repository right away. Needs `--daemon`, the pushes are matched against
+    /// the repositories listed by its last run.
+    #[arg(long, env = "WEBHOOK_SECRET", requires = "daemon")]
+    webhook_secret: Option<String>,
+}
+
+/// Parse an octal file mode
+fn parse_mode(s: &str) -> Result<u32, String> {
+    match u32::from_str_radix(s, 8) {
+        Ok(m) if m <= 0o7777 => Ok(m),
+        Ok(_) => Err(format!("{s} is not a valid file mode")),
+        Err(e) => Err(format!("{s} is not an octal number ({e})")),
+    }
+}
+
+/// Parse a ratio between 0.0 and 1.0
+fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(r) if (0.0..=1.0).contains(&r) => Ok(r),
+        Ok(_) => Err(format!("{s} is not between 0.0 and 1.0")),
+        Err(e) => Err(format!("{s} is not a number ({e})")),
+    }
+}
+
+/// Parse a positive number of requests per second
+fn parse_rps(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(r) if r > 0.0 && r.is_finite() => Ok(r),
+        Ok(_) => Err(format!("{s} is not a positive rate")),
+        Err(e) => Err(format!("{s} is not a number ({e})")),
+    }
+}
+
+/// The notifier of the runs with failures, if any notification is configured
+fn notifier(
+    webhooks: Vec<String>,
+    smtp: Option<String>,
+    from: String,
+    to: Vec<String>,
+    always: bool,
+) -> Option<Notifier> {
+    let smtp = smtp.map(|relay| {
+        Smtp::new(&relay, from, to)
+            .unwrap_or_else(|e| Opt::command().error(ErrorKind::InvalidValue, e).exit())
+    });
+    if webhooks.is_empty() && smtp.is_none() {
+        return None;
+    }
+    Some(Notifier::new(webhooks, smtp, always))
+}
+
+/// Parse a size in gigabytes (GiB) into bytes
+fn parse_gigabytes(s: &str) -> Result<u64, String> {
+    match s.parse::<f64>() {
+        Ok(gb) if gb > 0.0 => Ok((gb * (1u64 << 30) as f64) as u64),
+        Ok(_) => Err(format!("{s} is not a positive size")),
+        Err(e) => Err(format!("{s} is not a number ({e})")),
+    }
+}
+
+/// The cleanup policy, `--remove-workrepo` being the older spelling of `--cleanup always`
+fn cleanup(opt: &Opt) -> Cleanup {
+    if opt.remove_workrepo {
+        Cleanup::Always
+    } else {
+        opt.cleanup
+    }
+}
+
+/// The bundles written with `--target`, exits if the target lacks its settings
+fn bundles(opt: &Opt) -> Option<Bundles> {
+    let s3 = match opt.target {
+        Target::Push if opt.bundle_dir.is_some() => Opt::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--bundle-dir needs --target bundle or s3://bucket/prefix",
+            )
+            .exit(),
+        Target::Push => return None,
+        // A bundle needs all objects of the bundled refs, also the ones a partial clone left out
+        _ if opt.partial_clone.is_some() => Opt::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--partial-clone isn't supported with --target bundle or s3://bucket/prefix",
+            )
+            .exit(),
+        Target::Bundle if opt.bundle_dir.is_none() => Opt::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "--target bundle needs --bundle-dir",
+            )
+            .exit(),
+        Target::Bundle => None,
+        Target::S3 {
+            ref bucket,
+            ref prefix,
+        } => {
+            let (access_key_id, secret_access_key) =
+                match (&opt.s3_access_key_id, &opt.s3_secret_access_key) {
+                    (Some(id), Some(key)) => (id.to_owned(), key.to_owned()),
+                    _ => Opt::command()
+                        .error(
+                            ErrorKind::MissingRequiredArgument,
+                            "An S3 target needs --s3-access-key-id and --s3-secret-access-key",
+                        )
+                        .exit(),
+                };
+            Some(S3Bucket {
+                endpoint: opt
+                    .s3_endpoint
+                    .to_owned()
+                    .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", opt.s3_region)),
+                region: opt.s3_region.to_owned(),
+                bucket: bucket.to_owned(),
+                prefix: prefix.to_owned(),
+                access_key_id,
+                secret_access_key,
+                session_token: opt.s3_session_token.to_owned(),
+            })
+        }
+    };
+    Some(Bundles {
+        dir: opt.bundle_dir.to_owned(),
+        s3,
+    })
+}
+
+/// Check if the reduced GitLab listing of `--simple-listing` has all the fields needed
+fn simple_listing(opt: &Opt) -> bool {
+    if !opt.simple_listing {
+        return false;
+    }
+    let needed = [
+        (opt.include_wikis, "--include-wikis"),
+        (opt.visibility != Visibility::All, "--visibility"),
+        (opt.repo_timeout_per_gb.is_some(), "--repo-timeout-per-gb"),
+        (opt.skip_archived, "--skip-archived"),
+        (opt.skip_forks, "--skip-forks"),
+    ];
+    match needed.iter().find(|(needs, _)| *needs) {
+        Some((_, option)) => {
+            info!("Using the full listing, {} needs its fields", option);
+            false
+        }
+        None => true,
+    }
+}
+
+/// The GitHub App of `--github-app-id`, exits if its key can't be read
+fn github_app(opt: &Opt) -> Option<GitHubApp> {
+    let (app_id, installation_id, key) = match (
+        opt.github_app_id,
+        opt.github_installation_id,
+        opt.github_app_key.as_deref(),
+    ) {
+        (Some(app_id), Some(installation_id), Some(key)) => (app_id, installation_id, key),
+        _ => return None,
+    };
+    match GitHubApp::new(app_id, installation_id, key) {
+        Ok(app) => Some(app),
+        Err(e) => Opt::command().error(ErrorKind::InvalidValue, e).exit(),
+    }
 }
 
 impl From<Opt> for MirrorOptions {
     fn from(opt: Opt) -> MirrorOptions {
+        let cleanup = cleanup(&opt);
+        let bundles = bundles(&opt);
         MirrorOptions {
             mirror_dir: opt.mirror_dir,
             dry_run: opt.dry_run,
             worker_count: opt.worker_count,
             metrics_file: opt.metric_file,
+            pushgateway_url: opt.pushgateway_url,
+            pushgateway_job: opt.pushgateway_job,
+            bundles,
             junit_file: opt.junit_report,
+            json_report: opt.json_report,
             git_executable: opt.git_executable,
+            git_backend: opt.git_backend,
             refspec: opt.refspec,
-            remove_workrepo: opt.remove_workrepo,
+            ref_mapping: RefMapping {
+                branches: opt.branch_refspec,
+                tags: opt.tag_refspec,
+                notes: opt.notes_refspec,
+            },
+            cleanup,
+            cleanup_after: opt.cleanup_after,
+            prune: opt.prune,
+            prune_remote: opt.prune_remote,
+            work_tmp: opt.work_tmp,
             fail_on_sync_error: opt.fail_on_sync_error,
             mirror_lfs: opt.lfs,
+            lfs_failure: opt.lfs_failure,
+            dest_rewrites: Vec::new(),
+            renames: opt.rename,
+            overrides: Vec::new(),
+            dest_case: opt.dest_case,
+            local_dir_name: opt.local_dir_name,
+            include_wikis: opt.include_wikis,
+            only_changed: opt.only_changed,
+            force_sync: opt.force_sync,
+            tolerate_rejected_refs: opt.tolerate_rejected_refs,
+            push_options: opt.push_options,
+            stream_listing: opt.stream_listing,
+            clone_mode: opt.clone_mode,
+            partial_clone: opt.partial_clone,
+            shard: opt.shard,
+            shared: opt.shared,
+            dir_mode: opt.dir_mode,
+            lock_file: opt.lock_file,
+            pack_compression: opt.pack_compression,
+            git_protocol: opt.git_protocol,
+            git_insecure: opt.git_insecure,
+            include_releases: opt.include_releases,
+            alternates: opt.alternates,
+            max_transfer: opt.max_transfer,
+            ssh: SshOptions {
+                jump: opt.ssh_jump.filter(|_| !opt.http),
+                key: opt.ssh_key,
+                known_hosts: opt.ssh_known_hosts,
+                strict_host_key_checking: opt.ssh_strict_host_key_checking,
+            },
+            report_sizes: opt.report_sizes,
+            report_interval: opt.report_interval,
+            subtree_branch: opt.subtree_branch,
+            queue_depth: opt.queue_depth,
+            max_refs: opt.max_refs,
+            min_stars: opt.min_stars,
+            min_forks: opt.min_forks,
+            includes: opt
+                .includes
+                .into_iter()
+                .chain(opt.include_regexes)
+                .collect(),
+            excludes: opt
+                .excludes
+                .into_iter()
+                .chain(opt.exclude_regexes)
+                .collect(),
+            skip_archived: opt.skip_archived,
+            skip_forks: opt.skip_forks,
+            stage: opt.stage,
+            fsck: opt.fsck,
+            branches: opt.branches,
+            tags: opt.tags,
+            verify_signatures: opt
+                .gpg_home
+                .filter(|_| opt.verify_signatures || opt.require_signatures),
+            require_signatures: opt.require_signatures,
+            push_batch: opt.push_batch.map(|n| n as usize),
+            write_commit_graph: opt.write_commit_graph,
+            write_midx: opt.write_midx,
+            transfer_stats: opt.transfer_stats,
+            transform_hook: opt.transform_hook,
+            repo_timeout: opt.repo_timeout,
+            repo_timeout_per_gb: opt.repo_timeout_per_gb,
+            list_retry: RetryPolicy::new(
+                opt.list_retry_count.unwrap_or(opt.retries),
+                opt.list_retry_delay.unwrap_or(opt.retry_backoff),
+                Jitter::None,
+            ),
+            baseline_report: opt.baseline_report,
+            diff_report: opt.diff_report,
+            #[cfg(feature = "nats")]
+            events: opt
+                .nats_url
+                .map(|url| NatsPublisher::new(url, opt.nats_subject)),
+            #[cfg(feature = "sqlite")]
+            history: opt.history_db.map(|path| {
+                HistoryDb::open(&path)
+                    .unwrap_or_else(|e| Opt::command().error(ErrorKind::Io, e).exit())
+            }),
+            audit_log: opt.audit_log.map(|path| {
+                AuditLog::open(&path)
+                    .unwrap_or_else(|e| Opt::command().error(ErrorKind::Io, e).exit())
+            }),
+            notify: notifier(
+                opt.notify_webhook,
+                opt.notify_smtp,
+                opt.notify_from,
+                opt.notify_email,
+                opt.notify_always,
+            ),
+            progress: None,
+            maintenance: opt.maintenance,
+            maintenance_tasks: opt.maintenance_tasks,
+            dissociate: opt.dissociate,
+            retry: RetryPolicy::new(opt.retries, opt.retry_backoff, opt.retry_jitter),
+            repo_log_dir: opt.repo_log_dir,
+            annotate_sync: opt.annotate_sync,
+            set_default_branch: opt.set_default_branch,
+            sync_metadata: opt.sync_metadata,
+            no_force: opt.no_force,
+            prune_protect: opt.prune_protect,
+            summary_format: opt.summary_format,
+            max_failures: opt.max_failures,
+            min_success_rate: opt.min_success_rate,
+            include_pr_refs: opt.include_pr_refs,
+            exclude_refs: opt.exclude_refs,
+            min_expected_repos: if opt.allow_empty {
+                0
+            } else {
+                opt.min_expected_repos
+            },
+        }
+    }
+}
+
+/// Options whose values are secrets, redacted by `--print-config`
+const SECRET_OPTIONS: [&str; 7] = [
+    "private_token",
+    "dest_private_token",
+    "s3_secret_access_key",
+    "s3_session_token",
+    "nats_url",
+    "webhook_secret",
+    "notify_webhook",
+];
+
+/// The plain and regex rewrites of the destination in the order they were given
+fn dest_rewrites(opt: &Opt, matches: &ArgMatches) -> Vec<DestRewrite> {
+    let indices = |id| matches.indices_of(id).into_iter().flatten();
+    let mut rewrites: Vec<_> = indices("dest_rewrite")
+        .zip(&opt.dest_rewrite)
+        .chain(indices("dest_rewrite_regex").zip(&opt.dest_rewrite_regex))
+        .collect();
+    rewrites.sort_by_key(|(i, _)| *i);
+    rewrites.into_iter().map(|(_, r)| r.clone()).collect()
+}
+
+/// The effective options for `--print-config`, with the source of their value
+fn effective_config(
+    matches: &ArgMatches,
+    from_config: &BTreeSet<String>,
+    provider: &dyn Provider,
+) -> serde_json::Value {
+    let mut options = serde_json::Map::new();
+    for arg in Opt::command().get_arguments() {
+        let id = arg.get_id().as_str();
+        if matches!(id, "help" | "version") {
+            continue;
         }
+        let values: Vec<String> = match matches.get_raw(id) {
+            Some(raw) => raw
+                .map(|v| {
+                    let v = v.to_string_lossy();
+                    match id {
+                        _ if SECRET_OPTIONS.contains(&id) => "***".to_string(),
+                        // Only the header name is shown
+                        "api_headers" => match v.split_once('=') {
+                            Some((name, _)) => format!("{name}=***"),
+                            None => "***".to_string(),
+                        },
+                        _ => v.into_owned(),
+                    }
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        let value: serde_json::Value = match arg.get_action() {
+            ArgAction::Count => matches.get_count(id).into(),
+            ArgAction::SetTrue => matches.get_flag(id).into(),
+            ArgAction::Append => values.into(),
+            _ => values.into_iter().next().into(),
+        };
+        let source = match matches.value_source(id) {
+            // Passed as command line arguments
+            _ if from_config.contains(id) => "config file",
+            Some(ValueSource::CommandLine) => "command line",
+            Some(ValueSource::EnvVariable) => "environment",
+            Some(ValueSource::DefaultValue) => "default",
+            _ => "unset",
+        };
+        let name = arg.get_long().unwrap_or(id);
+        options.insert(
+            name.to_string(),
+            serde_json::json!({ "value": value, "source": source }),
+        );
     }
+    serde_json::json!({
+        "provider": provider.get_label(),
+        "options": options,
+    })
+}
+
+/// Turn the options of the config file into command line arguments, the options of later
+/// sections take precedence. Options given on the command line or in the environment are left out.
+/// Returns the arguments and the ids of the options.
+fn config_args(
+    sections: &[&ConfigOptions],
+    given: &ArgMatches,
+) -> Result<(Vec<String>, BTreeSet<String>), String> {
+    let command = Opt::command();
+    let mut options = BTreeMap::new();
+    for section in sections {
+        options.extend(section.iter());
+    }
+    let (mut args, mut ids) = (Vec::new(), BTreeSet::new());
+    for (name, value) in options {
+        let arg = command
+            .get_arguments()
+            .find(|a| a.get_long() == Some(name.as_str()))
+            .filter(|a| !matches!(a.get_id().as_str(), "config" | "help" | "version"))
+            .ok_or_else(|| format!("Unknown option {name}"))?;
+        let id = arg.get_id().as_str();
+        if matches!(
+            given.value_source(id),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        ) {
+            continue;
+        }
+        let scalar = |v: &serde_yaml::Value| match v {
+            serde_yaml::Value::String(s) => Ok(s.to_owned()),
+            serde_yaml::Value::Number(n) => Ok(n.to_string()),
+            serde_yaml::Value::Bool(b) => Ok(b.to_string()),
+            _ => Err(format!("Invalid value of {name}")),
+        };
+        match arg.get_action() {
+            ArgAction::SetTrue => match value.as_bool() {
+                Some(true) => args.push(format!("--{name}")),
+                Some(false) => {}
+                None => return Err(format!("{name} has to be true or false")),
+            },
+            ArgAction::Count => match value.as_u64() {
+                Some(n) => args.extend((0..n).map(|_| format!("--{name}"))),
+                None => return Err(format!("{name} has to be a number")),
+            },
+            _ => {
+                let values = match value {
+                    serde_yaml::Value::Sequence(v) => v.iter().map(scalar).collect(),
+                    v => scalar(v).map(|v| vec![v]),
+                }?;
+                args.extend(values.iter().map(|v| format!("--{name}={v}")));
+            }
+        }
+        ids.insert(id.to_owned());
+    }
+    Ok((args, ids))
+}
+
+/// Arguments of a run, with the ids of the options from the config file
+type Run = (ArgMatches, BTreeSet<String>);
+
+/// The arguments of each source of the config file
+fn config_runs(
+    path: &Path,
+    args: &[OsString],
+    given: &ArgMatches,
+) -> Result<(Vec<Run>, Vec<RepoOverride>), String> {
+    let config = ConfigFile::read(path)?;
+    let global = ConfigOptions::new();
+    let sources = match config.sources.is_empty() {
+        true => vec![&global],
+        false => config.sources.iter().collect(),
+    };
+    let mut runs = Vec::new();
+    for (i, source) in sources.into_iter().enumerate() {
+        let (extra, ids) = config_args(&[&config.options, source], given)
+            .map_err(|e| format!("{path:?} source {}: {e}", i + 1))?;
+        let args = args[..1]
+            .iter()
+            .cloned()
+            .chain(extra.into_iter().map(OsString::from))
+            .chain(args[1..].iter().cloned());
+        let matches = Opt::command()
+            .try_get_matches_from(args)
+            .unwrap_or_else(|e| e.exit());
+        runs.push((matches, ids));
+    }
+    Ok((runs, config.repos))
 }
 
 fn main() {
     // Setup commandline parser
-    let opt = Opt::parse();
-    debug!("{:#?}", opt);
+    let args: Vec<OsString> = env::args_os().collect();
+    // Only used to find the config file and the options it must not override
+    let given = Opt::command().ignore_errors(true).get_matches_from(&args);
+    let (runs, overrides) = match given.get_one::<PathBuf>("config") {
+        Some(path) => config_runs(path, &args, &given)
+            .unwrap_or_else(|e| Opt::command().error(ErrorKind::InvalidValue, e).exit()),
+        None => (
+            vec![(Opt::command().get_matches_from(&args), BTreeSet::new())],
+            Vec::new(),
+        ),
+    };
+    let opts: Vec<Opt> = runs
+        .iter()
+        .map(|(matches, _)| Opt::from_arg_matches(matches).unwrap_or_else(|e| e.exit()))
+        .collect();
 
-    let env_log_level = match cmp::min(opt.verbose, 4) {
+    let env_log_level = match cmp::min(opts[0].verbose, 4) {
         4 => "git_mirror=trace",
         3 => "git_mirror=debug",
         2 => "git_mirror=info",
@@ -144,43 +1372,400 @@ fn main() {
     };
     env_logger::Builder::from_env(Env::default().default_filter_or(env_log_level)).init();
 
+    if opts.len() > 1 {
+        if opts.iter().any(|opt| opt.daemon) {
+            Opt::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    "--daemon runs a single source, start one daemon per source of the config file",
+                )
+                .exit()
+        }
+        // Each run removes the repositories of the others as stale
+        for (i, opt) in opts.iter().enumerate() {
+            if let Some(j) = opts[..i]
+                .iter()
+                .position(|o| o.mirror_dir == opt.mirror_dir)
+            {
+                Opt::command()
+                    .error(
+                        ErrorKind::ArgumentConflict,
+                        format!(
+                            "Sources {} and {} of the config file use the same --mirror-dir",
+                            j + 1,
+                            i + 1
+                        ),
+                    )
+                    .exit()
+            }
+        }
+    }
+
     // Run OpenSSL probing on all platforms even the ones not using it
     openssl_probe::init_ssl_cert_env_vars();
 
+    // The sources are mirrored one after the other, the first failure sets the exit code
+    let mut code = 0;
+    for ((matches, from_config), opt) in runs.iter().zip(opts) {
+        if let Err(e) = run(opt, matches, from_config, &overrides) {
+            error!("Error occured: {}", e);
+            if code == 0 {
+                code = e.into();
+            }
+        }
+    }
+    if code != 0 {
+        exit(code);
+    }
+}
+
+fn run(
+    opt: Opt,
+    matches: &ArgMatches,
+    from_config: &BTreeSet<String>,
+    overrides: &[RepoOverride],
+) -> Result<(), GitMirrorError> {
+    debug!("{:#?}", opt);
+
+    if opt.stage == Stage::Fetch && cleanup(&opt) != Cleanup::Never {
+        Opt::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--remove-workrepo and --cleanup would remove the repositories fetched with --stage fetch",
+            )
+            .exit()
+    }
+
+    if opt.git_backend == GitBackend::Libgit2 {
+        if !cfg!(feature = "libgit2") {
+            Opt::command()
+                .error(
+                    ErrorKind::InvalidValue,
+                    "--git-backend libgit2 needs git-mirror built with the libgit2 feature",
+                )
+                .exit()
+        }
+        let unsupported = [
+            (opt.clone_mode == CloneMode::Work, "--clone-mode work"),
+            (opt.partial_clone.is_some(), "--partial-clone"),
+            (opt.alternates.is_some(), "--alternates"),
+            (opt.ssh_jump.is_some(), "--ssh-jump"),
+            (opt.ssh_known_hosts.is_some(), "--ssh-known-hosts"),
+            (opt.git_protocol.is_some(), "--git-protocol"),
+            (opt.pack_compression.is_some(), "--pack-compression"),
+            (opt.push_batch.is_some(), "--push-batch"),
+            (opt.fsck, "--fsck"),
+        ];
+        if let Some((_, flag)) = unsupported.iter().find(|(set, _)| *set) {
+            Opt::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    format!("{flag} isn't supported by --git-backend libgit2"),
+                )
+                .exit()
+        }
+    }
+
+    if opt.target != Target::Push {
+        let pushing = [
+            (opt.dest_provider.is_some(), "--dest-provider"),
+            (opt.lfs, "--lfs"),
+            (opt.audit_log.is_some(), "--audit-log"),
+            (opt.annotate_sync, "--annotate-sync"),
+            (opt.include_pr_refs, "--include-pr-refs"),
+            (opt.set_default_branch, "--set-default-branch"),
+            (opt.sync_metadata, "--sync-metadata"),
+        ];
+        if let Some((_, flag)) = pushing.iter().find(|(set, _)| *set) {
+            Opt::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    format!("{flag} needs --target push"),
+                )
+                .exit()
+        }
+    }
+
+    if opt.http && opt.ssh_jump.is_some() {
+        warn!("--ssh-jump is ignored with --http");
+    }
+
+    let group = || -> String {
+        opt.group.to_owned().unwrap_or_else(|| {
+            Opt::command()
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    "--group is required for this provider",
+                )
+                .exit()
+        })
+    };
+
+    let api = ApiOptions {
+        user_agent: opt
+            .user_agent
+            .to_owned()
+            .unwrap_or_else(|| format!("{}/{}", crate_name!(), crate_version!())),
+        headers: opt.api_headers.to_owned(),
+        pool_size: opt.api_pool_size.map(|n| n as usize),
+        http2: opt.api_http2,
+        listing_cache: opt
+            .listing_cache
+            .as_deref()
+            .map(|f| Arc::new(ListingCache::open(f))),
+        throttle: Arc::new(Throttle::new(opt.max_api_rps)),
+        ..Default::default()
+    };
+    let topics = TopicFilter {
+        topics: opt.topics.to_owned(),
+        mode: opt.topic_match,
+    };
+
+    if opt.github_app_id.is_some() && !matches!(opt.provider, Providers::GitHub) {
+        Opt::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--github-app-id needs the GitHub provider",
+            )
+            .exit()
+    }
+
+    if !opt.topics.is_empty() && matches!(opt.provider, Providers::Bitbucket) {
+        Opt::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--topic is not supported by the Bitbucket provider",
+            )
+            .exit()
+    }
+
     let provider: Box<dyn Provider> = match opt.provider {
+        _ if opt.local_source.is_some() => Box::new(LocalSource {
+            dir: opt.local_source.to_owned().unwrap_or_default(),
+            dest_template: opt.dest_template.to_owned().unwrap_or_default(),
+        }),
         Providers::GitLab => Box::new(GitLab {
-            url: opt.url.to_owned(),
-            group: opt.group.to_owned(),
-            use_http: opt.http,
+            url: opt.url.to_owned().unwrap_or_default(),
+            group: group(),
+            use_http: opt.http || opt.dest_transport == Some(Transport::Http),
+            origin_transport: opt.origin_transport,
             private_token: opt.private_token.to_owned(),
-            recursive: true,
+            api: api.to_owned(),
+            recursive_depth: opt.recursive_depth,
+            list_concurrency: opt.list_concurrency,
+            topics: topics.to_owned(),
+            visibility: opt.visibility,
+            namespace_type: opt.namespace_type,
+            statistics: opt.repo_timeout_per_gb.is_some(),
+            simple: simple_listing(&opt),
+            list_origins: opt.dest_provider.is_some(),
         }),
         Providers::GitHub => Box::new(GitHub {
-            url: opt.url.to_owned(),
-            org: opt.group.to_owned(),
-            use_http: opt.http,
+            url: opt.url.to_owned().unwrap_or_default(),
+            org: group(),
+            use_http: opt.http || opt.dest_transport == Some(Transport::Http),
+            origin_transport: opt.origin_transport,
             private_token: opt.private_token.to_owned(),
-            useragent: format!("{}/{}", crate_name!(), crate_version!()),
+            app: github_app(&opt),
+            api: api.to_owned(),
+            topics: topics.to_owned(),
+            visibility: opt.visibility,
+            list_origins: opt.dest_provider.is_some(),
+        }),
+        Providers::Bitbucket | Providers::External if opt.dest_provider.is_some() => Opt::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--dest-provider needs the GitLab or GitHub provider",
+            )
+            .exit(),
+        Providers::Bitbucket => Box::new(Bitbucket {
+            url: opt.url.to_owned().unwrap_or_default(),
+            group: group(),
+            use_http: opt.http || opt.dest_transport == Some(Transport::Http),
+            origin_transport: opt.origin_transport,
+            private_token: opt.private_token.to_owned(),
+            user: opt.bitbucket_user.to_owned(),
+            api: api.to_owned(),
+            visibility: opt.visibility,
+        }),
+        Providers::External => Box::new(ExternalCommand {
+            command: opt.provider_command.to_owned().unwrap_or_default(),
+            topics,
+            visibility: opt.visibility,
         }),
     };
 
-    let opts: MirrorOptions = opt.into();
-
-    match do_mirror(provider, &opts) {
-        Ok(_) => {
-            info!("All done");
-        }
-        Err(e) => {
-            error!("Error occured: {}", e);
-            exit(e.into());
+    let provider: Box<dyn Provider> = match opt.dest_provider {
+        Some(kind) => {
+            let url = opt.dest_url.to_owned().unwrap_or_default();
+            let group = opt.dest_group.to_owned().unwrap_or_default();
+            let use_http = opt.http || opt.dest_transport == Some(Transport::Http);
+            let private_token = opt.dest_private_token.to_owned();
+            let dest = match kind {
+                DestProviders::GitLab => Destination::GitLab(GitLab {
+                    url,
+                    group,
+                    use_http,
+                    origin_transport: None,
+                    private_token,
+                    api: api.to_owned(),
+                    recursive_depth: None,
+                    list_concurrency: opt.list_concurrency,
+                    topics: TopicFilter::default(),
+                    visibility: Visibility::default(),
+                    namespace_type: NamespaceType::Group,
+                    statistics: false,
+                    simple: false,
+                    list_origins: false,
+                }),
+                DestProviders::GitHub => Destination::GitHub(GitHub {
+                    url,
+                    org: group,
+                    use_http,
+                    origin_transport: None,
+                    private_token,
+                    app: None,
+                    api: api.to_owned(),
+                    topics: TopicFilter::default(),
+                    visibility: Visibility::default(),
+                    list_origins: false,
+                }),
+            };
+            Box::new(DestProvider {
+                source: provider,
+                dest,
+                token: opt.dest_private_token.to_owned(),
+            })
         }
+        None => provider,
+    };
+
+    if opt.print_config {
+        let config = effective_config(matches, from_config, provider.as_ref());
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&config).expect("Unable to serialize config")
+        );
+        return Ok(());
+    }
+
+    let validate = opt.validate_config;
+    let daemon = opt.daemon.then(|| {
+        (
+            opt.interval,
+            opt.listen.to_owned(),
+            opt.webhook_secret.to_owned(),
+        )
+    });
+    let file_renames = match opt.rename_file {
+        Some(ref f) => Rename::read_file(f)
+            .unwrap_or_else(|e| Opt::command().error(ErrorKind::InvalidValue, e).exit()),
+        None => Vec::new(),
     };
+    let dest_rewrites = dest_rewrites(&opt, matches);
+    let mut opts: MirrorOptions = opt.into();
+    opts.dest_rewrites = dest_rewrites;
+    // The first matching rename is used, the ones on the command line take precedence
+    opts.renames.extend(file_renames);
+    opts.overrides = overrides.to_vec();
+
+    let result = match daemon {
+        _ if validate => validate_config(provider, &opts),
+        Some((interval, listen, secret)) => run_daemon(provider, &opts, interval, &listen, secret),
+        None => do_mirror(provider, &opts),
+    };
+
+    if result.is_ok() {
+        info!("All done");
+    }
+    result
 }
 
 #[cfg(test)]
 mod tests {
     use super::Opt;
 
+    #[test]
+    fn rate() {
+        use super::parse_rate;
+        assert_eq!(parse_rate("0.95"), Ok(0.95));
+        assert!(parse_rate("1.5").is_err());
+        assert!(parse_rate("x").is_err());
+    }
+
+    #[test]
+    fn mode() {
+        use super::parse_mode;
+        assert_eq!(parse_mode("2775"), Ok(0o2775));
+        assert!(parse_mode("8").is_err());
+        assert!(parse_mode("17777").is_err());
+    }
+
+    #[test]
+    fn gigabytes() {
+        use super::parse_gigabytes;
+        assert_eq!(parse_gigabytes("2"), Ok(2 << 30));
+        assert_eq!(parse_gigabytes("0.5"), Ok(1 << 29));
+        assert!(parse_gigabytes("0").is_err());
+        assert!(parse_gigabytes("x").is_err());
+    }
+
+    #[test]
+    fn pack_compression() {
+        use clap::Parser;
+        let args = ["git-mirror", "-g", "group", "--pack-compression"];
+        let opt = Opt::try_parse_from(args.iter().chain(&["9"])).unwrap();
+        assert_eq!(opt.pack_compression, Some(9));
+        assert!(Opt::try_parse_from(args.iter().chain(&["10"])).is_err());
+    }
+
+    #[test]
+    fn git_protocol() {
+        use clap::Parser;
+        let args = ["git-mirror", "-g", "group", "--git-protocol"];
+        let opt = Opt::try_parse_from(args.iter().chain(&["2"])).unwrap();
+        assert_eq!(opt.git_protocol, Some(2));
+        assert!(Opt::try_parse_from(args.iter().chain(&["3"])).is_err());
+    }
+
+    #[test]
+    fn git_insecure() {
+        use clap::Parser;
+        let opt = Opt::try_parse_from(["git-mirror", "-g", "group", "--git-insecure"]).unwrap();
+        assert_eq!(opt.git_insecure, [""]);
+        let args = [
+            "git-mirror",
+            "-g",
+            "group",
+            "--git-insecure=https://a.example.com/",
+            "--git-insecure=https://b.example.com/",
+        ];
+        let opt = Opt::try_parse_from(args).unwrap();
+        assert_eq!(
+            opt.git_insecure,
+            ["https://a.example.com/", "https://b.example.com/"]
+        );
+    }
+
+    #[test]
+    fn dest_rewrite_order() {
+        use clap::{CommandFactory, FromArgMatches};
+        let args = [
+            "git-mirror",
+            "-g",
+            "group",
+            "--dest-rewrite-regex=^git@([^:]+):=https://$1/",
+            "--dest-rewrite=https://old.example.com/=https://new.example.com/",
+        ];
+        let matches = Opt::command().get_matches_from(args);
+        let opt = Opt::from_arg_matches(&matches).unwrap();
+        let rewrites = super::dest_rewrites(&opt, &matches);
+        assert_eq!(
+            git_mirror::rewrite::rewrite_destination(&rewrites, "git@old.example.com:a/b.git"),
+            "https://new.example.com/a/b.git"
+        );
+    }
+
     #[test]
     fn verify_app() {
         use clap::CommandFactory;
@@ -0,0 +1,371 @@
+/*
+ * Copyright (c) 2023 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use reqwest::header::CONTENT_TYPE;
+use time::format_description::well_known::Rfc2822;
+use time::OffsetDateTime;
+
+use crate::summary::{JobStatus, Summary};
+use crate::GitFailureKind;
+
+const DEFAULT_SMTP_PORT: u16 = 25;
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Failed jobs listed in a notification, the others are only counted
+const MAX_LISTED: usize = 50;
+
+/// Longest error message of a job in a notification
+const MAX_ERROR_LEN: usize = 500;
+
+/// SMTP relay the notifications are mailed through, without TLS or authentication
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Smtp {
+    addr: String,
+    from: String,
+    to: Vec<String>,
+}
+
+impl Smtp {
+    /// Relay at `host[:port]` sending from `from` to the recipients
+    pub fn new(relay: &str, from: String, to: Vec<String>) -> Result<Smtp, String> {
+        let addr = match relay.rsplit_once(':') {
+            Some((host, port)) if port.parse::<u16>().is_ok() && !host.is_empty() => {
+                relay.to_string()
+            }
+            Some(_) => return Err(format!("Invalid SMTP relay {relay}, expected host[:port]")),
+            None => format!("{relay}:{DEFAULT_SMTP_PORT}"),
+        };
+        Ok(Smtp { addr, from, to })
+    }
+}
+
+/// A failed job in the webhook payload
+#[derive(Serialize, Debug)]
+struct Failure<'a> {
+    repository: &'a str,
+    error: Option<&'a str>,
+    failure_kind: Option<GitFailureKind>,
+}
+
+/// Payload posted to the webhooks, `text` is what Slack and compatible chats show
+#[derive(Serialize, Debug)]
+struct Payload<'a> {
+    text: &'a str,
+    provider: &'a str,
+    summary: &'a Summary,
+    failed: Vec<Failure<'a>>,
+}
+
+/// Sends a summary of the runs with failures, failures to notify are only logged
+#[derive(Debug, Default)]
+pub struct Notifier {
+    webhooks: Vec<String>,
+    smtp: Option<Smtp>,
+    /// Also notify about runs without failures
+    always: bool,
+}
+
+impl Notifier {
+    pub fn new(webhooks: Vec<String>, smtp: Option<Smtp>, always: bool) -> Notifier {
+        Notifier {
+            webhooks,
+            smtp,
+            always,
+        }
+    }
+
+    /// Notify about the finished run of the provider with the label
+    pub(crate) fn run_finished(&self, label: &str, summary: &Summary) {
+        let failed = summary.failed > 0 || summary.error.is_some();
+        if !failed && !self.always {
+            debug!("No failures, not notifying");
+            return;
+        }
+        let (subject, text) = message(label, summary);
+        for url in self.webhooks.iter() {
+            match post(url, &payload(&text, label, summary)) {
+                Ok(()) => info!("Notified {}", redact(url)),
+                Err(e) => warn!("Unable to notify {} ({})", redact(url), e),
+            }
+        }
+        if let Some(ref smtp) = self.smtp {
+            match mail(smtp, &subject, &text) {
+                Ok(()) => info!("Mailed the notification to {}", smtp.to.join(", ")),
+                Err(e) => warn!("Unable to mail the notification via {} ({})", smtp.addr, e),
+            }
+        }
+    }
+}
+
+/// The URL without its path, Slack webhook URLs contain their secret there
+fn redact(url: &str) -> String {
+    match reqwest::Url::parse(url) {
+        Ok(u) => format!("{}://{}", u.scheme(), u.host_str().unwrap_or_default()),
+        Err(_) => "webhook".to_string(),
+    }
+}
+
+fn truncate(s: &str, max: usize) -> String {
+    match s.char_indices().nth(max) {
+        Some((i, _)) => format!("{}...", &s[..i]),
+        None => s.to_string(),
+    }
+}
+
+/// Subject line and text of the notification
+fn message(label: &str, summary: &Summary) -> (String, String) {
+    let subject = match summary.error {
+        Some(ref error) if summary.failed == 0 => {
+            format!("git-mirror {label}: run failed ({error})")
+        }
+        _ if summary.failed > 0 => format!(
+            "git-mirror {label}: {} of {} repositories failed",
+            summary.failed, summary.total
+        ),
+        _ => format!(
+            "git-mirror {label}: {} of {} repositories synced",
+            summary.success, summary.total
+        ),
+    };
+    let mut text = format!(
+        "{subject}\n\n{} synced, {} skipped, {} failed in {}\n",
+        summary.success,
+        summary.skipped,
+        summary.failed,
+        humantime::format_duration(Duration::from_secs(summary.duration_secs as u64))
+    );
+    let failed: Vec<_> = summary
+        .jobs
+        .iter()
+        .filter(|j| j.result == JobStatus::Failed)
+        .collect();
+    if !failed.is_empty() {
+        text.push('\n');
+    }
+    for job in failed.iter().take(MAX_LISTED) {
+        let error = job.error.as_deref().unwrap_or("failed");
+        let error = truncate(error.lines().next().unwrap_or_default(), MAX_ERROR_LEN);
+        text.push_str(&format!("- {}: {}\n", job.name, error));
+    }
+    if failed.len() > MAX_LISTED {
+        text.push_str(&format!("- and {} more\n", failed.len() - MAX_LISTED));
+    }
+    (subject, text)
+}
+
+fn payload<'a>(text: &'a str, label: &'a str, summary: &'a Summary) -> Payload<'a> {
+    Payload {
+        text,
+        provider: label,
+        summary,
+        failed: summary
+            .jobs
+            .iter()
+            .filter(|j| j.result == JobStatus::Failed)
+            .map(|j| Failure {
+                repository: &j.name,
+                error: j.error.as_deref(),
+                failure_kind: j.failure_kind,
+            })
+            .collect(),
+    }
+}
+
+fn post(url: &str, payload: &Payload) -> Result<(), String> {
+    let client = reqwest::blocking::Client::builder()
+        .timeout(TIMEOUT)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let res = client
+        .post(url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::to_string(payload).expect("Unable to serialize notification"))
+        .send()
+        .map_err(|e| e.without_url().to_string())?;
+    if !res.status().is_success() {
+        return Err(format!("received status {}", res.status()));
+    }
+    Ok(())
+}
+
+/// Read a reply of the SMTP server, failing unless its code starts with `expected`
+fn reply(conn: &mut BufReader<TcpStream>, expected: char) -> io::Result<()> {
+    loop {
+        let mut line = String::new();
+        if conn.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Connection closed",
+            ));
+        }
+        // Continued by `250-...`, ended by `250 ...`
+        if line.as_bytes().get(3) == Some(&b'-') {
+            continue;
+        }
+        if !line.starts_with(expected) {
+            return Err(io::Error::other(format!(
+                "Unexpected reply: {}",
+                line.trim()
+            )));
+        }
+        return Ok(());
+    }
+}
+
+fn command(conn: &mut BufReader<TcpStream>, command: &str, expected: char) -> io::Result<()> {
+    write!(conn.get_mut(), "{command}\r\n")?;
+    reply(conn, expected)
+}
+
+/// Mail the text to the recipients
+fn mail(smtp: &Smtp, subject: &str, text: &str) -> io::Result<()> {
+    let stream = TcpStream::connect(&smtp.addr)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut conn = BufReader::new(stream);
+    reply(&mut conn, '2')?;
+    command(&mut conn, "EHLO git-mirror", '2')?;
+    command(&mut conn, &format!("MAIL FROM:<{}>", smtp.from), '2')?;
+    for to in smtp.to.iter() {
+        command(&mut conn, &format!("RCPT TO:<{to}>"), '2')?;
+    }
+    command(&mut conn, "DATA", '3')?;
+
+    let date = OffsetDateTime::now_utc()
+        .format(&Rfc2822)
+        .unwrap_or_default();
+    let mut data = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\n\
+         MIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n",
+        smtp.from,
+        smtp.to.join(", "),
+        subject.replace(['\r', '\n'], " "),
+        date
+    );
+    for line in text.lines() {
+        // Lines starting with a dot are escaped by doubling it
+        if line.starts_with('.') {
+            data.push('.');
+        }
+        data.push_str(line);
+        data.push_str("\r\n");
+    }
+    data.push('.');
+    command(&mut conn, &data, '2')?;
+    command(&mut conn, "QUIT", '2')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::summary::JobReport;
+    use std::net::TcpListener;
+
+    fn summary() -> Summary {
+        let job = |name: &str, result, error: Option<&str>| JobReport {
+            name: name.to_string(),
+            source: None,
+            destination: None,
+            duration_secs: 1.0,
+            result,
+            error: error.map(str::to_string),
+            failure_kind: None,
+            skip_reason: None,
+            commits_fetched: None,
+            commits_pushed: None,
+            retries: 0,
+        };
+        Summary {
+            total: 2,
+            success: 1,
+            failed: 1,
+            duration_secs: 75.0,
+            jobs: vec![
+                job("a -> b", JobStatus::Success, None),
+                job("c -> d", JobStatus::Failed, Some("auth failed\ndetails")),
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn text() {
+        let (subject, text) = message("gitlab.com/group", &summary());
+        assert_eq!(
+            subject,
+            "git-mirror gitlab.com/group: 1 of 2 repositories failed"
+        );
+        assert_eq!(
+            text,
+            "git-mirror gitlab.com/group: 1 of 2 repositories failed\n\n\
+             1 synced, 0 skipped, 1 failed in 1m 15s\n\n\
+             - c -> d: auth failed\n"
+        );
+        assert_eq!(
+            redact("https://hooks.slack.com/services/T0/B0/secret"),
+            "https://hooks.slack.com"
+        );
+    }
+
+    #[test]
+    fn smtp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let relay = listener.local_addr().unwrap().to_string();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut conn = BufReader::new(stream);
+            conn.get_mut().write_all(b"220 relay\r\n").unwrap();
+            let mut received = String::new();
+            let mut data = false;
+            loop {
+                let mut line = String::new();
+                if conn.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                received.push_str(&line);
+                let answer: &[u8] = match line.trim_end() {
+                    "." if data => {
+                        data = false;
+                        b"250 queued\r\n"
+                    }
+                    _ if data => continue,
+                    "DATA" => {
+                        data = true;
+                        b"354 go ahead\r\n"
+                    }
+                    "EHLO git-mirror" => b"250-relay\r\n250 8BITMIME\r\n",
+                    "QUIT" => b"221 bye\r\n",
+                    _ => b"250 ok\r\n",
+                };
+                conn.get_mut().write_all(answer).unwrap();
+            }
+            received
+        });
+
+        let smtp = Smtp::new(
+            &relay,
+            "git-mirror@example.com".to_string(),
+            vec!["ops@example.com".to_string()],
+        )
+        .unwrap();
+        mail(&smtp, "Subject", "first\n.dot\n").unwrap();
+        let received = server.join().unwrap();
+        assert!(received.contains("RCPT TO:<ops@example.com>\r\n"));
+        assert!(received.contains("Subject: Subject\r\n"));
+        assert!(received.contains("\r\n\r\nfirst\r\n..dot\r\n.\r\nQUIT\r\n"));
+
+        assert_eq!(
+            Smtp::new("mail", String::new(), vec![]).unwrap().addr,
+            "mail:25"
+        );
+        assert!(Smtp::new("mail:smtp", String::new(), vec![]).is_err());
+    }
+}
@@ -0,0 +1,169 @@
+/*
+ * Copyright (c) 2023 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::summary::{JobReport, Summary};
+
+/// Progress of a run, reported to the callback of `MirrorOptions::progress`. The repositories
+/// are named `<origin> -> <destination>` as in the reports.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ProgressEvent {
+    /// The repository was listed and waits for a worker
+    Queued { repository: String },
+    /// The origin is fetched into the local repository
+    Fetching { repository: String },
+    /// The local repository is pushed to the destination
+    Pushing { repository: String },
+    /// The job succeeded or was skipped
+    Done(JobReport),
+    /// The job failed, after its retries
+    Failed(JobReport),
+    /// The run ended, always the last event of a run
+    Finished(Box<Summary>),
+}
+
+/// Cancels a run from another thread. Running jobs are finished, the ones not started yet are
+/// skipped.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Receives the progress of the runs. The callback is called from the worker threads.
+pub struct Progress {
+    callback: Box<dyn Fn(ProgressEvent) + Send + Sync>,
+    cancel: CancelToken,
+}
+
+impl Progress {
+    pub fn new(callback: impl Fn(ProgressEvent) + Send + Sync + 'static) -> Progress {
+        Progress {
+            callback: Box::new(callback),
+            cancel: CancelToken::default(),
+        }
+    }
+
+    /// Token to cancel the runs reporting to this callback
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    pub(crate) fn report(&self, event: ProgressEvent) {
+        (self.callback)(event)
+    }
+}
+
+/// Report the event built by `event`, if a callback is set
+pub(crate) fn report(progress: &Option<Progress>, event: impl FnOnce() -> ProgressEvent) {
+    if let Some(ref progress) = progress {
+        progress.report(event());
+    }
+}
+
+#[cfg(feature = "async")]
+pub use stream::{mirror_stream, MirrorStream};
+
+#[cfg(feature = "async")]
+mod stream {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use std::thread;
+
+    use futures_channel::mpsc::{self, UnboundedReceiver};
+    use futures_core::Stream;
+
+    use super::{CancelToken, Progress, ProgressEvent};
+    use crate::provider::Provider;
+    use crate::MirrorOptions;
+
+    /// Events of a run started by [`mirror_stream`], ending after `ProgressEvent::Finished`.
+    /// Dropping the stream cancels the run.
+    pub struct MirrorStream {
+        events: UnboundedReceiver<ProgressEvent>,
+        cancel: CancelToken,
+    }
+
+    impl MirrorStream {
+        /// Cancel the run, the events of the running jobs and the summary are still received
+        pub fn cancel(&self) {
+            self.cancel.cancel();
+        }
+    }
+
+    impl Stream for MirrorStream {
+        type Item = ProgressEvent;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            Pin::new(&mut self.events).poll_next(cx)
+        }
+    }
+
+    impl Drop for MirrorStream {
+        fn drop(&mut self) {
+            self.cancel.cancel();
+        }
+    }
+
+    /// Run a sync of all repositories of the provider on a thread of its own, streaming its
+    /// progress. Replaces the progress callback of `opts`.
+    pub fn mirror_stream(
+        provider: Box<dyn Provider + Send>,
+        mut opts: MirrorOptions,
+    ) -> MirrorStream {
+        let (tx, events) = mpsc::unbounded();
+        let progress = Progress::new(move |event| {
+            // The stream may have been dropped already
+            let _ = tx.unbounded_send(event);
+        });
+        let cancel = progress.cancel_token();
+        opts.progress = Some(progress);
+        thread::spawn(move || crate::do_mirror(provider, &opts));
+        MirrorStream { events, cancel }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn callback() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let received = events.clone();
+        let progress = Some(Progress::new(move |e| received.lock().unwrap().push(e)));
+        let queued = || ProgressEvent::Queued {
+            repository: "a -> b".to_string(),
+        };
+        report(&progress, queued);
+        report(&None, queued);
+        assert_eq!(*events.lock().unwrap(), vec![queued()]);
+        assert_eq!(
+            serde_json::to_string(&queued()).unwrap(),
+            r#"{"event":"queued","repository":"a -> b"}"#
+        );
+
+        let token = progress.as_ref().unwrap().cancel_token();
+        assert!(!progress.as_ref().unwrap().is_cancelled());
+        token.cancel();
+        assert!(progress.as_ref().unwrap().is_cancelled());
+    }
+}
@@ -0,0 +1,471 @@
+/*
+ * Copyright (c) 2023 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+// Used for error and debug logging
+use log::trace;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use reqwest::blocking::Client;
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION};
+use reqwest::StatusCode;
+
+use crate::provider::cache::{etag, Page};
+use crate::provider::{
+    ApiOptions, Desc, Mirror, MirrorError, MirrorResult, Provider, Transport, Visibility,
+};
+
+/// Projects of Bitbucket Cloud (`bitbucket.org`, API `https://api.bitbucket.org/2.0`) or of a
+/// Bitbucket Data Center server (API below `/rest/api/1.0`)
+pub struct Bitbucket {
+    /// URL of the Bitbucket Cloud API (ending with `/2.0`) or of the Bitbucket Data Center server
+    pub url: String,
+    /// Workspace (Bitbucket Cloud) or project key (Bitbucket Data Center)
+    pub group: String,
+    pub use_http: bool,
+    /// Convert the origins to this transport
+    pub origin_transport: Option<Transport>,
+    /// App password of `user` or else an access token, sent as bearer token
+    pub private_token: Option<String>,
+    /// Authenticate with the `private_token` as app password of this user
+    pub user: Option<String>,
+    pub api: ApiOptions,
+    /// Only list the repositories with this visibility
+    pub visibility: Visibility,
+}
+
+// Number of repositories per page to request, the maximum of both APIs
+const PER_PAGE: u8 = 100;
+
+#[derive(Deserialize, Debug)]
+struct Link {
+    name: String,
+    href: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Href {
+    href: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Branch {
+    name: String,
+}
+
+/// A repository from the Bitbucket Cloud API
+#[derive(Deserialize, Debug)]
+struct CloudRepo {
+    uuid: String,
+    full_name: String,
+    description: Option<String>,
+    #[serde(default)]
+    is_private: bool,
+    /// Size of the repository in bytes
+    size: Option<u64>,
+    mainbranch: Option<Branch>,
+    /// The repository a fork was created from
+    parent: Option<serde_json::Value>,
+    links: CloudLinks,
+}
+
+#[derive(Deserialize, Debug)]
+struct CloudLinks {
+    #[serde(default)]
+    clone: Vec<Link>,
+    html: Href,
+}
+
+/// A page of the Bitbucket Cloud API, followed by the `next` URL
+#[derive(Deserialize, Debug)]
+struct CloudPage {
+    values: Vec<CloudRepo>,
+    next: Option<String>,
+}
+
+/// A repository from the Bitbucket Data Center API
+#[derive(Deserialize, Debug)]
+struct ServerRepo {
+    id: u64,
+    slug: String,
+    description: Option<String>,
+    #[serde(default)]
+    public: bool,
+    /// Only returned by Bitbucket 8.0 and newer
+    #[serde(default)]
+    archived: bool,
+    /// The repository a fork was created from
+    origin: Option<serde_json::Value>,
+    project: ServerProject,
+    links: ServerLinks,
+}
+
+#[derive(Deserialize, Debug)]
+struct ServerProject {
+    key: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct ServerLinks {
+    #[serde(default)]
+    clone: Vec<Link>,
+    #[serde(rename = "self", default)]
+    web: Vec<Href>,
+}
+
+/// A page of the Bitbucket Data Center API, followed by `start=<nextPageStart>`
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ServerPage {
+    values: Vec<ServerRepo>,
+    #[serde(default = "crate::provider::bool_true")]
+    is_last_page: bool,
+    next_page_start: Option<u64>,
+}
+
+/// A listed repository of either API
+#[derive(Debug, PartialEq, Eq)]
+struct Repo {
+    /// `workspace/slug` or `KEY/slug`
+    name: String,
+    web_url: String,
+    description: String,
+    http_url: Option<String>,
+    ssh_url: Option<String>,
+    visibility: Visibility,
+    id: String,
+    size: Option<u64>,
+    default_branch: Option<String>,
+    archived: bool,
+    fork: bool,
+}
+
+/// The clone URL named `name`, without the name of the user Bitbucket adds to http(s) URLs
+fn clone_url(links: &[Link], name: &str) -> Option<String> {
+    let href = &links.iter().find(|l| l.name == name)?.href;
+    let url = match href.split_once("://") {
+        Some((scheme @ ("http" | "https"), rest)) => {
+            let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+            let host = authority.rsplit('@').next().unwrap_or(authority);
+            format!("{scheme}://{host}/{path}")
+        }
+        _ => href.clone(),
+    };
+    Some(url)
+}
+
+impl From<CloudRepo> for Repo {
+    fn from(r: CloudRepo) -> Repo {
+        Repo {
+            name: r.full_name,
+            web_url: r.links.html.href,
+            description: r.description.unwrap_or_default(),
+            http_url: clone_url(&r.links.clone, "https"),
+            ssh_url: clone_url(&r.links.clone, "ssh"),
+            visibility: if r.is_private {
+                Visibility::Private
+            } else {
+                Visibility::Public
+            },
+            id: format!("bitbucket:{}", r.uuid),
+            size: r.size,
+            default_branch: r.mainbranch.map(|b| b.name),
+            archived: false,
+            fork: r.parent.is_some(),
+        }
+    }
+}
+
+impl From<ServerRepo> for Repo {
+    fn from(r: ServerRepo) -> Repo {
+        Repo {
+            name: format!("{}/{}", r.project.key, r.slug),
+            web_url: r
+                .links
+                .web
+                .into_iter()
+                .next()
+                .map(|l| l.href)
+                .unwrap_or_default(),
+            description: r.description.unwrap_or_default(),
+            http_url: clone_url(&r.links.clone, "http"),
+            ssh_url: clone_url(&r.links.clone, "ssh"),
+            visibility: if r.public {
+                Visibility::Public
+            } else {
+                Visibility::Private
+            },
+            id: format!("bitbucket:{}", r.id),
+            size: None,
+            default_branch: None,
+            archived: r.archived,
+            fork: r.origin.is_some(),
+        }
+    }
+}
+
+/// Parse a page of the Bitbucket Cloud API, returning its repositories and the URL of the
+/// following page
+fn parse_cloud_page(body: &str) -> Result<(Vec<Repo>, Option<String>), String> {
+    let page: CloudPage = serde_json::from_str(body)
+        .map_err(|e| format!("Unable to parse response as JSON ({e:?})"))?;
+    Ok((page.values.into_iter().map(Repo::from).collect(), page.next))
+}
+
+/// Parse a page of the Bitbucket Data Center API, returning its repositories and the start of
+/// the following page
+fn parse_server_page(body: &str) -> Result<(Vec<Repo>, Option<u64>), String> {
+    let page: ServerPage = serde_json::from_str(body)
+        .map_err(|e| format!("Unable to parse response as JSON ({e:?})"))?;
+    let next = match page.is_last_page {
+        true => None,
+        false => page.next_page_start,
+    };
+    Ok((page.values.into_iter().map(Repo::from).collect(), next))
+}
+
+impl Bitbucket {
+    /// Whether the URL is the one of the Bitbucket Cloud API
+    fn cloud(&self) -> bool {
+        self.url.trim_end_matches('/').ends_with("/2.0")
+    }
+
+    fn api_url(&self) -> String {
+        let url = self.url.trim_end_matches('/');
+        match self.cloud() {
+            true => url.to_string(),
+            false => format!("{url}/rest/api/1.0"),
+        }
+    }
+
+    /// URL of the page of the Bitbucket Data Center listing starting at `start`
+    fn server_page_url(&self, start: u64) -> String {
+        format!(
+            "{}/projects/{}/repos?limit={}&start={}",
+            self.api_url(),
+            self.group,
+            PER_PAGE,
+            start
+        )
+    }
+
+    /// Headers for the API requests, with the credentials if set
+    fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+        let authorization = match (&self.user, &self.private_token) {
+            (Some(user), Some(password)) => {
+                format!("Basic {}", STANDARD.encode(format!("{user}:{password}")))
+            }
+            (None, Some(token)) => format!("Bearer {token}"),
+            (_, None) => return headers,
+        };
+        if let Ok(mut value) = HeaderValue::from_str(&authorization) {
+            value.set_sensitive(true);
+            headers.insert(AUTHORIZATION, value);
+        }
+        headers
+    }
+
+    /// Get the body of a listing page, from the listing cache if it is unchanged
+    fn get_page(&self, client: &Client, url: &str) -> Result<String, String> {
+        trace!("URL: {}", url);
+        let request = client.get(url).headers(self.headers());
+        let res = match self.api.listing_cache {
+            Some(ref cache) => cache.send(url, request, |r| self.api.send(r)),
+            None => self.api.send(request).map(Page::Fresh),
+        }
+        .map_err(|e| format!("Unable to connect to: {url} ({e})"))?;
+        match res {
+            Page::Cached(page) => Ok(page.body),
+            Page::Fresh(res) if res.status() == StatusCode::UNAUTHORIZED => Err(format!(
+                "API call received unautorized ({}) for: {}. \
+                 Please make sure the `PRIVATE_TOKEN` environment \
+                 variable (and `BITBUCKET_USER` for app passwords) is set.",
+                res.status(),
+                url
+            )),
+            Page::Fresh(res) if res.status() != StatusCode::OK => Err(format!(
+                "API call received invalid status ({}) for : {}",
+                res.status(),
+                url
+            )),
+            Page::Fresh(res) => {
+                let etag = etag(&res);
+                let body = res
+                    .text()
+                    .map_err(|e| format!("Unable to read response of {url} ({e})"))?;
+                // The following page is part of the body
+                if let Some(ref cache) = self.api.listing_cache {
+                    cache.store(url, etag.as_ref(), None, &body);
+                }
+                Ok(body)
+            }
+        }
+    }
+
+    /// List all repositories of the workspace or project, following the pages
+    fn list(&self) -> Result<Vec<Repo>, String> {
+        let client = self.api.client()?;
+        let mut repos = Vec::new();
+        if self.cloud() {
+            let mut next = Some(format!(
+                "{}/repositories/{}?pagelen={}",
+                self.api_url(),
+                self.group,
+                PER_PAGE
+            ));
+            while let Some(url) = next {
+                let (page, following) = parse_cloud_page(&self.get_page(&client, &url)?)?;
+                repos.extend(page);
+                next = following;
+            }
+        } else {
+            let mut next = Some(0);
+            while let Some(start) = next {
+                let url = self.server_page_url(start);
+                let (page, following) = parse_server_page(&self.get_page(&client, &url)?)?;
+                repos.extend(page);
+                next = following;
+            }
+        }
+        self.api.save_listing_cache();
+        Ok(repos)
+    }
+}
+
+impl Provider for Bitbucket {
+    fn get_label(&self) -> String {
+        match self.cloud() {
+            true => format!("{}/repositories/{}", self.api_url(), self.group),
+            false => format!("{}/projects/{}", self.api_url(), self.group),
+        }
+    }
+
+    fn get_mirror_repos(&self) -> Result<Vec<MirrorResult>, String> {
+        let mut mirrors: Vec<MirrorResult> = Vec::new();
+
+        for r in self.list()? {
+            if !self.visibility.includes(Some(r.visibility)) {
+                trace!("Visibility doesn't match: {}", r.web_url);
+                continue;
+            }
+            match Desc::parse(&r.web_url, &r.description) {
+                Ok(desc) => {
+                    if desc.disabled() {
+                        mirrors.push(Err(MirrorError::Skip(r.web_url)));
+                        continue;
+                    }
+                    let destination = if desc.dest_http(self.use_http) {
+                        r.http_url
+                    } else {
+                        r.ssh_url
+                    };
+                    let destination = match destination {
+                        Some(d) => d,
+                        None => {
+                            trace!("No clone URL for the transport: {}", r.web_url);
+                            continue;
+                        }
+                    };
+                    trace!("{0} -> {1}", desc.origin, destination);
+                    let (refspec, prune_refs) = desc.refspec();
+                    mirrors.push(Ok(Mirror {
+                        origin: desc.origin_url(self.origin_transport),
+                        destination,
+                        refspec,
+                        prune_refs,
+                        lfs: desc.lfs,
+                        // The wikis of Bitbucket Cloud aren't at `<repo>.wiki.git`
+                        has_wiki: false,
+                        wiki: false,
+                        dest_token: desc.dest_token,
+                        project: Some(r.name),
+                        visibility: Some(r.visibility),
+                        id: Some(r.id),
+                        subtree_prefix: None,
+                        size: r.size,
+                        stars: None,
+                        forks: None,
+                        default_branch: r.default_branch,
+                        archived: r.archived,
+                        fork: r.fork,
+                        // Of the repository with the description, not of the origin
+                        activity: None,
+                    }));
+                }
+                Err(e) => {
+                    mirrors.push(Err(e));
+                }
+            }
+        }
+        Ok(mirrors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cloud_page() {
+        let body = r#"{"pagelen": 1, "values": [{"uuid": "{1f2e}", "full_name": "ws/app",
+            "description": "origin: https://example.org/app.git", "is_private": true,
+            "size": 2048, "mainbranch": {"name": "main", "type": "branch"},
+            "links": {"html": {"href": "https://bitbucket.org/ws/app"}, "clone": [
+                {"name": "https", "href": "https://alice@bitbucket.org/ws/app.git"},
+                {"name": "ssh", "href": "git@bitbucket.org:ws/app.git"}]}}],
+            "next": "https://api.bitbucket.org/2.0/repositories/ws?pagelen=1&page=2"}"#;
+        let (repos, next) = parse_cloud_page(body).unwrap();
+        assert_eq!(
+            next.as_deref(),
+            Some("https://api.bitbucket.org/2.0/repositories/ws?pagelen=1&page=2")
+        );
+        assert_eq!(
+            repos,
+            vec![Repo {
+                name: "ws/app".to_string(),
+                web_url: "https://bitbucket.org/ws/app".to_string(),
+                description: "origin: https://example.org/app.git".to_string(),
+                http_url: Some("https://bitbucket.org/ws/app.git".to_string()),
+                ssh_url: Some("git@bitbucket.org:ws/app.git".to_string()),
+                visibility: Visibility::Private,
+                id: "bitbucket:{1f2e}".to_string(),
+                size: Some(2048),
+                default_branch: Some("main".to_string()),
+                archived: false,
+                fork: false,
+            }]
+        );
+        let (_, next) = parse_cloud_page(r#"{"values": []}"#).unwrap();
+        assert_eq!(next, None);
+    }
+
+    #[test]
+    fn server_page() {
+        let body = r#"{"size": 1, "limit": 1, "isLastPage": false, "nextPageStart": 1,
+            "values": [{"id": 7, "slug": "app", "public": false, "archived": true,
+            "origin": {"id": 3}, "project": {"key": "LEG"}, "links": {
+                "self": [{"href": "https://bb.example.com/projects/LEG/repos/app/browse"}],
+                "clone": [{"name": "http", "href": "https://bb.example.com/scm/leg/app.git"},
+                    {"name": "ssh", "href": "ssh://git@bb.example.com:7999/leg/app.git"}]}}]}"#;
+        let (repos, next) = parse_server_page(body).unwrap();
+        assert_eq!(next, Some(1));
+        assert_eq!(repos[0].name, "LEG/app");
+        assert_eq!(
+            repos[0].web_url,
+            "https://bb.example.com/projects/LEG/repos/app/browse"
+        );
+        assert_eq!(
+            repos[0].ssh_url.as_deref(),
+            Some("ssh://git@bb.example.com:7999/leg/app.git")
+        );
+        assert_eq!(repos[0].description, "");
+        assert!(repos[0].archived && repos[0].fork);
+        let (_, next) = parse_server_page(r#"{"values": [], "isLastPage": true}"#).unwrap();
+        assert_eq!(next, None);
+    }
+}
@@ -0,0 +1,206 @@
+/*
+ * Copyright (c) 2023 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use log::{debug, warn};
+use reqwest::blocking::{RequestBuilder, Response};
+use reqwest::header::{HeaderValue, ETAG, IF_NONE_MATCH};
+use reqwest::StatusCode;
+
+/// An API response, kept with its ETag to be reused when the server answers `304 Not Modified`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CachedPage {
+    pub etag: String,
+    /// URL of the following page
+    pub next: Option<String>,
+    pub body: String,
+}
+
+struct Pages {
+    pages: BTreeMap<String, CachedPage>,
+    /// URLs requested since the last save, the others are dropped when saving
+    requested: BTreeSet<String>,
+}
+
+/// Listing pages by URL, stored in a file between the runs
+pub struct ListingCache {
+    file: PathBuf,
+    pages: Mutex<Pages>,
+}
+
+impl fmt::Debug for ListingCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ListingCache")
+            .field("file", &self.file)
+            .finish()
+    }
+}
+
+/// Answer to a request sent with the cached ETag
+pub enum Page {
+    /// Unchanged since it was cached
+    Cached(CachedPage),
+    Fresh(Response),
+}
+
+impl ListingCache {
+    /// Load the cache from `file`, a missing or unreadable file is an empty cache
+    pub fn open(file: &Path) -> ListingCache {
+        let pages = match fs::read_to_string(file) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                warn!("Ignoring invalid listing cache {:?} ({})", file, e);
+                BTreeMap::new()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                warn!("Unable to read listing cache {:?} ({})", file, e);
+                BTreeMap::new()
+            }
+        };
+        ListingCache {
+            file: file.to_owned(),
+            pages: Mutex::new(Pages {
+                pages,
+                requested: BTreeSet::new(),
+            }),
+        }
+    }
+
+    /// Send the request for `url` with `send`, with the ETag of the cached page if there is one
+    pub fn send(
+        &self,
+        url: &str,
+        mut request: RequestBuilder,
+        send: impl FnOnce(RequestBuilder) -> reqwest::Result<Response>,
+    ) -> reqwest::Result<Page> {
+        let cached = {
+            let mut pages = self.pages.lock().unwrap_or_else(|e| e.into_inner());
+            pages.requested.insert(url.to_owned());
+            pages.pages.get(url).cloned()
+        };
+        if let Some(ref page) = cached {
+            if let Ok(etag) = HeaderValue::from_str(&page.etag) {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+        }
+        let res = send(request)?;
+        match cached {
+            Some(page) if res.status() == StatusCode::NOT_MODIFIED => {
+                debug!("Unchanged since the last listing: {}", url);
+                Ok(Page::Cached(page))
+            }
+            _ => Ok(Page::Fresh(res)),
+        }
+    }
+
+    /// Remember the page received for `url`, pages without ETag can't be reused
+    pub fn store(&self, url: &str, etag: Option<&HeaderValue>, next: Option<String>, body: &str) {
+        let mut pages = self.pages.lock().unwrap_or_else(|e| e.into_inner());
+        match etag.and_then(|e| e.to_str().ok()) {
+            Some(etag) => {
+                pages.pages.insert(
+                    url.to_owned(),
+                    CachedPage {
+                        etag: etag.to_owned(),
+                        next,
+                        body: body.to_owned(),
+                    },
+                );
+            }
+            None => {
+                pages.pages.remove(url);
+            }
+        }
+    }
+
+    /// Write the pages requested since the last save to the file
+    pub fn save(&self) -> Result<(), String> {
+        let mut pages = self.pages.lock().unwrap_or_else(|e| e.into_inner());
+        let requested = std::mem::take(&mut pages.requested);
+        pages.pages.retain(|url, _| requested.contains(url));
+        let content = serde_json::to_string(&pages.pages)
+            .map_err(|e| format!("Unable to serialize listing cache ({e})"))?;
+        write_private(&self.file, &content)
+            .map_err(|e| format!("Unable to write listing cache {:?} ({e})", self.file))
+    }
+}
+
+/// Get the ETag of a response, to store the page with
+pub fn etag(res: &Response) -> Option<HeaderValue> {
+    res.headers().get(ETAG).cloned()
+}
+
+/// Write to a temporary file only readable by the owner and rename it, the descriptions in the
+/// responses can contain secrets
+fn write_private(file: &Path, content: &str) -> io::Result<()> {
+    let mut tmp = file.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(&tmp)?.write_all(content.as_bytes())?;
+    fs::rename(&tmp, file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_requested_pages() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("listing-cache.json");
+        let cache = ListingCache::open(&file);
+        let etag = HeaderValue::from_static("W/\"1\"");
+        for url in ["https://gitlab.com/1", "https://gitlab.com/2"] {
+            cache
+                .pages
+                .lock()
+                .unwrap()
+                .requested
+                .insert(url.to_string());
+            cache.store(url, Some(&etag), None, "[]");
+        }
+        // Without ETag
+        cache
+            .pages
+            .lock()
+            .unwrap()
+            .requested
+            .insert("https://gitlab.com/3".to_string());
+        cache.store("https://gitlab.com/3", None, None, "[]");
+        cache.save().unwrap();
+
+        let cache = ListingCache::open(&file);
+        {
+            let mut pages = cache.pages.lock().unwrap();
+            assert_eq!(
+                pages.pages.keys().collect::<Vec<_>>(),
+                ["https://gitlab.com/1", "https://gitlab.com/2"]
+            );
+            assert_eq!(pages.pages["https://gitlab.com/1"].etag, "W/\"1\"");
+            pages.requested.insert("https://gitlab.com/2".to_string());
+        }
+        // Only the pages requested since the last save are kept
+        cache.save().unwrap();
+        let cache = ListingCache::open(&file);
+        assert_eq!(
+            cache.pages.lock().unwrap().pages.keys().collect::<Vec<_>>(),
+            ["https://gitlab.com/2"]
+        );
+    }
+}
@@ -0,0 +1,135 @@
+/*
+ * Copyright (c) 2023 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+// Used for error and debug logging
+use log::trace;
+
+use crate::provider::{
+    GitHub, GitLab, Mirror, MirrorResult, Provider, PruneRemote, RepoMetadata, Secret,
+};
+
+/// A repository on the destination instance
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DestRepo {
+    /// URL to push to
+    pub url: String,
+    /// Identifier of the repository in the API of the destination, see [`Mirror::project`]
+    pub project: String,
+}
+
+/// Instance the repositories are mirrored to with `--dest-provider`, with the group or
+/// organization they are created in
+pub enum Destination {
+    GitLab(GitLab),
+    GitHub(GitHub),
+}
+
+impl Destination {
+    fn provider(&self) -> &dyn Provider {
+        match self {
+            Destination::GitLab(gitlab) => gitlab,
+            Destination::GitHub(github) => github,
+        }
+    }
+
+    /// The repository for `path` below the group of the origins
+    fn repo(&self, path: &str) -> DestRepo {
+        match self {
+            Destination::GitLab(gitlab) => gitlab.dest_repo(path),
+            Destination::GitHub(github) => github.dest_repo(path),
+        }
+    }
+
+    /// Create the repository `project` as private repository if it doesn't exist yet
+    fn create(&self, project: &str) -> Result<(), String> {
+        match self {
+            Destination::GitLab(gitlab) => gitlab.create_project(project),
+            Destination::GitHub(github) => github.create_repo(project),
+        }
+    }
+
+    /// Archive or delete the repository `project`. Only repositories in the group are touched,
+    /// local repositories of an earlier setup can refer to other projects.
+    fn prune(&self, project: &str, how: PruneRemote) -> Result<(), String> {
+        let (group, prefix) = match self {
+            Destination::GitLab(gitlab) => (&gitlab.group, format!("{}%2F", gitlab.group)),
+            Destination::GitHub(github) => (&github.org, format!("{}/", github.org)),
+        };
+        if !project.starts_with(&prefix) {
+            return Err(format!("{project} is not a repository of {group}"));
+        }
+        match self {
+            Destination::GitLab(gitlab) => gitlab.prune_project(project, how),
+            Destination::GitHub(github) => github.prune_repo(project, how),
+        }
+    }
+}
+
+/// Provider mirroring all repositories listed by `source` as origins to `dest`, creating
+/// the destination repositories before they are pushed to
+pub struct DestProvider {
+    /// Lists the origins, with the path below its group as destination
+    pub source: Box<dyn Provider>,
+    pub dest: Destination,
+    /// Token used to push to http(s) destinations
+    pub token: Option<String>,
+}
+
+impl DestProvider {
+    fn to_dest(&self, result: MirrorResult) -> MirrorResult {
+        result.map(|mut m| {
+            let repo = self.dest.repo(&m.destination);
+            trace!("{0} -> {1}", m.origin, repo.url);
+            m.destination = repo.url;
+            m.project = Some(repo.project);
+            m.dest_token = self.token.clone().map(Secret);
+            m
+        })
+    }
+}
+
+impl Provider for DestProvider {
+    fn get_label(&self) -> String {
+        self.source.get_label()
+    }
+
+    fn get_mirror_repos(&self) -> Result<Vec<MirrorResult>, String> {
+        Ok(self
+            .source
+            .get_mirror_repos()?
+            .into_iter()
+            .map(|m| self.to_dest(m))
+            .collect())
+    }
+
+    fn stream_mirror_repos(&self, f: &mut dyn FnMut(MirrorResult)) -> Result<(), String> {
+        self.source.stream_mirror_repos(&mut |m| f(self.to_dest(m)))
+    }
+
+    fn create_destination(&self, mirror: &Mirror) -> Result<(), String> {
+        // The wiki belongs to the repository of the project
+        match mirror.project {
+            Some(ref project) => self.dest.create(project),
+            None => Ok(()),
+        }
+    }
+
+    fn prune_destination(&self, project: &str, how: PruneRemote) -> Result<(), String> {
+        self.dest.prune(project, how)
+    }
+
+    fn set_default_branch(&self, mirror: &Mirror, branch: &str) -> Result<(), String> {
+        self.dest.provider().set_default_branch(mirror, branch)
+    }
+
+    fn get_origin_metadata(&self, mirror: &Mirror) -> Result<RepoMetadata, String> {
+        self.source.get_origin_metadata(mirror)
+    }
+
+    fn set_metadata(&self, mirror: &Mirror, metadata: &RepoMetadata) -> Result<(), String> {
+        self.dest.provider().set_metadata(mirror, metadata)
+    }
+}
@@ -0,0 +1,254 @@
+/*
+ * Copyright (c) 2023 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::process::Command;
+
+// Used for error and debug logging
+use log::{debug, trace};
+
+use crate::provider::{
+    bool_true, select_refspec, unix_time, Mirror, MirrorError, MirrorResult, Provider, Secret,
+    TopicFilter, Visibility,
+};
+
+/// Provider getting the repositories from the output of an external command
+#[derive(Debug)]
+pub struct ExternalCommand {
+    pub command: String,
+    /// Only use the entries with matching topics
+    pub topics: TopicFilter,
+    /// Only use the entries with this visibility
+    pub visibility: Visibility,
+}
+
+/// A single line of the command output
+#[derive(Deserialize, Debug)]
+struct Entry {
+    origin: String,
+    destination: String,
+    #[serde(default)]
+    skip: bool,
+    #[serde(default = "bool_true")]
+    enabled: bool,
+    refspec: Option<Vec<String>>,
+    branches: Option<Vec<String>>,
+    tags: Option<Vec<String>>,
+    #[serde(default = "bool_true")]
+    lfs: bool,
+    #[serde(default)]
+    has_wiki: bool,
+    dest_token: Option<Secret>,
+    #[serde(default)]
+    topics: Vec<String>,
+    visibility: Option<Visibility>,
+    id: Option<String>,
+    subtree_prefix: Option<String>,
+    /// In bytes
+    size: Option<u64>,
+    stars: Option<u64>,
+    forks: Option<u64>,
+    #[serde(default)]
+    archived: bool,
+    #[serde(default)]
+    fork: bool,
+    /// RFC 3339
+    pushed_at: Option<String>,
+}
+
+impl ExternalCommand {
+    fn shell_cmd(&self) -> Command {
+        if cfg!(windows) {
+            let mut cmd = Command::new("cmd");
+            cmd.arg("/C").arg(&self.command);
+            cmd
+        } else {
+            let mut cmd = Command::new("sh");
+            cmd.arg("-c").arg(&self.command);
+            cmd
+        }
+    }
+}
+
+/// Parse the JSON lines output of the command. Empty lines are ignored.
+fn parse_output(
+    output: &str,
+    topics: &TopicFilter,
+    visibility: Visibility,
+) -> Result<Vec<MirrorResult>, String> {
+    let mut mirrors: Vec<MirrorResult> = Vec::new();
+
+    for (i, line) in output.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let e: Entry = serde_json::from_str(line)
+            .map_err(|e| format!("Invalid entry on line {}: {} ({})", i + 1, line, e))?;
+        if !topics.matches(&e.topics) || !visibility.includes(e.visibility) {
+            trace!("Topics or visibility don't match: {}", e.origin);
+            continue;
+        }
+        if e.skip || !e.enabled {
+            mirrors.push(Err(MirrorError::Skip(e.origin)));
+            continue;
+        }
+        trace!("{0} -> {1}", e.origin, e.destination);
+        let (refspec, prune_refs) = select_refspec(&e.refspec, &e.branches, &e.tags);
+        mirrors.push(Ok(Mirror {
+            origin: e.origin,
+            destination: e.destination,
+            refspec,
+            prune_refs,
+            lfs: e.lfs,
+            has_wiki: e.has_wiki,
+            wiki: false,
+            dest_token: e.dest_token,
+            project: None,
+            visibility: e.visibility,
+            id: e.id,
+            subtree_prefix: e.subtree_prefix,
+            size: e.size,
+            stars: e.stars,
+            forks: e.forks,
+            default_branch: None,
+            archived: e.archived,
+            fork: e.fork,
+            activity: unix_time(e.pushed_at.as_deref()),
+        }));
+    }
+
+    Ok(mirrors)
+}
+
+impl Provider for ExternalCommand {
+    fn get_label(&self) -> String {
+        format!("external/{}", self.command)
+    }
+
+    fn get_mirror_repos(&self) -> Result<Vec<MirrorResult>, String> {
+        let mut cmd = self.shell_cmd();
+        debug!("Run provider command: {:?}", cmd);
+
+        let output = cmd
+            .output()
+            .map_err(|e| format!("Unable to run provider command: {} ({})", self.command, e))?;
+
+        if !output.status.success() {
+            return Err(format!(
+                "Provider command {} failed with exit code: {}, Stderr: {}",
+                self.command,
+                output.status.code().unwrap_or_default(),
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+
+        parse_output(
+            &String::from_utf8_lossy(&output.stdout),
+            &self.topics,
+            self.visibility,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_output;
+    use crate::provider::{MirrorError, TopicFilter, TopicMatch, Visibility};
+
+    #[test]
+    fn parse_entries() {
+        let output = r#"{"origin": "https://example.com/a.git", "destination": "git@example.org:a.git"}
+
+{"origin": "https://example.com/b.git", "destination": "git@example.org:b.git", "refspec": ["main"], "lfs": false}
+{"origin": "https://example.com/c.git", "destination": "", "skip": true}
+"#;
+        let mirrors = parse_output(output, &TopicFilter::default(), Visibility::All).unwrap();
+        assert_eq!(mirrors.len(), 3);
+
+        let a = mirrors[0].as_ref().unwrap();
+        assert_eq!(a.destination, "git@example.org:a.git");
+        assert!(a.lfs);
+        assert_eq!(a.refspec, None);
+
+        let b = mirrors[1].as_ref().unwrap();
+        assert_eq!(b.refspec, Some(vec!["main".to_owned()]));
+        assert!(!b.lfs);
+
+        assert!(mirrors[2].is_err());
+    }
+
+    #[test]
+    fn malformed_line() {
+        let output = r#"{"origin": "https://example.com/a.git", "destination": "a"}
+{"origin": "https://example.com/b.git"}
+"#;
+        let err = parse_output(output, &TopicFilter::default(), Visibility::All).unwrap_err();
+        assert!(err.starts_with("Invalid entry on line 2:"), "{}", err);
+    }
+
+    #[test]
+    fn topics() {
+        let output = r#"{"origin": "a", "destination": "a", "topics": ["mirror", "backup"]}
+{"origin": "b", "destination": "b", "topics": ["Mirror"]}
+{"origin": "c", "destination": "c"}
+"#;
+        let origins = |mode| {
+            let filter = TopicFilter {
+                topics: vec!["mirror".to_owned(), "backup".to_owned()],
+                mode,
+            };
+            parse_output(output, &filter, Visibility::All)
+                .unwrap()
+                .into_iter()
+                .map(|m| m.unwrap().origin)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(origins(TopicMatch::Any), ["a", "b"]);
+        assert_eq!(origins(TopicMatch::All), ["a"]);
+        assert_eq!(
+            parse_output(output, &TopicFilter::default(), Visibility::All)
+                .unwrap()
+                .len(),
+            3
+        );
+    }
+
+    #[test]
+    fn disabled() {
+        let output = r#"{"origin": "a", "destination": "a", "enabled": false, "topics": ["mirror"]}
+{"origin": "b", "destination": "b", "enabled": false}
+{"origin": "c", "destination": "c", "skip": true, "topics": ["mirror"]}
+{"origin": "d", "destination": "d", "topics": ["mirror"]}
+"#;
+        // Disabled entries are only reported if selected by the filters
+        let filter = TopicFilter {
+            topics: vec!["mirror".to_owned()],
+            mode: TopicMatch::Any,
+        };
+        let mirrors = parse_output(output, &filter, Visibility::All).unwrap();
+        assert_eq!(mirrors.len(), 3);
+        assert!(matches!(&mirrors[0], Err(MirrorError::Skip(url)) if url == "a"));
+        assert!(matches!(&mirrors[1], Err(MirrorError::Skip(url)) if url == "c"));
+        assert_eq!(mirrors[2].as_ref().unwrap().origin, "d");
+    }
+
+    #[test]
+    fn visibility() {
+        let output = r#"{"origin": "a", "destination": "a", "visibility": "private"}
+{"origin": "b", "destination": "b", "visibility": "public"}
+{"origin": "c", "destination": "c"}
+"#;
+        let origins = |visibility| {
+            parse_output(output, &TopicFilter::default(), visibility)
+                .unwrap()
+                .into_iter()
+                .map(|m| m.unwrap().origin)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(origins(Visibility::Private), ["a"]);
+        assert_eq!(origins(Visibility::Internal), Vec::<String>::new());
+        assert_eq!(origins(Visibility::All), ["a", "b", "c"]);
+    }
+}
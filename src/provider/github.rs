@@ -5,30 +5,249 @@
  */
 
 // Used for error and debug logging
-use log::trace;
+use log::{info, trace};
+
+use std::path::Path;
 
 // Used for github API access via HTTPS
-use reqwest::blocking::Client;
-use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, USER_AGENT};
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_TYPE};
 use reqwest::StatusCode;
 
-use crate::provider::{Desc, Mirror, MirrorError, MirrorResult, Provider};
+use crate::provider::cache::{etag, Page};
+use crate::provider::dest::DestRepo;
+use crate::provider::github_app::GitHubApp;
+use crate::provider::{
+    api_host, download, hosted_path, next_link, unix_time, ApiOptions, Asset, Desc, Mirror,
+    MirrorError, MirrorResult, Provider, PruneRemote, Release, RepoMetadata, Secret, TopicFilter,
+    Transport, Visibility,
+};
 
 pub struct GitHub {
     pub url: String,
     pub org: String,
     pub use_http: bool,
+    /// Convert the origins to this transport
+    pub origin_transport: Option<Transport>,
     pub private_token: Option<String>,
-    pub useragent: String,
+    /// Authenticate as GitHub App installation instead of with `private_token`, also for
+    /// pushing to http(s) destinations
+    pub app: Option<GitHubApp>,
+    pub api: ApiOptions,
+    /// Only list the repositories with matching topics
+    pub topics: TopicFilter,
+    /// Only list the repositories with this visibility
+    pub visibility: Visibility,
+    /// List the repositories themselves as origins, with their name as destination, instead
+    /// of reading the origin from the description (`--dest-provider`)
+    pub list_origins: bool,
 }
 
-/// A project from the GitLab API
+// Number of repositories per page to request
+const PER_PAGE: u8 = 100;
+
+/// A project from the GitHub API
 #[derive(Deserialize, Debug)]
 struct Project {
+    id: Option<u64>,
+    full_name: String,
     description: Option<String>,
     url: String,
     ssh_url: String,
     clone_url: String,
+    #[serde(default)]
+    has_wiki: bool,
+    #[serde(default)]
+    topics: Vec<String>,
+    #[serde(default)]
+    private: bool,
+    /// Only returned by newer versions, the only way to tell `internal` repositories apart
+    visibility: Option<Visibility>,
+    /// Size of the repository in KiB
+    size: Option<u64>,
+    stargazers_count: Option<u64>,
+    forks_count: Option<u64>,
+    default_branch: Option<String>,
+    #[serde(default)]
+    archived: bool,
+    #[serde(default)]
+    fork: bool,
+    pushed_at: Option<String>,
+}
+
+impl Project {
+    fn visibility(&self) -> Visibility {
+        match self.visibility {
+            Some(v) => v,
+            None if self.private => Visibility::Private,
+            None => Visibility::Public,
+        }
+    }
+}
+
+/// The settings of a repository copied by `--sync-metadata`
+#[derive(Deserialize, Debug)]
+struct RepoSettings {
+    #[serde(default)]
+    topics: Vec<String>,
+    default_branch: Option<String>,
+}
+
+/// A release from the GitHub API
+#[derive(Deserialize, Debug)]
+struct ApiRelease {
+    tag_name: String,
+    #[serde(default)]
+    assets: Vec<ApiAsset>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ApiAsset {
+    name: String,
+    /// API URL of the asset, works for private repositories as well
+    url: String,
+    size: u64,
+}
+
+impl From<ApiRelease> for Release {
+    fn from(r: ApiRelease) -> Release {
+        Release {
+            tag: r.tag_name,
+            assets: r
+                .assets
+                .into_iter()
+                .map(|a| Asset {
+                    name: a.name,
+                    url: a.url,
+                    size: Some(a.size),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Host of the repositories, the API of github.com is on api.github.com and the one of
+/// GitHub Enterprise on the same host
+fn repo_host(api_url: &str) -> Option<String> {
+    api_host(api_url).map(|host| host.strip_prefix("api.").unwrap_or(&host).to_string())
+}
+
+impl GitHub {
+    /// The installation token of the app or the private token, if set
+    fn token(&self) -> Result<Option<String>, String> {
+        match self.app {
+            Some(ref app) => app.token(&self.url, &self.api).map(Some),
+            None => Ok(self.private_token.clone()),
+        }
+    }
+
+    /// Headers for the API requests, with the token if set
+    fn release_headers(&self, accept: &'static str) -> Result<HeaderMap, String> {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static(accept));
+        if let Some(token) = self.token()? {
+            if let Ok(mut value) = HeaderValue::from_str(&format!("token {token}")) {
+                value.set_sensitive(true);
+                headers.insert(AUTHORIZATION, value);
+            }
+        }
+        Ok(headers)
+    }
+}
+
+/// Creating the destinations of `--dest-provider`
+impl GitHub {
+    /// The repository for `path` in the organization. GitHub has no nested namespaces, the
+    /// repositories of subgroups are named with their path joined by `-`.
+    pub(super) fn dest_repo(&self, path: &str) -> DestRepo {
+        let name = path.replace('/', "-");
+        let host = repo_host(&self.url).unwrap_or_default();
+        let url = if self.use_http {
+            format!("https://{}/{}/{}.git", host, self.org, name)
+        } else {
+            format!("git@{}:{}/{}.git", host, self.org, name)
+        };
+        DestRepo {
+            url,
+            project: format!("{}/{}", self.org, name),
+        }
+    }
+
+    /// Create the private repository `project` (`owner/name`) in the organization, if it
+    /// doesn't exist yet
+    pub(super) fn create_repo(&self, project: &str) -> Result<(), String> {
+        let client = self.api.client()?;
+        let url = format!("{}/repos/{}", self.url, project);
+        trace!("URL: {}", url);
+
+        let res = self
+            .api
+            .send(
+                client
+                    .get(&url)
+                    .headers(self.release_headers("application/vnd.github.v3+json")?),
+            )
+            .map_err(|e| format!("Unable to connect to: {url} ({e})"))?;
+        match res.status() {
+            StatusCode::OK => return Ok(()),
+            StatusCode::NOT_FOUND => {}
+            status => {
+                return Err(format!(
+                    "API call received invalid status ({status}) for : {url}"
+                ))
+            }
+        }
+
+        let name = project.rsplit('/').next().unwrap_or(project);
+        let url = format!("{}/orgs/{}/repos", self.url, self.org);
+        trace!("URL: {}", url);
+        info!("Creating repository {} on {}", project, self.url);
+
+        let res = self
+            .api
+            .send(
+                client
+                    .post(&url)
+                    .headers(self.release_headers("application/vnd.github.v3+json")?)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(serde_json::json!({ "name": name, "private": true }).to_string()),
+            )
+            .map_err(|e| format!("Unable to connect to: {url} ({e})"))?;
+        if res.status() != StatusCode::CREATED {
+            return Err(format!(
+                "API call received invalid status ({}) for : {}",
+                res.status(),
+                url
+            ));
+        }
+        Ok(())
+    }
+
+    /// Archive or delete the repository `project` (`owner/name`), a repository that is already
+    /// gone counts as pruned
+    pub(super) fn prune_repo(&self, project: &str, how: PruneRemote) -> Result<(), String> {
+        let client = self.api.client()?;
+        let url = format!("{}/repos/{}", self.url, project);
+        let request = match how {
+            PruneRemote::Archive => client
+                .patch(&url)
+                .header(CONTENT_TYPE, "application/json")
+                .body(serde_json::json!({ "archived": true }).to_string()),
+            PruneRemote::Delete => client.delete(&url),
+        };
+        trace!("URL: {}", url);
+        info!("Pruning ({}) repository {} on {}", how, project, self.url);
+
+        let res = self
+            .api
+            .send(request.headers(self.release_headers("application/vnd.github.v3+json")?))
+            .map_err(|e| format!("Unable to connect to: {url} ({e})"))?;
+        match res.status() {
+            StatusCode::OK | StatusCode::NO_CONTENT | StatusCode::NOT_FOUND => Ok(()),
+            status => Err(format!(
+                "API call received invalid status ({status}) for : {url}"
+            )),
+        }
+    }
 }
 
 impl Provider for GitHub {
@@ -37,72 +256,310 @@ impl Provider for GitHub {
     }
 
     fn get_mirror_repos(&self) -> Result<Vec<MirrorResult>, String> {
-        let client = Client::new();
+        // Github rejects requests without user agent, it is set by the client
+        let client = self.api.client()?;
 
         let use_http = self.use_http;
 
-        let mut headers = HeaderMap::new();
-        // Github rejects requests without user agent
-        let useragent = HeaderValue::from_str(&self.useragent).expect("User agent invalid!");
-        headers.insert(USER_AGENT, useragent);
         // Set the accept header to make sure the v3 api is used
-        let accept = HeaderValue::from_static("application/vnd.github.v3+json");
-        headers.insert(ACCEPT, accept);
+        let headers = self.release_headers("application/vnd.github.v3+json")?;
 
-        let url = format!("{}/orgs/{}/repos", self.url, self.org);
-        trace!("URL: {}", url);
+        // Pages are followed by the `Link` header
+        let mut next = Some(format!(
+            "{}/orgs/{}/repos?per_page={}",
+            self.url, self.org, PER_PAGE
+        ));
+        let mut projects: Vec<Project> = Vec::new();
+        while let Some(url) = next {
+            trace!("URL: {}", url);
 
-        let res = client
-            .get(&url)
-            .headers(headers)
-            .send()
+            let request = client.get(&url).headers(headers.clone());
+            let res = match self.api.listing_cache {
+                Some(ref cache) => cache.send(&url, request, |r| self.api.send(r)),
+                None => self.api.send(request).map(Page::Fresh),
+            }
             .map_err(|e| format!("Unable to connect to: {url} ({e})"))?;
+            let body = match res {
+                Page::Cached(page) => {
+                    next = page.next;
+                    page.body
+                }
+                Page::Fresh(res) if res.status() != StatusCode::OK => {
+                    if res.status() == StatusCode::UNAUTHORIZED {
+                        return Err(format!(
+                            "API call received unautorized ({}) for: {}. \
+                         Please make sure the `PRIVATE_TOKEN` environment \
+                         variable or the GitHub App options are set.",
+                            res.status(),
+                            url
+                        ));
+                    } else {
+                        return Err(format!(
+                            "API call received invalid status ({}) for : {}",
+                            res.status(),
+                            url
+                        ));
+                    }
+                }
+                Page::Fresh(res) => {
+                    next = res
+                        .headers()
+                        .get("link")
+                        .and_then(|l| l.to_str().ok())
+                        .and_then(next_link);
+                    let etag = etag(&res);
+                    let body = res
+                        .text()
+                        .map_err(|e| format!("Unable to read response of {url} ({e})"))?;
+                    if let Some(ref cache) = self.api.listing_cache {
+                        cache.store(&url, etag.as_ref(), next.clone(), &body);
+                    }
+                    body
+                }
+            };
 
-        if res.status() != StatusCode::OK {
-            if res.status() == StatusCode::UNAUTHORIZED {
-                return Err(format!(
-                    "API call received unautorized ({}) for: {}. \
-                     Please make sure the `GITHUB_PRIVATE_TOKEN` environment \
-                     variable is set.",
-                    res.status(),
-                    url
-                ));
-            } else {
-                return Err(format!(
-                    "API call received invalid status ({}) for : {}",
-                    res.status(),
-                    url
-                ));
-            }
+            let page: Vec<Project> = serde_json::from_str(&body)
+                .map_err(|e| format!("Unable to parse response as JSON ({e:?})"))?;
+            projects.extend(page);
         }
-
-        let projects: Vec<Project> = serde_json::from_reader(res)
-            .map_err(|e| format!("Unable to parse response as JSON ({e:?})"))?;
+        self.api.save_listing_cache();
 
         let mut mirrors: Vec<MirrorResult> = Vec::new();
 
         for p in projects {
-            match serde_yaml::from_str::<Desc>(&p.description.unwrap_or_default()) {
+            if !self.topics.matches(&p.topics) || !self.visibility.includes(Some(p.visibility())) {
+                trace!("Topics or visibility don't match: {}", p.url);
+                continue;
+            }
+            let visibility = p.visibility();
+            if self.list_origins {
+                let origin = if self.origin_transport == Some(Transport::Http) {
+                    p.clone_url
+                } else {
+                    p.ssh_url
+                };
+                let name = p.full_name.rsplit('/').next().unwrap_or_default();
+                mirrors.push(Ok(Mirror {
+                    origin,
+                    destination: name.to_owned(),
+                    refspec: None,
+                    prune_refs: false,
+                    lfs: true,
+                    has_wiki: p.has_wiki,
+                    wiki: false,
+                    dest_token: None,
+                    project: None,
+                    visibility: Some(visibility),
+                    id: p.id.map(|id| format!("github:{id}")),
+                    subtree_prefix: None,
+                    size: p.size.map(|kib| kib * 1024),
+                    stars: p.stargazers_count,
+                    forks: p.forks_count,
+                    default_branch: None,
+                    archived: p.archived,
+                    fork: p.fork,
+                    activity: unix_time(p.pushed_at.as_deref()),
+                }));
+                continue;
+            }
+            match Desc::parse(&p.url, p.description.as_deref().unwrap_or_default()) {
                 Ok(desc) => {
-                    if desc.skip {
+                    if desc.disabled() {
                         mirrors.push(Err(MirrorError::Skip(p.url)));
                         continue;
                     }
                     trace!("{0} -> {1}", desc.origin, p.ssh_url);
-                    let destination = if use_http { p.clone_url } else { p.ssh_url };
+                    let destination = if desc.dest_http(use_http) {
+                        p.clone_url
+                    } else {
+                        p.ssh_url
+                    };
+                    let (refspec, prune_refs) = desc.refspec();
                     let m = Mirror {
-                        origin: desc.origin,
+                        origin: desc.origin_url(self.origin_transport),
                         destination,
-                        refspec: desc.refspec,
+                        refspec,
+                        prune_refs,
                         lfs: desc.lfs,
+                        has_wiki: p.has_wiki,
+                        wiki: false,
+                        dest_token: desc.dest_token,
+                        project: Some(p.full_name),
+                        visibility: Some(visibility),
+                        id: p.id.map(|id| format!("github:{id}")),
+                        subtree_prefix: None,
+                        size: p.size.map(|kib| kib * 1024),
+                        stars: p.stargazers_count,
+                        forks: p.forks_count,
+                        default_branch: p.default_branch,
+                        archived: p.archived,
+                        fork: p.fork,
+                        // Of the repository with the description, not of the origin
+                        activity: None,
                     };
                     mirrors.push(Ok(m));
                 }
                 Err(e) => {
-                    mirrors.push(Err(MirrorError::Description(p.url, e)));
+                    mirrors.push(Err(e));
                 }
             }
         }
         Ok(mirrors)
     }
+    fn get_releases(&self, mirror: &Mirror) -> Result<Vec<Release>, String> {
+        let name = mirror
+            .project
+            .as_ref()
+            .ok_or_else(|| format!("Unknown repository of {}", mirror.destination))?;
+        let url = format!("{}/repos/{}/releases?per_page=100", self.url, name);
+        trace!("URL: {}", url);
+
+        let res = self
+            .api
+            .send(
+                self.api
+                    .client()?
+                    .get(&url)
+                    .headers(self.release_headers("application/vnd.github.v3+json")?),
+            )
+            .map_err(|e| format!("Unable to connect to: {url} ({e})"))?;
+        if res.status() != StatusCode::OK {
+            return Err(format!(
+                "API call received invalid status ({}) for : {}",
+                res.status(),
+                url
+            ));
+        }
+
+        let releases: Vec<ApiRelease> = serde_json::from_reader(res)
+            .map_err(|e| format!("Unable to parse response as JSON ({e:?})"))?;
+        Ok(releases.into_iter().map(Release::from).collect())
+    }
+
+    fn dest_token(&self, mirror: &Mirror) -> Result<Option<Secret>, String> {
+        // The token of the description takes precedence
+        match (&mirror.dest_token, &self.app) {
+            (None, Some(_)) => Ok(self.token()?.map(Secret)),
+            _ => Ok(mirror.dest_token.clone()),
+        }
+    }
+
+    fn download_asset(&self, asset: &Asset, file: &Path) -> Result<(), String> {
+        // Redirects to the storage host, which doesn't get the authorization header
+        let request = self
+            .api
+            .client()?
+            .get(&asset.url)
+            .headers(self.release_headers("application/octet-stream")?);
+        download(&self.api, request, file)
+    }
+
+    fn set_default_branch(&self, mirror: &Mirror, branch: &str) -> Result<(), String> {
+        let name = mirror
+            .project
+            .as_ref()
+            .ok_or_else(|| format!("Unknown repository of {}", mirror.destination))?;
+        let url = format!("{}/repos/{}", self.url, name);
+        trace!("URL: {}", url);
+
+        let res = self
+            .api
+            .send(
+                self.api
+                    .client()?
+                    .patch(&url)
+                    .headers(self.release_headers("application/vnd.github.v3+json")?)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(serde_json::json!({ "default_branch": branch }).to_string()),
+            )
+            .map_err(|e| format!("Unable to connect to: {url} ({e})"))?;
+        if res.status() != StatusCode::OK {
+            return Err(format!(
+                "API call received invalid status ({}) for : {}",
+                res.status(),
+                url
+            ));
+        }
+        Ok(())
+    }
+
+    fn get_origin_metadata(&self, mirror: &Mirror) -> Result<RepoMetadata, String> {
+        let path = repo_host(&self.url)
+            .and_then(|host| hosted_path(&mirror.origin, &host))
+            .ok_or_else(|| format!("Origin {} is not hosted on {}", mirror.origin, self.url))?;
+        let url = format!("{}/repos/{}", self.url, path);
+        trace!("URL: {}", url);
+
+        let res = self
+            .api
+            .send(
+                self.api
+                    .client()?
+                    .get(&url)
+                    .headers(self.release_headers("application/vnd.github.v3+json")?),
+            )
+            .map_err(|e| format!("Unable to connect to: {url} ({e})"))?;
+        if res.status() != StatusCode::OK {
+            return Err(format!(
+                "API call received invalid status ({}) for : {}",
+                res.status(),
+                url
+            ));
+        }
+        let settings: RepoSettings = serde_json::from_reader(res)
+            .map_err(|e| format!("Unable to parse the settings of {url} ({e})"))?;
+        Ok(RepoMetadata {
+            topics: settings.topics,
+            default_branch: settings.default_branch,
+        })
+    }
+
+    fn set_metadata(&self, mirror: &Mirror, metadata: &RepoMetadata) -> Result<(), String> {
+        let name = mirror
+            .project
+            .as_ref()
+            .ok_or_else(|| format!("Unknown repository of {}", mirror.destination))?;
+        let url = format!("{}/repos/{}/topics", self.url, name);
+        trace!("URL: {}", url);
+
+        let res = self
+            .api
+            .send(
+                self.api
+                    .client()?
+                    .put(&url)
+                    .headers(self.release_headers("application/vnd.github.v3+json")?)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(serde_json::json!({ "names": metadata.topics }).to_string()),
+            )
+            .map_err(|e| format!("Unable to connect to: {url} ({e})"))?;
+        if res.status() != StatusCode::OK {
+            return Err(format!(
+                "API call received invalid status ({}) for : {}",
+                res.status(),
+                url
+            ));
+        }
+        match metadata.default_branch {
+            Some(ref branch) => self.set_default_branch(mirror, branch),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ApiRelease;
+    use crate::provider::Release;
+
+    #[test]
+    fn parse_release() {
+        let json = r#"{"tag_name": "v1.0", "name": "First", "assets": [{"name": "a.bin",
+            "url": "https://api.github.com/repos/o/a/releases/assets/1", "size": 42,
+            "browser_download_url": "https://github.com/o/a/releases/download/v1.0/a.bin"}]}"#;
+        let release: Release = serde_json::from_str::<ApiRelease>(json).unwrap().into();
+        assert_eq!(release.tag, "v1.0");
+        assert_eq!(release.assets[0].name, "a.bin");
+        assert_eq!(release.assets[0].size, Some(42));
+    }
 }
@@ -0,0 +1,159 @@
+/*
+ * Copyright (c) 2023 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+// Used for error and debug logging
+use log::{debug, trace};
+
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+use reqwest::header::{ACCEPT, AUTHORIZATION};
+use reqwest::StatusCode;
+use time::format_description::well_known::Rfc3339;
+use time::{Duration, OffsetDateTime};
+
+use crate::provider::ApiOptions;
+
+/// Installation tokens are replaced once they expire within this time, so a job started with
+/// the token can still push with it
+const REFRESH_MARGIN: Duration = Duration::minutes(10);
+
+/// Credentials of a GitHub App, used to get installation tokens instead of a personal access
+/// token (`--github-app-id`)
+pub struct GitHubApp {
+    pub app_id: u64,
+    pub installation_id: u64,
+    key: PKey<Private>,
+    /// The current installation token with its expiry
+    token: Mutex<Option<(String, OffsetDateTime)>>,
+}
+
+/// Response of `POST /app/installations/{id}/access_tokens`
+#[derive(Deserialize, Debug)]
+struct InstallationToken {
+    token: String,
+    expires_at: String,
+}
+
+impl GitHubApp {
+    /// Read the private key of the app from the PEM file downloaded from GitHub
+    pub fn new(app_id: u64, installation_id: u64, key_file: &Path) -> Result<GitHubApp, String> {
+        let pem = fs::read(key_file)
+            .map_err(|e| format!("Unable to read the GitHub App key {key_file:?} ({e})"))?;
+        let key = PKey::private_key_from_pem(&pem)
+            .map_err(|e| format!("Invalid GitHub App key {key_file:?} ({e})"))?;
+        Ok(GitHubApp {
+            app_id,
+            installation_id,
+            key,
+            token: Mutex::new(None),
+        })
+    }
+
+    /// JSON Web Token authenticating as the app, valid for 9 minutes. It is issued a minute
+    /// in the past to allow for clock drift.
+    fn jwt(&self, now: OffsetDateTime) -> Result<String, String> {
+        let header = URL_SAFE_NO_PAD.encode(r#"{"alg":"RS256","typ":"JWT"}"#);
+        let claims = serde_json::json!({
+            "iat": (now - Duration::minutes(1)).unix_timestamp(),
+            "exp": (now + Duration::minutes(9)).unix_timestamp(),
+            "iss": self.app_id.to_string(),
+        });
+        let claims = URL_SAFE_NO_PAD.encode(claims.to_string());
+        let message = format!("{header}.{claims}");
+        let signature = Signer::new(MessageDigest::sha256(), &self.key)
+            .and_then(|mut signer| signer.sign_oneshot_to_vec(message.as_bytes()))
+            .map_err(|e| format!("Unable to sign the GitHub App token ({e})"))?;
+        Ok(format!("{message}.{}", URL_SAFE_NO_PAD.encode(signature)))
+    }
+
+    /// The installation token, a new one is requested from the API at `url` if there is none
+    /// yet or it is about to expire
+    pub fn token(&self, url: &str, api: &ApiOptions) -> Result<String, String> {
+        // Held while requesting, so the workers don't all request a new token at once
+        let mut current = self.token.lock().unwrap_or_else(|e| e.into_inner());
+        let now = OffsetDateTime::now_utc();
+        if let Some((ref token, expires)) = *current {
+            if expires - now > REFRESH_MARGIN {
+                return Ok(token.clone());
+            }
+        }
+
+        let url = format!(
+            "{}/app/installations/{}/access_tokens",
+            url, self.installation_id
+        );
+        trace!("URL: {}", url);
+        debug!(
+            "Requesting an installation token of GitHub App {}",
+            self.app_id
+        );
+        let res = api
+            .send(
+                api.client()?
+                    .post(&url)
+                    .header(ACCEPT, "application/vnd.github.v3+json")
+                    .header(AUTHORIZATION, format!("Bearer {}", self.jwt(now)?)),
+            )
+            .map_err(|e| format!("Unable to connect to: {url} ({e})"))?;
+        if res.status() != StatusCode::CREATED {
+            return Err(format!(
+                "API call received invalid status ({}) for : {}. \
+                 Please make sure the GitHub App id, installation id and key are correct.",
+                res.status(),
+                url
+            ));
+        }
+        let installation: InstallationToken = serde_json::from_reader(res)
+            .map_err(|e| format!("Unable to parse response as JSON ({e:?})"))?;
+        let expires = OffsetDateTime::parse(&installation.expires_at, &Rfc3339)
+            .map_err(|e| format!("Invalid expiry of the installation token ({e})"))?;
+        *current = Some((installation.token.clone(), expires));
+        Ok(installation.token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::rsa::Rsa;
+    use openssl::sign::Verifier;
+
+    #[test]
+    fn jwt() {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let app = GitHubApp {
+            app_id: 42,
+            installation_id: 7,
+            key: key.clone(),
+            token: Mutex::new(None),
+        };
+        let now = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        let jwt = app.jwt(now).unwrap();
+
+        let (message, signature) = jwt.rsplit_once('.').unwrap();
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &key).unwrap();
+        assert!(verifier
+            .verify_oneshot(
+                &URL_SAFE_NO_PAD.decode(signature).unwrap(),
+                message.as_bytes()
+            )
+            .unwrap());
+        let claims = message.split_once('.').unwrap().1;
+        let claims: serde_json::Value =
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims).unwrap()).unwrap();
+        assert_eq!(
+            claims,
+            serde_json::json!({"iat": 1_699_999_940, "exp": 1_700_000_540, "iss": "42"})
+        );
+    }
+}
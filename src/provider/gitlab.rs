@@ -4,37 +4,176 @@
  * SPDX-License-Identifier:     MIT
  */
 
-// Get Max of u32
-use std::u32;
-
 // Used for error and debug logging
-use log::{debug, error, trace, warn};
+use log::{debug, error, info, trace, warn};
 
 use reqwest::blocking::Client;
-use reqwest::header::{HeaderMap, HeaderValue};
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
 use reqwest::StatusCode;
 
-use crate::provider::{Desc, Mirror, MirrorError, MirrorResult, Provider};
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+use serde::de::IgnoredAny;
+
+use std::path::Path;
+
+use crate::provider::cache::{etag, Page};
+use crate::provider::dest::DestRepo;
+use crate::provider::{
+    api_host, download, hosted_path, next_link, unix_time, ApiOptions, Asset, Desc, Mirror,
+    MirrorError, MirrorResult, Provider, PruneRemote, Release, RepoMetadata, TopicFilter,
+    Transport, Visibility,
+};
 
 #[derive(Debug)]
 pub struct GitLab {
     pub url: String,
     pub group: String,
     pub use_http: bool,
+    /// Convert the origins to this transport
+    pub origin_transport: Option<Transport>,
     pub private_token: Option<String>,
-    pub recursive: bool,
+    pub api: ApiOptions,
+    /// Levels of subgroups listed below the group, `Some(0)` only lists the group itself and
+    /// `None` all subgroups
+    pub recursive_depth: Option<usize>,
+    /// Number of concurrent API requests while listing
+    pub list_concurrency: usize,
+    /// Only list the projects with matching topics
+    pub topics: TopicFilter,
+    /// Only list the projects with this visibility
+    pub visibility: Visibility,
+    /// Whether `group` is a group or a user
+    pub namespace_type: NamespaceType,
+    /// Request the repository sizes, which needs more work from the server
+    pub statistics: bool,
+    /// Only request the reduced field set of the projects (`simple=true`), which lacks the
+    /// visibility, the wiki flag and the statistics
+    pub simple: bool,
+    /// List the projects themselves as origins, with their path below the group as
+    /// destination, instead of reading the origin from the description (`--dest-provider`)
+    pub list_origins: bool,
+}
+
+/// Kind of the namespace the projects are listed from
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NamespaceType {
+    /// A group, or a user if there is no such group
+    Auto,
+    /// A group and its subgroups
+    Group,
+    /// The personal projects of a user
+    User,
 }
 
 /// A project from the GitLab API
 #[derive(Deserialize, Debug, Clone)]
 struct Project {
+    id: u64,
     description: String,
     web_url: String,
+    #[serde(default)]
+    path: String,
+    #[serde(default)]
+    path_with_namespace: String,
     ssh_url_to_repo: String,
     http_url_to_repo: String,
+    #[serde(default)]
+    wiki_enabled: bool,
+    #[serde(default)]
+    topics: Vec<String>,
+    /// Deprecated name of `topics`, returned by GitLab before 14.0
+    #[serde(default)]
+    tag_list: Vec<String>,
+    visibility: Option<Visibility>,
+    /// Only returned with `statistics=true` to members with at least the reporter role
+    statistics: Option<Statistics>,
+    star_count: Option<u64>,
+    forks_count: Option<u64>,
+    /// Not set for empty projects
+    default_branch: Option<String>,
+    #[serde(default)]
+    archived: bool,
+    /// Only returned for forks, not with `simple=true`
+    forked_from_project: Option<IgnoredAny>,
+    /// Updated at most once an hour
+    last_activity_at: Option<String>,
+}
+
+/// The settings of a project copied by `--sync-metadata`
+#[derive(Deserialize, Debug)]
+struct ProjectSettings {
+    #[serde(default)]
+    topics: Vec<String>,
+    /// Deprecated name of `topics`, returned by GitLab before 14.0
+    #[serde(default)]
+    tag_list: Vec<String>,
+    default_branch: Option<String>,
 }
 
-/// A (sub)group from the GitLab API
+#[derive(Deserialize, Debug, Clone)]
+struct Statistics {
+    /// In bytes
+    repository_size: u64,
+}
+
+impl Project {
+    fn selected(&self, gitlab: &GitLab) -> bool {
+        gitlab.topics.matches(self.topics()) && gitlab.visibility.includes(self.visibility)
+    }
+
+    fn topics(&self) -> &[String] {
+        if self.topics.is_empty() {
+            &self.tag_list
+        } else {
+            &self.topics
+        }
+    }
+}
+
+/// A release from the GitLab API
+#[derive(Deserialize, Debug)]
+struct ApiRelease {
+    tag_name: String,
+    #[serde(default)]
+    assets: ApiAssets,
+}
+
+/// The assets of a release, the generated source archives are not needed as they can be
+/// recreated from the repository
+#[derive(Deserialize, Debug, Default)]
+struct ApiAssets {
+    #[serde(default)]
+    links: Vec<ApiLink>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ApiLink {
+    name: String,
+    url: String,
+    direct_asset_url: Option<String>,
+}
+
+impl From<ApiRelease> for Release {
+    fn from(r: ApiRelease) -> Release {
+        Release {
+            tag: r.tag_name,
+            assets: r
+                .assets
+                .links
+                .into_iter()
+                .map(|l| Asset {
+                    name: l.name,
+                    url: l.direct_asset_url.unwrap_or(l.url),
+                    size: None,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// A (sub)group from the GitLab API, also used for the ids of namespaces and created projects
 #[derive(Deserialize, Debug, Clone)]
 struct Group {
     id: u64,
@@ -52,18 +191,67 @@ impl GitLab {
     ) -> Result<Vec<T>, String> {
         let mut results: Vec<T> = Vec::new();
 
-        for page in 1..u32::MAX {
-            let url = format!("{url}?per_page={PER_PAGE}&page={page}");
+        self.for_each_page(url, client, headers, false, &mut |page| {
+            results.extend(page)
+        })?;
+
+        Ok(results)
+    }
+
+    /// Request all pages of `url`, passing each page to `f` as soon as it is received.
+    /// With `keyset` the faster keyset pagination is requested, GitLab versions or endpoints
+    /// not supporting it fall back to offset pagination.
+    fn for_each_page<T: serde::de::DeserializeOwned>(
+        &self,
+        url: &str,
+        client: &Client,
+        headers: &HeaderMap,
+        keyset: bool,
+        f: &mut dyn FnMut(Vec<T>),
+    ) -> Result<(), String> {
+        let endpoint = url;
+        let sep = if url.contains('?') { '&' } else { '?' };
+        let base_url = if keyset {
+            format!("{url}{sep}pagination=keyset&order_by=id&sort=asc&per_page={PER_PAGE}")
+        } else {
+            format!("{url}{sep}per_page={PER_PAGE}")
+        };
+        let mut next = Some(format!("{base_url}&page=1"));
+        let mut first = true;
+
+        while let Some(url) = next {
             trace!("URL: {}", url);
 
-            let res = client
-                .get(&url)
-                .headers(headers.clone())
-                .send()
-                .map_err(|e| format!("Unable to connect to: {url} ({e})"))?;
+            let request = client.get(&url).headers(headers.clone());
+            let res = match self.api.listing_cache {
+                Some(ref cache) => cache.send(&url, request, |r| self.api.send(r)),
+                None => self.api.send(request).map(Page::Fresh),
+            }
+            .map_err(|e| format!("Unable to connect to: {url} ({e})"))?;
+            let res = match res {
+                Page::Cached(page) => {
+                    next = page.next;
+                    let results_page: Vec<T> = serde_json::from_str(&page.body)
+                        .map_err(|e| format!("Unable to parse cached response as JSON ({e})"))?;
+                    f(results_page);
+                    first = false;
+                    continue;
+                }
+                Page::Fresh(res) => res,
+            };
 
             debug!("HTTP Status Received: {}", res.status());
 
+            if keyset
+                && first
+                && (res.status() == StatusCode::BAD_REQUEST
+                    || res.status() == StatusCode::METHOD_NOT_ALLOWED)
+            {
+                debug!("Keyset pagination not supported, falling back to offset pagination");
+                return self.for_each_page(endpoint, client, headers, false, f);
+            }
+            first = false;
+
             if res.status() != StatusCode::OK {
                 if res.status() == StatusCode::UNAUTHORIZED {
                     return Err(format!(
@@ -82,75 +270,108 @@ impl GitLab {
                 }
             }
 
-            let has_next = match res.headers().get("x-next-page") {
-                None => {
+            let link = res
+                .headers()
+                .get("link")
+                .and_then(|l| l.to_str().ok())
+                .and_then(next_link);
+
+            next = match (link, res.headers().get("x-next-page")) {
+                (Some(link), _) => {
+                    trace!("Next page: {}", link);
+                    Some(link)
+                }
+                (None, None) => {
                     trace!("No more pages, x-next-page header missing.");
-                    false
+                    None
                 }
-                Some(n) => {
-                    if n.is_empty() {
-                        trace!("No more pages, x-next-page-header empty.");
-                        false
-                    } else {
+                (None, Some(n)) => match n.to_str() {
+                    Ok(n) if !n.is_empty() => {
                         trace!("Next page: {:?}", n);
-                        true
+                        Some(format!("{base_url}&page={n}"))
                     }
-                }
+                    _ => {
+                        trace!("No more pages, x-next-page-header empty.");
+                        None
+                    }
+                },
             };
 
-            let results_page: Vec<T> = serde_json::from_reader(res)
+            let etag = etag(&res);
+            let body = res
+                .text()
+                .map_err(|e| format!("Unable to read response of {url} ({e})"))?;
+            let results_page: Vec<T> = serde_json::from_str(&body)
                 .map_err(|e| format!("Unable to parse response as JSON ({e})"))?;
-
-            results.extend(results_page);
-
-            if !has_next {
-                break;
+            if let Some(ref cache) = self.api.listing_cache {
+                cache.store(&url, etag.as_ref(), next.clone(), &body);
             }
-        }
-        Ok(results)
-    }
-
-    fn get_projects(
-        &self,
-        id: &str,
-        client: &Client,
-        headers: &HeaderMap,
-    ) -> Result<Vec<Project>, String> {
-        let url = format!("{}/api/v4/groups/{}/projects", self.url, id);
 
-        self.get_paged::<Project>(&url, client, headers)
+            f(results_page);
+        }
+        Ok(())
     }
 
+    /// The group `id` and its subgroups down to `depth` levels below it
     fn get_subgroups(
         &self,
         id: &str,
+        depth: Option<usize>,
         client: &Client,
         headers: &HeaderMap,
     ) -> Result<Vec<String>, String> {
+        let depth = match depth {
+            Some(0) => return Ok(vec![id.to_owned()]),
+            depth => depth.map(|d| d - 1),
+        };
         let url = format!("{}/api/v4/groups/{}/subgroups", self.url, id);
 
         let groups = self.get_paged::<Group>(&url, client, headers)?;
 
-        let mut subgroups: Vec<String> = vec![id.to_owned()];
+        let nested = groups
+            .par_iter()
+            .map(|group| self.get_subgroups(&format!("{}", group.id), depth, client, headers))
+            .collect::<Result<Vec<Vec<String>>, String>>()?;
 
-        for group in groups {
-            subgroups.extend(self.get_subgroups(&format!("{}", group.id), client, headers)?);
-        }
+        let mut subgroups: Vec<String> = vec![id.to_owned()];
+        subgroups.extend(nested.into_iter().flatten());
 
         Ok(subgroups)
     }
-}
 
-impl Provider for GitLab {
-    fn get_label(&self) -> String {
-        format!("{}/{}", self.url, self.group)
+    /// Thread pool for the API requests, separate from the mirror workers
+    fn list_pool(&self) -> Result<ThreadPool, String> {
+        ThreadPoolBuilder::new()
+            .num_threads(self.list_concurrency.max(1))
+            .build()
+            .map_err(|e| format!("Unable to create listing threads ({e})"))
     }
 
-    fn get_mirror_repos(&self) -> Result<Vec<MirrorResult>, String> {
-        let client = Client::new();
+    fn get_projects(
+        &self,
+        url: &str,
+        client: &Client,
+        headers: &HeaderMap,
+    ) -> Result<Vec<Project>, String> {
+        let mut projects = Vec::new();
 
-        let use_http = self.use_http;
+        self.for_each_page::<Project>(url, client, headers, true, &mut |page| {
+            projects.extend(page)
+        })?;
 
+        Ok(projects)
+    }
+}
+
+impl GitLab {
+    fn get_headers(&self) -> HeaderMap {
+        if self.private_token.is_none() {
+            warn!("PRIVATE_TOKEN not set")
+        }
+        self.auth_headers()
+    }
+
+    fn auth_headers(&self) -> HeaderMap {
         let mut headers = HeaderMap::new();
         if let Some(ref token) = self.private_token {
             match HeaderValue::from_str(token) {
@@ -161,56 +382,478 @@ impl Provider for GitLab {
                     error!("Unable to parse PRIVATE_TOKEN: {}", err);
                 }
             }
+        }
+        headers
+    }
+
+    fn get_groups(&self, client: &Client, headers: &HeaderMap) -> Result<Vec<String>, String> {
+        if self.recursive_depth == Some(0) {
+            return Ok(vec![self.group.clone()]);
+        }
+        let pool = self.list_pool()?;
+        pool.install(|| self.get_subgroups(&self.group, self.recursive_depth, client, headers))
+            .or_else(|e| -> Result<Vec<String>, String> {
+                warn!("Unable to get subgroups: {}", e);
+                Ok(vec![self.group.clone()])
+            })
+    }
+
+    /// Check if the namespace is a user instead of a group
+    fn is_user(&self, client: &Client, headers: &HeaderMap) -> Result<bool, String> {
+        match self.namespace_type {
+            NamespaceType::Group => Ok(false),
+            NamespaceType::User => Ok(true),
+            NamespaceType::Auto => {
+                let url = format!("{}/api/v4/groups/{}", self.url, self.group);
+                let res = self
+                    .api
+                    .send(client.get(&url).headers(headers.clone()))
+                    .map_err(|e| format!("Unable to connect to: {url} ({e})"))?;
+                debug!("HTTP Status Received: {}", res.status());
+                Ok(res.status() == StatusCode::NOT_FOUND)
+            }
+        }
+    }
+
+    /// URLs listing the projects of the namespace, those of the group and its subgroups
+    /// or the personal projects of the user
+    fn project_urls(&self, client: &Client, headers: &HeaderMap) -> Result<Vec<String>, String> {
+        let query = match (self.statistics, self.simple) {
+            (true, _) => "?statistics=true",
+            (false, true) => "?simple=true",
+            (false, false) => "",
+        };
+        if self.is_user(client, headers)? {
+            debug!("Listing the projects of user {}", self.group);
+            // Includes the private projects if the token belongs to the user or an admin
+            return Ok(vec![format!(
+                "{}/api/v4/users/{}/projects{query}",
+                self.url, self.group
+            )]);
+        }
+        Ok(self
+            .get_groups(client, headers)?
+            .iter()
+            .map(|group| format!("{}/api/v4/groups/{}/projects{query}", self.url, group))
+            .collect())
+    }
+
+    /// The project as origin, to be mirrored to the same path below the destination group
+    fn origin_mirror(&self, p: Project) -> Mirror {
+        let origin = if self.origin_transport == Some(Transport::Http) {
+            p.http_url_to_repo
         } else {
-            warn!("PRIVATE_TOKEN not set")
+            p.ssh_url_to_repo
+        };
+        // The group can also be given by its id
+        let prefix = format!("{}/", self.group);
+        let path = match p.path_with_namespace.strip_prefix(&prefix) {
+            Some(path) => path.to_owned(),
+            None => p.path,
+        };
+        Mirror {
+            origin,
+            destination: path,
+            refspec: None,
+            prune_refs: false,
+            lfs: true,
+            has_wiki: p.wiki_enabled,
+            wiki: false,
+            dest_token: None,
+            project: None,
+            visibility: p.visibility,
+            id: Some(format!("gitlab:{}", p.id)),
+            subtree_prefix: None,
+            size: p.statistics.map(|s| s.repository_size),
+            stars: p.star_count,
+            forks: p.forks_count,
+            default_branch: None,
+            archived: p.archived,
+            fork: p.forked_from_project.is_some(),
+            activity: unix_time(p.last_activity_at.as_deref()),
         }
+    }
 
-        let groups = if self.recursive {
-            self.get_subgroups(&self.group, &client, &headers).or_else(
-                |e| -> Result<Vec<String>, String> {
-                    warn!("Unable to get subgroups: {}", e);
-                    Ok(vec![self.group.clone()])
-                },
-            )?
+    fn to_mirror(&self, p: Project) -> MirrorResult {
+        if self.list_origins {
+            return Ok(self.origin_mirror(p));
+        }
+        match Desc::parse(&p.web_url, &p.description) {
+            Ok(desc) => {
+                if desc.disabled() {
+                    return Err(MirrorError::Skip(p.web_url));
+                }
+                trace!("{0} -> {1}", desc.origin, p.ssh_url_to_repo);
+                let destination = if desc.dest_http(self.use_http) {
+                    p.http_url_to_repo
+                } else {
+                    p.ssh_url_to_repo
+                };
+                let (refspec, prune_refs) = desc.refspec();
+                Ok(Mirror {
+                    origin: desc.origin_url(self.origin_transport),
+                    destination,
+                    refspec,
+                    prune_refs,
+                    lfs: desc.lfs,
+                    has_wiki: p.wiki_enabled,
+                    wiki: false,
+                    dest_token: desc.dest_token,
+                    project: Some(p.id.to_string()),
+                    visibility: p.visibility,
+                    id: Some(format!("gitlab:{}", p.id)),
+                    subtree_prefix: None,
+                    size: p.statistics.map(|s| s.repository_size),
+                    stars: p.star_count,
+                    forks: p.forks_count,
+                    default_branch: p.default_branch,
+                    archived: p.archived,
+                    fork: p.forked_from_project.is_some(),
+                    // Of the project with the description, not of the origin
+                    activity: None,
+                })
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Creating the destinations of `--dest-provider`
+impl GitLab {
+    /// The project for `path` below the group
+    pub(super) fn dest_repo(&self, path: &str) -> DestRepo {
+        let path = format!("{}/{}", self.group, path);
+        let url = if self.use_http {
+            format!("{}/{}.git", self.url, path)
         } else {
-            vec![self.group.clone()]
+            let host = api_host(&self.url).unwrap_or_default();
+            format!("git@{host}:{path}.git")
         };
+        DestRepo {
+            url,
+            project: path.replace('/', "%2F"),
+        }
+    }
 
-        let mut projects: Vec<Project> = Vec::new();
+    /// Get `url`, `None` if it doesn't exist
+    fn get_existing<T: serde::de::DeserializeOwned>(
+        &self,
+        client: &Client,
+        url: &str,
+    ) -> Result<Option<T>, String> {
+        trace!("URL: {}", url);
+        let res = self
+            .api
+            .send(client.get(url).headers(self.auth_headers()))
+            .map_err(|e| format!("Unable to connect to: {url} ({e})"))?;
+        match res.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            StatusCode::OK => serde_json::from_str(
+                &res.text()
+                    .map_err(|e| format!("Unable to read response of {url} ({e})"))?,
+            )
+            .map(Some)
+            .map_err(|e| format!("Unable to parse response as JSON ({e})")),
+            status => Err(format!(
+                "API call received invalid status ({status}) for : {url}"
+            )),
+        }
+    }
 
-        for group in groups {
-            projects.extend(self.get_projects(&group, &client, &headers)?);
+    /// Post `body` to `url`, returning the id of the created group or project
+    fn post_create(
+        &self,
+        client: &Client,
+        url: &str,
+        body: serde_json::Value,
+    ) -> Result<u64, String> {
+        trace!("URL: {}", url);
+        let res = self
+            .api
+            .send(
+                client
+                    .post(url)
+                    .headers(self.auth_headers())
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(body.to_string()),
+            )
+            .map_err(|e| format!("Unable to connect to: {url} ({e})"))?;
+        if res.status() != StatusCode::CREATED {
+            return Err(format!(
+                "API call received invalid status ({}) for : {}",
+                res.status(),
+                url
+            ));
         }
+        let created: Group = serde_json::from_str(
+            &res.text()
+                .map_err(|e| format!("Unable to read response of {url} ({e})"))?,
+        )
+        .map_err(|e| format!("Unable to parse response as JSON ({e})"))?;
+        Ok(created.id)
+    }
 
-        let mut mirrors: Vec<MirrorResult> = Vec::new();
+    /// Id of the group or user `path`, missing subgroups are created
+    fn namespace_id(&self, client: &Client, path: &str) -> Result<u64, String> {
+        let url = format!(
+            "{}/api/v4/namespaces/{}",
+            self.url,
+            path.replace('/', "%2F")
+        );
+        if let Some(namespace) = self.get_existing::<Group>(client, &url)? {
+            return Ok(namespace.id);
+        }
+        let (parent, name) = path
+            .rsplit_once('/')
+            .ok_or_else(|| format!("Group {} doesn't exist on {}", path, self.url))?;
+        let parent_id = self.namespace_id(client, parent)?;
+        info!("Creating group {} on {}", path, self.url);
+        let body = serde_json::json!({
+            "name": name,
+            "path": name,
+            "parent_id": parent_id,
+            "visibility": "private",
+        });
+        let created = self.post_create(client, &format!("{}/api/v4/groups", self.url), body);
+        match created {
+            Ok(id) => Ok(id),
+            // Created at the same time for another project
+            Err(e) => match self.get_existing::<Group>(client, &url)? {
+                Some(namespace) => Ok(namespace.id),
+                None => Err(e),
+            },
+        }
+    }
 
-        for p in projects {
-            match serde_yaml::from_str::<Desc>(&p.description) {
-                Ok(desc) => {
-                    if desc.skip {
-                        mirrors.push(Err(MirrorError::Skip(p.web_url)));
-                        continue;
-                    }
-                    trace!("{0} -> {1}", desc.origin, p.ssh_url_to_repo);
-                    let destination = if use_http {
-                        p.http_url_to_repo
+    /// Create the private project `project` (its URL encoded path) with its missing subgroups,
+    /// if it doesn't exist yet
+    pub(super) fn create_project(&self, project: &str) -> Result<(), String> {
+        let client = self.api.client()?;
+        let url = format!("{}/api/v4/projects/{}", self.url, project);
+        if self.get_existing::<Group>(&client, &url)?.is_some() {
+            return Ok(());
+        }
+        let path = project.replace("%2F", "/");
+        let (namespace, name) = path
+            .rsplit_once('/')
+            .ok_or_else(|| format!("Project {path} has no group"))?;
+        let namespace_id = self.namespace_id(&client, namespace)?;
+        info!("Creating project {} on {}", path, self.url);
+        let body = serde_json::json!({
+            "name": name,
+            "path": name,
+            "namespace_id": namespace_id,
+            "visibility": "private",
+        });
+        self.post_create(&client, &format!("{}/api/v4/projects", self.url), body)
+            .map(|_| ())
+    }
+
+    /// Archive or delete the project `project`, a project that is already gone counts as pruned
+    pub(super) fn prune_project(&self, project: &str, how: PruneRemote) -> Result<(), String> {
+        let client = self.api.client()?;
+        let url = format!("{}/api/v4/projects/{}", self.url, project);
+        let request = match how {
+            PruneRemote::Archive => client.post(format!("{url}/archive")),
+            PruneRemote::Delete => client.delete(&url),
+        };
+        trace!("URL: {}", url);
+        info!("Pruning ({}) project {} on {}", how, project, self.url);
+
+        let res = self
+            .api
+            .send(request.headers(self.auth_headers()))
+            .map_err(|e| format!("Unable to connect to: {url} ({e})"))?;
+        match res.status() {
+            StatusCode::OK | StatusCode::CREATED | StatusCode::ACCEPTED | StatusCode::NOT_FOUND => {
+                Ok(())
+            }
+            status => Err(format!(
+                "API call received invalid status ({status}) for : {url}"
+            )),
+        }
+    }
+}
+
+impl Provider for GitLab {
+    fn get_label(&self) -> String {
+        format!("{}/{}", self.url, self.group)
+    }
+
+    fn get_mirror_repos(&self) -> Result<Vec<MirrorResult>, String> {
+        let client = self.api.client()?;
+        let headers = self.get_headers();
+
+        let urls = self.project_urls(&client, &headers)?;
+        let projects = self.list_pool()?.install(|| {
+            urls.par_iter()
+                .map(|url| self.get_projects(url, &client, &headers))
+                .collect::<Result<Vec<Vec<Project>>, String>>()
+        })?;
+
+        self.api.save_listing_cache();
+
+        Ok(projects
+            .into_iter()
+            .flatten()
+            .filter(|p| p.selected(self))
+            .map(|p| self.to_mirror(p))
+            .collect())
+    }
+
+    fn stream_mirror_repos(&self, f: &mut dyn FnMut(MirrorResult)) -> Result<(), String> {
+        let client = self.api.client()?;
+        let headers = self.get_headers();
+
+        for url in self.project_urls(&client, &headers)? {
+            self.for_each_page::<Project>(&url, &client, &headers, true, &mut |projects| {
+                for p in projects {
+                    if p.selected(self) {
+                        f(self.to_mirror(p));
                     } else {
-                        p.ssh_url_to_repo
-                    };
-                    let m = Mirror {
-                        origin: desc.origin,
-                        destination,
-                        refspec: desc.refspec,
-                        lfs: desc.lfs,
-                    };
-                    mirrors.push(Ok(m));
-                }
-                Err(e) => {
-                    mirrors.push(Err(MirrorError::Description(p.web_url, e)));
+                        trace!("Topics or visibility don't match: {}", p.web_url);
+                    }
                 }
-            }
+            })?;
         }
+        self.api.save_listing_cache();
+
+        Ok(())
+    }
+
+    fn get_releases(&self, mirror: &Mirror) -> Result<Vec<Release>, String> {
+        let id = mirror
+            .project
+            .as_ref()
+            .ok_or_else(|| format!("Unknown project of {}", mirror.destination))?;
+        let url = format!("{}/api/v4/projects/{}/releases", self.url, id);
+        let releases =
+            self.get_paged::<ApiRelease>(&url, &self.api.client()?, &self.auth_headers())?;
+        Ok(releases.into_iter().map(Release::from).collect())
+    }
+
+    fn download_asset(&self, asset: &Asset, file: &Path) -> Result<(), String> {
+        let mut request = self.api.client()?.get(&asset.url);
+        // Links can point to other hosts, which must not get the token
+        if asset.url.starts_with(&format!("{}/", self.url)) {
+            request = request.headers(self.auth_headers());
+        }
+        download(&self.api, request, file)
+    }
+
+    fn set_default_branch(&self, mirror: &Mirror, branch: &str) -> Result<(), String> {
+        let id = mirror
+            .project
+            .as_ref()
+            .ok_or_else(|| format!("Unknown project of {}", mirror.destination))?;
+        let url = format!("{}/api/v4/projects/{}", self.url, id);
+        trace!("URL: {}", url);
+
+        let res = self
+            .api
+            .send(
+                self.api
+                    .client()?
+                    .put(&url)
+                    .headers(self.auth_headers())
+                    .query(&[("default_branch", branch)]),
+            )
+            .map_err(|e| format!("Unable to connect to: {url} ({e})"))?;
+        if res.status() != StatusCode::OK {
+            return Err(format!(
+                "API call received invalid status ({}) for : {}",
+                res.status(),
+                url
+            ));
+        }
+        Ok(())
+    }
+
+    fn get_origin_metadata(&self, mirror: &Mirror) -> Result<RepoMetadata, String> {
+        // Only origins on this GitLab instance can be read with its API and token
+        let path = api_host(&self.url)
+            .and_then(|host| hosted_path(&mirror.origin, &host))
+            .ok_or_else(|| format!("Origin {} is not hosted on {}", mirror.origin, self.url))?;
+        let url = format!("{}/api/v4/projects/{}", self.url, path.replace('/', "%2F"));
+        trace!("URL: {}", url);
+
+        let res = self
+            .api
+            .send(self.api.client()?.get(&url).headers(self.auth_headers()))
+            .map_err(|e| format!("Unable to connect to: {url} ({e})"))?;
+        if res.status() != StatusCode::OK {
+            return Err(format!(
+                "API call received invalid status ({}) for : {}",
+                res.status(),
+                url
+            ));
+        }
+        let body = res
+            .text()
+            .map_err(|e| format!("Unable to get the settings of {url} ({e})"))?;
+        let settings: ProjectSettings = serde_json::from_str(&body)
+            .map_err(|e| format!("Unable to parse the settings of {url} ({e})"))?;
+        Ok(RepoMetadata {
+            topics: if settings.topics.is_empty() {
+                settings.tag_list
+            } else {
+                settings.topics
+            },
+            default_branch: settings.default_branch,
+        })
+    }
+
+    fn set_metadata(&self, mirror: &Mirror, metadata: &RepoMetadata) -> Result<(), String> {
+        let id = mirror
+            .project
+            .as_ref()
+            .ok_or_else(|| format!("Unknown project of {}", mirror.destination))?;
+        let url = format!("{}/api/v4/projects/{}", self.url, id);
+        trace!("URL: {}", url);
+
+        let mut body = serde_json::json!({ "topics": metadata.topics });
+        if let Some(ref branch) = metadata.default_branch {
+            body["default_branch"] = branch.as_str().into();
+        }
+        let res = self
+            .api
+            .send(
+                self.api
+                    .client()?
+                    .put(&url)
+                    .headers(self.auth_headers())
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(body.to_string()),
+            )
+            .map_err(|e| format!("Unable to connect to: {url} ({e})"))?;
+        if res.status() != StatusCode::OK {
+            return Err(format!(
+                "API call received invalid status ({}) for : {}",
+                res.status(),
+                url
+            ));
+        }
+        Ok(())
+    }
+}
 
-        Ok(mirrors)
+#[cfg(test)]
+mod tests {
+    use super::ApiRelease;
+    use crate::provider::Release;
+
+    #[test]
+    fn parse_release() {
+        let json = r#"{"tag_name": "v1.0", "assets": {"count": 2,
+            "sources": [{"format": "zip", "url": "https://gitlab.example.com/a/-/archive/v1.0/a-v1.0.zip"}],
+            "links": [{"name": "a.bin", "url": "https://example.com/a.bin",
+                       "direct_asset_url": "https://gitlab.example.com/a/-/releases/v1.0/downloads/a.bin"}]}}"#;
+        let release: Release = serde_json::from_str::<ApiRelease>(json).unwrap().into();
+        assert_eq!(release.tag, "v1.0");
+        assert_eq!(release.assets.len(), 1);
+        assert_eq!(
+            release.assets[0].url,
+            "https://gitlab.example.com/a/-/releases/v1.0/downloads/a.bin"
+        );
     }
 }
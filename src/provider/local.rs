@@ -0,0 +1,117 @@
+/*
+ * Copyright (c) 2023 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Used for error and debug logging
+use log::{debug, trace};
+
+use crate::provider::{Mirror, MirrorResult, Provider};
+
+/// Provider using the bare repositories (`*.git` directories) below a local directory as origins
+#[derive(Debug)]
+pub struct LocalSource {
+    pub dir: PathBuf,
+    /// Template for the destination, `{name}` and `{path}` are replaced
+    /// by the name and relative path of the repository without `.git`
+    pub dest_template: String,
+}
+
+/// Compute the destination of the repository at the relative `path` (e.g. `group/project.git`)
+fn destination(template: &str, path: &Path) -> String {
+    let path = path.to_string_lossy().replace('\\', "/");
+    let path = path.strip_suffix(".git").unwrap_or(&path);
+    let name = path.rsplit('/').next().unwrap_or(path);
+    template.replace("{path}", path).replace("{name}", name)
+}
+
+/// Recursively collect all `*.git` directories below `dir`, sorted by path
+fn find_repos(dir: &Path, repos: &mut Vec<PathBuf>) -> Result<(), String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("Unable to read {dir:?} ({e})"))?;
+    let mut dirs: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.is_dir())
+        .collect();
+    dirs.sort();
+
+    for d in dirs {
+        if d.extension().is_some_and(|e| e == "git") {
+            repos.push(d);
+        } else {
+            find_repos(&d, repos)?;
+        }
+    }
+    Ok(())
+}
+
+impl Provider for LocalSource {
+    fn get_label(&self) -> String {
+        format!("local/{}", self.dir.display())
+    }
+
+    fn get_mirror_repos(&self) -> Result<Vec<MirrorResult>, String> {
+        let dir = self
+            .dir
+            .canonicalize()
+            .map_err(|e| format!("Unable to access local source {:?} ({})", self.dir, e))?;
+        debug!("Scan {:?} for repositories", dir);
+
+        let mut repos = Vec::new();
+        find_repos(&dir, &mut repos)?;
+
+        Ok(repos
+            .into_iter()
+            .map(|repo| {
+                let rel = repo.strip_prefix(&dir).unwrap_or(&repo);
+                let destination = destination(&self.dest_template, rel);
+                let origin = repo.to_string_lossy().into_owned();
+                trace!("{0} -> {1}", origin, destination);
+                Ok(Mirror {
+                    origin,
+                    destination,
+                    refspec: None,
+                    prune_refs: false,
+                    lfs: true,
+                    has_wiki: false,
+                    wiki: false,
+                    dest_token: None,
+                    project: None,
+                    visibility: None,
+                    id: None,
+                    subtree_prefix: None,
+                    size: None,
+                    stars: None,
+                    forks: None,
+                    default_branch: None,
+                    archived: false,
+                    fork: false,
+                    activity: None,
+                })
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::destination;
+    use std::path::Path;
+
+    #[test]
+    fn template() {
+        let template = "git@example.com:backup/{path}.git";
+        assert_eq!(
+            destination(template, Path::new("group/sub/project.git")),
+            "git@example.com:backup/group/sub/project.git"
+        );
+        assert_eq!(
+            destination("https://example.com/{name}", Path::new("group/project.git")),
+            "https://example.com/project"
+        );
+    }
+}
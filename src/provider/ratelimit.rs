@@ -0,0 +1,206 @@
+/*
+ * Copyright (c) 2023 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use prometheus::{register_gauge_vec, GaugeVec};
+use reqwest::blocking::{RequestBuilder, Response};
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::StatusCode;
+use time::OffsetDateTime;
+
+/// Rate limited answers to a request before it fails, e.g. if the quota is used up by others
+const MAX_WAITS: u32 = 5;
+
+/// Longest wait for a rate limit to reset, a request that would wait longer fails right away
+const MAX_WAIT: Duration = Duration::from_secs(15 * 60);
+
+/// Wait if the server doesn't tell when to try again
+const DEFAULT_WAIT: Duration = Duration::from_secs(60);
+
+/// Spaces the API requests of all threads to at most `--max-api-rps` per second
+#[derive(Debug, Default)]
+pub struct Throttle {
+    /// Unlimited if `None`
+    max_rps: Option<f64>,
+    /// Earliest time the next request may be sent
+    next: Mutex<Option<Instant>>,
+}
+
+impl Throttle {
+    pub fn new(max_rps: Option<f64>) -> Throttle {
+        Throttle {
+            max_rps,
+            next: Mutex::new(None),
+        }
+    }
+
+    /// Block until the next request may be sent
+    fn wait(&self) {
+        let interval = match self.max_rps {
+            Some(rps) => Duration::from_secs_f64(1.0 / rps),
+            None => return,
+        };
+        let slot = {
+            let mut next = self.next.lock().unwrap_or_else(|e| e.into_inner());
+            let now = Instant::now();
+            let slot = next.map_or(now, |n| n.max(now));
+            *next = Some(slot + interval);
+            slot
+        };
+        thread::sleep(slot.saturating_duration_since(Instant::now()));
+    }
+}
+
+/// Remaining requests of the rate limit window by API host, exported with the metrics
+fn quota_gauge() -> &'static GaugeVec {
+    static QUOTA: OnceLock<GaugeVec> = OnceLock::new();
+    QUOTA.get_or_init(|| {
+        register_gauge_vec!(
+            "git_mirror_api_rate_limit_remaining",
+            "Requests left in the current rate limit window of the API",
+            &["host"]
+        )
+        .unwrap()
+    })
+}
+
+/// Value of the first of the headers that is a number. GitHub sends `X-RateLimit-*`,
+/// GitLab `RateLimit-*`.
+fn header_number(headers: &HeaderMap, names: &[&str]) -> Option<u64> {
+    names
+        .iter()
+        .find_map(|name| headers.get(*name)?.to_str().ok()?.trim().parse().ok())
+}
+
+/// Remaining and total requests of the current rate limit window
+fn quota(headers: &HeaderMap) -> Option<(u64, Option<u64>)> {
+    let remaining = header_number(headers, &["x-ratelimit-remaining", "ratelimit-remaining"])?;
+    let limit = header_number(headers, &["x-ratelimit-limit", "ratelimit-limit"]);
+    Some((remaining, limit))
+}
+
+/// How long to wait before sending a request again that was rejected as rate limited, `None`
+/// if it wasn't. GitHub rejects with `403` and `429`, GitLab with `429`.
+fn rate_limit_wait(status: StatusCode, headers: &HeaderMap, now: i64) -> Option<Duration> {
+    let retry_after = header_number(headers, &[RETRY_AFTER.as_str()]);
+    let exhausted = quota(headers).is_some_and(|(remaining, _)| remaining == 0);
+    let limited = status == StatusCode::TOO_MANY_REQUESTS
+        || (status == StatusCode::FORBIDDEN && (retry_after.is_some() || exhausted));
+    if !limited {
+        return None;
+    }
+    if let Some(secs) = retry_after {
+        return Some(Duration::from_secs(secs));
+    }
+    // Unix time the window resets at
+    match header_number(headers, &["x-ratelimit-reset", "ratelimit-reset"]) {
+        Some(reset) => Some(Duration::from_secs(
+            reset.saturating_sub(now.max(0) as u64).max(1),
+        )),
+        None => Some(DEFAULT_WAIT),
+    }
+}
+
+/// Send `request` after waiting for its turn. Rate limited requests are sent again once the
+/// rate limit resets, the last answer is returned if it doesn't reset in time.
+pub fn send(throttle: &Throttle, mut request: RequestBuilder) -> reqwest::Result<Response> {
+    let mut waits = 0;
+    loop {
+        let again = request.try_clone();
+        throttle.wait();
+        let res = request.send()?;
+        let host = res.url().host_str().unwrap_or_default().to_string();
+        if let Some((remaining, limit)) = quota(res.headers()) {
+            match limit {
+                Some(limit) => debug!("API quota of {host}: {remaining} of {limit} requests left"),
+                None => debug!("API quota of {host}: {remaining} requests left"),
+            }
+            quota_gauge()
+                .with_label_values(&[&host])
+                .set(remaining as f64);
+        }
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let wait = match rate_limit_wait(res.status(), res.headers(), now) {
+            Some(wait) if waits < MAX_WAITS && wait <= MAX_WAIT => wait,
+            _ => return Ok(res),
+        };
+        request = match again {
+            Some(again) => again,
+            None => return Ok(res),
+        };
+        warn!(
+            "Rate limited by {} ({}), waiting {} before resuming",
+            host,
+            res.status(),
+            humantime::format_duration(wait)
+        );
+        thread::sleep(wait);
+        waits += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn wait() {
+        let now = 1_700_000_000;
+        let secs = |s| Some(Duration::from_secs(s));
+        let forbidden = StatusCode::FORBIDDEN;
+        let too_many = StatusCode::TOO_MANY_REQUESTS;
+
+        // GitHub secondary rate limit
+        let h = headers(&[("retry-after", "30")]);
+        assert_eq!(rate_limit_wait(forbidden, &h, now), secs(30));
+        // GitHub primary rate limit, until the window resets
+        let h = headers(&[
+            ("x-ratelimit-remaining", "0"),
+            ("x-ratelimit-reset", "1700000090"),
+        ]);
+        assert_eq!(rate_limit_wait(forbidden, &h, now), secs(90));
+        // GitLab
+        let h = headers(&[
+            ("ratelimit-remaining", "0"),
+            ("ratelimit-reset", "1699999999"),
+        ]);
+        assert_eq!(rate_limit_wait(too_many, &h, now), secs(1));
+        assert_eq!(
+            rate_limit_wait(too_many, &headers(&[]), now),
+            Some(DEFAULT_WAIT)
+        );
+        // Missing permissions
+        let h = headers(&[("x-ratelimit-remaining", "4999")]);
+        assert_eq!(rate_limit_wait(forbidden, &h, now), None);
+        assert_eq!(rate_limit_wait(StatusCode::OK, &h, now), None);
+
+        let h = headers(&[("ratelimit-remaining", "12"), ("ratelimit-limit", "600")]);
+        assert_eq!(quota(&h), Some((12, Some(600))));
+    }
+
+    #[test]
+    fn throttle() {
+        let throttle = Throttle::new(Some(50.0));
+        let start = Instant::now();
+        for _ in 0..6 {
+            throttle.wait();
+        }
+        // The first request is sent right away
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+}
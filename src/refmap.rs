@@ -0,0 +1,180 @@
+/*
+ * Copyright (c) 2023 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+/// Destination namespaces of the ref types, e.g. tags pushed to `refs/upstream-tags/*`
+/// so they don't clobber the release tags of the destination
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RefMapping {
+    /// Destination of `refs/heads/*`
+    pub branches: Option<String>,
+    /// Destination of `refs/tags/*`
+    pub tags: Option<String>,
+    /// Destination of `refs/notes/*`
+    pub notes: Option<String>,
+}
+
+impl RefMapping {
+    /// Parse a destination pattern, a ref with a single `*` like `refs/upstream-tags/*`
+    pub fn parse_dest(s: &str) -> Result<String, String> {
+        if !s.starts_with("refs/") {
+            return Err(format!("Destination must start with refs/: {s}"));
+        }
+        if s.matches('*').count() != 1 {
+            return Err(format!("Destination must contain exactly one *: {s}"));
+        }
+        Ok(s.to_owned())
+    }
+
+    /// Check if any ref type has a mapping
+    pub fn is_empty(&self) -> bool {
+        self.branches.is_none() && self.tags.is_none() && self.notes.is_none()
+    }
+
+    /// The combined refspec, the ref types without mapping are pushed 1:1.
+    /// Other refs (e.g. `refs/merge-requests/*`) are not pushed. `None` without any mapping.
+    pub fn refspec(&self) -> Option<Vec<String>> {
+        if self.is_empty() {
+            return None;
+        }
+        let types = [
+            ("refs/heads/*", &self.branches),
+            ("refs/tags/*", &self.tags),
+            ("refs/notes/*", &self.notes),
+        ];
+        Some(
+            types
+                .iter()
+                .map(|(src, dest)| format!("+{}:{}", src, dest.as_deref().unwrap_or(src)))
+                .collect(),
+        )
+    }
+}
+
+/// Parse a pattern of the refs below `prefix`, with or without the prefix
+fn parse_pattern(s: &str, prefix: &str, kind: &str) -> Result<String, String> {
+    let pattern = s.strip_prefix(prefix).unwrap_or(s);
+    if pattern.is_empty() || pattern.starts_with('/') || pattern.ends_with('/') {
+        return Err(format!("Invalid {} pattern: {s}", kind.to_lowercase()));
+    }
+    if pattern.matches('*').count() > 1 {
+        return Err(format!("{kind} pattern must contain at most one *: {s}"));
+    }
+    if pattern
+        .chars()
+        .any(|c| c.is_whitespace() || c.is_control() || ":?[\\^~".contains(c))
+    {
+        return Err(format!(
+            "Invalid character in {} pattern: {s}",
+            kind.to_lowercase()
+        ));
+    }
+    Ok(pattern.to_owned())
+}
+
+/// Parse a branch pattern like `main` or `release/*`, with or without `refs/heads/`
+pub fn parse_branch_pattern(s: &str) -> Result<String, String> {
+    parse_pattern(s, "refs/heads/", "Branch")
+}
+
+/// Parse a tag pattern like `v*`, with or without `refs/tags/`
+pub fn parse_tag_pattern(s: &str) -> Result<String, String> {
+    parse_pattern(s, "refs/tags/", "Tag")
+}
+
+/// Refspec pushing the refs below `prefix` matching the patterns to the same name
+fn pattern_refspec<'a>(
+    prefix: &'a str,
+    patterns: &'a [String],
+) -> impl Iterator<Item = String> + 'a {
+    patterns.iter().map(move |p| {
+        let p = p.strip_prefix(prefix).unwrap_or(p);
+        format!("+{prefix}{p}:{prefix}{p}")
+    })
+}
+
+/// Refspec pushing the branches matching the patterns to the same name
+pub fn branch_refspec(patterns: &[String]) -> Vec<String> {
+    pattern_refspec("refs/heads/", patterns).collect()
+}
+
+/// Refspec pushing only the branches and tags matching the patterns, `None` without any pattern
+pub fn filter_refspec(branches: &[String], tags: &[String]) -> Option<Vec<String>> {
+    if branches.is_empty() && tags.is_empty() {
+        return None;
+    }
+    Some(
+        pattern_refspec("refs/heads/", branches)
+            .chain(pattern_refspec("refs/tags/", tags))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn combined_refspec() {
+        assert_eq!(RefMapping::default().refspec(), None);
+
+        let mapping = RefMapping {
+            tags: Some("refs/upstream-tags/*".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            mapping.refspec().unwrap(),
+            [
+                "+refs/heads/*:refs/heads/*",
+                "+refs/tags/*:refs/upstream-tags/*",
+                "+refs/notes/*:refs/notes/*"
+            ]
+        );
+    }
+
+    #[test]
+    fn branches() {
+        assert_eq!(
+            branch_refspec(&["main".to_string(), "refs/heads/release/*".to_string()]),
+            [
+                "+refs/heads/main:refs/heads/main",
+                "+refs/heads/release/*:refs/heads/release/*"
+            ]
+        );
+        assert_eq!(
+            parse_branch_pattern("refs/heads/release/*").unwrap(),
+            "release/*"
+        );
+        assert!(parse_branch_pattern("").is_err());
+        assert!(parse_branch_pattern("release/*/*").is_err());
+        assert!(parse_branch_pattern("main:other").is_err());
+        assert!(parse_branch_pattern("release/").is_err());
+    }
+
+    #[test]
+    fn tags() {
+        assert_eq!(filter_refspec(&[], &[]), None);
+        assert_eq!(
+            filter_refspec(&["main".to_string()], &["refs/tags/v*".to_string()]).unwrap(),
+            [
+                "+refs/heads/main:refs/heads/main",
+                "+refs/tags/v*:refs/tags/v*"
+            ]
+        );
+        assert_eq!(parse_tag_pattern("refs/tags/v1.*").unwrap(), "v1.*");
+        assert_eq!(
+            parse_tag_pattern("v*/*").unwrap_err(),
+            "Tag pattern must contain at most one *: v*/*"
+        );
+    }
+
+    #[test]
+    fn parse_dest() {
+        assert!(RefMapping::parse_dest("refs/upstream-tags/*").is_ok());
+        assert!(RefMapping::parse_dest("upstream-tags/*").is_err());
+        assert!(RefMapping::parse_dest("refs/upstream-tags").is_err());
+        assert!(RefMapping::parse_dest("refs/*/*").is_err());
+    }
+}
@@ -0,0 +1,99 @@
+/*
+ * Copyright (c) 2023 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::{debug, info, trace};
+use slug::slugify;
+
+use crate::provider::{Asset, Mirror, Provider};
+
+/// Directory of the release assets of the repository mirrored from `origin`,
+/// next to the local repository
+pub fn releases_dir(mirror_dir: &Path, origin: &str) -> PathBuf {
+    mirror_dir.join(format!("{}.releases", slugify(origin)))
+}
+
+/// Make a tag or asset name usable as a single path component
+fn path_component(name: &str) -> String {
+    let name = name.replace(['/', '\\'], "_");
+    match name.as_str() {
+        "" | "." | ".." => format!("_{name}"),
+        _ => name,
+    }
+}
+
+/// Check if the asset was already downloaded to `file`. Without a known size any existing file counts.
+fn is_present(file: &Path, asset: &Asset) -> bool {
+    match (fs::metadata(file), asset.size) {
+        (Ok(m), Some(size)) => m.is_file() && m.len() == size,
+        (Ok(m), None) => m.is_file(),
+        (Err(_), _) => false,
+    }
+}
+
+/// Download the assets of all releases of the listed project to `<dir>/<tag>/<name>`,
+/// skipping those already present. Returns the number of downloaded assets.
+pub fn mirror_releases(
+    provider: &dyn Provider,
+    mirror: &Mirror,
+    dir: &Path,
+) -> Result<usize, String> {
+    let releases = provider.get_releases(mirror)?;
+    debug!("{} releases of {}", releases.len(), mirror.destination);
+
+    let mut downloaded = 0;
+    for release in releases {
+        let tag_dir = dir.join(path_component(&release.tag));
+        for asset in release.assets {
+            let file = tag_dir.join(path_component(&asset.name));
+            if is_present(&file, &asset) {
+                trace!("Release asset already present: {:?}", file);
+                continue;
+            }
+            fs::create_dir_all(&tag_dir)
+                .map_err(|e| format!("Unable to create {tag_dir:?} ({e})"))?;
+
+            // Only complete downloads end up under the final name
+            let part = file.with_file_name(format!("{}.part", path_component(&asset.name)));
+            info!("Download release asset {} to {:?}", asset.url, file);
+            provider.download_asset(&asset, &part)?;
+            fs::rename(&part, &file)
+                .map_err(|e| format!("Unable to move {part:?} to {file:?} ({e})"))?;
+            downloaded += 1;
+        }
+    }
+    Ok(downloaded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitized_names() {
+        assert_eq!(path_component("v1.0"), "v1.0");
+        assert_eq!(path_component("release/1.0"), "release_1.0");
+        assert_eq!(path_component(".."), "_..");
+    }
+
+    #[test]
+    fn present_by_size() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("a.bin");
+        fs::write(&file, "1234").unwrap();
+        let asset = |size| Asset {
+            name: "a.bin".to_string(),
+            url: "https://example.com/a.bin".to_string(),
+            size,
+        };
+        assert!(is_present(&file, &asset(Some(4))));
+        assert!(!is_present(&file, &asset(Some(5))));
+        assert!(is_present(&file, &asset(None)));
+        assert!(!is_present(&tmp.path().join("b.bin"), &asset(None)));
+    }
+}
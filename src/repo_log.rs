@@ -0,0 +1,107 @@
+/*
+ * Copyright (c) 2023 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use log::warn;
+
+// Used to create sane local file names
+use slug::slugify;
+
+use time::OffsetDateTime;
+
+/// Log file of a single repository
+#[derive(Debug, Default)]
+pub struct RepoLog {
+    file: Option<Mutex<File>>,
+}
+
+/// Get the path of a repository from its URL, e.g. `group/project` for
+/// `git@example.com:group/project.git` or `https://example.com/group/project.git`
+pub fn repo_path(url: &str) -> PathBuf {
+    let path = match url.split_once("://") {
+        Some((_, rest)) => rest.split_once('/').map(|(_, p)| p).unwrap_or_default(),
+        None => match url.split_once(':') {
+            // scp like syntax, but not a windows drive letter
+            Some((host, p)) if host.len() > 1 && !host.contains('/') => p,
+            _ => url,
+        },
+    };
+    let path = path.strip_suffix(".git").unwrap_or(path);
+
+    let path: PathBuf = path
+        .split(['/', '\\'])
+        .map(slugify)
+        .filter(|c| !c.is_empty())
+        .collect();
+
+    if path.as_os_str().is_empty() {
+        PathBuf::from(slugify(url))
+    } else {
+        path
+    }
+}
+
+impl RepoLog {
+    /// A log that discards all messages
+    pub fn disabled() -> RepoLog {
+        RepoLog::default()
+    }
+
+    /// Create (or overwrite) the log file for the repository `url` inside `dir`
+    pub fn create(dir: &Path, url: &str) -> RepoLog {
+        let path = dir.join(repo_path(url)).with_extension("log");
+        let file = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| File::create(&path));
+        match file {
+            Ok(f) => RepoLog {
+                file: Some(Mutex::new(f)),
+            },
+            Err(e) => {
+                warn!("Unable to create repository log {:?} ({})", path, e);
+                RepoLog::disabled()
+            }
+        }
+    }
+
+    /// Append a timestamped message to the log
+    pub fn log(&self, msg: impl Display) {
+        if let Some(ref file) = self.file {
+            let mut file = file.lock().unwrap();
+            if let Err(e) = writeln!(file, "[{}] {}", OffsetDateTime::now_utc(), msg) {
+                warn!("Unable to write repository log ({})", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::repo_path;
+    use std::path::PathBuf;
+
+    #[test]
+    fn paths() {
+        assert_eq!(
+            repo_path("git@example.com:group/sub/project.git"),
+            PathBuf::from("group/sub/project")
+        );
+        assert_eq!(
+            repo_path("https://example.com/group/project.wiki.git"),
+            PathBuf::from("group/project-wiki")
+        );
+        assert_eq!(
+            repo_path("ssh://git@example.com:2222/group/../project"),
+            PathBuf::from("group/project")
+        );
+    }
+}
@@ -0,0 +1,226 @@
+/*
+ * Copyright (c) 2023 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::fmt::Display;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+
+/// Upper bound for the delay between two attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Randomization of the exponential backoff delay
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Jitter {
+    /// Use the exponential delay as is
+    None,
+    /// Random delay between zero and the exponential delay
+    Full,
+    /// Half of the exponential delay plus a random delay up to the other half
+    Equal,
+}
+
+/// SplitMix64 pseudo random number generator, good enough to spread retries
+#[derive(Debug)]
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Random duration between zero and `max` (inclusive)
+    fn duration(&mut self, max: Duration) -> Duration {
+        let max = max.as_millis() as u64;
+        Duration::from_millis(self.next() % (max + 1))
+    }
+}
+
+/// Policy for retrying failed operations with exponential backoff
+#[derive(Debug)]
+pub struct RetryPolicy {
+    /// Number of retries after the first attempt
+    pub retries: u32,
+    /// Delay before the first retry, doubled for every further retry
+    pub backoff: Duration,
+    pub jitter: Jitter,
+    rng: Mutex<Rng>,
+}
+
+impl RetryPolicy {
+    pub fn new(retries: u32, backoff: Duration, jitter: Jitter) -> RetryPolicy {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
+        RetryPolicy {
+            retries,
+            backoff,
+            jitter,
+            rng: Mutex::new(Rng(seed)),
+        }
+    }
+
+    /// Use a fixed seed for the jitter, to get reproducible delays
+    pub fn with_seed(self, seed: u64) -> RetryPolicy {
+        RetryPolicy {
+            rng: Mutex::new(Rng(seed)),
+            ..self
+        }
+    }
+
+    /// Delay before retry number `retry` (starting at 0)
+    pub fn delay(&self, retry: u32) -> Duration {
+        let base = self
+            .backoff
+            .checked_mul(2u32.saturating_pow(retry))
+            .unwrap_or(MAX_BACKOFF)
+            .min(MAX_BACKOFF);
+        let mut rng = self.rng.lock().unwrap();
+        match self.jitter {
+            Jitter::None => base,
+            Jitter::Full => rng.duration(base),
+            Jitter::Equal => base / 2 + rng.duration(base / 2),
+        }
+    }
+
+    /// Run `f` until it succeeds or the retries are exhausted
+    pub fn retry<T, E: Display>(
+        &self,
+        what: &str,
+        f: impl FnMut() -> Result<T, E>,
+    ) -> Result<T, E> {
+        self.retry_if(what, f, |_| true)
+    }
+
+    /// Run `f` until it succeeds, the retries are exhausted or it fails with an error
+    /// for which `transient` is false
+    pub fn retry_if<T, E: Display>(
+        &self,
+        what: &str,
+        f: impl FnMut() -> Result<T, E>,
+        transient: impl Fn(&E) -> bool,
+    ) -> Result<T, E> {
+        self.retry_counted(what, f, transient).0
+    }
+
+    /// Like [`RetryPolicy::retry_if`], also returning the number of retries needed
+    pub fn retry_counted<T, E: Display>(
+        &self,
+        what: &str,
+        mut f: impl FnMut() -> Result<T, E>,
+        transient: impl Fn(&E) -> bool,
+    ) -> (Result<T, E>, u32) {
+        let mut retry = 0;
+        loop {
+            match f() {
+                Err(e) if retry < self.retries && transient(&e) => {
+                    let delay = self.delay(retry);
+                    warn!(
+                        "{} failed ({}), retry {}/{} in {:?}",
+                        what,
+                        e,
+                        retry + 1,
+                        self.retries,
+                        delay
+                    );
+                    thread::sleep(delay);
+                    retry += 1;
+                }
+                r => return (r, retry),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exponential_delay() {
+        let policy = RetryPolicy::new(5, Duration::from_secs(1), Jitter::None);
+        assert_eq!(policy.delay(0), Duration::from_secs(1));
+        assert_eq!(policy.delay(3), Duration::from_secs(8));
+        assert_eq!(policy.delay(20), MAX_BACKOFF);
+        assert_eq!(policy.delay(u32::MAX), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn jitter_bounds() {
+        let full = RetryPolicy::new(5, Duration::from_secs(4), Jitter::Full).with_seed(42);
+        let equal = RetryPolicy::new(5, Duration::from_secs(4), Jitter::Equal).with_seed(42);
+        for _ in 0..100 {
+            assert!(full.delay(1) <= Duration::from_secs(8));
+            let d = equal.delay(1);
+            assert!(d >= Duration::from_secs(4) && d <= Duration::from_secs(8));
+        }
+    }
+
+    #[test]
+    fn seeded_jitter_is_reproducible() {
+        let a = RetryPolicy::new(5, Duration::from_secs(4), Jitter::Full).with_seed(7);
+        let b = RetryPolicy::new(5, Duration::from_secs(4), Jitter::Full).with_seed(7);
+        for retry in 0..5 {
+            assert_eq!(a.delay(retry), b.delay(retry));
+        }
+    }
+
+    #[test]
+    fn retry_until_success() {
+        let policy = RetryPolicy::new(3, Duration::ZERO, Jitter::None);
+        let mut attempts = 0;
+        let r: Result<u32, String> = policy.retry("test", || {
+            attempts += 1;
+            if attempts < 3 {
+                Err("fail".to_string())
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert_eq!(r, Ok(3));
+
+        attempts = 0;
+        let (r, retries) = policy.retry_counted(
+            "test",
+            || {
+                attempts += 1;
+                if attempts < 2 {
+                    Err("fail".to_string())
+                } else {
+                    Ok(())
+                }
+            },
+            |_| true,
+        );
+        assert_eq!((r, retries), (Ok(()), 1));
+
+        let r: Result<(), String> = policy.retry("test", || Err("fail".to_string()));
+        assert!(r.is_err());
+    }
+
+    #[test]
+    fn no_retry_of_permanent_errors() {
+        let policy = RetryPolicy::new(3, Duration::ZERO, Jitter::None);
+        let mut attempts = 0;
+        let r: Result<(), String> = policy.retry_if(
+            "test",
+            || {
+                attempts += 1;
+                Err("denied".to_string())
+            },
+            |e| e != "denied",
+        );
+        assert!(r.is_err());
+        assert_eq!(attempts, 1);
+    }
+}
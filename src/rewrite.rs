@@ -0,0 +1,238 @@
+/*
+ * Copyright (c) 2023 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::fs;
+use std::path::Path;
+
+use regex::Regex;
+
+/// A rewrite rule applied to a destination URL before pushing
+#[derive(Debug, Clone)]
+pub enum DestRewrite {
+    /// Replace the first occurrence of `from` with `to`
+    Replace { from: String, to: String },
+    /// Replace all matches of `pattern` with `replacement`
+    Regex { pattern: Regex, replacement: String },
+}
+
+/// Split a `key=value` argument at the first `=`
+fn split_rule(s: &str) -> Result<(&str, &str), String> {
+    match s.split_once('=') {
+        Some(("", _)) => Err(format!("Invalid rewrite rule, empty pattern: {s}")),
+        Some(rule) => Ok(rule),
+        None => Err(format!("Invalid rewrite rule, expected <from>=<to>: {s}")),
+    }
+}
+
+impl DestRewrite {
+    /// Parse a `from=to` substring rewrite rule
+    pub fn parse_replace(s: &str) -> Result<DestRewrite, String> {
+        let (from, to) = split_rule(s)?;
+        Ok(DestRewrite::Replace {
+            from: from.to_owned(),
+            to: to.to_owned(),
+        })
+    }
+
+    /// Parse a `pattern=replacement` regex rewrite rule
+    pub fn parse_regex(s: &str) -> Result<DestRewrite, String> {
+        let (pattern, replacement) = split_rule(s)?;
+        let pattern =
+            Regex::new(pattern).map_err(|e| format!("Invalid rewrite regex: {pattern} ({e})"))?;
+        Ok(DestRewrite::Regex {
+            pattern,
+            replacement: replacement.to_owned(),
+        })
+    }
+
+    /// Apply the rule to the given destination
+    pub fn apply(&self, dest: &str) -> String {
+        match self {
+            DestRewrite::Replace { from, to } => dest.replacen(from.as_str(), to, 1),
+            DestRewrite::Regex {
+                pattern,
+                replacement,
+            } => pattern.replace_all(dest, replacement.as_str()).into_owned(),
+        }
+    }
+}
+
+/// Rename of a repository on the destination, `from` and `to` are repository paths like
+/// `group/project`, without host and `.git` suffix
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rename {
+    pub from: String,
+    pub to: String,
+}
+
+impl Rename {
+    /// Parse a `from=to` rename
+    pub fn parse(s: &str) -> Result<Rename, String> {
+        let (from, to) = split_rule(s)?;
+        let (from, to) = (from.trim().trim_matches('/'), to.trim().trim_matches('/'));
+        if to.is_empty() {
+            return Err(format!("Invalid rename, empty destination: {s}"));
+        }
+        Ok(Rename {
+            from: from.to_owned(),
+            to: to.to_owned(),
+        })
+    }
+
+    /// Read the renames from a file with a `from=to` rename per line.
+    /// Empty lines and lines starting with `#` are ignored.
+    pub fn read_file(path: &Path) -> Result<Vec<Rename>, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read rename file {path:?} ({e})"))?;
+        content
+            .lines()
+            .enumerate()
+            .map(|(i, l)| (i, l.trim()))
+            .filter(|(_, l)| !l.is_empty() && !l.starts_with('#'))
+            .map(|(i, l)| Rename::parse(l).map_err(|e| format!("{path:?} line {}: {e}", i + 1)))
+            .collect()
+    }
+}
+
+/// Split a repository URL into the part before the path, the path and the `.git` suffix
+fn split_path(url: &str) -> (&str, &str, &str) {
+    let start = match url.split_once("://") {
+        Some((scheme, rest)) => rest
+            .find('/')
+            .map_or(url.len(), |i| scheme.len() + 3 + i + 1),
+        None => match url.split_once(':') {
+            // scp like syntax, but not a windows drive letter
+            Some((host, _)) if host.len() > 1 && !host.contains('/') => host.len() + 1,
+            _ => 0,
+        },
+    };
+    let (prefix, path) = url.split_at(start);
+    let prefix_slashes = path.len() - path.trim_start_matches('/').len();
+    let (prefix, path) = url.split_at(prefix.len() + prefix_slashes);
+    match path.strip_suffix(".git") {
+        Some(p) => (prefix, p, ".git"),
+        None => (prefix, path, ""),
+    }
+}
+
+/// Path of the repository at `url`, without host and `.git` suffix
+pub(crate) fn repo_path(url: &str) -> &str {
+    split_path(url).1
+}
+
+/// Apply the first rename matching the repository path of the destination
+pub fn rename_destination(renames: &[Rename], dest: &str) -> String {
+    let (prefix, path, suffix) = split_path(dest);
+    match renames.iter().find(|r| r.from == path) {
+        Some(r) => format!("{prefix}{}{suffix}", r.to),
+        None => dest.to_owned(),
+    }
+}
+
+/// Case of the repository paths on the destination
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DestCase {
+    #[default]
+    Preserve,
+    Lower,
+    Upper,
+}
+
+impl DestCase {
+    /// Change the case of the repository path of the destination, the host and the `.git` suffix
+    /// are kept
+    pub fn apply(self, dest: &str) -> String {
+        let (prefix, path, suffix) = split_path(dest);
+        match self {
+            DestCase::Preserve => dest.to_owned(),
+            DestCase::Lower => format!("{prefix}{}{suffix}", path.to_lowercase()),
+            DestCase::Upper => format!("{prefix}{}{suffix}", path.to_uppercase()),
+        }
+    }
+}
+
+/// Apply all rules in order to the given destination
+pub fn rewrite_destination(rules: &[DestRewrite], dest: &str) -> String {
+    rules
+        .iter()
+        .fold(dest.to_owned(), |dest, rule| rule.apply(&dest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replace_host() {
+        let rule =
+            DestRewrite::parse_replace("old-backup.example.com=new-backup.example.com").unwrap();
+        assert_eq!(
+            rule.apply("git@old-backup.example.com:group/repo.git"),
+            "git@new-backup.example.com:group/repo.git"
+        );
+    }
+
+    #[test]
+    fn regex_rules_apply_in_order() {
+        let rules = vec![
+            DestRewrite::parse_regex(r"^git@([^:]+):=https://$1/").unwrap(),
+            DestRewrite::parse_replace("example.com=example.org").unwrap(),
+        ];
+        assert_eq!(
+            rewrite_destination(&rules, "git@example.com:group/repo.git"),
+            "https://example.org/group/repo.git"
+        );
+    }
+
+    #[test]
+    fn rename() {
+        let renames = vec![
+            Rename::parse("upstream/Foo=mirror/foo-legacy").unwrap(),
+            Rename::parse("con=con-repo").unwrap(),
+        ];
+        assert_eq!(
+            rename_destination(&renames, "git@example.com:upstream/Foo.git"),
+            "git@example.com:mirror/foo-legacy.git"
+        );
+        assert_eq!(
+            rename_destination(&renames, "https://example.com/con"),
+            "https://example.com/con-repo"
+        );
+        assert_eq!(
+            rename_destination(&renames, "ssh://git@example.com:2222/upstream/Foo.git"),
+            "ssh://git@example.com:2222/mirror/foo-legacy.git"
+        );
+        // Only complete paths are renamed
+        assert_eq!(
+            rename_destination(&renames, "git@example.com:upstream/Foobar.git"),
+            "git@example.com:upstream/Foobar.git"
+        );
+        assert!(Rename::parse("from=").is_err());
+    }
+
+    #[test]
+    fn dest_case() {
+        assert_eq!(
+            DestCase::Lower.apply("git@Example.com:Group/MyRepo.git"),
+            "git@Example.com:group/myrepo.git"
+        );
+        assert_eq!(
+            DestCase::Upper.apply("https://example.com/group/MyRepo.git"),
+            "https://example.com/GROUP/MYREPO.git"
+        );
+        assert_eq!(
+            DestCase::Preserve.apply("ssh://git@example.com:2222/Group/MyRepo"),
+            "ssh://git@example.com:2222/Group/MyRepo"
+        );
+    }
+
+    #[test]
+    fn invalid_rules() {
+        assert!(DestRewrite::parse_replace("no-separator").is_err());
+        assert!(DestRewrite::parse_replace("=to").is_err());
+        assert!(DestRewrite::parse_regex("(=x").is_err());
+    }
+}
@@ -0,0 +1,152 @@
+/*
+ * Copyright (c) 2023 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::{debug, warn};
+use slug::slugify;
+
+/// Directory in `work_tmp` the local repository `local_dir` of `mirror_dir` is synced in.
+/// Separate for every mirror directory, so runs against different mirror directories can share
+/// the scratch space while the lock of the mirror directory keeps each one to a single run.
+pub fn scratch_dir(work_tmp: &Path, mirror_dir: &Path, local_dir: &Path) -> PathBuf {
+    let mirror_dir = mirror_dir
+        .canonicalize()
+        .unwrap_or_else(|_| mirror_dir.to_path_buf());
+    let name = local_dir.file_name().unwrap_or(local_dir.as_os_str());
+    work_tmp
+        .join(slugify(mirror_dir.to_string_lossy()))
+        .join(name)
+}
+
+/// Move the local repository into the scratch directory to sync it there.
+/// A scratch copy left by an interrupted run is only used if the local repository is missing,
+/// as it then holds the latest state.
+pub fn enter(local_dir: &Path, scratch: &Path) -> io::Result<()> {
+    if scratch.exists() {
+        if !local_dir.exists() {
+            debug!("Continue with the scratch copy {:?}", scratch);
+            return Ok(());
+        }
+        warn!("Removing the stale scratch copy {:?}", scratch);
+        fs::remove_dir_all(scratch)?;
+    }
+    if let Some(parent) = scratch.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if local_dir.exists() {
+        relocate(local_dir, scratch)?;
+    }
+    Ok(())
+}
+
+/// Move the synced repository back from the scratch directory into the mirror directory.
+/// Nothing is moved if the sync removed it (e.g. `--cleanup always` or a failed clone).
+pub fn leave(scratch: &Path, local_dir: &Path) -> io::Result<()> {
+    if scratch.exists() {
+        relocate(scratch, local_dir)?;
+    }
+    Ok(())
+}
+
+/// Move the directory `from` to `to`. Across file systems `from` is copied next to `to` and
+/// renamed into place, so `to` never holds a partial copy, before `from` is removed.
+fn relocate(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            debug!("Copy {:?} to {:?} across file systems", from, to);
+            let mut tmp = to.as_os_str().to_owned();
+            tmp.push(".tmp");
+            let tmp = PathBuf::from(tmp);
+            if tmp.exists() {
+                fs::remove_dir_all(&tmp)?;
+            }
+            copy_tree(from, &tmp)?;
+            fs::rename(&tmp, to)?;
+            fs::remove_dir_all(from)
+        }
+        result => result,
+    }
+}
+
+/// Copy the directory `from` with its files, permissions and symlinks to `to`
+fn copy_tree(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir(to)?;
+    fs::set_permissions(to, fs::metadata(from)?.permissions())?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let (src, dst) = (entry.path(), to.join(entry.file_name()));
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            copy_tree(&src, &dst)?;
+        } else if file_type.is_symlink() {
+            copy_symlink(&src, &dst)?;
+        } else {
+            fs::copy(&src, &dst)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn copy_symlink(src: &Path, dst: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(fs::read_link(src)?, dst)
+}
+
+#[cfg(not(unix))]
+fn copy_symlink(src: &Path, dst: &Path) -> io::Result<()> {
+    fs::copy(src, dst).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn copy_tree_with_contents() {
+        let tmp = tempfile::tempdir().unwrap();
+        let from = tmp.path().join("from");
+        fs::create_dir_all(from.join("objects/pack")).unwrap();
+        fs::write(from.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        fs::write(from.join("objects/pack/a.pack"), "pack").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("HEAD", from.join("link")).unwrap();
+
+        let to = tmp.path().join("to");
+        copy_tree(&from, &to).unwrap();
+        assert_eq!(
+            fs::read_to_string(to.join("HEAD")).unwrap(),
+            "ref: refs/heads/main\n"
+        );
+        assert_eq!(
+            fs::read_to_string(to.join("objects/pack/a.pack")).unwrap(),
+            "pack"
+        );
+        #[cfg(unix)]
+        assert_eq!(fs::read_link(to.join("link")).unwrap(), Path::new("HEAD"));
+    }
+
+    #[test]
+    fn enter_and_leave() {
+        let tmp = tempfile::tempdir().unwrap();
+        let local = tmp.path().join("mirror/repo");
+        let scratch = tmp.path().join("scratch/mirror/repo");
+        fs::create_dir_all(&local).unwrap();
+        fs::write(local.join("HEAD"), "new").unwrap();
+        // Left by an interrupted run while the local repository is still there
+        fs::create_dir_all(&scratch).unwrap();
+        fs::write(scratch.join("HEAD"), "stale").unwrap();
+
+        enter(&local, &scratch).unwrap();
+        assert!(!local.exists());
+        assert_eq!(fs::read_to_string(scratch.join("HEAD")).unwrap(), "new");
+        leave(&scratch, &local).unwrap();
+        assert!(!scratch.exists());
+        assert_eq!(fs::read_to_string(local.join("HEAD")).unwrap(), "new");
+    }
+}
@@ -0,0 +1,80 @@
+/*
+ * Copyright (c) 2023 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+/// A part of the repositories, to split the sync across multiple hosts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+    /// Index of this shard, starting at 0
+    pub index: u64,
+    pub total: u64,
+}
+
+/// FNV-1a hash, stable across runs, hosts and versions (unlike the std `DefaultHasher`)
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+impl Shard {
+    /// Parse a shard in the form `<index>/<total>`, e.g. `0/3`
+    pub fn parse(s: &str) -> Result<Shard, String> {
+        let (index, total) = s
+            .split_once('/')
+            .ok_or_else(|| format!("Shard must be in the form <index>/<total>: {s}"))?;
+        let index = index
+            .parse()
+            .map_err(|e| format!("Invalid shard index {index} ({e})"))?;
+        let total: u64 = total
+            .parse()
+            .map_err(|e| format!("Invalid shard total {total} ({e})"))?;
+        if total == 0 {
+            return Err("Shard total must be at least 1".to_string());
+        }
+        if index >= total {
+            return Err(format!(
+                "Shard index must be between 0 and {} for {} shards",
+                total - 1,
+                total
+            ));
+        }
+        Ok(Shard { index, total })
+    }
+
+    /// Check if the repository identified by `key` belongs to this shard
+    pub fn contains(&self, key: &str) -> bool {
+        fnv1a(key.as_bytes()) % self.total == self.index
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(Shard::parse("1/3"), Ok(Shard { index: 1, total: 3 }));
+        assert!(Shard::parse("3/3").is_err());
+        assert!(Shard::parse("0/0").is_err());
+        assert!(Shard::parse("1").is_err());
+        assert!(Shard::parse("a/3").is_err());
+    }
+
+    #[test]
+    fn stable_hash() {
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn disjoint_and_complete() {
+        let shards: Vec<Shard> = (0..3).map(|index| Shard { index, total: 3 }).collect();
+        for i in 0..100 {
+            let key = format!("git@example.com:group/project-{i}.git");
+            assert_eq!(shards.iter().filter(|s| s.contains(&key)).count(), 1);
+        }
+    }
+}
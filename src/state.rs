@@ -0,0 +1,124 @@
+/*
+ * Copyright (c) 2023 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::{trace, warn};
+
+/// Name of the file inside the local repository the state is stored in
+const STATE_FILE: &str = "git-mirror-state.json";
+
+/// State of a mirrored repository persisted between runs
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct RepoState {
+    /// Refs of the origin as seen during the last successful sync
+    #[serde(default)]
+    pub origin_refs: BTreeMap<String, String>,
+    /// Stable identifier of the project, see `Mirror::id`
+    #[serde(default)]
+    pub id: Option<String>,
+    /// Time of the last complete sync, in seconds since the Unix epoch
+    #[serde(default)]
+    pub last_success: Option<i64>,
+    /// Origin the repository was last fetched from, checked before pruning its destination
+    #[serde(default)]
+    pub origin: Option<String>,
+    /// Destination the repository was last pushed to
+    #[serde(default)]
+    pub destination: Option<String>,
+    /// Identifier of the project in the provider API, see `Mirror::project`
+    #[serde(default)]
+    pub project: Option<String>,
+    /// Activity of the project listed before the last complete sync, see `Mirror::activity`
+    #[serde(default)]
+    pub activity: Option<i64>,
+    /// Time the origin was fetched by the last complete sync, in seconds since the Unix epoch
+    #[serde(default)]
+    pub fetched_at: Option<i64>,
+    /// Refs in the bundles written so far by destination, the next bundle only contains their
+    /// changes
+    #[serde(default)]
+    pub bundled_refs: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+fn state_file(repo_dir: &Path) -> PathBuf {
+    repo_dir.join(STATE_FILE)
+}
+
+impl RepoState {
+    /// Load the state of the repository in `repo_dir`.
+    /// A missing or unreadable state results in an empty state.
+    pub fn load(repo_dir: &Path) -> RepoState {
+        let path = state_file(repo_dir);
+        match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                warn!("Ignoring invalid state file {:?} ({})", path, e);
+                RepoState::default()
+            }),
+            Err(e) => {
+                trace!("No state loaded from {:?} ({})", path, e);
+                RepoState::default()
+            }
+        }
+    }
+
+    /// Store the state of the repository in `repo_dir`
+    pub fn store(&self, repo_dir: &Path) -> io::Result<()> {
+        let data = serde_json::to_vec_pretty(self)?;
+        fs::write(state_file(repo_dir), data)
+    }
+}
+
+/// The local repositories in `mirror_dir` by the stable identifier of their project
+pub fn repo_ids(mirror_dir: &Path) -> BTreeMap<String, PathBuf> {
+    let entries = match fs::read_dir(mirror_dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            trace!("No repositories found in {:?} ({})", mirror_dir, e);
+            return BTreeMap::new();
+        }
+    };
+    entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|dir| state_file(dir).is_file())
+        .filter_map(|dir| RepoState::load(&dir).id.map(|id| (id, dir)))
+        .collect()
+}
+
+/// The local repositories in `mirror_dir` with a stored state, sorted by path
+pub fn repo_dirs(mirror_dir: &Path) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(mirror_dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            trace!("No repositories found in {:?} ({})", mirror_dir, e);
+            return Vec::new();
+        }
+    };
+    let mut dirs: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|dir| state_file(dir).is_file())
+        .collect();
+    dirs.sort();
+    dirs
+}
+
+/// The local repositories in `mirror_dir` not synced completely since `cutoff` (seconds since
+/// the Unix epoch). Repositories without a recorded sync are never stale.
+pub fn stale_repos(mirror_dir: &Path, cutoff: i64) -> Vec<PathBuf> {
+    repo_dirs(mirror_dir)
+        .into_iter()
+        .filter(|dir| {
+            RepoState::load(dir)
+                .last_success
+                .is_some_and(|t| t < cutoff)
+        })
+        .collect()
+}
@@ -0,0 +1,350 @@
+/*
+ * Copyright (c) 2023 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use junit_report::{TestCase, TestResult, TestSuite};
+use log::warn;
+
+use crate::error::{GitMirrorError, Result};
+use crate::git::GitFailureKind;
+use crate::transfer::TransferStats;
+use crate::SkipReason;
+
+/// Format of the summary printed at the end of a run
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SummaryFormat {
+    /// Only the human readable `DONE` line
+    Text,
+    /// Additionally a single line JSON object as last line of the output
+    Json,
+}
+
+/// Result of a complete run
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Summary {
+    pub total: usize,
+    pub success: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    /// Number of failed jobs by cause, e.g. `{"auth": 47, "network": 3}`
+    pub failure_kinds: BTreeMap<GitFailureKind, usize>,
+    /// Number of skipped jobs by cause, e.g. `{"disabled": 2, "too_many_refs": 1}`
+    pub skip_reasons: BTreeMap<SkipReason, usize>,
+    /// Ratio of successful to attempted (not skipped) jobs, 1.0 if no job was attempted
+    pub success_rate: f64,
+    /// Retries of all jobs
+    pub retries: u64,
+    pub duration_secs: f64,
+    /// `ok`, `sync_failures`, `locked` or `error`
+    pub exit_reason: String,
+    pub exit_code: i32,
+    /// Message of the error that ended the run
+    pub error: Option<String>,
+    /// The results are simulated by `--dry-run`
+    pub dry_run: bool,
+    /// Bytes received and sent as reported by git, only counted with `--max-transfer`
+    pub transferred_bytes: Option<u64>,
+    /// Size of the local repositories with `--report-sizes`, by destination
+    pub repo_sizes: Option<Vec<RepoSize>>,
+    /// Total size of the local repositories with `--report-sizes`
+    pub total_size_bytes: Option<u64>,
+    /// Transfers of every attempted job with `--transfer-stats`, by destination
+    pub repo_transfers: Option<Vec<RepoTransfer>>,
+    /// Jobs that synced the refs, but not the LFS objects with `--lfs-failure warn`
+    pub lfs_failures: Option<Vec<String>>,
+    /// Set if only a shard of the repositories was synced
+    pub shard: Option<ShardSummary>,
+    /// Result of every job by `<origin> -> <destination>` with `--baseline-report`, the baseline
+    /// of the next run
+    pub repos: Option<BTreeMap<String, JobStatus>>,
+    /// Changes since the baseline run
+    pub diff: Option<ReportDiff>,
+    /// Results of the jobs for `--json-report`, not part of the summary
+    #[serde(skip)]
+    pub jobs: Vec<JobReport>,
+}
+
+/// Result of a sync job
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Success,
+    Skipped,
+    Failed,
+}
+
+impl JobStatus {
+    pub fn of(tc: &TestCase) -> JobStatus {
+        match tc.result {
+            TestResult::Success => JobStatus::Success,
+            TestResult::Skipped => JobStatus::Skipped,
+            TestResult::Error { .. } | TestResult::Failure { .. } => JobStatus::Failed,
+        }
+    }
+}
+
+/// Result of a sync job in the `--json-report`
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct JobReport {
+    /// `<origin> -> <destination>`, as in the JUnit report
+    pub name: String,
+    /// Origin of the job, not set for invalid descriptions
+    pub source: Option<String>,
+    pub destination: Option<String>,
+    pub duration_secs: f64,
+    pub result: JobStatus,
+    /// Message of a failed job
+    pub error: Option<String>,
+    pub failure_kind: Option<GitFailureKind>,
+    pub skip_reason: Option<SkipReason>,
+    /// Commits the fetch added to the local repository
+    pub commits_fetched: Option<u64>,
+    /// Commits of the local repository the destination didn't have before the push
+    pub commits_pushed: Option<u64>,
+    /// Number of retries of the sync
+    pub retries: u32,
+}
+
+/// Content of the file written with `--json-report`
+#[derive(Serialize, Debug)]
+pub struct JsonReport<'a> {
+    #[serde(flatten)]
+    pub summary: &'a Summary,
+    pub projects: &'a [JobReport],
+}
+
+/// Changes of the job results since a previous run
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct ReportDiff {
+    /// Failed in this run, but not in the baseline (including new repositories)
+    pub newly_failed: Vec<String>,
+    /// Failed in the baseline and succeeded in this run
+    pub newly_succeeded: Vec<String>,
+    /// In the baseline, but not in this run
+    pub disappeared: Vec<String>,
+}
+
+impl ReportDiff {
+    pub fn new(
+        baseline: &BTreeMap<String, JobStatus>,
+        current: &BTreeMap<String, JobStatus>,
+    ) -> ReportDiff {
+        let before = |name: &String| baseline.get(name).copied();
+        let mut diff = ReportDiff::default();
+        for (name, status) in current {
+            match (before(name), status) {
+                (Some(JobStatus::Failed), JobStatus::Failed) => {}
+                (_, JobStatus::Failed) => diff.newly_failed.push(name.clone()),
+                (Some(JobStatus::Failed), JobStatus::Success) => {
+                    diff.newly_succeeded.push(name.clone())
+                }
+                _ => {}
+            }
+        }
+        diff.disappeared = baseline
+            .keys()
+            .filter(|name| !current.contains_key(*name))
+            .cloned()
+            .collect();
+        diff
+    }
+}
+
+/// The part of a JSON summary needed as baseline
+#[derive(Deserialize)]
+struct Baseline {
+    repos: Option<BTreeMap<String, JobStatus>>,
+}
+
+/// Read the job results of a previous run from its JSON summary, or from its output ending with
+/// the summary. A missing file is an empty baseline, e.g. for the first run.
+pub fn load_baseline(path: &Path) -> Result<BTreeMap<String, JobStatus>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            warn!("Baseline report {:?} doesn't exist yet", path);
+            return Ok(BTreeMap::new());
+        }
+        Err(e) => {
+            return Err(GitMirrorError::GenericError(format!(
+                "Unable to read baseline report {path:?} ({e})"
+            )))
+        }
+    };
+    let summary = content
+        .lines()
+        .rev()
+        .find(|l| l.starts_with('{'))
+        .unwrap_or_default();
+    let baseline: Baseline = serde_json::from_str(summary).map_err(|e| {
+        GitMirrorError::GenericError(format!("Invalid baseline report {path:?} ({e})"))
+    })?;
+    baseline.repos.ok_or_else(|| {
+        GitMirrorError::GenericError(format!(
+            "Baseline report {path:?} has no job results, it must be the JSON summary of a run \
+             with --baseline-report"
+        ))
+    })
+}
+
+/// Size of the shard of a run
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ShardSummary {
+    pub index: u64,
+    pub total: u64,
+    /// Number of repositories listed by the provider
+    pub listed: usize,
+    /// Number of listed repositories in the shard
+    pub repos: usize,
+}
+
+/// Disk usage of a synced local repository
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct RepoSize {
+    pub origin: String,
+    pub destination: String,
+    /// Size of the objects, loose and packed
+    pub bytes: u64,
+}
+
+/// Transfers of a sync job
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct RepoTransfer {
+    pub origin: String,
+    pub destination: String,
+    #[serde(flatten)]
+    pub stats: TransferStats,
+    /// Size of the local repository after the job, as reported by `git count-objects -v`
+    pub repo_bytes: Option<u64>,
+}
+
+impl Summary {
+    /// Take the counts of the sync jobs from the test suite
+    pub fn count(&mut self, ts: &TestSuite) {
+        self.total = ts.tests();
+        self.skipped = ts.skipped();
+        self.failed = ts.errors() + ts.failures();
+        self.success = self.total - self.skipped - self.failed;
+        self.success_rate = match self.success + self.failed {
+            0 => 1.0,
+            attempted => self.success as f64 / attempted as f64,
+        };
+    }
+
+    /// Record the result of every job, jobs without name (invalid descriptions) can't be compared
+    pub fn statuses(&mut self, ts: &TestSuite) {
+        let statuses = ts
+            .testcases
+            .iter()
+            .filter(|tc| !tc.name.is_empty())
+            .map(|tc| (tc.name.clone(), JobStatus::of(tc)));
+        self.repos = Some(statuses.collect());
+    }
+
+    /// Record the sizes of the local repositories and their total
+    pub fn sizes(&mut self, mut sizes: Vec<RepoSize>) {
+        sizes.sort_by(|a, b| a.destination.cmp(&b.destination));
+        self.total_size_bytes = Some(sizes.iter().map(|s| s.bytes).sum());
+        self.repo_sizes = Some(sizes);
+    }
+
+    /// Set the transfers of the jobs
+    pub fn transfers(&mut self, mut transfers: Vec<RepoTransfer>) {
+        transfers.sort_by(|a, b| a.destination.cmp(&b.destination));
+        self.repo_transfers = Some(transfers);
+    }
+
+    /// Record the duration and the result of the run
+    pub fn finish(&mut self, duration: Duration, result: &Result<()>) {
+        self.duration_secs = duration.as_secs_f64();
+        match result {
+            Ok(()) => {
+                self.exit_reason = "ok".to_string();
+                self.exit_code = 0;
+            }
+            Err(e) => {
+                self.exit_reason = match e {
+                    GitMirrorError::SyncError(_) => "sync_failures",
+                    GitMirrorError::Locked(_) => "locked",
+                    _ => "error",
+                }
+                .to_string();
+                self.exit_code = e.exit_code();
+                self.error = Some(e.to_string());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use junit_report::{TestCaseBuilder, TestSuiteBuilder};
+
+    #[test]
+    fn counts_and_result() {
+        let ts = TestSuiteBuilder::new("Sync Job")
+            .add_testcase(TestCaseBuilder::success("a", time::Duration::ZERO).build())
+            .add_testcase(TestCaseBuilder::skipped("b").build())
+            .add_testcase(
+                TestCaseBuilder::error("c", time::Duration::ZERO, "sync error", "failed").build(),
+            )
+            .build();
+
+        let mut summary = Summary::default();
+        summary.count(&ts);
+        summary.finish(Duration::from_secs(2), &Err(GitMirrorError::SyncError(1)));
+
+        assert_eq!((summary.total, summary.success), (3, 1));
+        assert_eq!((summary.skipped, summary.failed), (1, 1));
+        assert_eq!(summary.success_rate, 0.5);
+        assert_eq!(summary.exit_reason, "sync_failures");
+        assert_eq!(summary.exit_code, 1);
+        assert_eq!(summary.duration_secs, 2.0);
+    }
+
+    #[test]
+    fn diff() {
+        let statuses = |s: &[(&str, JobStatus)]| -> BTreeMap<String, JobStatus> {
+            s.iter().map(|(n, s)| (n.to_string(), *s)).collect()
+        };
+        let baseline = statuses(&[
+            ("a", JobStatus::Success),
+            ("b", JobStatus::Failed),
+            ("c", JobStatus::Failed),
+            ("d", JobStatus::Success),
+        ]);
+        let current = statuses(&[
+            ("a", JobStatus::Failed),
+            ("b", JobStatus::Success),
+            ("c", JobStatus::Failed),
+            ("e", JobStatus::Failed),
+        ]);
+        assert_eq!(
+            ReportDiff::new(&baseline, &current),
+            ReportDiff {
+                newly_failed: vec!["a".to_string(), "e".to_string()],
+                newly_succeeded: vec!["b".to_string()],
+                disappeared: vec!["d".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn rate_without_attempts() {
+        let ts = TestSuiteBuilder::new("Sync Job")
+            .add_testcase(TestCaseBuilder::skipped("a").build())
+            .build();
+        let mut summary = Summary::default();
+        summary.count(&ts);
+        assert_eq!(summary.success_rate, 1.0);
+    }
+}
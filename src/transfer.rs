@@ -0,0 +1,267 @@
+/*
+ * Copyright (c) 2023 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Bytes transferred by git during the current run, as reported in its progress output
+static TRANSFERRED: AtomicU64 = AtomicU64::new(0);
+
+/// Start counting the transferred bytes of a new run
+pub fn reset() {
+    TRANSFERRED.store(0, Ordering::SeqCst);
+}
+
+/// Count bytes transferred by a git command
+pub fn add(bytes: u64) {
+    TRANSFERRED.fetch_add(bytes, Ordering::SeqCst);
+}
+
+/// Bytes transferred since the start of the run
+pub fn transferred() -> u64 {
+    TRANSFERRED.load(Ordering::SeqCst)
+}
+
+/// Transfers of a sync job, with `--transfer-stats`
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct TransferStats {
+    /// Wall time of fetching from the origin
+    pub fetch_secs: f64,
+    /// Wall time of pushing to the destination
+    pub push_secs: f64,
+    pub objects_received: u64,
+    pub bytes_received: u64,
+    pub objects_sent: u64,
+    pub bytes_sent: u64,
+    /// Commits added to the local repository by the fetch, counted with `--json-report`
+    pub commits_fetched: Option<u64>,
+    /// Commits the destination didn't have before the push, counted with `--json-report`
+    pub commits_pushed: Option<u64>,
+}
+
+/// Phase of a sync job
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Fetch,
+    Push,
+}
+
+thread_local! {
+    /// Stats of the job running on this thread, if they are recorded
+    static JOB: RefCell<Option<TransferStats>> = const { RefCell::new(None) };
+}
+
+/// Run the job `f`, recording the transfers of the git commands it runs on this thread
+pub fn record<T>(f: impl FnOnce() -> T) -> (T, TransferStats) {
+    let outer = JOB.with(|j| j.replace(Some(TransferStats::default())));
+    let result = f();
+    let stats = JOB.with(|j| j.replace(outer)).unwrap_or_default();
+    (result, stats)
+}
+
+/// Count the progress output of a git command for the job of this thread. A command transfers
+/// at most one pack in each direction, its progress can be split over several lines.
+pub fn add_job_progress(stderr: &str) {
+    JOB.with(|j| {
+        if let Some(ref mut stats) = *j.borrow_mut() {
+            let (mut received, mut sent) = ((0, 0), (0, 0));
+            for p in stderr
+                .lines()
+                .filter_map(|l| l.rsplit('\r').find_map(progress))
+            {
+                let total = match p.phase {
+                    Phase::Fetch => &mut received,
+                    Phase::Push => &mut sent,
+                };
+                *total = (total.0.max(p.objects), total.1.max(p.bytes));
+            }
+            stats.objects_received += received.0;
+            stats.bytes_received += received.1;
+            stats.objects_sent += sent.0;
+            stats.bytes_sent += sent.1;
+        }
+    })
+}
+
+/// Count a transfer of the job of this thread reported without progress output
+#[cfg(feature = "libgit2")]
+pub fn add_job_transfer(phase: Phase, objects: u64, bytes: u64) {
+    add(bytes);
+    JOB.with(|j| {
+        if let Some(ref mut stats) = *j.borrow_mut() {
+            match phase {
+                Phase::Fetch => {
+                    stats.objects_received += objects;
+                    stats.bytes_received += bytes;
+                }
+                Phase::Push => {
+                    stats.objects_sent += objects;
+                    stats.bytes_sent += bytes;
+                }
+            }
+        }
+    })
+}
+
+/// Count bytes sent by the job of this thread without a git transfer, e.g. a written bundle
+pub fn add_job_sent(bytes: u64) {
+    add(bytes);
+    JOB.with(|j| {
+        if let Some(ref mut stats) = *j.borrow_mut() {
+            stats.bytes_sent += bytes;
+        }
+    })
+}
+
+/// Add the time spent in a phase to the job of this thread
+pub fn add_job_time(phase: Phase, time: Duration) {
+    JOB.with(|j| {
+        if let Some(ref mut stats) = *j.borrow_mut() {
+            match phase {
+                Phase::Fetch => stats.fetch_secs += time.as_secs_f64(),
+                Phase::Push => stats.push_secs += time.as_secs_f64(),
+            }
+        }
+    })
+}
+
+/// Set the number of commits transferred in a phase by the job of this thread
+pub fn set_job_commits(phase: Phase, commits: u64) {
+    JOB.with(|j| {
+        if let Some(ref mut stats) = *j.borrow_mut() {
+            match phase {
+                Phase::Fetch => stats.commits_fetched = Some(commits),
+                Phase::Push => stats.commits_pushed = Some(commits),
+            }
+        }
+    })
+}
+
+/// A final progress update
+struct Progress {
+    phase: Phase,
+    objects: u64,
+    bytes: u64,
+}
+
+/// Parse a progress update, e.g. `Receiving objects: 100% (3/3), 1.20 MiB | 2.00 MiB/s, done.`
+fn progress(update: &str) -> Option<Progress> {
+    let update = update.trim_start();
+    let (phase, rest) = match update.strip_prefix("Receiving objects:") {
+        Some(rest) => (Phase::Fetch, rest),
+        None => (Phase::Push, update.strip_prefix("Writing objects:")?),
+    };
+    let (count, rest) = rest.split_once(')')?;
+    let objects = count.rsplit('/').next()?.trim().parse().ok()?;
+    // Without size until enough was transferred
+    let bytes = rest.strip_prefix(", ").and_then(size).unwrap_or(0);
+    Some(Progress {
+        phase,
+        objects,
+        bytes,
+    })
+}
+
+/// Parse the size of a progress update, e.g. `1.20 MiB | 2.00 MiB/s, done.`
+fn size(s: &str) -> Option<u64> {
+    let size = s.split([',', '|']).next()?.trim();
+    let (number, unit) = size.split_once(' ')?;
+    let number: f64 = number.parse().ok()?;
+    let factor: u64 = match unit {
+        "bytes" => 1,
+        "KiB" => 1 << 10,
+        "MiB" => 1 << 20,
+        "GiB" => 1 << 30,
+        _ => return None,
+    };
+    Some((number * factor as f64) as u64)
+}
+
+/// Bytes received and sent according to the progress output (`--progress`) of a git command.
+/// The updates of a progress line are separated by `\r`, the last one has the final size.
+pub fn parse_progress(stderr: &str) -> u64 {
+    stderr
+        .lines()
+        .filter_map(|l| l.rsplit('\r').find_map(progress))
+        .map(|p| p.bytes)
+        .sum()
+}
+
+/// Only keep the last update of each progress line, for the logs
+pub fn final_progress(stderr: &str) -> String {
+    stderr
+        .lines()
+        .map(|l| l.split('\r').rfind(|u| !u.is_empty()).unwrap_or_default())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Human readable size in the units used by git
+pub fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        b if b >= 1 << 30 => format!("{:.2} GiB", b as f64 / (1u64 << 30) as f64),
+        b if b >= 1 << 20 => format!("{:.2} MiB", b as f64 / (1u64 << 20) as f64),
+        b if b >= 1 << 10 => format!("{:.2} KiB", b as f64 / (1u64 << 10) as f64),
+        b => format!("{b} bytes"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress() {
+        let stderr = "remote: Enumerating objects: 5, done.\n\
+            Receiving objects:  33% (1/3)\rReceiving objects:  66% (2/3), 1.00 MiB | 1.00 MiB/s\r\
+            Receiving objects: 100% (3/3), 1.50 MiB | 1.00 MiB/s, done.\n\
+            Resolving deltas: 100% (1/1), done.\n\
+            Writing objects: 100% (3/3), 222 bytes | 222.00 KiB/s, done.\n";
+        assert_eq!(parse_progress(stderr), 1_572_864 + 222);
+        assert_eq!(parse_progress("Everything up-to-date\n"), 0);
+        assert_eq!(
+            final_progress("Writing objects:  50% (1/2)\rWriting objects: 100% (2/2), done.\n"),
+            "Writing objects: 100% (2/2), done."
+        );
+    }
+
+    #[test]
+    fn job_stats() {
+        let ((), stats) = record(|| {
+            add_job_progress(
+                "Receiving objects:  50% (1/2), 512 bytes\n\
+                 Receiving objects: 100% (2/2), 1.00 KiB | 1.00 MiB/s, done.\n",
+            );
+            add_job_progress("Writing objects: 100% (7/7), 222 bytes | 222.00 KiB/s, done.\n");
+            add_job_time(Phase::Fetch, Duration::from_millis(1500));
+            set_job_commits(Phase::Push, 3);
+        });
+        assert_eq!(
+            stats,
+            TransferStats {
+                fetch_secs: 1.5,
+                push_secs: 0.0,
+                objects_received: 2,
+                bytes_received: 1024,
+                objects_sent: 7,
+                bytes_sent: 222,
+                commits_fetched: None,
+                commits_pushed: Some(3),
+            }
+        );
+        // Nothing is recorded outside of a job
+        add_job_progress("Writing objects: 100% (7/7), 222 bytes | 222.00 KiB/s, done.\n");
+        assert_eq!(record(|| ()).1, TransferStats::default());
+    }
+
+    #[test]
+    fn formatted() {
+        assert_eq!(format_bytes(222), "222 bytes");
+        assert_eq!(format_bytes(1_572_864), "1.50 MiB");
+        assert_eq!(format_bytes(3 << 30), "3.00 GiB");
+    }
+}
@@ -0,0 +1,210 @@
+/*
+ * Copyright (c) 2023 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+
+use crate::provider::hosted_path;
+
+/// A push to a repository, reported by a webhook of GitHub or GitLab
+#[derive(Debug, PartialEq, Eq)]
+pub struct Push {
+    pub host: String,
+    /// Path of the repository on `host`, e.g. `group/project`
+    pub path: String,
+}
+
+impl Push {
+    /// Check if the job mirrors the pushed repository
+    pub fn matches(&self, origin: &str) -> bool {
+        hosted_path(origin, &self.host).is_some_and(|p| p.eq_ignore_ascii_case(&self.path))
+    }
+}
+
+/// A webhook request
+#[derive(Debug, PartialEq, Eq)]
+pub enum Hook {
+    Push(Push),
+    /// Any other event, e.g. the `ping` GitHub sends when the webhook is created
+    Ignored(String),
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum WebhookError {
+    #[error("Invalid webhook secret")]
+    Unauthorized,
+    #[error("Invalid webhook ({0})")]
+    Invalid(String),
+}
+
+#[derive(Deserialize)]
+struct GitHubPush {
+    repository: GitHubRepository,
+}
+
+#[derive(Deserialize)]
+struct GitHubRepository {
+    full_name: String,
+    html_url: String,
+}
+
+#[derive(Deserialize)]
+struct GitLabPush {
+    project: GitLabProject,
+}
+
+#[derive(Deserialize)]
+struct GitLabProject {
+    path_with_namespace: String,
+    web_url: String,
+}
+
+/// Compare the secrets in constant time
+fn secret_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && memcmp::eq(a, b)
+}
+
+/// `sha256=<hex>` signature GitHub sends in `X-Hub-Signature-256`
+fn github_signature(secret: &str, body: &[u8]) -> Result<String, WebhookError> {
+    let key = PKey::hmac(secret.as_bytes()).map_err(|e| WebhookError::Invalid(e.to_string()))?;
+    let mac = Signer::new(MessageDigest::sha256(), &key)
+        .and_then(|mut signer| signer.sign_oneshot_to_vec(body))
+        .map_err(|e| WebhookError::Invalid(e.to_string()))?;
+    let hex: String = mac.iter().map(|b| format!("{b:02x}")).collect();
+    Ok(format!("sha256={hex}"))
+}
+
+fn payload<T: DeserializeOwned>(body: &[u8]) -> Result<T, WebhookError> {
+    serde_json::from_slice(body).map_err(|e| WebhookError::Invalid(e.to_string()))
+}
+
+/// Repository of the push with the web URL of its project
+fn push(path: String, web_url: &str) -> Result<Hook, WebhookError> {
+    let host = reqwest::Url::parse(web_url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .ok_or_else(|| WebhookError::Invalid(format!("No host in {web_url}")))?;
+    Ok(Hook::Push(Push { host, path }))
+}
+
+/// Check the secret of the webhook request with the headers (looked up case insensitive by
+/// `header`) and `body` and read the pushed repository
+pub fn parse(
+    header: impl Fn(&str) -> Option<String>,
+    body: &[u8],
+    secret: &str,
+) -> Result<Hook, WebhookError> {
+    if let Some(event) = header("X-GitHub-Event") {
+        let signature = header("X-Hub-Signature-256").ok_or(WebhookError::Unauthorized)?;
+        if !secret_eq(
+            signature.as_bytes(),
+            github_signature(secret, body)?.as_bytes(),
+        ) {
+            return Err(WebhookError::Unauthorized);
+        }
+        if event != "push" {
+            return Ok(Hook::Ignored(event));
+        }
+        let p: GitHubPush = payload(body)?;
+        return push(p.repository.full_name, &p.repository.html_url);
+    }
+    if let Some(event) = header("X-Gitlab-Event") {
+        let token = header("X-Gitlab-Token").ok_or(WebhookError::Unauthorized)?;
+        if !secret_eq(token.as_bytes(), secret.as_bytes()) {
+            return Err(WebhookError::Unauthorized);
+        }
+        if event != "Push Hook" && event != "Tag Push Hook" {
+            return Ok(Hook::Ignored(event));
+        }
+        let p: GitLabPush = payload(body)?;
+        return push(p.project.path_with_namespace, &p.project.web_url);
+    }
+    Err(WebhookError::Invalid(
+        "neither a GitHub nor a GitLab webhook".to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers<'a>(pairs: &'a [(&str, &str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        move |name| {
+            pairs
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.to_string())
+        }
+    }
+
+    #[test]
+    fn github() {
+        let body = br#"{"ref": "refs/heads/main", "repository": {"full_name": "octo/app",
+            "html_url": "https://github.com/octo/app"}}"#;
+        let signature = github_signature("s3cret", body).unwrap();
+        let h = [
+            ("x-github-event", "push"),
+            ("x-hub-signature-256", &signature),
+        ];
+        let hook = parse(headers(&h), body, "s3cret").unwrap();
+        let push = match hook {
+            Hook::Push(push) => push,
+            hook => panic!("{hook:?}"),
+        };
+        assert_eq!(push.path, "octo/app");
+        assert!(push.matches("git@github.com:octo/app.git"));
+        assert!(push.matches("https://github.com/Octo/App"));
+        assert!(!push.matches("https://gitlab.com/octo/app.git"));
+        assert!(!push.matches("https://github.com/octo/app-fork.git"));
+
+        assert_eq!(
+            parse(headers(&h), body, "other"),
+            Err(WebhookError::Unauthorized)
+        );
+        let h = [
+            ("x-github-event", "ping"),
+            ("x-hub-signature-256", &signature),
+        ];
+        assert_eq!(
+            parse(headers(&h), body, "s3cret"),
+            Ok(Hook::Ignored("ping".to_string()))
+        );
+    }
+
+    #[test]
+    fn gitlab() {
+        let body = br#"{"object_kind": "push", "project": {"path_with_namespace": "group/sub/app",
+            "web_url": "https://gitlab.example.com/group/sub/app"}}"#;
+        let h = [
+            ("X-Gitlab-Event", "Push Hook"),
+            ("X-Gitlab-Token", "s3cret"),
+        ];
+        assert_eq!(
+            parse(headers(&h), body, "s3cret"),
+            Ok(Hook::Push(Push {
+                host: "gitlab.example.com".to_string(),
+                path: "group/sub/app".to_string(),
+            }))
+        );
+        assert_eq!(
+            parse(headers(&h), body, "s3cre"),
+            Err(WebhookError::Unauthorized)
+        );
+        let h = [("X-Gitlab-Event", "Push Hook")];
+        assert_eq!(
+            parse(headers(&h), body, "s3cret"),
+            Err(WebhookError::Unauthorized)
+        );
+        assert!(matches!(
+            parse(headers(&[]), body, "s3cret"),
+            Err(WebhookError::Invalid(_))
+        ));
+    }
+}
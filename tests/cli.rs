@@ -1,6 +1,8 @@
 use assert_cmd::prelude::*; // Add methods on commands
 use clap::{crate_name, crate_version};
 use predicates::prelude::*; // Used for writing assertions
+use std::fs;
+use std::path::Path;
 use std::process::Command; // Run programs
 
 #[test]
@@ -19,3 +21,4509 @@ fn version_flag_working() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+/// Run git with a fixed identity in the given directory
+#[cfg(unix)]
+fn git(dir: &Path, args: &[&str]) {
+    let status = Command::new("git")
+        .current_dir(dir)
+        .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+        .args(args)
+        .status()
+        .unwrap();
+    assert!(status.success(), "git {args:?} failed");
+}
+
+#[cfg(unix)]
+#[test]
+fn external_provider_mirror() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    let destination = tmp.path().join("destination");
+    fs::create_dir(&origin)?;
+    fs::create_dir(&destination)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    git(&destination, &["init", "-q", "--bare"]);
+
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!(
+            "{{\"origin\": {:?}, \"destination\": {:?}}}\n",
+            origin, destination
+        ),
+    )?;
+
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "External", "--provider-command"])
+        .arg(format!("cat {list:?}"))
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .arg("--fail-on-sync-error");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("END(OK) 0/1"));
+
+    assert!(destination.join("refs/heads/main").exists());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn external_provider_malformed_output() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "External", "--provider-command"])
+        .arg("echo '{\"origin\": \"x\"}'")
+        .arg("--mirror-dir")
+        .arg(tmp.path());
+
+    cmd.assert().code(2);
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn local_source_mirror() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let work = tmp.path().join("work");
+    let source = tmp.path().join("source");
+    let destination = tmp.path().join("destination");
+    fs::create_dir_all(&work)?;
+    fs::create_dir_all(source.join("group"))?;
+    fs::create_dir_all(destination.join("group/project.git"))?;
+    git(&work, &["init", "-q", "-b", "main"]);
+    git(&work, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    git(
+        tmp.path(),
+        &["clone", "-q", "--bare", "work", "source/group/project.git"],
+    );
+    git(
+        &destination.join("group/project.git"),
+        &["init", "-q", "--bare"],
+    );
+
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.arg("--local-source")
+        .arg(&source)
+        .arg("--dest-template")
+        .arg(format!("{}/{{path}}.git", destination.display()))
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .arg("--fail-on-sync-error");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("END(OK) 0/1"));
+
+    assert!(destination
+        .join("group/project.git/refs/heads/main")
+        .exists());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn prune_protect_keeps_destination_refs() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    let destination = tmp.path().join("destination");
+    fs::create_dir(&origin)?;
+    fs::create_dir(&destination)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    git(&destination, &["init", "-q", "--bare"]);
+    git(
+        &origin,
+        &[
+            "push",
+            "-q",
+            destination.to_str().unwrap(),
+            "main:refs/keep/a",
+            "main:refs/heads/stale",
+        ],
+    );
+
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!(
+            "{{\"origin\": {:?}, \"destination\": {:?}}}\n",
+            origin, destination
+        ),
+    )?;
+
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "External", "--provider-command"])
+        .arg(format!("cat {list:?}"))
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .args(["--prune-protect", "refs/keep/*"])
+        .arg("--fail-on-sync-error");
+
+    cmd.assert().success();
+
+    assert!(destination.join("refs/heads/main").exists());
+    assert!(destination.join("refs/keep/a").exists());
+    assert!(!destination.join("refs/heads/stale").exists());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn json_summary() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "External", "--provider-command"])
+        .arg("echo '{\"origin\": \"x\"}'")
+        .arg("--mirror-dir")
+        .arg(tmp.path())
+        .args(["--summary-format", "json"]);
+
+    let output = cmd.output()?;
+    assert_eq!(output.status.code(), Some(2));
+
+    let stdout = String::from_utf8(output.stdout)?;
+    let summary: serde_json::Value = serde_json::from_str(stdout.lines().last().unwrap())?;
+    assert_eq!(summary["exit_reason"], "error");
+    assert_eq!(summary["exit_code"], 2);
+    assert_eq!(summary["total"], 0);
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn failure_kinds() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!(
+            "{{\"origin\": {:?}, \"destination\": {:?}}}\n",
+            tmp.path().join("missing"),
+            tmp.path().join("destination")
+        ),
+    )?;
+
+    // A missing origin is not retried, the test would wait for the backoff otherwise
+    let mut cmd = assert_cmd::Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "External", "--provider-command"])
+        .arg(format!("cat {list:?}"))
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .args(["--retries", "3", "--retry-backoff", "1h"])
+        .args(["--summary-format", "json"])
+        .timeout(std::time::Duration::from_secs(60));
+
+    let output = cmd.output()?;
+    assert!(output.status.code().is_some(), "Timed out");
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(stdout.contains("FAILURES: 1 not_found"), "{}", stdout);
+    let summary: serde_json::Value = serde_json::from_str(stdout.lines().last().unwrap())?;
+    assert_eq!(summary["failed"], 1);
+    assert_eq!(summary["failure_kinds"]["not_found"], 1);
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn include_pr_refs() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    let destination = tmp.path().join("destination");
+    fs::create_dir(&origin)?;
+    fs::create_dir(&destination)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    git(&origin, &["update-ref", "refs/pull/1/head", "main"]);
+    git(&destination, &["init", "-q", "--bare"]);
+
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!(
+            "{{\"origin\": {:?}, \"destination\": {:?}}}\n",
+            origin, destination
+        ),
+    )?;
+
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "External", "--provider-command"])
+        .arg(format!("cat {list:?}"))
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .arg("--include-pr-refs")
+        .arg("--fail-on-sync-error");
+
+    cmd.assert().success();
+
+    assert!(destination.join("refs/heads/main").exists());
+    assert!(destination.join("refs/mirror/pull/1/head").exists());
+    assert!(!destination.join("refs/pull").exists());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn exclude_ref() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    let destination = tmp.path().join("destination");
+    fs::create_dir(&origin)?;
+    fs::create_dir(&destination)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    git(&origin, &["update-ref", "refs/changes/01/1/1", "main"]);
+    git(&destination, &["init", "-q", "--bare"]);
+
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!(
+            "{{\"origin\": {:?}, \"destination\": {:?}}}\n",
+            origin, destination
+        ),
+    )?;
+
+    let mirror_dir = tmp.path().join("mirror-dir");
+    // The second run updates the existing local repository
+    for _ in 0..2 {
+        let mut cmd = Command::cargo_bin("git-mirror")?;
+        cmd.args(["--provider", "External", "--provider-command"])
+            .arg(format!("cat {list:?}"))
+            .arg("--mirror-dir")
+            .arg(&mirror_dir)
+            .args(["--exclude-ref", "refs/changes/*"])
+            .arg("--fail-on-sync-error");
+
+        cmd.assert().success();
+    }
+
+    assert!(destination.join("refs/heads/main").exists());
+    assert!(!destination.join("refs/changes").exists());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn empty_listing() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "External", "--provider-command", "true"])
+        .arg("--mirror-dir")
+        .arg(tmp.path());
+    cmd.assert().code(2);
+
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "External", "--provider-command", "true"])
+        .arg("--mirror-dir")
+        .arg(tmp.path())
+        .arg("--allow-empty");
+    cmd.assert().success();
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn shared_and_dir_mode() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    let destination = tmp.path().join("destination");
+    fs::create_dir(&origin)?;
+    fs::create_dir(&destination)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    git(&destination, &["init", "-q", "--bare"]);
+
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!(
+            "{{\"origin\": {:?}, \"destination\": {:?}}}\n",
+            origin, destination
+        ),
+    )?;
+
+    let mirror_dir = tmp.path().join("mirror-dir");
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "External", "--provider-command"])
+        .arg(format!("cat {list:?}"))
+        .arg("--mirror-dir")
+        .arg(&mirror_dir)
+        .args(["--shared", "group", "--dir-mode", "2770"])
+        .arg("--fail-on-sync-error");
+    cmd.assert().success();
+
+    let mode = fs::metadata(&mirror_dir)?.permissions().mode();
+    assert_eq!(mode & 0o7777, 0o2770);
+
+    let repo = fs::read_dir(&mirror_dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .find(|p| p.is_dir())
+        .expect("No local repository");
+    let output = Command::new("git")
+        .args(["config", "core.sharedRepository"])
+        .current_dir(&repo)
+        .output()?;
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "group");
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn dry_run_reports() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        "{\"origin\": \"https://example.com/a.git\", \"destination\": \"git@example.org:a.git\"}\n\
+         {\"origin\": \"https://example.com/b.git\", \"destination\": \"\", \"skip\": true}\n",
+    )?;
+    let metrics = tmp.path().join("metrics.prom");
+    let junit = tmp.path().join("junit.xml");
+
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "External", "--provider-command"])
+        .arg(format!("cat {list:?}"))
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .arg("--metric-file")
+        .arg(&metrics)
+        .arg("--junit-report")
+        .arg(&junit)
+        .args(["--summary-format", "json", "--dry-run"]);
+
+    let output = cmd.output()?;
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(
+        stdout.contains("(dry run, would clone and push)"),
+        "{}",
+        stdout
+    );
+    let summary: serde_json::Value = serde_json::from_str(stdout.lines().last().unwrap())?;
+    assert_eq!(summary["dry_run"], true);
+    assert_eq!(
+        (summary["success"].as_u64(), summary["skipped"].as_u64()),
+        (Some(1), Some(1))
+    );
+
+    let metrics = fs::read_to_string(metrics)?;
+    assert!(
+        metrics
+            .lines()
+            .any(|l| l.starts_with("git_mirror_dry_run{") && l.ends_with(" 1")),
+        "{}",
+        metrics
+    );
+
+    let junit = fs::read_to_string(junit)?;
+    assert!(
+        junit.contains("Dry run: would clone https://example.com/a.git"),
+        "{}",
+        junit
+    );
+
+    Ok(())
+}
+
+#[test]
+fn lock_file() -> Result<(), Box<dyn std::error::Error>> {
+    use fs2::FileExt;
+
+    let tmp = tempfile::tempdir()?;
+    let lock_file = tmp.path().join("run.lock");
+    let lock = fs::File::create(&lock_file)?;
+    lock.lock_exclusive()?;
+
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "External", "--provider-command", "true"])
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .arg("--lock-file")
+        .arg(&lock_file)
+        .arg("--allow-empty");
+    cmd.assert().code(5).stderr(predicate::str::contains(
+        "Another instance is already running",
+    ));
+
+    lock.unlock()?;
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "External", "--provider-command", "true"])
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .arg("--lock-file")
+        .arg(&lock_file)
+        .arg("--allow-empty");
+    cmd.assert().success();
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn alternates() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let upstream = tmp.path().join("upstream");
+    let fork = tmp.path().join("fork");
+    fs::create_dir(&upstream)?;
+    git(&upstream, &["init", "-q", "-b", "main"]);
+    git(
+        &upstream,
+        &["commit", "-q", "--allow-empty", "-m", "initial"],
+    );
+    git(tmp.path(), &["clone", "-q", "upstream", "fork"]);
+    git(&fork, &["commit", "-q", "--allow-empty", "-m", "fork"]);
+
+    let mut list = String::new();
+    for (origin, destination) in [(&upstream, "dst-upstream"), (&fork, "dst-fork")] {
+        let destination = tmp.path().join(destination);
+        fs::create_dir(&destination)?;
+        git(&destination, &["init", "-q", "--bare"]);
+        list.push_str(&format!(
+            "{{\"origin\": {:?}, \"destination\": {:?}}}\n",
+            origin, destination
+        ));
+    }
+    fs::write(tmp.path().join("list.jsonl"), list)?;
+
+    let mirror_dir = tmp.path().join("mirror-dir");
+    let pool = tmp.path().join("pool");
+    let run = |extra: &[&str]| -> Result<String, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("git-mirror")?;
+        cmd.args(["--provider", "External", "--provider-command"])
+            .arg(format!("cat {:?}", tmp.path().join("list.jsonl")))
+            .arg("--mirror-dir")
+            .arg(&mirror_dir)
+            .args(extra)
+            .arg("--fail-on-sync-error");
+        let output = cmd.output()?;
+        assert!(output.status.success(), "{:?}", output);
+        Ok(String::from_utf8(output.stderr)?)
+    };
+    let local_alternates = || -> Vec<bool> {
+        fs::read_dir(&mirror_dir)
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.is_dir())
+            .map(|p| p.join("objects/info/alternates").exists())
+            .collect()
+    };
+
+    run(&["--alternates", pool.to_str().unwrap()])?;
+    assert_eq!(local_alternates(), [true, true]);
+    assert!(tmp.path().join("dst-fork/refs/heads/main").exists());
+
+    // A lost pool leads to a new clone instead of a corrupted repository
+    fs::remove_dir_all(&pool)?;
+    let stderr = run(&["-v"])?;
+    assert!(stderr.contains("is missing, cloning again"), "{}", stderr);
+    assert_eq!(local_alternates(), [false, false]);
+
+    run(&["--alternates", pool.to_str().unwrap()])?;
+    fs::remove_dir_all(&mirror_dir)?;
+    run(&["--alternates", pool.to_str().unwrap()])?;
+    run(&["--dissociate"])?;
+    assert_eq!(local_alternates(), [false, false]);
+    fs::remove_dir_all(&pool)?;
+    let stderr = run(&["-v"])?;
+    assert!(!stderr.contains("cloning again"), "{}", stderr);
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn maintenance_task() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    let destination = tmp.path().join("destination");
+    fs::create_dir(&origin)?;
+    fs::create_dir(&destination)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    git(&destination, &["init", "-q", "--bare"]);
+
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!(
+            "{{\"origin\": {:?}, \"destination\": {:?}}}\n",
+            origin, destination
+        ),
+    )?;
+
+    let mirror_dir = tmp.path().join("mirror-dir");
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "External", "--provider-command"])
+        .arg(format!("cat {list:?}"))
+        .arg("--mirror-dir")
+        .arg(&mirror_dir)
+        .args(["--maintenance", "--maintenance-task", "commit-graph"])
+        .arg("--fail-on-sync-error");
+    cmd.assert().success();
+
+    let repo = fs::read_dir(&mirror_dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .find(|p| p.is_dir())
+        .expect("No local repository");
+    let graph = repo.join("objects/info/commit-graph");
+    let graphs = repo.join("objects/info/commit-graphs");
+    assert!(graph.exists() || graphs.exists());
+
+    Ok(())
+}
+
+#[test]
+fn maintenance_task_requires_maintenance() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--maintenance-task", "gc"]);
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("--maintenance"));
+
+    Ok(())
+}
+
+#[test]
+fn webhook_secret_requires_daemon() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["-g", "mirror-test", "--webhook-secret", "s3cret"]);
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("--daemon"));
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn max_transfer() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    fs::create_dir(&origin)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+
+    let mut list = String::new();
+    for name in ["first", "second"] {
+        let destination = tmp.path().join(name);
+        fs::create_dir(&destination)?;
+        git(&destination, &["init", "-q", "--bare"]);
+        list.push_str(&format!(
+            "{{\"origin\": {:?}, \"destination\": {:?}}}\n",
+            origin, destination
+        ));
+    }
+    let list_file = tmp.path().join("list.jsonl");
+    fs::write(&list_file, list)?;
+
+    // The push of the first repository already exceeds the budget of about 100 bytes
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "External", "--provider-command"])
+        .arg(format!("cat {list_file:?}"))
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .args(["-c", "1", "--max-transfer", "0.0000001"])
+        .args(["--summary-format", "json"]);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("(transfer budget exhausted)"))
+        .stdout(predicate::str::contains("\"skipped\":1"))
+        .stdout(predicate::str::is_match("\"transferred_bytes\":[1-9]")?);
+
+    assert!(tmp.path().join("first/refs/heads/main").exists());
+    assert!(!tmp.path().join("second/refs/heads/main").exists());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn tag_refspec() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    let destination = tmp.path().join("destination");
+    fs::create_dir(&origin)?;
+    fs::create_dir(&destination)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    git(&origin, &["tag", "v1.0"]);
+    git(&destination, &["init", "-q", "--bare"]);
+
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!(
+            "{{\"origin\": {:?}, \"destination\": {:?}}}\n",
+            origin, destination
+        ),
+    )?;
+
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "External", "--provider-command"])
+        .arg(format!("cat {list:?}"))
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .args(["--tag-refspec", "refs/upstream-tags/*"])
+        .arg("--fail-on-sync-error");
+    cmd.assert().success();
+
+    assert!(destination.join("refs/heads/main").exists());
+    assert!(destination.join("refs/upstream-tags/v1.0").exists());
+    assert!(!destination.join("refs/tags/v1.0").exists());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn ssh_jump() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmp = tempfile::tempdir()?;
+    // Records the arguments instead of connecting
+    let args = tmp.path().join("ssh-args");
+    let ssh = tmp.path().join("fake-ssh");
+    fs::write(
+        &ssh,
+        format!("#!/bin/sh\necho \"$@\" >> {args:?}\nexit 1\n"),
+    )?;
+    fs::set_permissions(&ssh, fs::Permissions::from_mode(0o755))?;
+
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        "{\"origin\": \"ssh://git@origin.example.com/a.git\", \"destination\": \"ssh://git@dest.example.com/a.git\"}\n",
+    )?;
+
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.env("GIT_SSH_COMMAND", &ssh)
+        .args(["--provider", "External", "--provider-command"])
+        .arg(format!("cat {list:?}"))
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .args(["--ssh-jump", "jump@bastion.example.com:2222"]);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("END(FAIL)"));
+
+    let args = fs::read_to_string(args)?;
+    assert!(
+        args.contains(&format!(
+            "-o ProxyCommand={} -W %h:%p ssh://jump@bastion.example.com:2222",
+            ssh.display()
+        )),
+        "{}",
+        args
+    );
+    assert!(args.contains("origin.example.com"), "{}", args);
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn ssh_key() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmp = tempfile::tempdir()?;
+    // Records the arguments, one per line, instead of connecting
+    let args = tmp.path().join("ssh-args");
+    let ssh = tmp.path().join("fake-ssh");
+    fs::write(
+        &ssh,
+        format!("#!/bin/sh\nprintf '%s\\n' \"$@\" >> {args:?}\nexit 1\n"),
+    )?;
+    fs::set_permissions(&ssh, fs::Permissions::from_mode(0o755))?;
+
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        "{\"origin\": \"ssh://git@origin.example.com/a.git\", \"destination\": \"ssh://git@dest.example.com/a.git\"}\n",
+    )?;
+    let key = tmp.path().join("deploy key's id");
+
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.env("GIT_SSH_COMMAND", &ssh)
+        .args(["--provider", "External", "--provider-command"])
+        .arg(format!("cat {list:?}"))
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .arg("--ssh-key")
+        .arg(&key)
+        .args(["--ssh-strict-host-key-checking", "false"])
+        .args(["--ssh-jump", "jump.example.com"]);
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("END(FAIL)"));
+
+    let args = fs::read_to_string(args)?;
+    let args: Vec<_> = args.lines().collect();
+    let key = key.to_string_lossy();
+    for expected in [
+        "-i",
+        key.as_ref(),
+        "IdentitiesOnly=yes",
+        "UserKnownHostsFile=/dev/null",
+        "StrictHostKeyChecking=no",
+    ] {
+        assert!(args.contains(&expected), "{expected}: {args:?}");
+    }
+    // The jump host is connected with the same key and host key options
+    let proxy = args
+        .iter()
+        .find(|arg| arg.starts_with("ProxyCommand="))
+        .ok_or("no ProxyCommand")?;
+    for expected in [
+        format!("-i '{}'", key.replace('\'', "'\\''")),
+        "-o IdentitiesOnly=yes".to_string(),
+        "-o UserKnownHostsFile='/dev/null'".to_string(),
+        "-o StrictHostKeyChecking=no".to_string(),
+        "-W %h:%p ssh://jump.example.com".to_string(),
+    ] {
+        assert!(proxy.contains(&expected), "{expected}: {proxy}");
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn report_sizes() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    let destination = tmp.path().join("destination");
+    fs::create_dir(&origin)?;
+    fs::create_dir(&destination)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    git(&destination, &["init", "-q", "--bare"]);
+
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!(
+            "{{\"origin\": {:?}, \"destination\": {:?}}}\n",
+            origin, destination
+        ),
+    )?;
+
+    let metrics = tmp.path().join("metrics.prom");
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "External", "--provider-command"])
+        .arg(format!("cat {list:?}"))
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .arg("--metric-file")
+        .arg(&metrics)
+        .args(["--report-sizes", "--summary-format", "json"])
+        .arg("--fail-on-sync-error");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("SIZE: "))
+        .stdout(predicate::str::is_match("\"total_size_bytes\":[1-9]")?)
+        .stdout(predicate::str::contains(format!(
+            "\"destination\":{:?}",
+            destination.to_string_lossy()
+        )));
+    assert!(fs::read_to_string(&metrics)?.contains("git_mirror_repo_size_bytes{"));
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn git_protocol() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    fs::create_dir(&origin)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+
+    for version in ["0", "2"] {
+        let destination = tmp.path().join(format!("destination{version}"));
+        fs::create_dir(&destination)?;
+        git(&destination, &["init", "-q", "--bare"]);
+        // Clone through the transport instead of hard linking
+        let list = tmp.path().join("list.jsonl");
+        fs::write(
+            &list,
+            format!(
+                "{{\"origin\": \"file://{}\", \"destination\": {:?}}}\n",
+                origin.to_string_lossy(),
+                destination
+            ),
+        )?;
+
+        let trace = tmp.path().join(format!("trace{version}"));
+        let mut cmd = Command::cargo_bin("git-mirror")?;
+        cmd.env("GIT_TRACE_PACKET", &trace)
+            .args(["--provider", "External", "--provider-command"])
+            .arg(format!("cat {list:?}"))
+            .arg("--mirror-dir")
+            .arg(tmp.path().join(format!("mirror-dir{version}")))
+            .args(["--git-protocol", version])
+            .arg("--fail-on-sync-error");
+        cmd.assert().success();
+
+        let trace = fs::read_to_string(trace)?;
+        assert_eq!(trace.contains("version 2"), version == "2");
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn partial_report() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    let destination = tmp.path().join("destination");
+    fs::create_dir(&origin)?;
+    fs::create_dir(&destination)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    git(&destination, &["init", "-q", "--bare"]);
+
+    // The second job hangs until the run is killed
+    let ssh = tmp.path().join("hanging-ssh");
+    fs::write(&ssh, "#!/bin/sh\nsleep 60\n")?;
+    fs::set_permissions(&ssh, fs::Permissions::from_mode(0o755))?;
+
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!(
+            "{{\"origin\": {:?}, \"destination\": {:?}}}\n\
+             {{\"origin\": \"ssh://git@origin.example.com/a.git\", \"destination\": {:?}}}\n",
+            origin, destination, destination
+        ),
+    )?;
+
+    let junit = tmp.path().join("junit.xml");
+    let mut cmd = assert_cmd::Command::cargo_bin("git-mirror")?;
+    cmd.env("GIT_SSH_COMMAND", &ssh)
+        .args(["--provider", "External", "--provider-command"])
+        .arg(format!("cat {list:?}"))
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .arg("--junit-report")
+        .arg(&junit)
+        .args(["-c", "1", "--report-interval", "0s"])
+        .timeout(std::time::Duration::from_secs(5));
+    cmd.assert().failure();
+
+    let report = fs::read_to_string(&junit)?;
+    assert!(
+        report.contains("Partial report, 1 jobs finished so far"),
+        "{}",
+        report
+    );
+    assert!(report.contains(&origin.display().to_string()), "{}", report);
+    assert!(!report.contains("origin.example.com"), "{}", report);
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn rename_file() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    let renamed = tmp.path().join("foo-renamed.git");
+    fs::create_dir(&origin)?;
+    fs::create_dir(&renamed)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    git(&renamed, &["init", "-q", "--bare"]);
+
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!(
+            "{{\"origin\": {:?}, \"destination\": \"{}/Foo.git\"}}\n",
+            origin,
+            tmp.path().display()
+        ),
+    )?;
+    let renames = tmp.path().join("renames");
+    fs::write(
+        &renames,
+        format!(
+            "# Reserved on the destination\n\n{0}/Foo={0}/foo-renamed\n",
+            tmp.path().display()
+        ),
+    )?;
+
+    let mirror_dir = tmp.path().join("mirror-dir");
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "External", "--provider-command"])
+        .arg(format!("cat {list:?}"))
+        .arg("--mirror-dir")
+        .arg(&mirror_dir)
+        .arg("--rename-file")
+        .arg(&renames)
+        .args(["--local-dir-name", "destination"])
+        .arg("--fail-on-sync-error");
+    cmd.assert().success();
+
+    assert!(renamed.join("refs/heads/main").exists());
+    assert!(!tmp.path().join("Foo.git").exists());
+    let local: Vec<String> = fs::read_dir(&mirror_dir)?
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().is_dir())
+        .map(|e| e.file_name().to_string_lossy().into_owned())
+        .collect();
+    assert!(
+        local.iter().all(|n| n.contains("foo-renamed")),
+        "{:?}",
+        local
+    );
+
+    Ok(())
+}
+
+#[test]
+fn rename_file_invalid() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let renames = tmp.path().join("renames");
+    fs::write(&renames, "no-separator\n")?;
+
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["-g", "group", "--rename-file"]).arg(&renames);
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("line 1"));
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn subtree_prefix() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let mut lines = String::new();
+    let destination = tmp.path().join("destination");
+    for name in ["a", "b"] {
+        let origin = tmp.path().join(format!("origin-{name}"));
+        fs::create_dir(&origin)?;
+        git(&origin, &["init", "-q", "-b", "main"]);
+        fs::write(origin.join(format!("{name}.txt")), name)?;
+        git(&origin, &["add", "."]);
+        git(&origin, &["commit", "-q", "-m", "initial"]);
+        lines.push_str(&format!(
+            "{{\"origin\": {:?}, \"destination\": {:?}, \"subtree_prefix\": \"vendor/{}\"}}\n",
+            origin, destination, name
+        ));
+    }
+
+    // Content outside of the prefixes is kept
+    let work = tmp.path().join("work");
+    fs::create_dir(&work)?;
+    git(&work, &["init", "-q", "-b", "main"]);
+    fs::write(work.join("README"), "monorepo")?;
+    git(&work, &["add", "."]);
+    git(&work, &["commit", "-q", "-m", "initial"]);
+    git(
+        tmp.path(),
+        &["clone", "-q", "--bare", "work", "destination"],
+    );
+
+    let list = tmp.path().join("list.jsonl");
+    fs::write(&list, lines)?;
+    let run = || -> Result<_, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("git-mirror")?;
+        cmd.args(["--provider", "External", "--provider-command"])
+            .arg(format!("cat {list:?}"))
+            .arg("--mirror-dir")
+            .arg(tmp.path().join("mirror-dir"))
+            .args(["-c", "1"])
+            .arg("--fail-on-sync-error");
+        Ok(cmd.assert().success())
+    };
+    run()?;
+    run()?.stdout(predicate::str::contains("(up-to-date)").count(2));
+
+    let output = Command::new("git")
+        .args(["ls-tree", "-r", "--name-only", "main"])
+        .current_dir(&destination)
+        .output()?;
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "README\nvendor/a/a.txt\nvendor/b/b.txt\n"
+    );
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn queue_depth() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    fs::create_dir(&origin)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+
+    let mut list = String::new();
+    for name in ["first", "second", "third"] {
+        let destination = tmp.path().join(name);
+        fs::create_dir(&destination)?;
+        git(&destination, &["init", "-q", "--bare"]);
+        list.push_str(&format!(
+            "{{\"origin\": {:?}, \"destination\": {:?}}}\n",
+            origin, destination
+        ));
+    }
+    let list_file = tmp.path().join("list.jsonl");
+    fs::write(&list_file, list)?;
+
+    // Every repository is handed directly to the single worker
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "External", "--provider-command"])
+        .arg(format!("cat {list_file:?}"))
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .args(["-c", "1", "--stream-listing", "--queue-depth", "0"])
+        .arg("--fail-on-sync-error");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("END(OK)").count(3));
+
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["-g", "group", "--queue-depth", "1"]);
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("--stream-listing"));
+
+    Ok(())
+}
+
+#[cfg(all(unix, feature = "nats"))]
+#[test]
+fn nats_events() -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    let destination = tmp.path().join("destination");
+    fs::create_dir(&origin)?;
+    fs::create_dir(&destination)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    git(&destination, &["init", "-q", "--bare"]);
+
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!(
+            "{{\"origin\": {:?}, \"destination\": {:?}}}\n",
+            origin, destination
+        ),
+    )?;
+
+    // Minimal NATS server, collecting the published subjects and payloads
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let port = listener.local_addr()?.port();
+    let server = std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut conn = BufReader::new(stream);
+        conn.get_mut().write_all(b"INFO {}\r\n").unwrap();
+        let mut published = Vec::new();
+        let mut line = String::new();
+        while conn.read_line(&mut line).unwrap() > 0 {
+            if line == "PING\r\n" {
+                conn.get_mut().write_all(b"PONG\r\n").unwrap();
+            } else if let Some(subject) = line.strip_prefix("PUB ") {
+                let subject = subject.split(' ').next().unwrap().to_string();
+                let mut payload = String::new();
+                conn.read_line(&mut payload).unwrap();
+                published.push((subject, payload));
+            }
+            line.clear();
+        }
+        published
+    });
+
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "External", "--provider-command"])
+        .arg(format!("cat {list:?}"))
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .arg("--nats-url")
+        .arg(format!("nats://127.0.0.1:{port}"))
+        .arg("--fail-on-sync-error");
+    cmd.assert().success();
+
+    let published = server.join().unwrap();
+    assert_eq!(published.len(), 2);
+    assert_eq!(published[0].0, "git-mirror.repo");
+    assert!(published[0].1.contains("\"status\":\"success\""));
+    assert_eq!(published[1].0, "git-mirror.run");
+    assert!(published[1].1.contains("\"success\":1"));
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn renamed_repository() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    let destination = tmp.path().join("destination");
+    fs::create_dir(&origin)?;
+    fs::create_dir(&destination)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    git(&destination, &["init", "-q", "--bare"]);
+
+    let mirror_dir = tmp.path().join("mirror-dir");
+    let list = tmp.path().join("list.jsonl");
+    let run = |origin: &Path| -> Result<(), Box<dyn std::error::Error>> {
+        fs::write(
+            &list,
+            format!(
+                "{{\"origin\": {:?}, \"destination\": {:?}, \"id\": \"ext:1\"}}\n",
+                origin, destination
+            ),
+        )?;
+        let mut cmd = Command::cargo_bin("git-mirror")?;
+        cmd.args(["--provider", "External", "--provider-command"])
+            .arg(format!("cat {list:?}"))
+            .arg("--mirror-dir")
+            .arg(&mirror_dir)
+            .arg("--fail-on-sync-error");
+        cmd.assert().success();
+        Ok(())
+    };
+    run(&origin)?;
+    let repos = || -> Vec<_> {
+        fs::read_dir(&mirror_dir)
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.is_dir())
+            .collect()
+    };
+    let old = repos();
+    assert_eq!(old.len(), 1);
+    // Only present if the local repository is reused
+    fs::write(old[0].join("marker"), "")?;
+
+    let renamed = tmp.path().join("renamed");
+    fs::rename(&origin, &renamed)?;
+    run(&renamed)?;
+    let new = repos();
+    assert_eq!(new.len(), 1);
+    assert_ne!(new, old);
+    assert!(new[0].join("marker").exists());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn max_refs() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    let destination = tmp.path().join("destination");
+    fs::create_dir(&origin)?;
+    fs::create_dir(&destination)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    for tag in ["v1", "v2"] {
+        git(&origin, &["tag", tag]);
+    }
+    git(&origin, &["tag", "-a", "v3", "-m", "annotated"]);
+    git(&destination, &["init", "-q", "--bare"]);
+
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!(
+            "{{\"origin\": {:?}, \"destination\": {:?}}}\n",
+            origin, destination
+        ),
+    )?;
+
+    // main and three tags
+    for (max, skipped) in [("3", true), ("4", false)] {
+        let mut cmd = Command::cargo_bin("git-mirror")?;
+        cmd.args(["--provider", "External", "--provider-command"])
+            .arg(format!("cat {list:?}"))
+            .arg("--mirror-dir")
+            .arg(tmp.path().join("mirror-dir"))
+            .args(["--max-refs", max])
+            .arg("--fail-on-sync-error");
+        let assert = cmd.assert().success();
+        if skipped {
+            assert.stdout(predicate::str::contains("(too many refs: 4 > 3)"));
+        } else {
+            assert.stdout(predicate::str::contains("END(OK)"));
+        }
+        assert_eq!(destination.join("refs/heads/main").exists(), !skipped);
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn min_stars() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let mut entries = String::new();
+    for (name, counts) in [
+        ("popular", r#", "stars": 10, "forks": 3"#),
+        ("few-stars", r#", "stars": 2, "forks": 3"#),
+        ("few-forks", r#", "stars": 10, "forks": 0"#),
+        ("unknown", ""),
+    ] {
+        let origin = tmp.path().join(name);
+        let destination = tmp.path().join(format!("{name}.git"));
+        fs::create_dir(&origin)?;
+        fs::create_dir(&destination)?;
+        git(&origin, &["init", "-q", "-b", "main"]);
+        git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+        git(&destination, &["init", "-q", "--bare"]);
+        entries.push_str(&format!(
+            "{{\"origin\": {origin:?}, \"destination\": {destination:?}{counts}}}\n"
+        ));
+    }
+    let list = tmp.path().join("list.jsonl");
+    fs::write(&list, entries)?;
+
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "External", "--provider-command"])
+        .arg(format!("cat {list:?}"))
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .args(["--min-stars", "5", "--min-forks", "1"])
+        .arg("--fail-on-sync-error");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains("(too few stars: 2 < 5)"))
+        .stdout(predicate::str::contains("(too few forks: 0 < 1)"));
+
+    for (name, mirrored) in [
+        ("popular", true),
+        ("few-stars", false),
+        ("few-forks", false),
+        ("unknown", true),
+    ] {
+        let head = tmp.path().join(format!("{name}.git/refs/heads/main"));
+        assert_eq!(head.exists(), mirrored, "{name}");
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn set_default_branch() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    let destination = tmp.path().join("destination");
+    fs::create_dir(&origin)?;
+    fs::create_dir(&destination)?;
+    git(&origin, &["init", "-q", "-b", "trunk"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    git(&destination, &["init", "-q", "--bare"]);
+    // Left behind by an earlier migration
+    git(&destination, &["symbolic-ref", "HEAD", "refs/heads/gone"]);
+
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!(
+            "{{\"origin\": {:?}, \"destination\": {:?}}}\n",
+            origin, destination
+        ),
+    )?;
+
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "External", "--provider-command"])
+        .arg(format!("cat {list:?}"))
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .arg("--set-default-branch")
+        .arg("--fail-on-sync-error");
+    cmd.assert().success();
+    assert_eq!(
+        fs::read_to_string(destination.join("HEAD"))?,
+        "ref: refs/heads/trunk\n"
+    );
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn audit_log() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    let destination = tmp.path().join("destination");
+    fs::create_dir(&origin)?;
+    fs::create_dir(&destination)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    git(&origin, &["branch", "old"]);
+    git(&destination, &["init", "-q", "--bare"]);
+
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!(
+            "{{\"origin\": {:?}, \"destination\": {:?}}}\n",
+            origin, destination
+        ),
+    )?;
+    let audit_log = tmp.path().join("audit.jsonl");
+    let run = || -> Result<_, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("git-mirror")?;
+        cmd.args(["--provider", "External", "--provider-command"])
+            .arg(format!("cat {list:?}"))
+            .arg("--mirror-dir")
+            .arg(tmp.path().join("mirror-dir"))
+            .arg("--audit-log")
+            .arg(&audit_log)
+            .arg("--fail-on-sync-error");
+        Ok(cmd.assert())
+    };
+    run()?.success();
+
+    // The destination rejects one of the refs, the others are still pushed
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "second"]);
+    git(&origin, &["branch", "-D", "old"]);
+    git(&origin, &["branch", "blocked"]);
+    let hook = destination.join("hooks/update");
+    fs::write(&hook, "#!/bin/sh\ntest \"$1\" != refs/heads/blocked\n")?;
+    fs::set_permissions(&hook, fs::Permissions::from_mode(0o755))?;
+    run()?.failure();
+
+    let rev = |name: &str| -> Result<String, Box<dyn std::error::Error>> {
+        let out = Command::new("git")
+            .current_dir(&origin)
+            .args(["rev-parse", name])
+            .output()?;
+        Ok(String::from_utf8(out.stdout)?.trim().to_string())
+    };
+    let (first, second) = (rev("main~1")?, rev("main")?);
+    let zero = "0".repeat(first.len());
+    let changes: Vec<(String, String, String)> = fs::read_to_string(&audit_log)?
+        .lines()
+        .map(|l| {
+            let c: serde_json::Value = serde_json::from_str(l).unwrap();
+            assert_eq!(c["destination"].as_str(), destination.to_str());
+            assert!(c["time"].is_string());
+            let field = |f: &str| c[f].as_str().unwrap().to_string();
+            (field("ref"), field("old"), field("new"))
+        })
+        .collect();
+    assert_eq!(
+        changes,
+        [
+            ("refs/heads/main", &zero, &first),
+            ("refs/heads/old", &zero, &first),
+            ("refs/heads/main", &first, &second),
+            ("refs/heads/old", &first, &zero),
+        ]
+        .map(|(r, o, n)| (r.to_string(), o.clone(), n.clone()))
+    );
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn in_flight_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::PermissionsExt;
+    use std::process::Stdio;
+    use std::time::{Duration, Instant};
+
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    fs::create_dir(&origin)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    let mut entries = String::new();
+    for dest in ["a", "b", "c"] {
+        git(tmp.path(), &["init", "-q", "--bare", dest]);
+        entries.push_str(&format!(
+            "{{\"origin\": {:?}, \"destination\": {:?}}}\n",
+            origin,
+            tmp.path().join(dest)
+        ));
+    }
+    let list = tmp.path().join("list.jsonl");
+    fs::write(&list, entries)?;
+    // Every clone takes 2 seconds
+    let slow_git = tmp.path().join("slow-git");
+    fs::write(
+        &slow_git,
+        "#!/bin/sh\ncase \" $* \" in *\" clone \"*) sleep 2 ;; esac\nexec git \"$@\"\n",
+    )?;
+    fs::set_permissions(&slow_git, fs::Permissions::from_mode(0o755))?;
+
+    let metrics = tmp.path().join("metrics.prom");
+    let mut child = Command::cargo_bin("git-mirror")?
+        .args(["--provider", "External", "--provider-command"])
+        .arg(format!("cat {list:?}"))
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .arg("--git-executable")
+        .arg(&slow_git)
+        .arg("--metric-file")
+        .arg(&metrics)
+        .args(["-c", "1", "--report-interval", "200ms"])
+        .stdout(Stdio::null())
+        .spawn()?;
+    let gauge = |text: &str, name: &str| -> Option<String> {
+        text.lines()
+            .find(|l| l.starts_with(&format!("{name}{{")))
+            .and_then(|l| l.rsplit(' ').next())
+            .map(str::to_string)
+    };
+
+    // Written while the first job is still running
+    let start = Instant::now();
+    let running = loop {
+        let text = fs::read_to_string(&metrics).unwrap_or_default();
+        if gauge(&text, "git_mirror_in_flight").as_deref() == Some("1") {
+            break text;
+        }
+        assert!(start.elapsed() < Duration::from_secs(10), "{}", text);
+        std::thread::sleep(Duration::from_millis(50));
+    };
+    assert_eq!(
+        gauge(&running, "git_mirror_queue_depth").as_deref(),
+        Some("2"),
+        "{}",
+        running
+    );
+    assert!(child.wait()?.success());
+
+    let done = fs::read_to_string(&metrics)?;
+    assert_eq!(gauge(&done, "git_mirror_in_flight").as_deref(), Some("0"));
+    assert_eq!(gauge(&done, "git_mirror_queue_depth").as_deref(), Some("0"));
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn lfs_mirror() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmp = tempfile::tempdir()?;
+    let mut entries = String::new();
+    for (name, attributes) in [
+        ("plain", "*.txt text\n"),
+        ("assets", "*.bin filter=lfs -text\n"),
+    ] {
+        let origin = tmp.path().join(name);
+        let destination = tmp.path().join(format!("{name}.git"));
+        fs::create_dir(&origin)?;
+        fs::create_dir(&destination)?;
+        git(&origin, &["init", "-q", "-b", "main"]);
+        fs::create_dir(origin.join("data"))?;
+        fs::write(origin.join("data/.gitattributes"), attributes)?;
+        git(&origin, &["add", "data"]);
+        git(&origin, &["commit", "-q", "-m", "initial"]);
+        git(&destination, &["init", "-q", "--bare"]);
+        entries.push_str(&format!(
+            "{{\"origin\": {origin:?}, \"destination\": {destination:?}}}\n"
+        ));
+    }
+    let list = tmp.path().join("list.jsonl");
+    fs::write(&list, entries)?;
+    // Records the LFS commands instead of running them
+    let calls = tmp.path().join("lfs-calls");
+    let lfs_log = tmp.path().join("lfs-log");
+    fs::write(
+        &lfs_log,
+        format!(
+            "#!/bin/sh\ncase \" $* \" in\n\
+             *\" lfs \"*) echo \"$*\" >> {calls:?}; exit 0 ;;\n\
+             esac\nexec git \"$@\"\n"
+        ),
+    )?;
+    fs::set_permissions(&lfs_log, fs::Permissions::from_mode(0o755))?;
+
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "External", "--provider-command"])
+        .arg(format!("cat {list:?}"))
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .arg("--git-executable")
+        .arg(&lfs_log)
+        .args(["--lfs", "--fail-on-sync-error"]);
+    let output = cmd.output()?;
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(output.status.success(), "{stdout}");
+
+    // Only the repository using LFS transfers LFS objects
+    let calls = fs::read_to_string(calls)?;
+    let fetches: Vec<&str> = calls.lines().filter(|l| l.contains("lfs fetch")).collect();
+    assert_eq!(fetches.len(), 1, "{calls}");
+    assert!(fetches[0].ends_with("lfs fetch --all"), "{calls}");
+    let push = format!("lfs push --all {}", tmp.path().join("assets.git").display());
+    assert!(calls.lines().any(|l| l.ends_with(&push)), "{calls}");
+    assert!(!calls.contains("plain.git"), "{calls}");
+    assert!(!calls.contains("lfs install"), "{calls}");
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn lfs_failure() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    let destination = tmp.path().join("destination");
+    fs::create_dir(&origin)?;
+    fs::create_dir(&destination)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    fs::write(origin.join(".gitattributes"), "*.bin filter=lfs -text\n")?;
+    git(&origin, &["add", ".gitattributes"]);
+    git(&origin, &["commit", "-q", "-m", "initial"]);
+    git(&destination, &["init", "-q", "--bare"]);
+    // The LFS server of the origin is down
+    let lfs_down = tmp.path().join("lfs-down");
+    fs::write(
+        &lfs_down,
+        "#!/bin/sh\ncase \" $* \" in\n\
+         *\" lfs version \"*) exit 0 ;;\n\
+         *\" lfs fetch \"*) echo 'batch request: LFS server unavailable' >&2; exit 2 ;;\n\
+         *\" lfs \"*) exit 0 ;;\n\
+         esac\nexec git \"$@\"\n",
+    )?;
+    fs::set_permissions(&lfs_down, fs::Permissions::from_mode(0o755))?;
+
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!(
+            "{{\"origin\": {:?}, \"destination\": {:?}}}\n",
+            origin, destination
+        ),
+    )?;
+    let run = |policy: &str, args: &[&str]| -> Result<_, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("git-mirror")?;
+        cmd.args(["--provider", "External", "--provider-command"])
+            .arg(format!("cat {list:?}"))
+            .arg("--mirror-dir")
+            .arg(tmp.path().join(format!("mirror-{policy}")))
+            .arg("--git-executable")
+            .arg(&lfs_down)
+            .args(["--lfs", "--lfs-failure", policy])
+            .args(["--summary-format", "json", "--fail-on-sync-error"])
+            .args(args);
+        Ok(cmd.output()?)
+    };
+
+    let output = run("fatal", &[])?;
+    assert!(!output.status.success());
+    assert!(!destination.join("refs/heads/main").exists());
+
+    let output = run("warn", &[])?;
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.contains("(partial, LFS fetch failed"), "{}", stdout);
+    assert!(
+        stdout.contains("LFS FAILURES: 1 repositories synced without their LFS objects"),
+        "{}",
+        stdout
+    );
+    assert!(destination.join("refs/heads/main").exists());
+    let summary: serde_json::Value = serde_json::from_str(stdout.lines().last().unwrap())?;
+    assert_eq!(summary["success"], 1);
+    assert_eq!(
+        summary["lfs_failures"][0].as_str(),
+        Some(format!("{} -> {}", origin.display(), destination.display()).as_str())
+    );
+
+    // The missing LFS objects are fetched again by the next run, even if the origin is unchanged
+    for _ in 0..2 {
+        let output = run("warn", &["--only-changed"])?;
+        let stdout = String::from_utf8(output.stdout)?;
+        assert!(stdout.contains("(partial, LFS fetch failed"), "{}", stdout);
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn cleanup_stale() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let mirror_dir = tmp.path().join("mirror-dir");
+    let mut entries = Vec::new();
+    for name in ["hot", "idle"] {
+        let origin = tmp.path().join(name);
+        let destination = tmp.path().join(format!("{name}.git"));
+        fs::create_dir(&origin)?;
+        fs::create_dir(&destination)?;
+        git(&origin, &["init", "-q", "-b", "main"]);
+        git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+        git(&destination, &["init", "-q", "--bare"]);
+        entries.push(format!(
+            "{{\"origin\": {origin:?}, \"destination\": {destination:?}}}\n"
+        ));
+    }
+    let list = tmp.path().join("list.jsonl");
+    let local_repos = || -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(&mirror_dir)
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().is_dir())
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    };
+    let mirror = |cleanup: &str| -> Result<assert_cmd::assert::Assert, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("git-mirror")?;
+        cmd.args(["--provider", "External", "--provider-command"])
+            .arg(format!("cat {list:?}"))
+            .arg("--mirror-dir")
+            .arg(&mirror_dir)
+            .args(["--cleanup", cleanup, "--cleanup-after", "1day"])
+            .arg("--fail-on-sync-error");
+        Ok(cmd.assert().success())
+    };
+
+    fs::write(&list, entries.concat())?;
+    mirror("never")?;
+    let repos = local_repos();
+    assert_eq!(repos.len(), 2);
+
+    // The idle repository was last synced long ago and isn't listed anymore
+    let idle = mirror_dir.join(repos.iter().find(|n| n.contains("idle")).unwrap());
+    let state_file = idle.join("git-mirror-state.json");
+    let mut state: serde_json::Value = serde_json::from_slice(&fs::read(&state_file)?)?;
+    assert!(state["last_success"].is_i64());
+    state["last_success"] = 1_000_000_000.into();
+    fs::write(&state_file, state.to_string())?;
+    fs::write(&list, &entries[0])?;
+
+    mirror("stale")?.stdout(predicate::str::contains(
+        "CLEANUP: 1 stale repositories removed",
+    ));
+    let repos = local_repos();
+    assert_eq!(repos.len(), 1);
+    assert!(repos[0].contains("hot"));
+
+    // Recently synced repositories are kept
+    mirror("stale")?.stdout(predicate::str::contains(
+        "CLEANUP: 0 stale repositories removed",
+    ));
+    assert_eq!(local_repos(), repos);
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn work_tmp() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    let destination = tmp.path().join("destination");
+    fs::create_dir(&origin)?;
+    fs::create_dir(&destination)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    git(&destination, &["init", "-q", "--bare"]);
+
+    // Records the arguments of the git commands
+    let args = tmp.path().join("git-args");
+    let wrapper = tmp.path().join("git-wrapper");
+    fs::write(
+        &wrapper,
+        format!("#!/bin/sh\necho \"$@\" >> {args:?}\nexec git \"$@\"\n"),
+    )?;
+    fs::set_permissions(&wrapper, fs::Permissions::from_mode(0o755))?;
+
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!("{{\"origin\": {origin:?}, \"destination\": {destination:?}}}\n"),
+    )?;
+    let mirror_dir = tmp.path().join("mirror-dir");
+    let scratch = tmp.path().join("scratch");
+    let mirror = || -> Result<(), Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("git-mirror")?;
+        cmd.args(["--provider", "External", "--provider-command"])
+            .arg(format!("cat {list:?}"))
+            .arg("--mirror-dir")
+            .arg(&mirror_dir)
+            .arg("--work-tmp")
+            .arg(&scratch)
+            .arg("--git-executable")
+            .arg(&wrapper)
+            .arg("--fail-on-sync-error");
+        cmd.assert().success();
+        Ok(())
+    };
+
+    // Cloned in the scratch directory and moved to the mirror directory
+    mirror()?;
+    let cloned = fs::read_to_string(&args)?;
+    assert!(cloned.contains(&*scratch.to_string_lossy()));
+    assert!(!cloned.contains(&*mirror_dir.to_string_lossy()));
+    let local: Vec<_> = fs::read_dir(&mirror_dir)?
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().is_dir())
+        .collect();
+    assert_eq!(local.len(), 1);
+    let leftover = fs::read_dir(&scratch)?.next().unwrap()?.path();
+    assert_eq!(fs::read_dir(leftover)?.count(), 0);
+
+    // Updated in the scratch directory as well
+    fs::remove_file(&args)?;
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "second"]);
+    mirror()?;
+    assert!(!fs::read_to_string(&args)?.contains(&*mirror_dir.to_string_lossy()));
+    let head = |dir: &Path| {
+        let out = Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(["rev-parse", "refs/heads/main"])
+            .output()
+            .unwrap();
+        String::from_utf8(out.stdout).unwrap()
+    };
+    assert_eq!(head(&local[0].path()), head(&origin));
+    assert_eq!(head(&destination), head(&origin));
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn sync_metadata() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    let destination = tmp.path().join("destination");
+    fs::create_dir(&origin)?;
+    fs::create_dir(&destination)?;
+    git(&origin, &["init", "-q", "-b", "trunk"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    git(&destination, &["init", "-q", "--bare"]);
+
+    // Fakes the GitLab instance hosting both the origin and the destination project
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let port = server.server_addr().to_ip().unwrap().port();
+    let origin_url = format!("http://127.0.0.1:{port}/origins/origin.git");
+    let listing = serde_json::json!([{
+        "id": 7, "description": format!("origin: {origin_url}"),
+        "web_url": "http://127.0.0.1/mirror-test/origin",
+        "ssh_url_to_repo": destination, "http_url_to_repo": destination,
+        "topics": [], "default_branch": "main"
+    }])
+    .to_string();
+    let handle = std::thread::spawn(move || loop {
+        let mut request = server.recv().unwrap();
+        let url = request.url().to_string();
+        if request.method() == &tiny_http::Method::Put {
+            let mut body = String::new();
+            request.as_reader().read_to_string(&mut body).unwrap();
+            request
+                .respond(tiny_http::Response::from_string("{}"))
+                .unwrap();
+            return (url, body);
+        }
+        let response = if url.starts_with("/api/v4/projects/origins%2Forigin") {
+            r#"{"id": 3, "topics": ["rust", "cli"], "default_branch": "trunk"}"#.to_string()
+        } else {
+            listing.clone()
+        };
+        request
+            .respond(tiny_http::Response::from_string(response))
+            .unwrap();
+    });
+
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "GitLab", "--group", "mirror-test", "--url"])
+        .arg(format!("http://127.0.0.1:{port}"))
+        .args(["--namespace-type", "user", "--sync-metadata"])
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .arg("--fail-on-sync-error")
+        // Clone the origin from the local repository instead of the fake instance
+        .env("GIT_CONFIG_COUNT", "1")
+        .env(
+            "GIT_CONFIG_KEY_0",
+            format!("url.{}.insteadOf", origin.display()),
+        )
+        .env("GIT_CONFIG_VALUE_0", &origin_url);
+    cmd.assert().success();
+
+    let (url, body) = handle.join().unwrap();
+    assert_eq!(url, "/api/v4/projects/7");
+    let body: serde_json::Value = serde_json::from_str(&body)?;
+    assert_eq!(
+        body,
+        serde_json::json!({"topics": ["rust", "cli"], "default_branch": "trunk"})
+    );
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn no_force() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    let destination = tmp.path().join("destination");
+    fs::create_dir(&origin)?;
+    fs::create_dir(&destination)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    git(&destination, &["init", "-q", "--bare"]);
+
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!("{{\"origin\": {origin:?}, \"destination\": {destination:?}}}\n"),
+    )?;
+    let run = || -> Result<_, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("git-mirror")?;
+        cmd.args(["--provider", "External", "--provider-command"])
+            .arg(format!("cat {list:?}"))
+            .arg("--mirror-dir")
+            .arg(tmp.path().join("mirror-dir"))
+            .args(["--no-force", "--summary-format", "json"]);
+        Ok(cmd.output()?)
+    };
+    let rev = |dir: &Path, name: &str| {
+        let out = Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(["rev-parse", name])
+            .output()
+            .unwrap();
+        String::from_utf8(out.stdout).unwrap()
+    };
+    assert!(run()?.status.success());
+
+    // The destination got a commit of its own, while the origin moved on
+    let tree = rev(&destination, "main^{tree}");
+    let own = Command::new("git")
+        .arg("-C")
+        .arg(&destination)
+        .args(["commit-tree", "-p", "main", "-m", "own", tree.trim()])
+        .env("GIT_AUTHOR_NAME", "test")
+        .env("GIT_AUTHOR_EMAIL", "test@example.com")
+        .env("GIT_COMMITTER_NAME", "test")
+        .env("GIT_COMMITTER_EMAIL", "test@example.com")
+        .output()?;
+    let own = String::from_utf8(own.stdout)?;
+    git(&destination, &["update-ref", "refs/heads/main", own.trim()]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "second"]);
+    git(&origin, &["branch", "feature"]);
+
+    let output = run()?;
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(
+        stdout.contains("Destination diverged, not overwriting refs: refs/heads/main"),
+        "{stdout}"
+    );
+    let summary: serde_json::Value = serde_json::from_str(stdout.lines().last().unwrap())?;
+    assert_eq!(summary["failure_kinds"]["diverged"], 1);
+    // The own commit is kept, the other refs are still pushed
+    assert_eq!(rev(&destination, "main"), own);
+    assert_eq!(rev(&destination, "feature"), rev(&origin, "feature"));
+
+    Ok(())
+}
+
+#[test]
+fn recursive_depth() -> Result<(), Box<dyn std::error::Error>> {
+    // Group `top` with the subgroup 1, which has the subgroup 2, each with one project
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let port = server.server_addr().to_ip().unwrap().port();
+    std::thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let url = request.url().to_string();
+            let path = url.split('?').next().unwrap_or_default();
+            let group = path
+                .strip_prefix("/api/v4/groups/")
+                .and_then(|p| p.split('/').next())
+                .unwrap_or_default()
+                .to_string();
+            let body = if path.ends_with("/subgroups") {
+                match group.as_str() {
+                    "top" => r#"[{"id": 1}]"#.to_string(),
+                    "1" => r#"[{"id": 2}]"#.to_string(),
+                    _ => "[]".to_string(),
+                }
+            } else {
+                format!(
+                    r#"[{{"id": 1, "description": "origin: https://example.org/{group}.git",
+                        "web_url": "https://example.com/{group}",
+                        "ssh_url_to_repo": "git@example.com:{group}.git",
+                        "http_url_to_repo": "https://example.com/{group}.git"}}]"#
+                )
+            };
+            request
+                .respond(tiny_http::Response::from_string(body))
+                .unwrap();
+        }
+    });
+
+    for (depth, listed) in [
+        (Some("0"), vec!["top"]),
+        (Some("1"), vec!["top", "1"]),
+        (None, vec!["top", "1", "2"]),
+    ] {
+        let tmp = tempfile::tempdir()?;
+        let mut cmd = Command::cargo_bin("git-mirror")?;
+        cmd.args(["--provider", "GitLab", "--group", "top", "--url"])
+            .arg(format!("http://127.0.0.1:{port}"))
+            .args(["--namespace-type", "group"])
+            .arg("--mirror-dir")
+            .arg(tmp.path().join("mirror-dir"))
+            .arg("--validate-config");
+        if let Some(depth) = depth {
+            cmd.args(["--recursive-depth", depth]);
+        }
+        let output = cmd.output()?;
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout)?;
+        for group in ["top", "1", "2"] {
+            assert_eq!(
+                stdout.contains(&format!("VALID https://example.org/{group}.git")),
+                listed.contains(&group),
+                "{depth:?} {group}: {stdout}"
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn skip_reasons() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let mut entries = String::new();
+    for (name, fields) in [
+        ("synced", ""),
+        ("disabled", r#", "skip": true"#),
+        ("few-stars", r#", "stars": 1"#),
+        ("few-forks", r#", "stars": 10, "forks": 0"#),
+        ("few-stars-2", r#", "stars": 2"#),
+    ] {
+        let origin = tmp.path().join(name);
+        let destination = tmp.path().join(format!("{name}.git"));
+        fs::create_dir(&origin)?;
+        fs::create_dir(&destination)?;
+        git(&origin, &["init", "-q", "-b", "main"]);
+        git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+        git(&destination, &["init", "-q", "--bare"]);
+        entries.push_str(&format!(
+            "{{\"origin\": {origin:?}, \"destination\": {destination:?}{fields}}}\n"
+        ));
+    }
+    let list = tmp.path().join("list.jsonl");
+    fs::write(&list, entries)?;
+    let metrics = tmp.path().join("metrics.prom");
+
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "External", "--provider-command"])
+        .arg(format!("cat {list:?}"))
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .args(["--min-stars", "5", "--min-forks", "1"])
+        .arg("--metric-file")
+        .arg(&metrics)
+        .args(["--summary-format", "json", "--fail-on-sync-error"]);
+    let output = cmd.output()?;
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(output.status.success(), "{stdout}");
+    assert!(
+        stdout.contains("SKIPPED: 1 disabled, 2 too_few_stars, 1 too_few_forks"),
+        "{stdout}"
+    );
+
+    let summary: serde_json::Value = serde_json::from_str(stdout.lines().last().unwrap())?;
+    assert_eq!(
+        summary["skip_reasons"],
+        serde_json::json!({"disabled": 1, "too_few_stars": 2, "too_few_forks": 1})
+    );
+    let metrics = fs::read_to_string(&metrics)?;
+    assert!(
+        metrics.contains(r#"reason="too_few_stars"} 2"#),
+        "{metrics}"
+    );
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn dest_provider() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    let destination = tmp.path().join("destination");
+    fs::create_dir(&origin)?;
+    fs::create_dir(&destination)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    git(&destination, &["init", "-q", "--bare"]);
+
+    // Fakes the GitLab instance hosting the origins in `src` and the destinations in `dst`
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let port = server.server_addr().to_ip().unwrap().port();
+    let listing = serde_json::json!([{
+        "id": 7, "description": "",
+        "web_url": "http://127.0.0.1/src/sub/app",
+        "path": "app", "path_with_namespace": "src/sub/app",
+        "ssh_url_to_repo": origin, "http_url_to_repo": origin,
+    }])
+    .to_string();
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || loop {
+        let mut request = server.recv().unwrap();
+        let url = request.url().to_string();
+        let mut body = String::new();
+        request.as_reader().read_to_string(&mut body).unwrap();
+        let (status, response) = match (request.method(), url.split('?').next().unwrap()) {
+            (tiny_http::Method::Post, "/api/v4/groups") => (201, r#"{"id": 2}"#.to_string()),
+            (tiny_http::Method::Post, "/api/v4/projects") => (201, r#"{"id": 3}"#.to_string()),
+            (_, "/api/v4/groups/src/projects") => (200, listing.clone()),
+            (_, "/api/v4/namespaces/dst") => (200, r#"{"id": 1}"#.to_string()),
+            _ => (404, "{}".to_string()),
+        };
+        tx.send(format!("{} {} {}", request.method(), url, body))
+            .unwrap();
+        request
+            .respond(tiny_http::Response::from_string(response).with_status_code(status))
+            .unwrap();
+    });
+
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "GitLab", "--group", "src", "--url"])
+        .arg(format!("http://127.0.0.1:{port}"))
+        .args(["--namespace-type", "group", "--recursive-depth", "0"])
+        .args([
+            "--dest-provider",
+            "GitLab",
+            "--dest-group",
+            "dst",
+            "--dest-url",
+        ])
+        .arg(format!("http://127.0.0.1:{port}"))
+        .arg("--http")
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .arg("--fail-on-sync-error")
+        // Push to the local repository instead of the fake instance
+        .env("GIT_CONFIG_COUNT", "1")
+        .env(
+            "GIT_CONFIG_KEY_0",
+            format!("url.{}.insteadOf", destination.display()),
+        )
+        .env(
+            "GIT_CONFIG_VALUE_0",
+            format!("http://127.0.0.1:{port}/dst/sub/app.git"),
+        );
+    cmd.assert().success();
+
+    let requests: Vec<String> = rx.try_iter().collect();
+    let created: Vec<&String> = requests.iter().filter(|r| r.starts_with("POST")).collect();
+    assert_eq!(created.len(), 2, "{requests:?}");
+    assert!(created[0].starts_with("POST /api/v4/groups "));
+    let group: serde_json::Value = serde_json::from_str(created[0].splitn(3, ' ').nth(2).unwrap())?;
+    assert_eq!(
+        group,
+        serde_json::json!({"name": "sub", "path": "sub", "parent_id": 1, "visibility": "private"})
+    );
+    assert!(created[1].starts_with("POST /api/v4/projects "));
+    let project: serde_json::Value =
+        serde_json::from_str(created[1].splitn(3, ' ').nth(2).unwrap())?;
+    assert_eq!(project["namespace_id"], 2);
+    assert_eq!(project["path"], "app");
+    let out = Command::new("git")
+        .arg("-C")
+        .arg(&destination)
+        .args(["log", "--format=%s", "main"])
+        .output()?;
+    assert_eq!(String::from_utf8(out.stdout)?, "initial\n");
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn repo_filters() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let mut entries = String::new();
+    for (name, fields) in [
+        ("team/app", ""),
+        ("team/sandbox-x", ""),
+        ("other/app", ""),
+        ("team/old", r#", "archived": true"#),
+        ("team/forked", r#", "fork": true"#),
+    ] {
+        let origin = tmp.path().join("origins").join(name);
+        let destination = tmp.path().join(format!("{name}.git"));
+        fs::create_dir_all(&origin)?;
+        fs::create_dir_all(&destination)?;
+        git(&origin, &["init", "-q", "-b", "main"]);
+        git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+        git(&destination, &["init", "-q", "--bare"]);
+        entries.push_str(&format!(
+            "{{\"origin\": {origin:?}, \"destination\": {destination:?}{fields}}}\n"
+        ));
+    }
+    let list = tmp.path().join("list.jsonl");
+    fs::write(&list, entries)?;
+
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "External", "--provider-command"])
+        .arg(format!("cat {list:?}"))
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .args([
+            "--include",
+            "**/team/*",
+            "--exclude-regex",
+            "/sandbox-[^/]*$",
+        ])
+        .args(["--skip-archived", "--skip-forks", "--fail-on-sync-error"]);
+    let output = cmd.output()?;
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(output.status.success(), "{stdout}");
+    assert!(
+        stdout.contains("SKIPPED: 2 excluded, 1 archived, 1 fork"),
+        "{stdout}"
+    );
+    assert!(stdout.contains("(excluded by /sandbox-[^/]*$)"), "{stdout}");
+    assert!(stdout.contains("other/app.git (not included)"), "{stdout}");
+    assert!(stdout.contains("END(OK) 0/5"), "{stdout}");
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn prune() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let mut entries = Vec::new();
+    for name in ["kept", "removed"] {
+        let origin = tmp.path().join(name);
+        let destination = tmp.path().join(format!("{name}.git"));
+        fs::create_dir(&origin)?;
+        fs::create_dir(&destination)?;
+        git(&origin, &["init", "-q", "-b", "main"]);
+        git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+        git(&destination, &["init", "-q", "--bare"]);
+        entries.push(format!(
+            "{{\"origin\": {origin:?}, \"destination\": {destination:?}}}\n"
+        ));
+    }
+    let list = tmp.path().join("list.jsonl");
+    let mirror_dir = tmp.path().join("mirror-dir");
+    let run = |args: &[&str]| -> Result<String, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("git-mirror")?;
+        cmd.args(["--provider", "External", "--provider-command"])
+            .arg(format!("cat {list:?}"))
+            .arg("--mirror-dir")
+            .arg(&mirror_dir)
+            .arg("--fail-on-sync-error")
+            .args(args);
+        let output = cmd.output()?;
+        let stdout = String::from_utf8(output.stdout)?;
+        assert!(output.status.success(), "{stdout}");
+        Ok(stdout)
+    };
+    let repos = || -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(&mirror_dir)
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().is_dir())
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    };
+
+    fs::write(&list, entries.concat())?;
+    run(&["--prune"])?;
+    assert_eq!(repos().len(), 2);
+
+    // The project is no longer listed
+    fs::write(&list, &entries[0])?;
+    let stdout = run(&["--prune", "--dry-run"])?;
+    assert!(stdout.contains("removed.git, dry run)"), "{stdout}");
+    assert_eq!(repos().len(), 2);
+    let stdout = run(&["--prune"])?;
+    assert!(
+        stdout.contains("PRUNE: 1 local repositories of removed projects removed"),
+        "{stdout}"
+    );
+    let remaining = repos();
+    assert_eq!(remaining.len(), 1);
+    assert!(remaining[0].ends_with("kept"), "{remaining:?}");
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn prune_remote() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let mut listing = Vec::new();
+    let mut config = Vec::new();
+    for name in ["kept", "disabled", "removed"] {
+        let origin = tmp.path().join(name);
+        // Not next to the origin, git would find it as `<origin>.git` after the origin is deleted
+        let destination = tmp.path().join("dst").join(format!("{name}.git"));
+        fs::create_dir(&origin)?;
+        fs::create_dir_all(&destination)?;
+        git(&origin, &["init", "-q", "-b", "main"]);
+        git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+        git(&destination, &["init", "-q", "--bare"]);
+        listing.push(serde_json::json!({
+            "id": listing.len() + 1, "description": "",
+            "web_url": format!("http://127.0.0.1/src/{name}"),
+            "path": name, "path_with_namespace": format!("src/{name}"),
+            "ssh_url_to_repo": origin, "http_url_to_repo": origin,
+        }));
+        config.push((destination, name));
+    }
+
+    // Fakes the GitLab instance hosting the origins in `src` and the existing destinations in `dst`
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let port = server.server_addr().to_ip().unwrap().port();
+    let listed = std::sync::Arc::new(std::sync::Mutex::new(serde_json::json!(listing)));
+    let (tx, rx) = std::sync::mpsc::channel();
+    {
+        let listed = listed.clone();
+        std::thread::spawn(move || loop {
+            let request = server.recv().unwrap();
+            let url = request.url().to_string();
+            let response = if url.starts_with("/api/v4/groups/src/projects") {
+                listed.lock().unwrap().to_string()
+            } else {
+                r#"{"id": 1}"#.to_string()
+            };
+            tx.send(format!("{} {}", request.method(), url)).unwrap();
+            request
+                .respond(tiny_http::Response::from_string(response))
+                .unwrap();
+        });
+    }
+
+    let config_file = tmp.path().join("config.yml");
+    fs::write(&config_file, "repos: []\n")?;
+    let run = || -> Result<String, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("git-mirror")?;
+        cmd.args(["--provider", "GitLab", "--group", "src", "--url"])
+            .arg(format!("http://127.0.0.1:{port}"))
+            .args(["--namespace-type", "group", "--recursive-depth", "0"])
+            .args([
+                "--dest-provider",
+                "GitLab",
+                "--dest-group",
+                "dst",
+                "--dest-url",
+            ])
+            .arg(format!("http://127.0.0.1:{port}"))
+            .args(["--http", "--prune", "--prune-remote", "archive"])
+            .arg("--mirror-dir")
+            .arg(tmp.path().join("mirror-dir"))
+            .arg("--config")
+            .arg(&config_file)
+            .arg("--fail-on-sync-error")
+            .env("GIT_CONFIG_COUNT", config.len().to_string());
+        // Push to the local repositories instead of the fake instance
+        for (i, (destination, name)) in config.iter().enumerate() {
+            cmd.env(
+                format!("GIT_CONFIG_KEY_{i}"),
+                format!("url.{}.insteadOf", destination.display()),
+            )
+            .env(
+                format!("GIT_CONFIG_VALUE_{i}"),
+                format!("http://127.0.0.1:{port}/dst/{name}.git"),
+            );
+        }
+        let output = cmd.output()?;
+        let stdout = String::from_utf8(output.stdout)?;
+        assert!(output.status.success(), "{stdout}");
+        Ok(stdout)
+    };
+
+    run()?;
+    // One origin is deleted, the other project is disabled and not listed either
+    listed.lock().unwrap().as_array_mut().unwrap().pop();
+    fs::remove_dir_all(tmp.path().join("removed"))?;
+    fs::write(
+        &config_file,
+        "repos:\n  - path: dst/disabled\n    disabled: true\n",
+    )?;
+    rx.try_iter().count();
+    let stdout = run()?;
+    assert!(
+        stdout.contains(
+            "1 local repositories of removed projects removed, 1 destinations pruned (archive)"
+        ),
+        "{stdout}"
+    );
+    let requests: Vec<String> = rx.try_iter().collect();
+    assert!(
+        requests.contains(&"POST /api/v4/projects/dst%2Fremoved/archive".to_string()),
+        "{requests:?}"
+    );
+    assert!(
+        !requests.iter().any(|r| r.contains("kept/archive")),
+        "{requests:?}"
+    );
+    // The destination of the disabled project survives, it is checked again by the next run
+    assert!(
+        !requests.iter().any(|r| r.contains("disabled/archive")),
+        "{requests:?}"
+    );
+    let stdout = run()?;
+    assert!(
+        stdout.contains(
+            "0 local repositories of removed projects removed, 0 destinations pruned (archive)"
+        ),
+        "{stdout}"
+    );
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn config_file() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let mut sources = String::new();
+    for (group, projects) in [("a", ["app", "legacy"]), ("b", ["lib", "moved"])] {
+        let mut entries = String::new();
+        for name in projects {
+            let origin = tmp.path().join("origins").join(group).join(name);
+            let destination = tmp.path().join(group).join(format!("{name}.git"));
+            fs::create_dir_all(&origin)?;
+            fs::create_dir_all(&destination)?;
+            git(&origin, &["init", "-q", "-b", "main"]);
+            git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+            git(&destination, &["init", "-q", "--bare"]);
+            entries.push_str(&format!(
+                "{{\"origin\": {origin:?}, \"destination\": {destination:?}}}\n"
+            ));
+        }
+        let list = tmp.path().join(format!("{group}.jsonl"));
+        fs::write(&list, entries)?;
+        let mirror_dir = tmp.path().join(format!("mirror-{group}"));
+        sources.push_str(&format!(
+            "  - provider-command: cat {list:?}\n    mirror-dir: {mirror_dir:?}\n"
+        ));
+    }
+    let moved = tmp.path().join("elsewhere.git");
+    fs::create_dir(&moved)?;
+    git(&moved, &["init", "-q", "--bare"]);
+    let config = tmp.path().join("mirror.yml");
+    fs::write(
+        &config,
+        format!(
+            "options:\n  provider: External\n  worker-count: 2\n  fail-on-sync-error: true\n\
+             sources:\n{sources}\
+             repos:\n  - path: \"**/a/legacy\"\n    disabled: true\n\
+             \x20 - path: \"**/b/moved\"\n    destination: {moved:?}\n"
+        ),
+    )?;
+
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.arg("--config").arg(&config);
+    let output = cmd.output()?;
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(output.status.success(), "{stdout}");
+    assert!(
+        stdout.contains("a/legacy.git (disabled by repo config)"),
+        "{stdout}"
+    );
+    assert!(stdout.contains("END(OK) 0/2"), "{stdout}");
+    assert!(tmp.path().join("mirror-a").is_dir());
+    assert!(tmp.path().join("mirror-b").is_dir());
+    let branches = Command::new("git")
+        .current_dir(&moved)
+        .args(["branch", "--list", "main"])
+        .output()?;
+    assert!(!branches.stdout.is_empty(), "{stdout}");
+
+    // The command line takes precedence over the config file
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.arg("--config")
+        .arg(&config)
+        .args(["--worker-count", "3", "--print-config"]);
+    let output = cmd.output()?;
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(output.status.success(), "{stdout}");
+    assert!(
+        stdout.contains("\"source\": \"config file\",\n      \"value\": \"External\""),
+        "{stdout}"
+    );
+    assert!(
+        stdout.contains("\"source\": \"command line\",\n      \"value\": \"3\""),
+        "{stdout}"
+    );
+
+    // Sources sharing a mirror directory would remove each others repositories
+    fs::write(
+        &config,
+        "sources:\n  - group: a\n  - group: b\n  - group: c\n    mirror-dir: c\n",
+    )?;
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.arg("--config").arg(&config);
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "Sources 1 and 2 of the config file use the same --mirror-dir",
+    ));
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn json_report() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    let destination = tmp.path().join("destination.git");
+    fs::create_dir(&origin)?;
+    fs::create_dir(&destination)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "first"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "second"]);
+    git(&destination, &["init", "-q", "--bare"]);
+    let missing = tmp.path().join("missing");
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!(
+            "{{\"origin\": {origin:?}, \"destination\": {destination:?}}}\n\
+             {{\"origin\": {missing:?}, \"destination\": {destination:?}}}\n"
+        ),
+    )?;
+    let report = tmp.path().join("report.json");
+    let run = || -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("git-mirror")?;
+        cmd.args(["--provider", "External", "--provider-command"])
+            .arg(format!("cat {list:?}"))
+            .arg("--mirror-dir")
+            .arg(tmp.path().join("mirror-dir"))
+            .arg("--json-report")
+            .arg(&report)
+            .args(["--worker-count", "1"]);
+        cmd.assert().success();
+        Ok(serde_json::from_str(&fs::read_to_string(&report)?)?)
+    };
+
+    let report = run()?;
+    assert_eq!(
+        (&report["total"], &report["failed"]),
+        (&2.into(), &1.into())
+    );
+    let projects = report["projects"].as_array().unwrap();
+    assert_eq!(projects.len(), 2);
+    let synced = &projects[0];
+    assert_eq!(synced["source"], origin.to_str().unwrap());
+    assert_eq!(synced["destination"], destination.to_str().unwrap());
+    assert_eq!(synced["result"], "success");
+    assert_eq!(synced["error"], serde_json::Value::Null);
+    assert_eq!(
+        (&synced["commits_fetched"], &synced["commits_pushed"]),
+        (&2.into(), &2.into())
+    );
+    let failed = &projects[1];
+    assert_eq!(failed["result"], "failed");
+    assert!(
+        failed["error"].as_str().is_some_and(|e| !e.is_empty()),
+        "{failed}"
+    );
+    assert_eq!(failed["commits_pushed"], serde_json::Value::Null);
+
+    // Only the new commits are counted
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "third"]);
+    let report = run()?;
+    let synced = &report["projects"][0];
+    assert_eq!(
+        (&synced["commits_fetched"], &synced["commits_pushed"]),
+        (&1.into(), &1.into())
+    );
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn retry_count() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    let destination = tmp.path().join("destination");
+    fs::create_dir(&origin)?;
+    fs::create_dir(&destination)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    git(&destination, &["init", "-q", "--bare"]);
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!("{{\"origin\": {origin:?}, \"destination\": {destination:?}}}\n"),
+    )?;
+    // The first clone fails with a flaky connection
+    let failed = tmp.path().join("failed");
+    let flaky_git = tmp.path().join("flaky-git");
+    fs::write(
+        &flaky_git,
+        format!(
+            "#!/bin/sh\ncase \" $* \" in\n\
+             *\" clone \"*) if [ ! -e {failed:?} ]; then touch {failed:?}; \
+             echo 'fatal: unable to access: Connection reset by peer' >&2; exit 128; fi ;;\n\
+             esac\nexec git \"$@\"\n"
+        ),
+    )?;
+    fs::set_permissions(&flaky_git, fs::Permissions::from_mode(0o755))?;
+
+    let report = tmp.path().join("report.json");
+    let metrics = tmp.path().join("metrics.prom");
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "External", "--provider-command"])
+        .arg(format!("cat {list:?}"))
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .arg("--git-executable")
+        .arg(&flaky_git)
+        .args(["--retries", "2", "--retry-backoff", "10ms"])
+        .arg("--json-report")
+        .arg(&report)
+        .arg("--metric-file")
+        .arg(&metrics)
+        .args(["--summary-format", "json", "--fail-on-sync-error"]);
+    let output = cmd.output()?;
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(output.status.success(), "{stdout}");
+    assert!(stdout.contains("(after 1 retry)"), "{stdout}");
+    let summary: serde_json::Value = serde_json::from_str(stdout.lines().last().unwrap())?;
+    assert_eq!(summary["retries"], 1);
+    let report: serde_json::Value = serde_json::from_str(&fs::read_to_string(&report)?)?;
+    assert_eq!(report["projects"][0]["retries"], 1);
+    let metrics = fs::read_to_string(&metrics)?;
+    assert!(
+        metrics
+            .lines()
+            .any(|l| l.starts_with("git_mirror_project_retries{") && l.ends_with(" 1")),
+        "{metrics}"
+    );
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn github_app() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    let destination = tmp.path().join("destination");
+    fs::create_dir(&origin)?;
+    fs::create_dir(&destination)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    git(&destination, &["init", "-q", "--bare"]);
+    let key = tmp.path().join("app.pem");
+    fs::write(
+        &key,
+        openssl::rsa::Rsa::generate(2048)?.private_key_to_pem()?,
+    )?;
+
+    // Fakes the GitHub API, records the authorization of every request
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let port = server.server_addr().to_ip().unwrap().port();
+    let origin_url = format!("http://127.0.0.1:{port}/upstream/origin.git");
+    let listing = serde_json::json!([{
+        "id": 7, "full_name": "mirror-test/origin", "description": format!("origin: {origin_url}"),
+        "url": "http://127.0.0.1/repos/mirror-test/origin",
+        "ssh_url": destination, "clone_url": destination
+    }])
+    .to_string();
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || loop {
+        let request = server.recv().unwrap();
+        let authorization = request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv("Authorization"))
+            .map(|h| h.value.to_string());
+        let (status, response) = match request.method() {
+            tiny_http::Method::Post => (
+                201,
+                r#"{"token": "ghs_test", "expires_at": "2099-01-01T00:00:00Z"}"#.to_string(),
+            ),
+            _ => (200, listing.clone()),
+        };
+        tx.send((request.url().to_string(), authorization)).unwrap();
+        request
+            .respond(tiny_http::Response::from_string(response).with_status_code(status))
+            .unwrap();
+    });
+
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "GitHub", "--group", "mirror-test", "--url"])
+        .arg(format!("http://127.0.0.1:{port}"))
+        .args(["--github-app-id", "42", "--github-installation-id", "7"])
+        .arg("--github-app-key")
+        .arg(&key)
+        .arg("--http")
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .arg("--fail-on-sync-error")
+        // Clone the origin from the local repository instead of the fake instance
+        .env("GIT_CONFIG_COUNT", "1")
+        .env(
+            "GIT_CONFIG_KEY_0",
+            format!("url.{}.insteadOf", origin.display()),
+        )
+        .env("GIT_CONFIG_VALUE_0", &origin_url);
+    cmd.assert().success();
+
+    // The token is requested once and used for the listing
+    let requests: Vec<_> = rx.try_iter().collect();
+    assert_eq!(requests.len(), 2, "{requests:?}");
+    assert_eq!(requests[0].0, "/app/installations/7/access_tokens");
+    assert!(requests[0].1.as_ref().unwrap().starts_with("Bearer "));
+    assert_eq!(requests[1].0, "/orgs/mirror-test/repos?per_page=100");
+    assert_eq!(requests[1].1.as_deref(), Some("token ghs_test"));
+    let head = |dir: &Path| {
+        let out = Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(["rev-parse", "refs/heads/main"])
+            .output()
+            .unwrap();
+        String::from_utf8(out.stdout).unwrap()
+    };
+    assert_eq!(head(&destination), head(&origin));
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn api_rate_limit() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+
+    // Fakes the GitHub API, rate limits the first request and lists with two pages
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let port = server.server_addr().to_ip().unwrap().port();
+    let repo = |name: &str| {
+        serde_json::json!({
+            "full_name": format!("mirror-test/{name}"),
+            "description": format!("origin: https://example.com/{name}.git"),
+            "url": format!("http://127.0.0.1/repos/mirror-test/{name}"),
+            "ssh_url": format!("git@127.0.0.1:mirror-test/{name}.git"),
+            "clone_url": format!("http://127.0.0.1/mirror-test/{name}.git")
+        })
+    };
+    let first = serde_json::json!([repo("a")]).to_string();
+    let second = serde_json::json!([repo("b")]).to_string();
+    let next = format!(
+        "<http://127.0.0.1:{port}/orgs/mirror-test/repos?per_page=100&page=2>; rel=\"next\""
+    );
+    let header = |name: &str, value: &str| {
+        tiny_http::Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()
+    };
+    std::thread::spawn(move || {
+        for i in 0.. {
+            let request = server.recv().unwrap();
+            let response = match i {
+                0 => tiny_http::Response::from_string("")
+                    .with_status_code(429)
+                    .with_header(header("Retry-After", "1")),
+                _ if request.url().ends_with("page=2") => {
+                    tiny_http::Response::from_string(second.clone())
+                        .with_header(header("X-RateLimit-Remaining", "4990"))
+                        .with_header(header("X-RateLimit-Limit", "5000"))
+                }
+                _ => tiny_http::Response::from_string(first.clone())
+                    .with_header(header("X-RateLimit-Remaining", "4991"))
+                    .with_header(header("Link", &next)),
+            };
+            request.respond(response).unwrap();
+        }
+    });
+
+    let metrics = tmp.path().join("metrics.prom");
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "GitHub", "--group", "mirror-test", "--url"])
+        .arg(format!("http://127.0.0.1:{port}"))
+        .args(["--max-api-rps", "20", "--dry-run", "-v"])
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .arg("--metric-file")
+        .arg(&metrics);
+    let output = cmd.output()?;
+    let stdout = String::from_utf8(output.stdout)?;
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(output.status.success(), "{stdout}{stderr}");
+    assert!(stderr.contains("Rate limited by 127.0.0.1"), "{stderr}");
+    assert!(stdout.contains("https://example.com/a.git"), "{stdout}");
+    assert!(stdout.contains("https://example.com/b.git"), "{stdout}");
+    let metrics = fs::read_to_string(&metrics)?;
+    assert!(
+        metrics.contains("git_mirror_api_rate_limit_remaining{host=\"127.0.0.1\"} 4990"),
+        "{metrics}"
+    );
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn webhook() -> Result<(), Box<dyn std::error::Error>> {
+    use std::net::TcpListener;
+    use std::process::Stdio;
+    use std::time::{Duration, Instant};
+
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    let destination = tmp.path().join("destination");
+    fs::create_dir(&origin)?;
+    fs::create_dir(&destination)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    git(&destination, &["init", "-q", "--bare"]);
+    // Not pushed to, only synced by the full runs
+    let other = tmp.path().join("other");
+    let other_destination = tmp.path().join("other-destination");
+    fs::create_dir(&other)?;
+    fs::create_dir(&other_destination)?;
+    git(&other, &["init", "-q", "-b", "main"]);
+    git(&other, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    git(&other_destination, &["init", "-q", "--bare"]);
+
+    // The origin is listed by its GitLab URL, which git fetches from the local repository
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!(
+            "{{\"origin\": \"http://gitlab.test/group/app.git\", \"destination\": {destination:?}}}\n\
+             {{\"origin\": {other:?}, \"destination\": {other_destination:?}}}\n"
+        ),
+    )?;
+    let report = tmp.path().join("report.json");
+    let metrics = tmp.path().join("metrics.prom");
+    let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+    let mut child = Command::cargo_bin("git-mirror")?
+        .args(["--provider", "External", "--provider-command"])
+        .arg(format!("cat {list:?}"))
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .args(["--daemon", "--listen"])
+        .arg(format!("127.0.0.1:{port}"))
+        .args(["--webhook-secret", "s3cret"])
+        .arg("--json-report")
+        .arg(&report)
+        .arg("--metric-file")
+        .arg(&metrics)
+        .env("GIT_CONFIG_COUNT", "1")
+        .env(
+            "GIT_CONFIG_KEY_0",
+            format!("url.{}.insteadOf", origin.display()),
+        )
+        .env("GIT_CONFIG_VALUE_0", "http://gitlab.test/group/app.git")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let base = format!("http://127.0.0.1:{port}");
+    let client = reqwest::blocking::Client::new();
+    let head = |dir: &Path| {
+        let out = Command::new("git")
+            .arg("--git-dir")
+            .arg(dir)
+            .args(["rev-parse", "main"])
+            .output()
+            .unwrap();
+        String::from_utf8_lossy(&out.stdout).trim().to_string()
+    };
+    let status = || -> serde_json::Value {
+        client
+            .get(format!("{base}/status"))
+            .send()
+            .and_then(|r| r.text())
+            .ok()
+            .and_then(|body| serde_json::from_str(&body).ok())
+            .unwrap_or_default()
+    };
+    let wait_for = |what: &str, done: &dyn Fn() -> bool| {
+        let start = Instant::now();
+        while !done() {
+            assert!(start.elapsed() < Duration::from_secs(20), "No {what}");
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    };
+    let hook = |token: &str, path: &str| {
+        let body = format!(
+            "{{\"object_kind\": \"push\", \"project\": {{\"path_with_namespace\": \"{path}\", \
+             \"web_url\": \"http://gitlab.test/{path}\"}}}}"
+        );
+        client
+            .post(format!("{base}/webhook"))
+            .header("X-Gitlab-Event", "Push Hook")
+            .header("X-Gitlab-Token", token)
+            .body(body)
+            .send()
+            .map(|r| r.status().as_u16())
+    };
+
+    let result = (|| -> Result<(), Box<dyn std::error::Error>> {
+        wait_for("first run", &|| status()["runs"] == 1);
+        git(&origin, &["commit", "-q", "--allow-empty", "-m", "pushed"]);
+        assert_ne!(head(&destination), head(&origin.join(".git")));
+
+        assert_eq!(hook("wrong", "group/app")?, 401);
+        assert_eq!(hook("s3cret", "group/other")?, 404);
+        assert_eq!(hook("s3cret", "group/app")?, 202);
+
+        wait_for("webhook run", &|| status()["webhook_runs"] == 1);
+        assert_eq!(head(&destination), head(&origin.join(".git")));
+        assert_eq!(status()["runs"], 1);
+
+        // The result of the webhook run replaces the one of the full run
+        let report: serde_json::Value = serde_json::from_str(&fs::read_to_string(&report)?)?;
+        assert_eq!(report["total"], 2, "{report}");
+        assert_eq!(report["projects"].as_array().map(Vec::len), Some(2));
+        let metrics = fs::read_to_string(&metrics)?;
+        for name in ["git_mirror_total", "git_mirror_ok"] {
+            assert!(
+                metrics
+                    .lines()
+                    .any(|l| l.starts_with(&format!("{name}{{")) && l.ends_with(" 2")),
+                "{metrics}"
+            );
+        }
+        Ok(())
+    })();
+    child.kill()?;
+    child.wait()?;
+    result
+}
+
+#[cfg(unix)]
+#[test]
+fn unchanged_activity() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    let destination = tmp.path().join("destination");
+    fs::create_dir(&origin)?;
+    fs::create_dir(&destination)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    git(&destination, &["init", "-q", "--bare"]);
+
+    let list = tmp.path().join("list.jsonl");
+    let run = |pushed_at: &str, args: &[&str]| -> Result<_, Box<dyn std::error::Error>> {
+        fs::write(
+            &list,
+            format!(
+                "{{\"origin\": {origin:?}, \"destination\": {destination:?}, \"pushed_at\": \"{pushed_at}\"}}\n"
+            ),
+        )?;
+        let mut cmd = Command::cargo_bin("git-mirror")?;
+        cmd.args(["--provider", "External", "--provider-command"])
+            .arg(format!("cat {list:?}"))
+            .arg("--mirror-dir")
+            .arg(tmp.path().join("mirror-dir"))
+            .arg("--only-changed")
+            .args(args)
+            .arg("--fail-on-sync-error");
+        Ok(cmd.assert().success())
+    };
+    let head = |dir: &Path| {
+        let output = Command::new("git")
+            .args(["rev-parse", "main"])
+            .current_dir(dir)
+            .output()
+            .unwrap();
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+
+    run("2020-01-01T00:00:00Z", &[])?;
+    assert_eq!(head(&destination), head(&origin));
+
+    // Not even checked with git ls-remote, as the listed activity didn't change
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "second"]);
+    run("2020-01-01T00:00:00Z", &[])?.stdout(predicate::str::contains("(up-to-date)"));
+    assert_ne!(head(&destination), head(&origin));
+
+    run("2020-01-01T00:00:00Z", &["--force-sync"])?
+        .stdout(predicate::str::contains("(up-to-date)").not());
+    assert_eq!(head(&destination), head(&origin));
+
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "third"]);
+    run("2020-01-02T00:00:00Z", &[])?.stdout(predicate::str::contains("(up-to-date)").not());
+    assert_eq!(head(&destination), head(&origin));
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn notify_webhook() -> Result<(), Box<dyn std::error::Error>> {
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let port = server.server_addr().to_ip().unwrap().port();
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for mut request in server.incoming_requests() {
+            let mut body = String::new();
+            request.as_reader().read_to_string(&mut body).unwrap();
+            tx.send((request.url().to_string(), body)).unwrap();
+            request
+                .respond(tiny_http::Response::from_string("ok"))
+                .unwrap();
+        }
+    });
+
+    let tmp = tempfile::tempdir()?;
+    let destination = tmp.path().join("destination");
+    fs::create_dir(&destination)?;
+    git(&destination, &["init", "-q", "--bare"]);
+    let list = tmp.path().join("list.jsonl");
+    let run = |origin: &Path, args: &[&str]| -> Result<_, Box<dyn std::error::Error>> {
+        fs::write(
+            &list,
+            format!("{{\"origin\": {origin:?}, \"destination\": {destination:?}}}\n"),
+        )?;
+        let mut cmd = Command::cargo_bin("git-mirror")?;
+        cmd.args(["--provider", "External", "--provider-command"])
+            .arg(format!("cat {list:?}"))
+            .arg("--mirror-dir")
+            .arg(tmp.path().join("mirror-dir"))
+            .arg("--notify-webhook")
+            .arg(format!("http://127.0.0.1:{port}/hook/secret"))
+            .args(args);
+        Ok(cmd.assert())
+    };
+
+    run(&tmp.path().join("missing"), &[])?
+        .success()
+        .stderr(predicate::str::contains("secret").not());
+    let (url, body) = rx.recv_timeout(std::time::Duration::from_secs(10))?;
+    assert_eq!(url, "/hook/secret");
+    let payload: serde_json::Value = serde_json::from_str(&body)?;
+    assert!(payload["text"]
+        .as_str()
+        .unwrap_or_default()
+        .contains("1 of 1 repositories failed"));
+    assert_eq!(payload["summary"]["failed"], 1);
+    assert!(payload["failed"][0]["repository"]
+        .as_str()
+        .unwrap_or_default()
+        .contains("missing"));
+
+    // Successful runs are only reported with --notify-always
+    let origin = tmp.path().join("origin");
+    fs::create_dir(&origin)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    run(&origin, &["--fail-on-sync-error"])?.success();
+    assert!(rx.try_recv().is_err());
+    run(&origin, &["--fail-on-sync-error", "--notify-always"])?.success();
+    let (_, body) = rx.recv_timeout(std::time::Duration::from_secs(10))?;
+    let payload: serde_json::Value = serde_json::from_str(&body)?;
+    assert_eq!(payload["summary"]["failed"], 0);
+    assert_eq!(payload["failed"], serde_json::json!([]));
+
+    Ok(())
+}
+
+#[cfg(all(unix, feature = "libgit2"))]
+#[test]
+fn libgit2_backend() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    let destination = tmp.path().join("destination");
+    fs::create_dir(&origin)?;
+    fs::create_dir(&destination)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "second"]);
+    git(&origin, &["branch", "topic"]);
+    git(&origin, &["tag", "v1"]);
+    git(&destination, &["init", "-q", "--bare"]);
+
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!("{{\"origin\": {origin:?}, \"destination\": {destination:?}}}\n"),
+    )?;
+    let sync = || -> Result<Command, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("git-mirror")?;
+        cmd.args(["--provider", "External", "--provider-command"])
+            .arg(format!("cat {list:?}"))
+            .arg("--mirror-dir")
+            .arg(tmp.path().join("mirror-dir"))
+            .args(["--git-backend", "libgit2"]);
+        Ok(cmd)
+    };
+    let rev = |dir: &Path, name: &str| {
+        let output = Command::new("git")
+            .current_dir(dir)
+            .args(["rev-parse", "--verify", "-q", name])
+            .output()
+            .unwrap();
+        String::from_utf8_lossy(&output.stdout).trim().to_string()
+    };
+    sync()?
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("END(OK)"));
+    assert_eq!(rev(&destination, "refs/heads/main"), rev(&origin, "main"));
+    assert_eq!(rev(&destination, "refs/tags/v1"), rev(&origin, "v1"));
+
+    // The update fetches the new commit and removes the deleted tag
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "third"]);
+    git(&origin, &["tag", "-d", "v1"]);
+    sync()?.assert().success();
+    assert_eq!(rev(&destination, "refs/heads/main"), rev(&origin, "main"));
+    assert_eq!(rev(&destination, "refs/tags/v1"), "");
+
+    // Without force the rewound branch isn't overwritten, the other refs are still pushed
+    git(&origin, &["branch", "-f", "topic", "main~2"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "fourth"]);
+    let output = sync()?.arg("--no-force").output()?;
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(
+        stdout.contains("Destination diverged, not overwriting refs: refs/heads/topic"),
+        "{stdout}"
+    );
+    assert_eq!(rev(&destination, "refs/heads/main"), rev(&origin, "main"));
+    assert_ne!(rev(&destination, "refs/heads/topic"), rev(&origin, "topic"));
+
+    // Options only the git command line supports
+    sync()?
+        .args(["--ssh-jump", "jump.example.com"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "--ssh-jump isn't supported by --git-backend libgit2",
+        ));
+
+    Ok(())
+}
+
+#[cfg(not(feature = "libgit2"))]
+#[test]
+fn libgit2_backend_missing() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["-g", "mirror-test", "--git-backend", "libgit2"]);
+    cmd.assert().failure().stderr(predicate::str::contains(
+        "--git-backend libgit2 needs git-mirror built with the libgit2 feature",
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn bitbucket_server() -> Result<(), Box<dyn std::error::Error>> {
+    // Project `LEG` with one repository per page, the second one without description
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let port = server.server_addr().to_ip().unwrap().port();
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let authorization = request
+                .headers()
+                .iter()
+                .find(|h| h.field.equiv("Authorization"))
+                .map(|h| h.value.to_string());
+            tx.send((request.url().to_string(), authorization)).unwrap();
+            let (slug, description, last) = match request.url() {
+                "/rest/api/1.0/projects/LEG/repos?limit=100&start=0" => {
+                    ("app", r#""origin: https://example.org/app.git""#, false)
+                }
+                _ => ("undescribed", "null", true),
+            };
+            let body = format!(
+                r#"{{"isLastPage": {last}, "nextPageStart": 1, "values": [{{"id": 1,
+                    "slug": "{slug}", "description": {description}, "project": {{"key": "LEG"}},
+                    "links": {{"self": [{{"href": "http://bb.test/projects/LEG/repos/{slug}/browse"}}],
+                    "clone": [{{"name": "ssh", "href": "ssh://git@bb.test:7999/leg/{slug}.git"}},
+                    {{"name": "http", "href": "http://alice@bb.test/scm/leg/{slug}.git"}}]}}}}]}}"#
+            );
+            request
+                .respond(tiny_http::Response::from_string(body))
+                .unwrap();
+        }
+    });
+
+    let tmp = tempfile::tempdir()?;
+    let output = Command::cargo_bin("git-mirror")?
+        .args(["--provider", "Bitbucket", "--group", "LEG", "--url"])
+        .arg(format!("http://127.0.0.1:{port}"))
+        .args([
+            "--bitbucket-user",
+            "alice",
+            "--private-token",
+            "app-password",
+        ])
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .arg("--validate-config")
+        .output()?;
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(
+        stdout.contains("VALID https://example.org/app.git -> ssh://git@bb.test:7999/leg/app.git"),
+        "{stdout}"
+    );
+    assert!(stdout.contains("MISSING http://bb.test/projects/LEG/repos/undescribed/browse"));
+
+    let requests: Vec<_> = rx.try_iter().collect();
+    assert_eq!(
+        requests,
+        vec![
+            (
+                "/rest/api/1.0/projects/LEG/repos?limit=100&start=0".to_string(),
+                Some("Basic YWxpY2U6YXBwLXBhc3N3b3Jk".to_string())
+            ),
+            (
+                "/rest/api/1.0/projects/LEG/repos?limit=100&start=1".to_string(),
+                Some("Basic YWxpY2U6YXBwLXBhc3N3b3Jk".to_string())
+            ),
+        ]
+    );
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn pushgateway() -> Result<(), Box<dyn std::error::Error>> {
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let port = server.server_addr().to_ip().unwrap().port();
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for mut request in server.incoming_requests() {
+            let mut body = String::new();
+            request.as_reader().read_to_string(&mut body).unwrap();
+            tx.send((
+                request.method().to_string(),
+                request.url().to_string(),
+                body,
+            ))
+            .unwrap();
+            request.respond(tiny_http::Response::empty(200)).unwrap();
+        }
+    });
+
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    let destination = tmp.path().join("destination");
+    fs::create_dir(&origin)?;
+    fs::create_dir(&destination)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    git(&destination, &["init", "-q", "--bare"]);
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!(
+            "{{\"origin\": {origin:?}, \"destination\": {destination:?}}}\n\
+             {{\"origin\": {:?}, \"destination\": {destination:?}}}\n",
+            tmp.path().join("missing")
+        ),
+    )?;
+
+    Command::cargo_bin("git-mirror")?
+        .args(["--provider", "External", "--provider-command"])
+        .arg(format!("cat {list:?}"))
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .arg("--pushgateway-url")
+        .arg(format!("http://127.0.0.1:{port}/"))
+        .assert()
+        .success();
+
+    let (method, url, body) = rx.recv_timeout(std::time::Duration::from_secs(10))?;
+    assert_eq!(method, "PUT");
+    // `git-mirror` in URL safe base64, grouped by the label of the mirror after it
+    assert!(
+        url.starts_with("/metrics/job@base64/Z2l0LW1pcnJvcg==/mirror@base64/"),
+        "{url}"
+    );
+    let series = |name: &str, origin: &Path, extra: &str| {
+        format!(
+            "{name}{{destination=\"{}\",mirror=\"external/cat \\\"{}\\\"\",origin=\"{}\"{extra}}}",
+            destination.display(),
+            list.display(),
+            origin.display()
+        )
+    };
+    for expected in [
+        series("git_mirror_project_result", &origin, ",result=\"ok\"") + " 1",
+        series(
+            "git_mirror_project_result",
+            &tmp.path().join("missing"),
+            ",result=\"failed\"",
+        ) + " 1",
+        series("git_mirror_project_duration_seconds", &origin, ""),
+        series("git_mirror_project_last_success", &origin, ""),
+    ] {
+        assert!(body.contains(&expected), "{expected}: {body}");
+    }
+    assert!(!body.contains(&series(
+        "git_mirror_project_last_success",
+        &tmp.path().join("missing"),
+        ""
+    )));
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn bundle_target() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    fs::create_dir(&origin)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!(
+            "{{\"origin\": {origin:?}, \"destination\": \"git@example.com:group/project.git\"}}\n"
+        ),
+    )?;
+    let bundle_dir = tmp.path().join("bundles");
+    let run = || -> Result<(), Box<dyn std::error::Error>> {
+        Command::cargo_bin("git-mirror")?
+            .args(["--provider", "External", "--provider-command"])
+            .arg(format!("cat {list:?}"))
+            .arg("--mirror-dir")
+            .arg(tmp.path().join("mirror-dir"))
+            .args(["--target", "bundle", "--bundle-dir"])
+            .arg(&bundle_dir)
+            .assert()
+            .success()
+            .stdout(predicate::str::contains("END(OK)"));
+        Ok(())
+    };
+    let bundles = || -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(bundle_dir.join("group/project"))
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    };
+
+    run()?;
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "second"]);
+    git(&origin, &["branch", "feature"]);
+    run()?;
+    // Nothing changed, nothing to bundle
+    run()?;
+    let names = bundles();
+    assert_eq!(names.len(), 2, "{names:?}");
+    assert!(names[0].ends_with("-full.bundle"), "{names:?}");
+    assert!(names[1].ends_with("-incremental.bundle"), "{names:?}");
+
+    // The bundles restore the repository in their order
+    let restored = tmp.path().join("restored");
+    fs::create_dir(&restored)?;
+    git(&restored, &["init", "-q", "--bare"]);
+    for name in names {
+        let bundle = bundle_dir.join("group/project").join(name);
+        git(
+            &restored,
+            &["fetch", "-q", bundle.to_str().unwrap(), "refs/*:refs/*"],
+        );
+    }
+    let tip = |dir: &Path, rev: &str| {
+        let out = Command::new("git")
+            .current_dir(dir)
+            .args(["rev-parse", rev])
+            .output()
+            .unwrap();
+        String::from_utf8(out.stdout).unwrap()
+    };
+    assert_eq!(tip(&restored, "main"), tip(&origin, "main"));
+    assert_eq!(tip(&restored, "feature"), tip(&origin, "main"));
+
+    // The bundles need all objects
+    Command::cargo_bin("git-mirror")?
+        .args(["-g", "mirror-test", "--target", "bundle", "--bundle-dir"])
+        .arg(&bundle_dir)
+        .args(["--partial-clone", "blobless"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "--partial-clone isn't supported with --target bundle",
+        ));
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn s3_target() -> Result<(), Box<dyn std::error::Error>> {
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let port = server.server_addr().to_ip().unwrap().port();
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for mut request in server.incoming_requests() {
+            let mut body = Vec::new();
+            request.as_reader().read_to_end(&mut body).unwrap();
+            let authorization = request
+                .headers()
+                .iter()
+                .find(|h| h.field.equiv("Authorization"))
+                .map(|h| h.value.to_string());
+            tx.send((
+                request.method().to_string(),
+                request.url().to_string(),
+                authorization,
+                body,
+            ))
+            .unwrap();
+            request.respond(tiny_http::Response::empty(200)).unwrap();
+        }
+    });
+
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    fs::create_dir(&origin)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!("{{\"origin\": {origin:?}, \"destination\": \"https://example.com/group/project.git\"}}\n"),
+    )?;
+
+    Command::cargo_bin("git-mirror")?
+        .args(["--provider", "External", "--provider-command"])
+        .arg(format!("cat {list:?}"))
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .args(["--target", "s3://bucket/mirrors"])
+        .arg("--s3-endpoint")
+        .arg(format!("http://127.0.0.1:{port}"))
+        .args([
+            "--s3-access-key-id",
+            "AKID",
+            "--s3-secret-access-key",
+            "secret",
+        ])
+        .env_remove("AWS_SESSION_TOKEN")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("END(OK)"));
+
+    let (method, url, authorization, body) = rx.recv_timeout(std::time::Duration::from_secs(10))?;
+    assert_eq!(method, "PUT");
+    assert!(url.starts_with("/bucket/mirrors/group/project/"), "{url}");
+    assert!(url.ends_with("-full.bundle"), "{url}");
+    let authorization = authorization.unwrap_or_default();
+    assert!(
+        authorization.starts_with("AWS4-HMAC-SHA256 Credential=AKID/"),
+        "{authorization}"
+    );
+    assert!(body.starts_with(b"# v2 git bundle\n"));
+    // Without --bundle-dir the uploaded bundle isn't kept
+    for repo in fs::read_dir(tmp.path().join("mirror-dir"))? {
+        let repo = repo?.path();
+        assert!(!repo.join(".git-mirror-bundle.tmp").exists(), "{repo:?}");
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn missing_wiki() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let dest = tmp.path().join("dest");
+    let mut entries = String::new();
+    for name in ["with-wiki", "without-wiki"] {
+        let origin = tmp.path().join(name);
+        let destination = dest.join(format!("{name}.git"));
+        fs::create_dir(&origin)?;
+        fs::create_dir_all(&destination)?;
+        git(&origin, &["init", "-q", "-b", "main"]);
+        git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+        git(&destination, &["init", "-q", "--bare"]);
+        entries.push_str(&format!(
+            "{{\"origin\": {origin:?}, \"destination\": {destination:?}, \"has_wiki\": true}}\n"
+        ));
+    }
+    // Only the first project has a wiki repository
+    let wiki = tmp.path().join("with-wiki.wiki.git");
+    let dest_wiki = dest.join("with-wiki.wiki.git");
+    fs::create_dir(&wiki)?;
+    fs::create_dir(&dest_wiki)?;
+    git(&wiki, &["init", "-q", "-b", "main"]);
+    git(&wiki, &["commit", "-q", "--allow-empty", "-m", "home"]);
+    git(&dest_wiki, &["init", "-q", "--bare"]);
+    let list = tmp.path().join("list.jsonl");
+    fs::write(&list, entries)?;
+
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "External", "--provider-command"])
+        .arg(format!("cat {list:?}"))
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .args(["--include-wikis", "--fail-on-sync-error"]);
+    let output = cmd.output()?;
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(output.status.success(), "{stdout}");
+    assert!(stdout.contains("SKIPPED: 1 no_wiki"), "{stdout}");
+    // The existing wiki is mirrored
+    git(
+        &dest_wiki,
+        &["rev-parse", "--verify", "-q", "refs/heads/main"],
+    );
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn tolerated_rejections() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    let destination = tmp.path().join("destination");
+    fs::create_dir(&origin)?;
+    fs::create_dir(&destination)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    git(&origin, &["branch", "protected"]);
+    git(&destination, &["init", "-q", "--bare"]);
+    let hook = destination.join("hooks").join("update");
+    fs::write(&hook, "#!/bin/sh\ntest \"$1\" != refs/heads/protected\n")?;
+    fs::set_permissions(&hook, fs::Permissions::from_mode(0o755))?;
+
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!("{{\"origin\": {origin:?}, \"destination\": {destination:?}}}\n"),
+    )?;
+
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "External", "--provider-command"])
+        .arg(format!("cat {list:?}"))
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .args(["--tolerate-rejected-refs", "--annotate-sync"]);
+    cmd.assert().success().stdout(predicate::str::contains(
+        "(partial, rejected: refs/heads/protected)",
+    ));
+
+    // The rest of the sync still ran
+    git(
+        &destination,
+        &["rev-parse", "--verify", "-q", "refs/heads/main"],
+    );
+    git(
+        &destination,
+        &["rev-parse", "--verify", "-q", "refs/mirror-meta/last-sync"],
+    );
+
+    Ok(())
+}
+
+#[test]
+fn print_config() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.env("PRIVATE_TOKEN", "secret-token")
+        .args(["-g", "mirror-test", "-c", "4"])
+        .args(["--dest-private-token", "dest-secret-token"])
+        .args(["--api-header", "X-Api-Key=secret-key", "--print-config"]);
+    let output = cmd.output()?;
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(!stdout.contains("secret-token") && !stdout.contains("secret-key"));
+
+    let config: serde_json::Value = serde_json::from_str(&stdout)?;
+    assert_eq!(config["provider"], "https://gitlab.com/mirror-test");
+    let options = &config["options"];
+    assert_eq!(options["private-token"]["source"], "environment");
+    assert_eq!(options["private-token"]["value"], "***");
+    assert_eq!(options["dest-private-token"]["source"], "command line");
+    assert_eq!(options["dest-private-token"]["value"], "***");
+    assert_eq!(options["api-header"]["value"][0], "X-Api-Key=***");
+    assert_eq!(options["worker-count"]["value"], "4");
+    assert_eq!(options["worker-count"]["source"], "command line");
+    assert_eq!(options["mirror-dir"]["source"], "default");
+    assert_eq!(options["dry-run"]["value"], false);
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn staged_sync() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    let destination = tmp.path().join("destination");
+    fs::create_dir(&origin)?;
+    fs::create_dir(&destination)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    git(&destination, &["init", "-q", "--bare"]);
+
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!(
+            "{{\"origin\": {:?}, \"destination\": {:?}}}\n",
+            origin, destination
+        ),
+    )?;
+    let stage = |mirror_dir: &str, stage: &str| -> Result<_, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("git-mirror")?;
+        cmd.args(["--provider", "External", "--provider-command"])
+            .arg(format!("cat {list:?}"))
+            .arg("--mirror-dir")
+            .arg(tmp.path().join(mirror_dir))
+            .args(["--stage", stage])
+            .arg("--fail-on-sync-error");
+        Ok(cmd.assert())
+    };
+    let head = |dir: &Path| {
+        let output = Command::new("git")
+            .args(["rev-parse", "main"])
+            .current_dir(dir)
+            .output()
+            .unwrap();
+        String::from_utf8_lossy(&output.stdout).trim().to_string()
+    };
+
+    stage("mirror-dir", "fetch")?
+        .success()
+        .stdout(predicate::str::contains("(fetched, not pushed)"));
+    assert!(!destination.join("refs/heads/main").exists());
+
+    // The push stage pushes what was fetched before
+    let fetched = head(&origin);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "second"]);
+    stage("mirror-dir", "push")?.success();
+    assert_eq!(head(&destination), fetched);
+
+    stage("other-mirror-dir", "push")?
+        .failure()
+        .stdout(predicate::str::contains("not fetched yet"));
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn fsck_corrupt_origin() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    let destination = tmp.path().join("destination");
+    fs::create_dir(&origin)?;
+    fs::create_dir(&destination)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    fs::write(origin.join("file"), "content")?;
+    git(&origin, &["add", "file"]);
+    git(&origin, &["commit", "-q", "-m", "initial"]);
+    git(&destination, &["init", "-q", "--bare"]);
+
+    // Replace the blob with a different valid object
+    let blob = Command::new("git")
+        .args(["rev-parse", "HEAD:file"])
+        .current_dir(&origin)
+        .output()?;
+    let blob = String::from_utf8(blob.stdout)?;
+    let path = origin
+        .join(".git/objects")
+        .join(&blob[..2])
+        .join(blob[2..].trim());
+    let other = tmp.path().join("other");
+    fs::create_dir(&other)?;
+    git(&other, &["init", "-q"]);
+    fs::write(other.join("file"), "other")?;
+    git(&other, &["add", "file"]);
+    let other_blob = Command::new("git")
+        .args(["rev-parse", ":file"])
+        .current_dir(&other)
+        .output()?;
+    let other_blob = String::from_utf8(other_blob.stdout)?;
+    let other_path = other
+        .join(".git/objects")
+        .join(&other_blob[..2])
+        .join(other_blob[2..].trim());
+    fs::remove_file(&path)?;
+    fs::copy(other_path, &path)?;
+
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!(
+            "{{\"origin\": {:?}, \"destination\": {:?}}}\n",
+            origin, destination
+        ),
+    )?;
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "External", "--provider-command"])
+        .arg(format!("cat {list:?}"))
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .arg("--fsck")
+        .arg("--fail-on-sync-error");
+    cmd.assert()
+        .failure()
+        .stdout(predicate::str::contains("is corrupt"));
+    assert!(!destination.join("refs/heads/main").exists());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn branch_patterns() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    fs::create_dir(&origin)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    for branch in ["feature/x", "release/1", "release/2"] {
+        git(&origin, &["branch", branch]);
+    }
+
+    let global = tmp.path().join("global");
+    let own = tmp.path().join("own");
+    for destination in [&global, &own] {
+        fs::create_dir(destination)?;
+        git(destination, &["init", "-q", "--bare"]);
+    }
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!(
+            "{{\"origin\": {:?}, \"destination\": {:?}}}\n\
+             {{\"origin\": {:?}, \"destination\": {:?}, \"branches\": [\"release/*\"]}}\n",
+            origin, global, origin, own
+        ),
+    )?;
+
+    // The branches of the repository replace the global ones
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "External", "--provider-command"])
+        .arg(format!("cat {list:?}"))
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .args(["--local-dir-name", "destination", "--branch", "main"])
+        .arg("--fail-on-sync-error");
+    cmd.assert().success();
+
+    let branches = |dir: &Path| {
+        let output = Command::new("git")
+            .args(["for-each-ref", "--format=%(refname:short)", "refs/heads"])
+            .current_dir(dir)
+            .output()
+            .unwrap();
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+    assert_eq!(branches(&global), "main\n");
+    assert_eq!(branches(&own), "release/1\nrelease/2\n");
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn tag_patterns() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    let destination = tmp.path().join("destination");
+    fs::create_dir(&origin)?;
+    fs::create_dir(&destination)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    for branch in ["feature/x", "release/1", "release/2", "release/old"] {
+        git(&origin, &["branch", branch]);
+    }
+    for tag in ["v1", "nightly-1"] {
+        git(&origin, &["tag", tag]);
+    }
+    git(&destination, &["init", "-q", "--bare"]);
+    // Only on the destination, but skipped
+    git(
+        &origin,
+        &["push", "-q", destination.to_str().unwrap(), "release/old"],
+    );
+    git(&origin, &["branch", "-q", "-D", "release/old"]);
+
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!(
+            "{{\"origin\": {:?}, \"destination\": {:?}}}\n",
+            origin, destination
+        ),
+    )?;
+    let sync = || -> Result<(), Box<dyn std::error::Error>> {
+        Command::cargo_bin("git-mirror")?
+            .args(["--provider", "External", "--provider-command"])
+            .arg(format!("cat {list:?}"))
+            .arg("--mirror-dir")
+            .arg(tmp.path().join("mirror-dir"))
+            .args(["--mirror-branches", "release/*", "--mirror-tags", "v*"])
+            .args(["--skip-refs", "refs/heads/release/old"])
+            .arg("--fail-on-sync-error")
+            .assert()
+            .success();
+        Ok(())
+    };
+    let refs = || {
+        let output = Command::new("git")
+            .args(["for-each-ref", "--format=%(refname)"])
+            .current_dir(&destination)
+            .output()
+            .unwrap();
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+
+    sync()?;
+    assert_eq!(
+        refs(),
+        "refs/heads/release/1\nrefs/heads/release/2\nrefs/heads/release/old\nrefs/tags/v1\n"
+    );
+
+    // Deleted on the destination as it matches a pattern
+    git(&origin, &["branch", "-q", "-D", "release/2"]);
+    sync()?;
+    assert_eq!(
+        refs(),
+        "refs/heads/release/1\nrefs/heads/release/old\nrefs/tags/v1\n"
+    );
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn signature_verification() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if Command::new("gpg").arg("--version").output().is_err() {
+        eprintln!("gpg not installed, skipping");
+        return Ok(());
+    }
+    let tmp = tempfile::tempdir()?;
+    let gpg_home = tmp.path().join("gnupg");
+    fs::create_dir(&gpg_home)?;
+    fs::set_permissions(&gpg_home, fs::Permissions::from_mode(0o700))?;
+    let status = Command::new("gpg")
+        .env("GNUPGHOME", &gpg_home)
+        .args(["--batch", "--passphrase", "", "--quick-gen-key"])
+        .args([
+            "Mirror Test <mirror@example.com>",
+            "ed25519",
+            "sign",
+            "never",
+        ])
+        .output()?
+        .status;
+    assert!(status.success());
+
+    let origin = tmp.path().join("origin");
+    let destination = tmp.path().join("destination");
+    fs::create_dir(&origin)?;
+    fs::create_dir(&destination)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    let status = Command::new("git")
+        .current_dir(&origin)
+        .env("GNUPGHOME", &gpg_home)
+        .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+        .args(["-c", "user.signingkey=mirror@example.com"])
+        .args(["commit", "-q", "-S", "--allow-empty", "-m", "signed"])
+        .status()?;
+    assert!(status.success());
+    git(&origin, &["checkout", "-q", "-b", "feature"]);
+    git(
+        &origin,
+        &["commit", "-q", "--allow-empty", "-m", "unsigned"],
+    );
+    git(&destination, &["init", "-q", "--bare"]);
+
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!(
+            "{{\"origin\": {:?}, \"destination\": {:?}}}\n",
+            origin, destination
+        ),
+    )?;
+    let run = |check: &str| -> Result<_, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("git-mirror")?;
+        cmd.args(["--provider", "External", "--provider-command"])
+            .arg(format!("cat {list:?}"))
+            .arg("--mirror-dir")
+            .arg(tmp.path().join("mirror-dir"))
+            .arg(check)
+            .arg("--gpg-home")
+            .arg(&gpg_home)
+            .arg("--fail-on-sync-error");
+        Ok(cmd.assert())
+    };
+
+    run("--require-signatures")?
+        .failure()
+        .stdout(predicate::str::contains("refs/heads/feature (unsigned)"))
+        .stdout(predicate::str::contains("refs/heads/main").not());
+    assert!(!destination.join("refs/heads/main").exists());
+
+    // Only a warning
+    run("--verify-signatures")?.success();
+    assert!(destination.join("refs/heads/feature").exists());
+
+    let _ = Command::new("gpgconf")
+        .env("GNUPGHOME", &gpg_home)
+        .args(["--kill", "gpg-agent"])
+        .status();
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn dest_case_collision() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    for name in ["MyRepo", "myrepo"] {
+        let origin = tmp.path().join("origin").join(name);
+        fs::create_dir_all(&origin)?;
+        git(&origin, &["init", "-q", "-b", name]);
+        git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    }
+    let destination = tmp.path().join("dest/myrepo");
+    fs::create_dir_all(&destination)?;
+    git(&destination, &["init", "-q", "--bare"]);
+
+    // Relative to the local repositories, the case of the temporary directory stays
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!(
+            "{{\"origin\": {:?}, \"destination\": \"../../dest/MyRepo\"}}\n\
+             {{\"origin\": {:?}, \"destination\": \"../../dest/myrepo\"}}\n",
+            tmp.path().join("origin/MyRepo"),
+            tmp.path().join("origin/myrepo"),
+        ),
+    )?;
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "External", "--provider-command"])
+        .arg(format!("cat {list:?}"))
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .args(["--dest-case", "lower", "--fail-on-sync-error"]);
+    cmd.assert().failure().stdout(predicate::str::contains(
+        "Destination ../../dest/myrepo collides with the destination of",
+    ));
+    // The first listed repository keeps the destination
+    assert!(destination.join("refs/heads/MyRepo").exists());
+    assert!(!destination.join("refs/heads/myrepo").exists());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn baseline_diff() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let mut entries = String::new();
+    for name in ["a", "b"] {
+        let origin = tmp.path().join("origin").join(name);
+        let destination = tmp.path().join("destination").join(name);
+        fs::create_dir_all(&origin)?;
+        fs::create_dir_all(&destination)?;
+        git(&origin, &["init", "-q", "-b", "main"]);
+        git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+        git(&destination, &["init", "-q", "--bare"]);
+        entries.push_str(&format!(
+            "{{\"origin\": {:?}, \"destination\": {:?}}}\n",
+            origin, destination
+        ));
+    }
+    let list = tmp.path().join("list.jsonl");
+    fs::write(&list, entries)?;
+    let baseline = tmp.path().join("baseline.out");
+    let diff = tmp.path().join("diff.json");
+    let run = || -> Result<_, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("git-mirror")?;
+        cmd.args(["--provider", "External", "--provider-command"])
+            .arg(format!("cat {list:?}"))
+            .arg("--mirror-dir")
+            .arg(tmp.path().join("mirror-dir"))
+            .args(["--summary-format", "json", "--baseline-report"])
+            .arg(&baseline)
+            .arg("--diff-report")
+            .arg(&diff);
+        Ok(cmd.output()?)
+    };
+
+    // Without a baseline yet
+    let output = run()?;
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout.clone())?
+        .contains("DIFF: 0 newly failed, 0 newly succeeded, 0 disappeared"));
+    fs::write(&baseline, output.stdout)?;
+
+    fs::remove_dir_all(tmp.path().join("origin/a"))?;
+    let output = run()?;
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(stdout.contains("DIFF: 1 newly failed, 0 newly succeeded, 0 disappeared"));
+    let failed = format!(
+        "{} -> {}",
+        tmp.path().join("origin/a").display(),
+        tmp.path().join("destination/a").display()
+    );
+    assert!(stdout.contains(&format!("NEWLY FAILED: {failed}")));
+    let diff: serde_json::Value = serde_json::from_str(&fs::read_to_string(&diff)?)?;
+    assert_eq!(diff["newly_failed"], serde_json::json!([failed]));
+
+    Ok(())
+}
+
+#[test]
+fn listing_retry() -> Result<(), Box<dyn std::error::Error>> {
+    // Answers with the given statuses in turn, then with an empty listing
+    let serve = |statuses: Vec<u16>| {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let port = server.server_addr().to_ip().unwrap().port();
+        let handle = std::thread::spawn(move || {
+            let mut requests = 0;
+            for status in statuses.into_iter().chain([200]) {
+                let request = server.recv().unwrap();
+                requests += 1;
+                let body = if status == 200 { "[]" } else { "{}" };
+                request
+                    .respond(tiny_http::Response::from_string(body).with_status_code(status))
+                    .unwrap();
+                if status == 401 {
+                    break;
+                }
+            }
+            requests
+        });
+        (port, handle)
+    };
+    let run = |port: u16| -> Result<_, Box<dyn std::error::Error>> {
+        let tmp = tempfile::tempdir()?;
+        let mut cmd = Command::cargo_bin("git-mirror")?;
+        cmd.args(["--provider", "GitHub", "--group", "mirror-test", "--url"])
+            .arg(format!("http://127.0.0.1:{port}"))
+            .arg("--mirror-dir")
+            .arg(tmp.path().join("mirror-dir"))
+            .args(["--allow-empty", "--list-retry-count", "2"])
+            .args(["--list-retry-delay", "10ms"]);
+        Ok(cmd.assert())
+    };
+
+    let (port, server) = serve(vec![503, 502]);
+    run(port)?.success();
+    assert_eq!(server.join().unwrap(), 3);
+
+    // Rejected credentials fail without retry
+    let (port, server) = serve(vec![401]);
+    run(port)?
+        .failure()
+        .stderr(predicate::str::contains("401 Unauthorized"));
+    assert_eq!(server.join().unwrap(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn listing_cache() -> Result<(), Box<dyn std::error::Error>> {
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let port = server.server_addr().to_ip().unwrap().port();
+    // Answers with the listing and its ETag, or 304 if the client has it already
+    let handle = std::thread::spawn(move || {
+        let mut statuses = Vec::new();
+        for _ in 0..2 {
+            let request = server.recv().unwrap();
+            let cached = request
+                .headers()
+                .iter()
+                .any(|h| h.field.equiv("If-None-Match") && h.value == "\"v1\"");
+            let etag = tiny_http::Header::from_bytes("ETag", "\"v1\"").unwrap();
+            let response = if cached {
+                tiny_http::Response::from_string("").with_status_code(304)
+            } else {
+                tiny_http::Response::from_string(
+                    r#"[{"id": 1, "full_name": "mirror-test/repo", "description": "origin: https://example.com/repo.git",
+                        "url": "https://example.com/mirror-test/repo", "ssh_url": "git@example.com:mirror-test/repo.git",
+                        "clone_url": "https://example.com/mirror-test/repo.git"}]"#,
+                )
+            };
+            let response = response.with_header(etag);
+            statuses.push(response.status_code().0);
+            request.respond(response).unwrap();
+        }
+        statuses
+    });
+
+    let tmp = tempfile::tempdir()?;
+    let cache = tmp.path().join("listing-cache.json");
+    for _ in 0..2 {
+        let mut cmd = Command::cargo_bin("git-mirror")?;
+        cmd.args(["--provider", "GitHub", "--group", "mirror-test", "--url"])
+            .arg(format!("http://127.0.0.1:{port}"))
+            .arg("--mirror-dir")
+            .arg(tmp.path().join("mirror-dir"))
+            .arg("--listing-cache")
+            .arg(&cache)
+            .arg("--validate-config");
+        cmd.assert().success().stdout(predicate::str::contains(
+            "VALID https://example.com/repo.git -> git@example.com:mirror-test/repo.git",
+        ));
+    }
+    assert_eq!(handle.join().unwrap(), [200, 304]);
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn push_batches() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    let destination = tmp.path().join("destination");
+    fs::create_dir(&origin)?;
+    fs::create_dir(&destination)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    // Hardly compressible content, a unique line per commit
+    let mut content = String::new();
+    for i in 0..30u64 {
+        content.push_str(&format!("{:x}\n", i.wrapping_mul(0x9e37_79b9_7f4a_7c15)));
+        fs::write(origin.join(format!("file{i}")), content.repeat(4))?;
+        git(&origin, &["add", "."]);
+        git(&origin, &["commit", "-q", "-m", &format!("commit {i}")]);
+    }
+    git(&destination, &["init", "-q", "--bare"]);
+    // The complete history exceeds the limit, a batch of 5 commits doesn't
+    git(&destination, &["config", "receive.maxInputSize", "4000"]);
+
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!(
+            "{{\"origin\": {:?}, \"destination\": {:?}}}\n",
+            origin, destination
+        ),
+    )?;
+    let run = |args: &[&str]| -> Result<_, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("git-mirror")?;
+        cmd.args(["--provider", "External", "--provider-command"])
+            .arg(format!("cat {list:?}"))
+            .arg("--mirror-dir")
+            .arg(tmp.path().join("mirror-dir"))
+            .arg("--fail-on-sync-error")
+            .args(args);
+        Ok(cmd.assert())
+    };
+
+    run(&[])?.failure();
+    assert!(!destination.join("refs/heads/main").exists());
+
+    run(&["--push-batch", "5"])?.success();
+    let head = |dir: &Path| {
+        let out = Command::new("git")
+            .args(["rev-parse", "main"])
+            .current_dir(dir)
+            .output()
+            .unwrap();
+        String::from_utf8(out.stdout).unwrap()
+    };
+    assert_eq!(head(&destination), head(&origin));
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn repo_timeout() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    fs::create_dir(&origin)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    for dest in ["small", "big"] {
+        git(tmp.path(), &["init", "-q", "--bare", dest]);
+    }
+    // Every clone and fetch takes 2 seconds
+    let slow_git = tmp.path().join("slow-git");
+    fs::write(
+        &slow_git,
+        "#!/bin/sh\ncase \" $* \" in *\" clone \"*|*\" fetch \"*) sleep 2 ;; esac\nexec git \"$@\"\n",
+    )?;
+    fs::set_permissions(&slow_git, fs::Permissions::from_mode(0o755))?;
+
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!(
+            "{{\"origin\": {0:?}, \"destination\": {1:?}}}\n\
+             {{\"origin\": {0:?}, \"destination\": {2:?}, \"size\": {3}}}\n",
+            origin,
+            tmp.path().join("small"),
+            tmp.path().join("big"),
+            // 4 GiB
+            4u64 << 30
+        ),
+    )?;
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "External", "--provider-command"])
+        .arg(format!("cat {list:?}"))
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .arg("--git-executable")
+        .arg(&slow_git)
+        .args(["--repo-timeout", "500ms", "--repo-timeout-per-gb", "2s"])
+        .args(["--retries", "2", "--retry-backoff", "1s"]);
+    let output = cmd.output()?;
+    let stdout = String::from_utf8(output.stdout)?;
+    let stderr = String::from_utf8(output.stderr)?;
+    // Repositories of unknown size get the flat timeout, the retries share it
+    assert!(stdout.contains("FAILURES: 1 timeout"), "{}", stdout);
+    assert!(
+        stderr.contains("exceeded its time budget of 500ms"),
+        "{}",
+        stderr
+    );
+    assert!(!tmp.path().join("small/refs/heads/main").exists());
+    // The big one gets 8.5s
+    assert!(tmp.path().join("big/refs/heads/main").exists());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn transform_hook() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    let destination = tmp.path().join("destination");
+    fs::create_dir(&origin)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    git(&origin, &["branch", "secret"]);
+    git(tmp.path(), &["init", "-q", "--bare", "destination"]);
+
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!(
+            "{{\"origin\": {:?}, \"destination\": {:?}}}\n",
+            origin, destination
+        ),
+    )?;
+    let run = |hook: &str| -> Result<_, Box<dyn std::error::Error>> {
+        let mut cmd = Command::cargo_bin("git-mirror")?;
+        cmd.args(["--provider", "External", "--provider-command"])
+            .arg(format!("cat {list:?}"))
+            .arg("--mirror-dir")
+            .arg(tmp.path().join("mirror-dir"))
+            .arg("--fail-on-sync-error")
+            .args(["--transform-hook", hook]);
+        Ok(cmd.assert())
+    };
+
+    run("echo failed >&2; exit 3")?
+        .failure()
+        .stderr(predicate::str::contains("Transform hook failed"));
+    assert!(!destination.join("refs/heads/main").exists());
+
+    run("test \"$GIT_MIRROR_REPO\" = \"$PWD\" && git update-ref -d refs/heads/secret")?.success();
+    assert!(destination.join("refs/heads/main").exists());
+    assert!(!destination.join("refs/heads/secret").exists());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn transfer_stats() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    let destination = tmp.path().join("destination");
+    fs::create_dir(&origin)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    fs::write(origin.join("file"), "content")?;
+    git(&origin, &["add", "file"]);
+    git(&origin, &["commit", "-q", "-m", "initial"]);
+    git(tmp.path(), &["init", "-q", "--bare", "destination"]);
+
+    // A file URL, local clones copy the objects instead of transferring them
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!(
+            "{{\"origin\": \"file://{}\", \"destination\": {:?}}}\n",
+            origin.display(),
+            destination
+        ),
+    )?;
+
+    let metrics = tmp.path().join("metrics.prom");
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "External", "--provider-command"])
+        .arg(format!("cat {list:?}"))
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .arg("--metric-file")
+        .arg(&metrics)
+        .args(["--transfer-stats", "--summary-format", "json"])
+        .arg("--fail-on-sync-error");
+    let output = cmd.output()?;
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(stdout.contains("TRANSFER: received "), "{}", stdout);
+    let summary: serde_json::Value = serde_json::from_str(stdout.lines().last().unwrap())?;
+    let repo = &summary["repo_transfers"][0];
+    // Commit, tree and blob
+    assert_eq!(repo["objects_received"], 3, "{}", repo);
+    assert_eq!(repo["objects_sent"], 3, "{}", repo);
+    assert!(repo["bytes_sent"].as_u64().unwrap() > 0, "{}", repo);
+    assert!(repo["repo_bytes"].as_u64().unwrap() > 0, "{}", repo);
+    assert!(repo["fetch_secs"].as_f64().unwrap() > 0.0, "{}", repo);
+
+    let metrics = fs::read_to_string(&metrics)?;
+    assert!(
+        metrics.contains("git_mirror_fetch_seconds_count{"),
+        "{}",
+        metrics
+    );
+    assert!(
+        metrics.contains("git_mirror_push_seconds_bucket{"),
+        "{}",
+        metrics
+    );
+    assert!(
+        metrics.contains("git_mirror_objects_transferred{")
+            && metrics.contains("direction=\"sent\""),
+        "{}",
+        metrics
+    );
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn transports() -> Result<(), Box<dyn std::error::Error>> {
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let port = server.server_addr().to_ip().unwrap().port();
+    let handle = std::thread::spawn(move || {
+        let request = server.recv().unwrap();
+        let response = tiny_http::Response::from_string(
+            r#"[{"id": 1, "full_name": "mirror-test/a", "description": "origin: git@example.org:a.git",
+                "url": "https://example.com/mirror-test/a", "ssh_url": "git@example.com:mirror-test/a.git",
+                "clone_url": "https://example.com/mirror-test/a.git"},
+                {"id": 2, "full_name": "mirror-test/b", "description": "origin: git@example.org:b.git\norigin_transport: ssh\ndest_transport: http",
+                "url": "https://example.com/mirror-test/b", "ssh_url": "git@example.com:mirror-test/b.git",
+                "clone_url": "https://example.com/mirror-test/b.git"}]"#,
+        );
+        request.respond(response).unwrap();
+    });
+
+    let tmp = tempfile::tempdir()?;
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "GitHub", "--group", "mirror-test", "--url"])
+        .arg(format!("http://127.0.0.1:{port}"))
+        .arg("--mirror-dir")
+        .arg(tmp.path().join("mirror-dir"))
+        .args(["--origin-transport", "http", "--dest-transport", "ssh"])
+        .arg("--validate-config");
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "VALID https://example.org/a.git -> git@example.com:mirror-test/a.git",
+        ))
+        // Overridden by the project
+        .stdout(predicate::str::contains(
+            "VALID git@example.org:b.git -> https://example.com/mirror-test/b.git",
+        ));
+    handle.join().unwrap();
+
+    Ok(())
+}
+
+#[test]
+fn simple_listing() -> Result<(), Box<dyn std::error::Error>> {
+    // Answers every request with the same project and reports the requested URLs
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let port = server.server_addr().to_ip().unwrap().port();
+    let handle = std::thread::spawn(move || {
+        let mut urls = Vec::new();
+        for _ in 0..2 {
+            let request = server.recv().unwrap();
+            urls.push(request.url().to_string());
+            let response = tiny_http::Response::from_string(
+                r#"[{"id": 1, "name": "a", "description": "origin: https://example.org/a.git",
+                    "web_url": "https://example.com/mirror-test/a",
+                    "ssh_url_to_repo": "git@example.com:mirror-test/a.git",
+                    "http_url_to_repo": "https://example.com/mirror-test/a.git",
+                    "star_count": 0, "forks_count": 0, "topics": []}]"#,
+            );
+            request.respond(response).unwrap();
+        }
+        urls
+    });
+
+    for wikis in [false, true] {
+        let tmp = tempfile::tempdir()?;
+        let mut cmd = Command::cargo_bin("git-mirror")?;
+        cmd.args(["--provider", "GitLab", "--group", "mirror-test", "--url"])
+            .arg(format!("http://127.0.0.1:{port}"))
+            .args(["--namespace-type", "user", "--simple-listing"])
+            .arg("--mirror-dir")
+            .arg(tmp.path().join("mirror-dir"))
+            .arg("--validate-config");
+        if wikis {
+            // Needs the wiki flag of the full listing
+            cmd.arg("--include-wikis");
+        }
+        cmd.assert().success().stdout(predicate::str::contains(
+            "VALID https://example.org/a.git -> git@example.com:mirror-test/a.git",
+        ));
+    }
+    let urls = handle.join().unwrap();
+    assert!(urls[0].contains("simple=true"), "{}", urls[0]);
+    assert!(!urls[1].contains("simple=true"), "{}", urls[1]);
+
+    Ok(())
+}
+
+#[cfg(all(unix, feature = "sqlite"))]
+#[test]
+fn history_db() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    fs::create_dir(&origin)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    git(tmp.path(), &["init", "-q", "--bare", "destination"]);
+
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!(
+            "{{\"origin\": {:?}, \"destination\": {:?}}}\n\
+             {{\"origin\": {:?}, \"destination\": {:?}}}\n",
+            origin,
+            tmp.path().join("destination"),
+            tmp.path().join("missing"),
+            tmp.path().join("destination-2")
+        ),
+    )?;
+    let db = tmp.path().join("history.db");
+    for _ in 0..2 {
+        let mut cmd = Command::cargo_bin("git-mirror")?;
+        cmd.args(["--provider", "External", "--provider-command"])
+            .arg(format!("cat {list:?}"))
+            .arg("--mirror-dir")
+            .arg(tmp.path().join("mirror-dir"))
+            .arg("--history-db")
+            .arg(&db)
+            .args(["-c", "2"]);
+        cmd.assert().success();
+    }
+
+    let conn = rusqlite::Connection::open(&db)?;
+    let runs: Vec<(i64, i64, String)> = conn
+        .prepare("SELECT success, failed, exit_reason FROM runs ORDER BY id")?
+        .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))?
+        .collect::<Result<_, _>>()?;
+    assert_eq!(runs, [(1, 1, "ok".to_string()), (1, 1, "ok".to_string())]);
+    let (failures, kind): (i64, String) = conn.query_row(
+        "SELECT COUNT(*), failure_kind FROM repo_results WHERE status = 'failure'",
+        [],
+        |r| Ok((r.get(0)?, r.get(1)?)),
+    )?;
+    assert_eq!((failures, kind.as_str()), (2, "not_found"));
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn write_indexes() -> Result<(), Box<dyn std::error::Error>> {
+    let tmp = tempfile::tempdir()?;
+    let origin = tmp.path().join("origin");
+    fs::create_dir(&origin)?;
+    git(&origin, &["init", "-q", "-b", "main"]);
+    git(&origin, &["commit", "-q", "--allow-empty", "-m", "initial"]);
+    git(tmp.path(), &["init", "-q", "--bare", "destination"]);
+
+    // Fetched as a pack over the file protocol
+    let list = tmp.path().join("list.jsonl");
+    fs::write(
+        &list,
+        format!(
+            "{{\"origin\": \"file://{}\", \"destination\": {:?}}}\n",
+            origin.display(),
+            tmp.path().join("destination")
+        ),
+    )?;
+
+    let mirror_dir = tmp.path().join("mirror-dir");
+    let mut cmd = Command::cargo_bin("git-mirror")?;
+    cmd.args(["--provider", "External", "--provider-command"])
+        .arg(format!("cat {list:?}"))
+        .arg("--mirror-dir")
+        .arg(&mirror_dir)
+        .args(["--write-commit-graph", "--write-midx"])
+        .arg("--fail-on-sync-error");
+    cmd.assert().success();
+
+    let repo = fs::read_dir(&mirror_dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .find(|p| p.is_dir())
+        .expect("No local repository");
+    assert!(repo.join("objects/info/commit-graph").exists());
+    assert!(repo.join("objects/pack/multi-pack-index").exists());
+
+    Ok(())
+}